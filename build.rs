@@ -0,0 +1,231 @@
+// Collects build information for the `version` module, generates the
+// kernel symbol table for the `symbols` module and embeds the disk and
+// initrd images given at build time.
+//
+// Build information is passed to the kernel as `RUST_OS_*` environment
+// variables, which `src/version.rs` reads with `env!`. The symbol table is
+// written to `$OUT_DIR/symbols.rs` and `$OUT_DIR/symbols.bin`, see
+// `symbol_table`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    println!("cargo:rustc-env=RUST_OS_GIT_HASH={}", git_hash());
+    println!("cargo:rustc-env=RUST_OS_BUILD_TIME={}", build_time());
+    println!("cargo:rustc-env=RUST_OS_RUSTC_VERSION={}", rustc_version());
+    println!("cargo:rustc-env=RUST_OS_FEATURES={}", features());
+    println!("cargo:rustc-env=RUST_OS_PROFILE={}", env::var("PROFILE").unwrap_or_default());
+
+    println!("cargo:rerun-if-env-changed=RUST_OS_SYMBOLS");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR not set"));
+    let symbols = symbol_table(&out_dir);
+    fs::write(out_dir.join("symbols.rs"), symbols).expect("failed to write the symbol table");
+
+    println!("cargo:rerun-if-env-changed=RUST_OS_RAMDISK");
+    fs::write(out_dir.join("ramdisk.rs"), ramdisk_image()).expect("failed to write the RAM disk image");
+
+    println!("cargo:rerun-if-env-changed=RUST_OS_INITRD");
+    fs::write(out_dir.join("initrd.rs"), initrd_image()).expect("failed to write the initrd image");
+}
+
+// Run a command and return its trimmed output, if it succeeded
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// The abbreviated commit hash, with "-dirty" if there are local changes
+fn git_hash() -> String {
+    let hash = match command_output("git", &["rev-parse", "--short", "HEAD"]) {
+        Some(hash) => hash,
+        None => return "unknown".to_string(),
+    };
+    match command_output("git", &["status", "--porcelain", "--untracked-files=no"]) {
+        Some(status) if !status.is_empty() => format!("{}-dirty", hash),
+        _ => hash,
+    }
+}
+
+// The build time in UTC as "YYYY-MM-DD HH:MM:SS". Honors SOURCE_DATE_EPOCH
+// for reproducible builds.
+fn build_time() -> String {
+    let seconds = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0)
+        });
+
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    let time = seconds % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date, using Howard
+// Hinnant's algorithm
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The version of the compiler building the kernel
+fn rustc_version() -> String {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string())
+}
+
+// The enabled cargo features, comma separated
+fn features() -> String {
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_string))
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+    features.join(",")
+}
+
+// The size of the symbol table, whatever it holds
+const SYMBOL_TABLE_SIZE: usize = 256 * 1024;
+
+// The function symbols of the kernel ELF file named by RUST_OS_SYMBOLS, as
+// Rust source for `src/symbols.rs`. The kernel can't contain its own final
+// symbols, so they are taken from a previous build of the same source:
+//
+//     cargo build
+//     RUST_OS_SYMBOLS=target/x86_64-rust_os/debug/rust_os cargo build
+//
+// For the second build to keep the addresses of the first, embedding the
+// symbols must not change the code. So the table is a blob of a fixed size,
+// written to `$OUT_DIR/symbols.bin` and read by `src/symbols.rs` through a
+// pointer the optimizer can't see into: only its contents differ between
+// the builds. Without RUST_OS_SYMBOLS the table is empty.
+//
+// The blob is little endian: the number of functions as a u32, an entry of
+// 24 bytes for each, sorted by address (the address and size as u64s, the
+// offset and length of the name as u32s), then the concatenated names.
+fn symbol_table(out_dir: &Path) -> String {
+    let mut symbols = match env::var_os("RUST_OS_SYMBOLS") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", PathBuf::from(&path).display());
+            let data = fs::read(&path).expect("failed to read RUST_OS_SYMBOLS");
+            read_function_symbols(&data)
+        }
+        None => Vec::new(),
+    };
+    symbols.sort();
+    symbols.dedup_by_key(|(address, _, _)| *address);
+
+    let mut table = Vec::with_capacity(SYMBOL_TABLE_SIZE);
+    table.extend((symbols.len() as u32).to_le_bytes());
+    let mut names_len = 0;
+    for (address, size, name) in &symbols {
+        table.extend(address.to_le_bytes());
+        table.extend(size.to_le_bytes());
+        table.extend((names_len as u32).to_le_bytes());
+        table.extend((name.len() as u32).to_le_bytes());
+        names_len += name.len();
+    }
+    for (_, _, name) in &symbols {
+        table.extend(name.bytes());
+    }
+    assert!(
+        table.len() <= SYMBOL_TABLE_SIZE,
+        "the symbol table takes {} bytes, more than SYMBOL_TABLE_SIZE in build.rs",
+        table.len()
+    );
+    table.resize(SYMBOL_TABLE_SIZE, 0);
+
+    let path = out_dir.join("symbols.bin");
+    fs::write(&path, table).expect("failed to write the symbol table");
+    format!(
+        "const TABLE_SIZE: usize = {};\n\
+         #[link_section = \".data.rust_os_symbols\"]\n\
+         static TABLE: [u8; TABLE_SIZE] = *include_bytes!({:?});\n",
+        SYMBOL_TABLE_SIZE, path
+    )
+}
+
+// The disk image named by RUST_OS_RAMDISK, as Rust source for
+// `src/ramdisk.rs`. The image is included as a mutable static, so it ends up
+// in `.data` and the RAM disk can be written in place. Without
+// RUST_OS_RAMDISK the image is empty.
+fn ramdisk_image() -> String {
+    let path = match env::var_os("RUST_OS_RAMDISK") {
+        Some(path) => fs::canonicalize(path).expect("failed to find RUST_OS_RAMDISK"),
+        None => return "static mut IMAGE: [u8; 0] = [];\n".to_string(),
+    };
+    println!("cargo:rerun-if-changed={}", path.display());
+    let len = fs::metadata(&path).expect("failed to read RUST_OS_RAMDISK").len();
+    if len % 512 != 0 {
+        println!("cargo:warning=RUST_OS_RAMDISK is not a whole number of 512 byte blocks");
+    }
+    format!("static mut IMAGE: [u8; {}] = *include_bytes!({:?});\n", len, path)
+}
+
+// The archive named by RUST_OS_INITRD, as Rust source for `src/initrd.rs`.
+// It is only read, so unlike the RAM disk image it stays in `.rodata`.
+// Without RUST_OS_INITRD the archive is empty.
+fn initrd_image() -> String {
+    let path = match env::var_os("RUST_OS_INITRD") {
+        Some(path) => fs::canonicalize(path).expect("failed to find RUST_OS_INITRD"),
+        None => return "static IMAGE: [u8; 0] = [];\n".to_string(),
+    };
+    println!("cargo:rerun-if-changed={}", path.display());
+    let len = fs::metadata(&path).expect("failed to read RUST_OS_INITRD").len();
+    format!("static IMAGE: [u8; {}] = *include_bytes!({:?});\n", len, path)
+}
+
+// Return the (address, size, demangled name) of the functions in an ELF file
+fn read_function_symbols(data: &[u8]) -> Vec<(u64, u64, String)> {
+    use xmas_elf::sections::SectionData;
+    use xmas_elf::symbol_table::{Entry, Type};
+    use xmas_elf::ElfFile;
+
+    let elf = ElfFile::new(data).expect("RUST_OS_SYMBOLS is not an ELF file");
+    let mut symbols = Vec::new();
+    for section in elf.section_iter() {
+        if let Ok(SectionData::SymbolTable64(entries)) = section.get_data(&elf) {
+            for entry in entries {
+                if entry.get_type() != Ok(Type::Func) || entry.value() == 0 {
+                    continue;
+                }
+                if let Ok(name) = entry.get_name(&elf) {
+                    let name = format!("{:#}", rustc_demangle::demangle(name));
+                    symbols.push((entry.value(), entry.size(), name));
+                }
+            }
+        }
+    }
+    symbols
+}
@@ -0,0 +1,462 @@
+// ACPI table discovery and parsing.
+//
+// The firmware describes the machine in a set of tables. The RSDP, found in
+// the BIOS area, points to the RSDT (32 bit pointers) or, since ACPI 2.0, the
+// XSDT (64 bit pointers), which list every other table by its signature.
+// `init` finds the RSDP, validates the tables and keeps the parsed contents
+// of the ones the kernel uses:
+//
+//     MADT  the local APIC of every CPU, the IO-APICs and interrupt overrides
+//     FADT  power management and reset registers, see `fadt`
+//     HPET  where the HPET registers are mapped
+//     MCFG  the PCI Express ECAM regions
+//
+// The tables live in memory reserved by the firmware, which the bootloader's
+// physical memory mapping covers, so they are read through `phys_to_virt`.
+
+use crate::memory;
+use crate::pci::ecam::{self, McfgEntry};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use x86_64::PhysAddr;
+
+pub mod fadt;
+pub mod madt;
+
+pub use fadt::Fadt;
+pub use madt::Madt;
+
+// Signature of the RSDP and where it is searched
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const EBDA_POINTER: u64 = 0x40E; // Real mode segment of the EBDA
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+// Size of the ACPI 1.0 RSDP, covered by its first checksum, and of the
+// ACPI 2.0 one
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+// Size of the header every other table starts with
+pub const SDT_HEADER_SIZE: usize = 36;
+
+// Tables claiming to be bigger than this are rejected as corrupted
+const MAX_TABLE_SIZE: usize = 1024 * 1024;
+
+// Address spaces of a generic address structure
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+// The tables found by `init`
+static ACPI: OnceCell<Acpi> = OnceCell::uninit();
+
+// Errors from finding the ACPI tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadRsdp,
+    BadRootTable,
+}
+
+// The four character signature of a table
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 4]);
+
+impl Signature {
+    pub const MADT: Signature = Signature(*b"APIC");
+    pub const FADT: Signature = Signature(*b"FACP");
+    pub const HPET: Signature = Signature(*b"HPET");
+    pub const MCFG: Signature = Signature(*b"MCFG");
+    pub const DSDT: Signature = Signature(*b"DSDT");
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &byte in &self.0 {
+            let c = if byte.is_ascii_graphic() { byte as char } else { '?' };
+            write!(f, "{}", c)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"{}\"", self)
+    }
+}
+
+// A table listed by the RSDT or XSDT
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub signature: Signature,
+    pub address: PhysAddr,
+    pub length: usize,
+    pub revision: u8,
+}
+
+impl Table {
+    // Return the bytes of the table, including the header
+    pub fn bytes(&self) -> &'static [u8] {
+        unsafe { phys_bytes(self.address, self.length) }
+    }
+}
+
+// The register a generic address structure points to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8, // `ADDRESS_SPACE_MEMORY` or `ADDRESS_SPACE_IO`
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    // Read the 12 byte structure at `offset`; `None` if it is out of the
+    // table or zero
+    fn parse(bytes: &[u8], offset: usize) -> Option<GenericAddress> {
+        bytes.get(offset..offset + 12)?;
+        let address = read_u64(bytes, offset + 4);
+        if address == 0 {
+            return None;
+        }
+        Some(GenericAddress {
+            address_space: bytes[offset],
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address,
+        })
+    }
+}
+
+// The HPET table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub base_address: u64,
+    pub number: u8,
+    pub comparators: u8,
+    pub min_tick: u16, // Smallest periodic tick without lost interrupts
+}
+
+impl Hpet {
+    fn parse(bytes: &[u8]) -> Option<Hpet> {
+        let block_id = read_u32(bytes, SDT_HEADER_SIZE);
+        let base = GenericAddress::parse(bytes, SDT_HEADER_SIZE + 4)?;
+        if base.address_space != ADDRESS_SPACE_MEMORY {
+            return None;
+        }
+        Some(Hpet {
+            base_address: base.address,
+            number: *bytes.get(SDT_HEADER_SIZE + 16)?,
+            comparators: ((block_id >> 8) & 0x1F) as u8 + 1,
+            min_tick: read_u16(bytes, SDT_HEADER_SIZE + 17),
+        })
+    }
+}
+
+// Everything `init` found
+struct Acpi {
+    revision: u8,
+    oem_id: [u8; 6],
+    tables: Vec<Table>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Vec<McfgEntry>,
+}
+
+// Find and parse the ACPI tables.
+//
+// Bootloaders that know where the RSDP is can pass it; otherwise the EBDA
+// and the BIOS area are searched. Tables with a bad checksum are skipped.
+// Must be called after `memory::init` and once the heap is set up.
+pub fn init(rsdp: Option<PhysAddr>) -> Result<(), AcpiError> {
+    let rsdp = match rsdp {
+        Some(rsdp) => rsdp,
+        None => find_rsdp().ok_or(AcpiError::NoRsdp)?,
+    };
+    let acpi = parse(rsdp)?;
+
+    log::info!(
+        "ACPI {} by {}: {} tables",
+        if acpi.revision >= 2 { "2.0+" } else { "1.0" },
+        core::str::from_utf8(&acpi.oem_id).unwrap_or("?").trim_end(),
+        acpi.tables.len()
+    );
+    if let Some(madt) = &acpi.madt {
+        log::info!(
+            "ACPI: {} CPUs, {} IO-APICs",
+            madt.processors.iter().filter(|cpu| cpu.enabled).count(),
+            madt.io_apics.len()
+        );
+    }
+
+    ACPI.try_init_once(|| acpi)
+        .expect("acpi::init should only be called once");
+    Ok(())
+}
+
+// Return whether `init` found the ACPI tables
+pub fn is_available() -> bool {
+    ACPI.get().is_some()
+}
+
+// Return every table listed by the RSDT or XSDT
+pub fn tables() -> &'static [Table] {
+    ACPI.get().map_or(&[], |acpi| &acpi.tables)
+}
+
+// Return the first table with the given signature
+pub fn find_table(signature: Signature) -> Option<&'static Table> {
+    tables().iter().find(|table| table.signature == signature)
+}
+
+// Return the parsed tables, if the firmware has them
+pub fn madt() -> Option<&'static Madt> {
+    ACPI.get()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    ACPI.get()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    ACPI.get()?.hpet.as_ref()
+}
+
+// Return the ECAM regions of the MCFG table
+pub fn mcfg() -> &'static [McfgEntry] {
+    ACPI.get().map_or(&[], |acpi| &acpi.mcfg)
+}
+
+// Return the SLP_TYPa and SLP_TYPb values that put the machine into the S5
+// (soft off) state, from the `\_S5` object of the DSDT
+pub fn s5_sleep_types() -> Option<(u8, u8)> {
+    let dsdt = find_table(Signature::DSDT)?;
+    parse_s5(&dsdt.bytes()[SDT_HEADER_SIZE..])
+}
+
+// Find the `_S5_` package in AML code without interpreting it.
+//
+// The object is almost always a plain name definition near the top level,
+// `Name (_S5, Package () { typa, typb, ... })`, which compiles to:
+//
+//     NameOp '_S5_' PackageOp PkgLength NumElements typa typb
+//
+// where the values are either a BytePrefix constant or ZeroOp/OneOp.
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    const NAME_OP: u8 = 0x08;
+    const PACKAGE_OP: u8 = 0x12;
+    const BYTE_PREFIX: u8 = 0x0A;
+
+    let name = aml.windows(4).position(|window| window == b"_S5_")?;
+    // The name may be preceded by a root prefix
+    let defined = match name {
+        0 => false,
+        1 => aml[0] == NAME_OP,
+        _ => aml[name - 1] == NAME_OP || (aml[name - 1] == b'\\' && aml[name - 2] == NAME_OP),
+    };
+    if !defined || *aml.get(name + 4)? != PACKAGE_OP {
+        return None;
+    }
+
+    // The top two bits of the PkgLength lead byte count its extra bytes
+    let mut offset = name + 5;
+    offset += ((*aml.get(offset)? >> 6) + 1) as usize;
+    offset += 1; // NumElements
+
+    let mut value = || {
+        let mut byte = *aml.get(offset)?;
+        if byte == BYTE_PREFIX {
+            offset += 1;
+            byte = *aml.get(offset)?;
+        }
+        offset += 1;
+        Some(byte)
+    };
+    Some((value()?, value()?))
+}
+
+// Search the first KiB of the EBDA and the BIOS area for the RSDP
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_segment = unsafe { phys_bytes(PhysAddr::new(EBDA_POINTER), 2) };
+    let ebda = (read_u16(ebda_segment, 0) as u64) << 4;
+
+    // The RSDP is on a 16 byte boundary
+    let ebda_end = if ebda == 0 { 0 } else { ebda + EBDA_SEARCH_SIZE };
+    let areas = [(ebda, ebda_end), (BIOS_AREA_START, BIOS_AREA_END)];
+    areas.iter().find_map(|&(start, end)| {
+        (start..end).step_by(16).map(PhysAddr::new).find(|&address| {
+            let bytes = unsafe { phys_bytes(address, RSDP_V1_SIZE) };
+            &bytes[..8] == RSDP_SIGNATURE && checksum(bytes) == 0
+        })
+    })
+}
+
+// Read the RSDP and every table its root table lists
+fn parse(rsdp_address: PhysAddr) -> Result<Acpi, AcpiError> {
+    let rsdp = unsafe { phys_bytes(rsdp_address, RSDP_V1_SIZE) };
+    if &rsdp[..8] != RSDP_SIGNATURE || checksum(rsdp) != 0 {
+        return Err(AcpiError::BadRsdp);
+    }
+    let revision = rsdp[15];
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // ACPI 2.0 adds the XSDT, with a checksum over the longer structure
+    let xsdt = if revision >= 2 {
+        let rsdp = unsafe { phys_bytes(rsdp_address, RSDP_V2_SIZE) };
+        if checksum(rsdp) != 0 {
+            return Err(AcpiError::BadRsdp);
+        }
+        read_u64(rsdp, 24)
+    } else {
+        0
+    };
+    let (root, pointer_size) = match xsdt {
+        0 => (read_u32(rsdp, 16) as u64, 4),
+        xsdt => (xsdt, 8),
+    };
+
+    let root = validate(PhysAddr::new(root)).ok_or(AcpiError::BadRootTable)?;
+    let mut acpi = Acpi {
+        revision,
+        oem_id,
+        tables: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: Vec::new(),
+    };
+
+    for pointer in root.bytes()[SDT_HEADER_SIZE..].chunks_exact(pointer_size) {
+        let address = match pointer_size {
+            4 => read_u32(pointer, 0) as u64,
+            _ => read_u64(pointer, 0),
+        };
+        match validate(PhysAddr::new(address)) {
+            Some(table) => acpi.add(table),
+            None => log::warn!("ACPI: skipping invalid table at {:#x}", address),
+        }
+    }
+
+    // The DSDT isn't listed in the root table but by the FADT
+    if let Some(dsdt) = acpi.fadt.as_ref().map(|fadt| fadt.dsdt) {
+        match validate(PhysAddr::new(dsdt)) {
+            Some(table) if table.signature == Signature::DSDT => acpi.tables.push(table),
+            _ => log::warn!("ACPI: invalid DSDT at {:#x}", dsdt),
+        }
+    }
+
+    Ok(acpi)
+}
+
+impl Acpi {
+    // Record a table and parse it if it is one the kernel uses
+    fn add(&mut self, table: Table) {
+        let bytes = table.bytes();
+        match table.signature {
+            Signature::MADT if self.madt.is_none() => self.madt = Some(Madt::parse(bytes)),
+            Signature::FADT if self.fadt.is_none() => self.fadt = Some(Fadt::parse(bytes)),
+            Signature::HPET if self.hpet.is_none() => self.hpet = Hpet::parse(bytes),
+            Signature::MCFG => match ecam::parse_mcfg(bytes) {
+                Ok(entries) => self.mcfg.extend(entries),
+                Err(err) => log::warn!("ACPI: invalid MCFG: {:?}", err),
+            },
+            _ => {}
+        }
+        self.tables.push(table);
+    }
+}
+
+// Check the header and checksum of the table at `address`
+fn validate(address: PhysAddr) -> Option<Table> {
+    if address.as_u64() == 0 {
+        return None;
+    }
+    let header = unsafe { phys_bytes(address, SDT_HEADER_SIZE) };
+    let length = read_u32(header, 4) as usize;
+    if !(SDT_HEADER_SIZE..=MAX_TABLE_SIZE).contains(&length) {
+        return None;
+    }
+
+    let table = Table {
+        signature: Signature([header[0], header[1], header[2], header[3]]),
+        address,
+        length,
+        revision: header[8],
+    };
+    if checksum(table.bytes()) != 0 {
+        return None;
+    }
+    Some(table)
+}
+
+// Return the bytes at a physical address through the physical memory mapping.
+//
+// Unsafe because the memory must stay unchanged for as long as the slice is
+// used, which holds for the firmware's tables.
+unsafe fn phys_bytes(address: PhysAddr, length: usize) -> &'static [u8] {
+    let virt = memory::phys_to_virt(address);
+    core::slice::from_raw_parts(virt.as_ptr(), length)
+}
+
+// The bytes of ACPI structures sum up to 0
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+// Little endian reads that return 0 past the end of a table, since older
+// revisions of a table are shorter
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes
+        .get(offset..offset + 2)
+        .map_or(0, |b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes
+        .get(offset..offset + 4)
+        .map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    bytes.get(offset..offset + 8).map_or(0, |b| {
+        u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+    })
+}
+
+#[test_case]
+fn test_hpet_table() {
+    let mut table = [0u8; SDT_HEADER_SIZE + 20];
+    table[..4].copy_from_slice(b"HPET");
+    table[SDT_HEADER_SIZE + 1] = 0x2; // Three comparators
+    let address = SDT_HEADER_SIZE + 8;
+    table[address..address + 8].copy_from_slice(&0xFED0_0000u64.to_le_bytes());
+    table[SDT_HEADER_SIZE + 17] = 0x80;
+
+    let hpet = Hpet::parse(&table).unwrap();
+    assert_eq!(hpet.base_address, 0xFED0_0000);
+    assert_eq!(hpet.comparators, 3);
+    assert_eq!(hpet.min_tick, 0x80);
+
+    // Fields past the end of a short table read as 0
+    assert_eq!(read_u32(&table, table.len() - 2), 0);
+}
+
+#[test_case]
+fn test_parse_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x10, 0x08, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00,
+        0x00, 0x00,
+    ];
+    assert_eq!(parse_s5(&aml[2..]), Some((5, 0)));
+
+    // A reference to the name isn't a definition
+    assert_eq!(parse_s5(b"\x70_S5_\x12"), None);
+}
@@ -0,0 +1,110 @@
+// The Fixed ACPI Description Table (FADT, signature "FACP").
+//
+// It holds the fixed hardware registers of ACPI: the PM1 control block used
+// to enter sleep states, the power management timer, the reset register and
+// the SCI interrupt, as well as the address of the DSDT. The table has grown
+// with every ACPI revision, so fields past the end of an older, shorter table
+// read as 0.
+
+use super::{read_u16, read_u32, read_u64, GenericAddress};
+
+// Field offsets
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVENT_BLOCK: usize = 56;
+const PM1B_EVENT_BLOCK: usize = 60;
+const PM1A_CONTROL_BLOCK: usize = 64;
+const PM1B_CONTROL_BLOCK: usize = 68;
+const PM_TIMER_BLOCK: usize = 76;
+const CENTURY: usize = 108;
+const BOOT_ARCHITECTURE: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+
+// Flag set if the reset register is supported
+const FLAG_RESET_REGISTER: u32 = 1 << 10;
+
+// Boot architecture flag set if there is an 8042 keyboard controller
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+// The parsed FADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    pub smi_command: u32, // 0 if the system is always in ACPI mode
+    pub acpi_enable: u8,  // Written to `smi_command` to enter ACPI mode
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32, // I/O ports, 0 if not present
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub century_register: u8, // CMOS register of the century, 0 if none
+    pub boot_architecture: u16,
+    pub flags: u32,
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub(super) fn parse(bytes: &[u8]) -> Fadt {
+        // ACPI 2.0 tables have a 64 bit DSDT pointer which wins if it is set
+        let dsdt = match read_u64(bytes, X_DSDT) {
+            0 => read_u32(bytes, DSDT) as u64,
+            x_dsdt => x_dsdt,
+        };
+        let flags = read_u32(bytes, FLAGS);
+
+        Fadt {
+            dsdt,
+            sci_interrupt: read_u16(bytes, SCI_INTERRUPT),
+            smi_command: read_u32(bytes, SMI_COMMAND),
+            acpi_enable: bytes.get(ACPI_ENABLE).copied().unwrap_or(0),
+            acpi_disable: bytes.get(ACPI_DISABLE).copied().unwrap_or(0),
+            pm1a_event_block: read_u32(bytes, PM1A_EVENT_BLOCK),
+            pm1b_event_block: read_u32(bytes, PM1B_EVENT_BLOCK),
+            pm1a_control_block: read_u32(bytes, PM1A_CONTROL_BLOCK),
+            pm1b_control_block: read_u32(bytes, PM1B_CONTROL_BLOCK),
+            pm_timer_block: read_u32(bytes, PM_TIMER_BLOCK),
+            century_register: bytes.get(CENTURY).copied().unwrap_or(0),
+            boot_architecture: read_u16(bytes, BOOT_ARCHITECTURE),
+            flags,
+            reset_register: if flags & FLAG_RESET_REGISTER != 0 {
+                GenericAddress::parse(bytes, RESET_REGISTER)
+            } else {
+                None
+            },
+            reset_value: bytes.get(RESET_VALUE).copied().unwrap_or(0),
+        }
+    }
+
+    // Return whether the machine has an 8042 keyboard controller. ACPI 1.0
+    // tables don't say, so they are assumed to have one.
+    pub fn has_8042(&self) -> bool {
+        self.boot_architecture == 0 || self.boot_architecture & BOOT_ARCH_8042 != 0
+    }
+}
+
+#[test_case]
+fn test_parse_fadt() {
+    // An ACPI 1.0 sized table
+    let mut table = [0u8; 116];
+    table[DSDT..DSDT + 4].copy_from_slice(&0x7FE_1000u32.to_le_bytes());
+    table[SCI_INTERRUPT] = 9;
+    table[PM1A_CONTROL_BLOCK..PM1A_CONTROL_BLOCK + 4].copy_from_slice(&0x604u32.to_le_bytes());
+    table[FLAGS + 1] = (FLAG_RESET_REGISTER >> 8) as u8;
+
+    let fadt = Fadt::parse(&table);
+    assert_eq!(fadt.dsdt, 0x7FE_1000);
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_eq!(fadt.pm1a_control_block, 0x604);
+    // The reset register is past the end of the table
+    assert_eq!(fadt.reset_register, None);
+    assert!(fadt.has_8042());
+}
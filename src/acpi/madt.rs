@@ -0,0 +1,226 @@
+// The Multiple APIC Description Table (MADT).
+//
+// After the local APIC address and flags, the table is a list of variable
+// length entries, each starting with its type and length. The ones the
+// kernel cares about describe the CPUs (one local APIC each), the IO-APICs
+// and how ISA IRQs are wired to IO-APIC inputs where that differs from the
+// identity mapping, like the PIT on input 2.
+
+use super::{read_u16, read_u32, read_u64, SDT_HEADER_SIZE};
+use alloc::vec::Vec;
+
+// Where the entries start
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+// Flag set if the machine also has 8259 PICs
+const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+// Entry types
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+
+// Local APIC flag set if the CPU can be used
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+// MPS interrupt flags of overrides and NMIs
+const POLARITY_MASK: u16 = 0x3;
+const POLARITY_ACTIVE_LOW: u16 = 0x3;
+const TRIGGER_MASK: u16 = 0x3 << 2;
+const TRIGGER_LEVEL: u16 = 0x3 << 2;
+
+// An entry of the MADT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Entry {
+    LocalApic(Processor),
+    IoApic(IoApic),
+    InterruptOverride(InterruptOverride),
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 }, // Processor 0xFF means all
+    LocalApicAddress(u64),
+    Other(u8), // An entry type that isn't parsed
+}
+
+// A CPU, identified by its local APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u32, // The ACPI processor UID
+    pub apic_id: u32,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32, // The global system interrupt of the first input
+}
+
+// An ISA IRQ that isn't wired to the IO-APIC input of the same number, or
+// doesn't use the ISA polarity and trigger mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub source: u8, // The ISA IRQ
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & POLARITY_MASK == POLARITY_ACTIVE_LOW
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        self.flags & TRIGGER_MASK == TRIGGER_LEVEL
+    }
+}
+
+// Iterates over the entries of a MADT
+pub struct Entries<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let header = self.bytes.get(self.offset..self.offset + 2)?;
+        let (kind, length) = (header[0], header[1] as usize);
+        // A zero length would never move on
+        let entry = self.bytes.get(self.offset..self.offset + length.max(2))?;
+        if length < 2 {
+            return None;
+        }
+        self.offset += length;
+
+        Some(match kind {
+            ENTRY_LOCAL_APIC if length >= 8 => Entry::LocalApic(Processor {
+                processor_id: entry[2] as u32,
+                apic_id: entry[3] as u32,
+                enabled: read_u32(entry, 4) & LOCAL_APIC_ENABLED != 0,
+            }),
+            ENTRY_IO_APIC if length >= 12 => Entry::IoApic(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE if length >= 10 => {
+                Entry::InterruptOverride(InterruptOverride {
+                    source: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                })
+            }
+            ENTRY_LOCAL_APIC_NMI if length >= 6 => Entry::LocalApicNmi {
+                processor_id: entry[2],
+                flags: read_u16(entry, 3),
+                lint: entry[5],
+            },
+            ENTRY_LOCAL_APIC_ADDRESS if length >= 12 => {
+                Entry::LocalApicAddress(read_u64(entry, 4))
+            }
+            ENTRY_LOCAL_X2APIC if length >= 16 => Entry::LocalApic(Processor {
+                processor_id: read_u32(entry, 12),
+                apic_id: read_u32(entry, 4),
+                enabled: read_u32(entry, 8) & LOCAL_APIC_ENABLED != 0,
+            }),
+            kind => Entry::Other(kind),
+        })
+    }
+}
+
+// Return the entries of a MADT
+pub fn entries(bytes: &[u8]) -> Entries<'_> {
+    Entries {
+        bytes,
+        offset: ENTRIES_OFFSET,
+    }
+}
+
+// The parsed MADT
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    pub has_8259: bool, // Whether the PICs exist and have to be masked
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    pub(super) fn parse(bytes: &[u8]) -> Madt {
+        let mut madt = Madt {
+            local_apic_address: read_u32(bytes, SDT_HEADER_SIZE) as u64,
+            has_8259: read_u32(bytes, SDT_HEADER_SIZE + 4) & FLAG_PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+        };
+
+        for entry in entries(bytes) {
+            match entry {
+                Entry::LocalApic(processor) => madt.processors.push(processor),
+                Entry::IoApic(io_apic) => madt.io_apics.push(io_apic),
+                Entry::InterruptOverride(over) => madt.overrides.push(over),
+                Entry::LocalApicAddress(address) => madt.local_apic_address = address,
+                Entry::LocalApicNmi { .. } | Entry::Other(_) => {}
+            }
+        }
+        madt
+    }
+
+    // Return the override of an ISA IRQ, if it has one
+    pub fn isa_override(&self, irq: u8) -> Option<&InterruptOverride> {
+        self.overrides.iter().find(|over| over.source == irq)
+    }
+
+    // Return the IO-APIC handling the given global system interrupt
+    pub fn io_apic_for(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics
+            .iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)
+    }
+}
+
+#[test_case]
+fn test_entries() {
+    let mut table = [0u8; ENTRIES_OFFSET + 8 + 12 + 10 + 2];
+    let mut offset = ENTRIES_OFFSET;
+    // A local APIC: processor 0, APIC ID 1, enabled
+    table[offset..offset + 8].copy_from_slice(&[0, 8, 0, 1, 1, 0, 0, 0]);
+    offset += 8;
+    // An IO-APIC at 0xFEC00000 starting at GSI 0
+    table[offset..offset + 12].copy_from_slice(&[1, 12, 2, 0, 0, 0, 0xC0, 0xFE, 0, 0, 0, 0]);
+    offset += 12;
+    // IRQ 9, level triggered and active low on GSI 9
+    table[offset..offset + 10].copy_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0]);
+    offset += 10;
+    // A zero length entry ends the walk
+    table[offset] = 0x7F;
+
+    let mut entries = entries(&table);
+    let processor = Processor {
+        processor_id: 0,
+        apic_id: 1,
+        enabled: true,
+    };
+    assert_eq!(entries.next(), Some(Entry::LocalApic(processor)));
+    let io_apic = IoApic {
+        id: 2,
+        address: 0xFEC0_0000,
+        gsi_base: 0,
+    };
+    assert_eq!(entries.next(), Some(Entry::IoApic(io_apic)));
+    match entries.next() {
+        Some(Entry::InterruptOverride(over)) => {
+            assert_eq!((over.source, over.gsi), (9, 9));
+            assert!(over.active_low() && over.level_triggered());
+        }
+        other => panic!("unexpected entry {:?}", other),
+    }
+    assert_eq!(entries.next(), None);
+}
@@ -0,0 +1,487 @@
+// A driver for AHCI SATA controllers.
+//
+// The controller is found on the PCI bus by its class (mass storage, SATA)
+// and programmed through the registers in its ABAR (BAR 5). Each port with
+// a disk attached gets a frame holding its command list, received FIS area
+// and a command table, plus a few frames for the data, all taken from the
+// frame allocator and reached through the physical memory mapping. Commands
+// are issued one at a time in slot 0 and completion is polled, so no
+// interrupt is needed.
+//
+// Every disk is registered as a `BlockDevice` named "sda", "sdb" and so on.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{memory, pci, time};
+use alloc::format;
+use alloc::sync::Arc;
+use core::ptr;
+use spin::Mutex;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+// The PCI subclass of SATA controllers
+const SUBCLASS_SATA: u8 = 0x06;
+
+// The BAR holding the controller's registers
+const ABAR: usize = 5;
+
+// Generic host control registers
+const HOST_CAPABILITIES: usize = 0x00;
+const HOST_CONTROL: usize = 0x04;
+const HOST_PORTS_IMPLEMENTED: usize = 0x0C;
+
+const CAP_64BIT: u32 = 1 << 31;
+const CONTROL_INTERRUPTS: u32 = 1 << 1;
+const CONTROL_AHCI_ENABLE: u32 = 1 << 31;
+
+// Port registers, relative to the port's registers
+const PORTS_OFFSET: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const MAX_PORTS: usize = 32;
+
+const PORT_COMMAND_LIST: usize = 0x00;
+const PORT_COMMAND_LIST_HIGH: usize = 0x04;
+const PORT_FIS: usize = 0x08;
+const PORT_FIS_HIGH: usize = 0x0C;
+const PORT_INTERRUPT_STATUS: usize = 0x10;
+const PORT_INTERRUPT_ENABLE: usize = 0x14;
+const PORT_COMMAND: usize = 0x18;
+const PORT_TASK_FILE: usize = 0x20;
+const PORT_SIGNATURE: usize = 0x24;
+const PORT_SATA_STATUS: usize = 0x28;
+const PORT_SATA_ERROR: usize = 0x30;
+const PORT_COMMAND_ISSUE: usize = 0x38;
+
+// Port command register bits
+const COMMAND_START: u32 = 1 << 0;
+const COMMAND_FIS_RECEIVE: u32 = 1 << 4;
+const COMMAND_FIS_RUNNING: u32 = 1 << 14;
+const COMMAND_LIST_RUNNING: u32 = 1 << 15;
+
+// Task file status bits
+const STATUS_ERROR: u32 = 1 << 0;
+const STATUS_DRQ: u32 = 1 << 3;
+const STATUS_BUSY: u32 = 1 << 7;
+
+// The task file error interrupt status bit
+const INTERRUPT_TASK_FILE_ERROR: u32 = 1 << 30;
+
+// A device is present and communication is established
+const SATA_STATUS_PRESENT: u32 = 0x3;
+const SATA_STATUS_ACTIVE: u32 = 0x1;
+
+// The signature of a SATA disk (and not e.g. an ATAPI drive)
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+// ATA commands
+const ATA_READ_DMA: u8 = 0xC8;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA: u8 = 0xCA;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_CACHE: u8 = 0xE7;
+const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
+const ATA_IDENTIFY: u8 = 0xEC;
+
+// A register host to device FIS
+const FIS_TYPE_H2D: u8 = 0x27;
+const FIS_COMMAND: u8 = 1 << 7;
+const FIS_LENGTH_DWORDS: u32 = 5;
+const DEVICE_LBA: u8 = 1 << 6;
+
+// Command header flag of commands writing to the device
+const HEADER_WRITE: u32 = 1 << 6;
+
+// The layout of a port's frame: the command list with 32 headers, the
+// received FIS area and the command table of slot 0, whose physical region
+// descriptor table (PRDT) starts at 0x80
+const COMMAND_LIST_OFFSET: usize = 0;
+const FIS_OFFSET: usize = 0x400;
+const COMMAND_TABLE_OFFSET: usize = 0x500;
+const PRDT_OFFSET: usize = COMMAND_TABLE_OFFSET + 0x80;
+const PRD_SIZE: usize = 16;
+
+// The frames data is transferred through, one PRDT entry each, which limits
+// the size of a single command
+const DATA_FRAMES: usize = 16;
+const FRAME_SIZE: usize = 4096;
+const MAX_SECTORS_PER_COMMAND: usize = DATA_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+
+// The highest sector LBA28 commands reach
+const LBA28_LIMIT: u64 = 1 << 28;
+
+// How long to wait for the port or a command
+const TIMEOUT_MS: u64 = 1000;
+
+// Find the AHCI controllers and register their disks as block devices.
+//
+// Requires the heap, `pci::init` and the timer.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut disks = 0;
+    for device in pci::find_by_class(pci::CLASS_MASS_STORAGE, SUBCLASS_SATA) {
+        let (address, size) = match device.bars[ABAR] {
+            Some(pci::Bar::Memory { address, size, .. }) => (address, size),
+            _ => {
+                log::warn!("AHCI {}: no register BAR", device.address);
+                continue;
+            }
+        };
+        device.enable_decoding();
+        device.enable_bus_master();
+
+        // Map every page of the registers
+        let mut base = None;
+        for offset in (0..size).step_by(FRAME_SIZE) {
+            let page = memory::map_mmio(PhysAddr::new(address + offset), mapper, frame_allocator)?;
+            base.get_or_insert(page.as_u64());
+        }
+        let base = match base {
+            Some(base) => base,
+            None => continue,
+        };
+
+        let capabilities = unsafe { read(base, HOST_CAPABILITIES) };
+        unsafe {
+            let control = read(base, HOST_CONTROL);
+            write(base, HOST_CONTROL, (control | CONTROL_AHCI_ENABLE) & !CONTROL_INTERRUPTS);
+        }
+        // Without 64-bit addressing the structures must be below 4 GiB
+        let dma_limit = if capabilities & CAP_64BIT != 0 { u64::MAX } else { u32::MAX as u64 };
+
+        let implemented = unsafe { read(base, HOST_PORTS_IMPLEMENTED) };
+        for index in 0..MAX_PORTS {
+            let offset = PORTS_OFFSET + index * PORT_SIZE;
+            if implemented & (1 << index) == 0 || (offset + PORT_SIZE) as u64 > size {
+                continue;
+            }
+            let regs = base + offset as u64;
+            if !has_disk(regs) {
+                continue;
+            }
+
+            let port = match Port::new(regs, dma_limit, frame_allocator) {
+                Some(port) => port,
+                None => return Err(MapToError::FrameAllocationFailed),
+            };
+            match Disk::new(port) {
+                Ok(disk) => {
+                    let name = format!("sd{}", (b'a' + disks) as char);
+                    if block::register_cached(name, Arc::new(disk)).is_ok() {
+                        disks += 1;
+                    }
+                }
+                Err(err) => log::warn!("AHCI {} port {}: {:?}", device.address, index, err),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Return whether a SATA disk is attached to the port
+fn has_disk(regs: u64) -> bool {
+    let status = unsafe { read(regs, PORT_SATA_STATUS) };
+    let detection = status & 0xF;
+    let power = (status >> 8) & 0xF;
+    let signature = unsafe { read(regs, PORT_SIGNATURE) };
+    detection == SATA_STATUS_PRESENT && power == SATA_STATUS_ACTIVE && signature == SIGNATURE_ATA
+}
+
+// A port and the memory its commands are built in
+struct Port {
+    regs: u64,
+    memory: PhysFrame,
+    data: [PhysFrame; DATA_FRAMES],
+}
+
+impl Port {
+    // Allocate the port's memory and start its command engine. Returns
+    // `None` if there aren't enough frames the controller can reach.
+    fn new(
+        regs: u64,
+        dma_limit: u64,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Option<Port> {
+        let mut allocate = || {
+            let frame = frame_allocator.allocate_frame()?;
+            let end = frame.start_address().as_u64() + FRAME_SIZE as u64 - 1;
+            if end > dma_limit {
+                return None;
+            }
+            unsafe { ptr::write_bytes(frame_ptr(frame), 0, FRAME_SIZE) };
+            Some(frame)
+        };
+        let memory = allocate()?;
+        let mut data = [memory; DATA_FRAMES];
+        for frame in data.iter_mut() {
+            *frame = allocate()?;
+        }
+
+        let port = Port { regs, memory, data };
+        port.stop();
+        let base = memory.start_address().as_u64();
+        unsafe {
+            port.write(PORT_COMMAND_LIST, (base + COMMAND_LIST_OFFSET as u64) as u32);
+            port.write(PORT_COMMAND_LIST_HIGH, ((base + COMMAND_LIST_OFFSET as u64) >> 32) as u32);
+            port.write(PORT_FIS, (base + FIS_OFFSET as u64) as u32);
+            port.write(PORT_FIS_HIGH, ((base + FIS_OFFSET as u64) >> 32) as u32);
+            port.write(PORT_INTERRUPT_ENABLE, 0);
+            // The status and error bits are cleared by writing ones
+            port.write(PORT_SATA_ERROR, u32::MAX);
+            port.write(PORT_INTERRUPT_STATUS, u32::MAX);
+        }
+        port.start();
+        Some(port)
+    }
+
+    unsafe fn read(&self, offset: usize) -> u32 {
+        read(self.regs, offset)
+    }
+
+    unsafe fn write(&self, offset: usize, value: u32) {
+        write(self.regs, offset, value)
+    }
+
+    // Stop processing the command list and receiving FISes
+    fn stop(&self) {
+        unsafe {
+            let command = self.read(PORT_COMMAND);
+            self.write(PORT_COMMAND, command & !COMMAND_START);
+            self.wait(|| self.read(PORT_COMMAND) & COMMAND_LIST_RUNNING == 0).ok();
+            let command = self.read(PORT_COMMAND);
+            self.write(PORT_COMMAND, command & !COMMAND_FIS_RECEIVE);
+            self.wait(|| self.read(PORT_COMMAND) & COMMAND_FIS_RUNNING == 0).ok();
+        }
+    }
+
+    fn start(&self) {
+        unsafe {
+            let command = self.read(PORT_COMMAND);
+            self.write(PORT_COMMAND, command | COMMAND_FIS_RECEIVE);
+            self.wait(|| self.read(PORT_TASK_FILE) & (STATUS_BUSY | STATUS_DRQ) == 0).ok();
+            let command = self.read(PORT_COMMAND);
+            self.write(PORT_COMMAND, command | COMMAND_START);
+        }
+    }
+
+    // Poll until `done` returns true
+    fn wait(&self, done: impl Fn() -> bool) -> Result<(), BlockError> {
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        while !done() {
+            if time::uptime_ms() >= deadline {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        Ok(())
+    }
+
+    // Run an ATA command transferring `bytes` bytes through the data frames
+    fn command(
+        &mut self,
+        command: u8,
+        lba: u64,
+        sectors: u16,
+        bytes: usize,
+        write: bool,
+    ) -> Result<(), BlockError> {
+        let memory = frame_ptr(self.memory);
+        let table = self.memory.start_address().as_u64() + COMMAND_TABLE_OFFSET as u64;
+        let prds = (bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        unsafe {
+            // The command header of slot 0
+            let header = memory.add(COMMAND_LIST_OFFSET) as *mut u32;
+            let flags = FIS_LENGTH_DWORDS | if write { HEADER_WRITE } else { 0 };
+            ptr::write_volatile(header, flags | (prds as u32) << 16);
+            ptr::write_volatile(header.add(1), 0);
+            ptr::write_volatile(header.add(2), table as u32);
+            ptr::write_volatile(header.add(3), (table >> 32) as u32);
+
+            // The command FIS
+            let fis = memory.add(COMMAND_TABLE_OFFSET);
+            ptr::write_bytes(fis, 0, PRDT_OFFSET - COMMAND_TABLE_OFFSET);
+            let device = if command == ATA_READ_DMA || command == ATA_WRITE_DMA {
+                // LBA28 commands take the top bits of the address here
+                DEVICE_LBA | (lba >> 24) as u8 & 0xF
+            } else if command == ATA_IDENTIFY {
+                0
+            } else {
+                DEVICE_LBA
+            };
+            let bytes_of_fis = [
+                FIS_TYPE_H2D,
+                FIS_COMMAND,
+                command,
+                0,
+                lba as u8,
+                (lba >> 8) as u8,
+                (lba >> 16) as u8,
+                device,
+                (lba >> 24) as u8,
+                (lba >> 32) as u8,
+                (lba >> 40) as u8,
+                0,
+                sectors as u8,
+                (sectors >> 8) as u8,
+            ];
+            for (i, &byte) in bytes_of_fis.iter().enumerate() {
+                ptr::write_volatile(fis.add(i), byte);
+            }
+
+            // One region per data frame
+            for (i, frame) in self.data.iter().take(prds).enumerate() {
+                let prd = memory.add(PRDT_OFFSET + i * PRD_SIZE) as *mut u32;
+                let address = frame.start_address().as_u64();
+                let length = (bytes - i * FRAME_SIZE).min(FRAME_SIZE);
+                ptr::write_volatile(prd, address as u32);
+                ptr::write_volatile(prd.add(1), (address >> 32) as u32);
+                ptr::write_volatile(prd.add(2), 0);
+                ptr::write_volatile(prd.add(3), length as u32 - 1);
+            }
+        }
+
+        let result = self.issue();
+        if result.is_err() {
+            // Get the port going again for the next command
+            self.stop();
+            unsafe {
+                self.write(PORT_SATA_ERROR, u32::MAX);
+                self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+            }
+            self.start();
+        }
+        result
+    }
+
+    // Issue the command in slot 0 and wait for it to complete
+    fn issue(&self) -> Result<(), BlockError> {
+        unsafe {
+            self.wait(|| self.read(PORT_TASK_FILE) & (STATUS_BUSY | STATUS_DRQ) == 0)?;
+            self.write(PORT_INTERRUPT_STATUS, u32::MAX);
+            self.write(PORT_COMMAND_ISSUE, 1);
+
+            self.wait(|| {
+                self.read(PORT_COMMAND_ISSUE) & 1 == 0
+                    || self.read(PORT_INTERRUPT_STATUS) & INTERRUPT_TASK_FILE_ERROR != 0
+            })?;
+            if self.read(PORT_INTERRUPT_STATUS) & INTERRUPT_TASK_FILE_ERROR != 0
+                || self.read(PORT_TASK_FILE) & STATUS_ERROR != 0
+            {
+                return Err(BlockError::Io);
+            }
+        }
+        Ok(())
+    }
+
+    // Fill `buffer` from the start of the data frames
+    fn copy_from_data(&self, buffer: &mut [u8]) {
+        for (chunk, &frame) in buffer.chunks_mut(FRAME_SIZE).zip(self.data.iter()) {
+            unsafe { ptr::copy_nonoverlapping(frame_ptr(frame), chunk.as_mut_ptr(), chunk.len()) };
+        }
+    }
+
+    // Copy `buffer` into the data frames
+    fn copy_to_data(&mut self, buffer: &[u8]) {
+        for (chunk, &frame) in buffer.chunks(FRAME_SIZE).zip(self.data.iter()) {
+            unsafe { ptr::copy_nonoverlapping(chunk.as_ptr(), frame_ptr(frame), chunk.len()) };
+        }
+    }
+}
+
+// A disk on an AHCI port
+struct Disk {
+    port: Mutex<Port>,
+    sectors: u64,
+    lba48: bool,
+}
+
+impl Disk {
+    // Identify the disk on the port
+    fn new(mut port: Port) -> Result<Disk, BlockError> {
+        port.command(ATA_IDENTIFY, 0, 0, SECTOR_SIZE, false)?;
+        let mut identify = [0u8; SECTOR_SIZE];
+        port.copy_from_data(&mut identify);
+        let word = |index: usize| {
+            u16::from_le_bytes([identify[2 * index], identify[2 * index + 1]]) as u64
+        };
+
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            word(100) | word(101) << 16 | word(102) << 32 | word(103) << 48
+        } else {
+            word(60) | word(61) << 16
+        };
+        Ok(Disk {
+            port: Mutex::new(port),
+            sectors,
+            lba48,
+        })
+    }
+
+    // Return the read or write command for a request
+    fn command_for(&self, lba: u64, write: bool) -> Result<u8, BlockError> {
+        match (self.lba48, write) {
+            (true, false) => Ok(ATA_READ_DMA_EXT),
+            (true, true) => Ok(ATA_WRITE_DMA_EXT),
+            (false, _) if lba >= LBA28_LIMIT => Err(BlockError::OutOfRange),
+            (false, false) => Ok(ATA_READ_DMA),
+            (false, true) => Ok(ATA_WRITE_DMA),
+        }
+    }
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let mut port = self.port.lock();
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            port.command(self.command_for(lba, false)?, lba, sectors, chunk.len(), false)?;
+            port.copy_from_data(chunk);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let mut port = self.port.lock();
+        let chunk_size = MAX_SECTORS_PER_COMMAND * SECTOR_SIZE;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            let sectors = (chunk.len() / SECTOR_SIZE) as u16;
+            port.copy_to_data(chunk);
+            port.command(self.command_for(lba, true)?, lba, sectors, chunk.len(), true)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let command = if self.lba48 { ATA_FLUSH_CACHE_EXT } else { ATA_FLUSH_CACHE };
+        self.port.lock().command(command, 0, 0, 0, false)
+    }
+}
+
+// The CPU's view of a frame, through the physical memory mapping
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+unsafe fn read(base: u64, offset: usize) -> u32 {
+    ptr::read_volatile((base as usize + offset) as *const u32)
+}
+
+unsafe fn write(base: u64, offset: usize, value: u32) {
+    ptr::write_volatile((base as usize + offset) as *mut u32, value);
+}
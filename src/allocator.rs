@@ -1,13 +1,16 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{ptr::null_mut};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB
+        mapper::MapToError, FrameAllocator, OffsetPageTable, Page, PageTableFlags, Size4KiB
     }, 
     VirtAddr,
 };
 use linked_list_allocator::LockedHeap;
 use bump::BumpAllocator;
+use crate::memory::{self, Frames};
 
 pub mod bump;
 
@@ -20,12 +23,196 @@ pub const HEAP_START: usize = 0x_7777_7777_7777;
 /// The size of the heap in bytes
 pub const HEAP_SIZE: usize = 700 * 1024;
 
+/// What free memory is filled with to find stray writes, on the heap and
+/// in freed frames (see `memcheck`)
+pub const POISON: u8 = 0xA5;
+
 // /// The global allocator instance
 // #[global_allocator]
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 #[global_allocator]
-static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+static ALLOCATOR: Counting<Locked<BumpAllocator>> = Counting::new(Locked::new(BumpAllocator::new()));
+
+/// Set by the first call to `init_heap`
+static HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Errors from initializing the heap
+#[derive(Debug)]
+pub enum HeapError {
+    /// `init_heap` was already called
+    AlreadyInitialized,
+    /// Mapping the heap pages failed
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for HeapError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        HeapError::Map(err)
+    }
+}
+
+/// A snapshot of the heap statistics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    /// The size of the heap in bytes
+    pub size: usize,
+    /// Bytes in live allocations
+    pub used: usize,
+    /// Bytes not in live allocations. Not all of them can be allocated;
+    /// see `available`.
+    pub not_live: usize,
+    /// Bytes the allocator can still hand out (see `available()`), `None`
+    /// if the heap was locked, e.g. by the code a panic interrupted
+    pub available: Option<usize>,
+    /// The most bytes in live allocations at any time
+    pub peak_used: usize,
+    /// Number of live allocations
+    pub allocations: usize,
+    /// Number of allocations since boot
+    pub total_allocations: usize,
+    /// Number of allocations that failed
+    pub failed_allocations: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "heap: {} KiB, {} bytes used ({} peak), {} available, \
+             {} allocations ({} total, {} failed)",
+            self.size / 1024,
+            self.used,
+            self.peak_used,
+            Available(self.available),
+            self.allocations,
+            self.total_allocations,
+            self.failed_allocations,
+        )
+    }
+}
+
+/// Formats `HeapStats::available`
+struct Available(Option<usize>);
+
+impl fmt::Display for Available {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(bytes) => write!(f, "{} bytes", bytes),
+            None => f.write_str("? bytes (heap locked)"),
+        }
+    }
+}
+
+/// Returns the current heap statistics.
+///
+/// Only reads counters, and doesn't wait for the heap lock, so it is safe to
+/// call from panic and interrupt handlers.
+pub fn stats() -> HeapStats {
+    let size = if HEAP_INITIALIZED.load(Ordering::Relaxed) { HEAP_SIZE } else { 0 };
+    let used = ALLOCATOR.used.load(Ordering::Relaxed);
+    HeapStats {
+        size,
+        used,
+        not_live: size.saturating_sub(used),
+        available: ALLOCATOR.inner.try_lock().map(|bump| bump.available()),
+        peak_used: ALLOCATOR.peak_used.load(Ordering::Relaxed),
+        allocations: ALLOCATOR.allocations.load(Ordering::Relaxed),
+        total_allocations: ALLOCATOR.total_allocations.load(Ordering::Relaxed),
+        failed_allocations: ALLOCATOR.failed_allocations.load(Ordering::Relaxed),
+    }
+}
+
+/// Whether the heap is locked, e.g. by code that an interrupt handler
+/// reporting this interrupted
+pub fn is_locked() -> bool {
+    ALLOCATOR.inner.is_locked()
+}
+
+/// Returns the number of bytes the allocator can still hand out. Freed
+/// allocations only add to it once all of them are freed, so this can be
+/// much less than the bytes outside of live allocations.
+pub fn available() -> usize {
+    ALLOCATOR.inner.lock().available()
+}
+
+/// Whether the allocator's own state makes sense
+pub fn is_consistent() -> bool {
+    ALLOCATOR.inner.lock().is_consistent()
+}
+
+/// A stray write found in the free part of the heap
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corruption {
+    /// The first byte that changed
+    pub address: usize,
+    /// The number of bytes that changed in the checked range
+    pub bytes: usize,
+}
+
+/// Fills up to `max` bytes of the free heap with the poison `check_free`
+/// looks for. Returns the number of bytes filled, 0 once the whole free
+/// heap is poisoned.
+pub fn scrub_free(max: usize) -> usize {
+    ALLOCATOR.inner.lock().scrub(max)
+}
+
+/// Checks the poisoned free bytes among the `len` bytes at `offset` into
+/// the heap. Changed bytes are poisoned again, so every stray write is
+/// reported once.
+pub fn check_free(offset: usize, len: usize) -> Option<Corruption> {
+    let start = HEAP_START + offset;
+    let range = start..start.saturating_add(len);
+    ALLOCATOR.inner.lock().check_poison(range).map(|(address, bytes)| Corruption { address, bytes })
+}
+
+/// Wraps an allocator and counts its allocations and the bytes in use
+pub struct Counting<A> {
+    inner: A,
+    used: AtomicUsize,
+    peak_used: AtomicUsize,
+    allocations: AtomicUsize,
+    total_allocations: AtomicUsize,
+    failed_allocations: AtomicUsize,
+}
+
+impl<A> Counting<A> {
+    pub const fn new(inner: A) -> Self {
+        Counting {
+            inner,
+            used: AtomicUsize::new(0),
+            peak_used: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            total_allocations: AtomicUsize::new(0),
+            failed_allocations: AtomicUsize::new(0),
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = if crate::fault::should_fail(crate::fault::Site::Heap) {
+            null_mut()
+        } else {
+            self.inner.alloc(layout)
+        };
+        if ptr.is_null() {
+            self.failed_allocations.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        let used = self.used.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+        self.peak_used.fetch_max(used, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.total_allocations.fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.allocations.fetch_sub(1, Ordering::Relaxed);
+    }
+}
 
 unsafe impl GlobalAlloc for Dummy {
     /// Allocates memory according to the specified layout.
@@ -42,10 +229,18 @@ unsafe impl GlobalAlloc for Dummy {
 }
 
 /// Initializes the heap by mapping physical frames to virtual memory pages.
+///
+/// Only the first call does anything; later ones fail with
+/// `HeapError::AlreadyInitialized`, even if the first one failed half way
+/// and left some heap pages mapped.
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>, 
+    mapper: &mut OffsetPageTable, 
     frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> Result<(), MapToError<Size4KiB>> {
+) -> Result<(), HeapError> {
+    if HEAP_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(HeapError::AlreadyInitialized);
+    }
+
     // Create a range of pages that cover the entire heap
     let page_range = {
         let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -55,18 +250,14 @@ pub fn init_heap(
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    // Map each page of the heap to a physical frame
-    for page in page_range {
-        let frame = frame_allocator.allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
-    }
+    // Map each page of the heap to a fresh physical frame
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        memory::map_range(mapper, page_range, Frames::Allocate, flags, frame_allocator)?
+    };
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.inner.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
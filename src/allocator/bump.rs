@@ -4,8 +4,9 @@
 /// for use cases where all allocations are made and then released in bulk.
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use super::{align_up, Locked};
-use core::ptr;
+use super::{align_up, Locked, POISON};
+use core::ops::Range;
+use core::{ptr, slice};
 
 /// The `BumpAllocator` struct contains the necessary information to manage
 /// heap memory using the bump allocation strategy.
@@ -23,6 +24,11 @@ pub struct BumpAllocator {
     /// In a simple bump allocator, deallocations do not immediately free memory,
     /// but this counter can help track how many allocations have been made.
     allocations: usize,
+
+    /// The start of the free bytes filled with `POISON` by `scrub`, which
+    /// reach up to `heap_end`. Never below `next`, since allocations
+    /// overwrite the poison.
+    poisoned_from: usize,
 }
 
 impl BumpAllocator {
@@ -43,6 +49,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            poisoned_from: 0,
         }
     }
 
@@ -72,6 +79,48 @@ impl BumpAllocator {
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
+        self.poisoned_from = self.heap_end;
+    }
+
+    /// The bytes between the bump pointer and the end of the heap, which is
+    /// all that can be allocated until every allocation is freed.
+    pub fn available(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    /// Whether the bump pointer lies inside the heap, and is back at its
+    /// start if there are no allocations.
+    pub fn is_consistent(&self) -> bool {
+        self.heap_start <= self.next
+            && self.next <= self.heap_end
+            && (self.allocations > 0 || self.next == self.heap_start)
+    }
+
+    /// Fills up to `max` free bytes that don't hold the poison yet with it,
+    /// going down from the poisoned ones towards `next`. Returns the number
+    /// of bytes poisoned, 0 once all free bytes are.
+    pub fn scrub(&mut self, max: usize) -> usize {
+        let start = self.poisoned_from.saturating_sub(max).max(self.next);
+        let len = self.poisoned_from - start;
+        unsafe { ptr::write_bytes(start as *mut u8, POISON, len) };
+        self.poisoned_from = start;
+        len
+    }
+
+    /// Checks that the poisoned bytes in `range` still hold the poison, and
+    /// poisons the ones that don't again. Returns the address of the first
+    /// changed byte and the number of changed bytes.
+    pub fn check_poison(&mut self, range: Range<usize>) -> Option<(usize, usize)> {
+        let start = range.start.max(self.poisoned_from);
+        let end = range.end.min(self.heap_end);
+        if start >= end {
+            return None;
+        }
+        let bytes = unsafe { slice::from_raw_parts_mut(start as *mut u8, end - start) };
+        let first = bytes.iter().position(|&byte| byte != POISON)?;
+        let changed = bytes[first..].iter().filter(|&&byte| byte != POISON).count();
+        bytes[first..].fill(POISON);
+        Some((start + first, changed))
     }
 }
 
@@ -89,6 +138,17 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
+
+    /// Locks unless someone holds the lock already
+    pub fn try_lock(&self) -> Option<spin::MutexGuard<A>> {
+        self.inner.try_lock()
+    }
+
+    /// Whether someone holds the lock. Doesn't wait, so it can be used from
+    /// interrupt handlers.
+    pub fn is_locked(&self) -> bool {
+        self.inner.try_lock().is_none()
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -106,6 +166,8 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            // The allocation holds no poison, even after a reset
+            bump.poisoned_from = bump.poisoned_from.max(alloc_end);
             alloc_start as *mut u8
         }
     }
@@ -0,0 +1,280 @@
+use crate::interrupts::PIC_1_OFFSET;
+use crate::{acpi, memory};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+// The model specific register holding the physical base of the local APIC
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+
+// Global enable bit in the IA32_APIC_BASE MSR
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// The physical address of the first IO-APIC on PC-compatible chipsets, used
+// when there is no ACPI MADT
+pub const IO_APIC_DEFAULT_BASE: u64 = 0xFEC0_0000;
+
+// The vector the local APIC delivers spurious interrupts to
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// The address range message signalled interrupts are written to; bits 12
+// to 19 select the destination local APIC
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+// Local APIC register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+// Local vector table flags
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+// Divide configuration value for dividing the timer input clock by 16
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+// How long the APIC timer is measured against the PIT
+const TIMER_CALIBRATION_MS: u32 = 10;
+
+// Software enable bit in the spurious interrupt vector register
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+
+// IO-APIC register indexes
+const IO_APIC_VERSION: u32 = 0x01;
+const IO_APIC_REDIRECTION_TABLE: u32 = 0x10;
+
+// Bits of an IO-APIC redirection entry
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECTION_MASKED: u32 = 1 << 16;
+
+// Whether the APICs replaced the 8259 PICs
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// The virtual addresses of the local APIC and IO-APIC registers
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IO_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// Serializes the select/window register pair of the IO-APIC
+static IO_APIC_LOCK: Mutex<()> = Mutex::new(());
+
+// Return whether the CPU has a local APIC, according to CPUID leaf 1.
+pub fn is_supported() -> bool {
+    let cpuid = unsafe { core::arch::x86_64::__cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+// Return whether interrupts are delivered through the APICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// Switch interrupt delivery from the 8259 PICs to the local APIC and IO-APIC.
+//
+// Maps the APIC registers, enables the local APIC, routes every line with a
+// handler (see `interrupts::register_irq`) through the IO-APIC to the same
+// vector the PICs used and masks the legacy PICs. Lines registered earlier,
+// while the PICs delivered interrupts, keep working this way. Does nothing if
+// the CPU has no local APIC.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    use crate::interrupts::{self as irqs, IRQ_COUNT, PICS};
+
+    if !is_supported() {
+        log::warn!("APIC not supported, keeping the 8259 PIC");
+        return Ok(());
+    }
+
+    let apic_base = unsafe { Msr::new(IA32_APIC_BASE_MSR).read() };
+    let lapic_phys = PhysAddr::new(apic_base & 0xF_FFFF_F000);
+    let lapic = memory::map_mmio(lapic_phys, mapper, frame_allocator)?;
+    // Only the IO-APIC serving the ISA IRQs is used
+    let io_apic_phys = acpi::madt()
+        .and_then(|madt| madt.io_apic_for(0))
+        .map_or(IO_APIC_DEFAULT_BASE, |io_apic| io_apic.address as u64);
+    let io_apic = memory::map_mmio(PhysAddr::new(io_apic_phys), mapper, frame_allocator)?;
+
+    interrupts::without_interrupts(|| {
+        LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+        IO_APIC_BASE.store(io_apic.as_u64(), Ordering::Relaxed);
+
+        unsafe {
+            // Make sure the local APIC is globally enabled
+            Msr::new(IA32_APIC_BASE_MSR).write(apic_base | APIC_BASE_ENABLE);
+
+            // Accept all interrupt priorities and software enable the local APIC
+            lapic_write(LAPIC_TPR, 0);
+            lapic_write(LAPIC_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+            // Start with every IO-APIC line masked
+            for entry in 0..io_apic_redirection_entries() {
+                io_apic_write(IO_APIC_REDIRECTION_TABLE + 2 * entry, REDIRECTION_MASKED);
+            }
+
+            // The PICs stay remapped so that spurious interrupts they may still
+            // raise don't collide with CPU exceptions
+            PICS.lock().disable();
+        }
+
+        ENABLED.store(true, Ordering::Release);
+
+        // The timer and keyboard lines, and whatever drivers registered
+        for irq in (0..IRQ_COUNT as u8).filter(|&irq| irqs::has_irq_handler(irq)) {
+            route_isa_irq(irq);
+        }
+    });
+
+    Ok(())
+}
+
+// Return the local APIC ID of the current CPU.
+pub fn local_apic_id() -> u8 {
+    (unsafe { lapic_read(LAPIC_ID) } >> 24) as u8
+}
+
+// Signal the end of interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    unsafe { lapic_write(LAPIC_EOI, 0) };
+}
+
+// Start the local APIC timer in periodic mode at the given frequency.
+//
+// The timer's input clock is bus-specific, so it is first calibrated by
+// counting down for a fixed interval measured with the PIT. The timer
+// interrupt is delivered on the vector the PIT used, so the existing timer
+// handler keeps working; the PIT line is masked afterwards.
+pub fn start_timer(frequency_hz: u32) {
+    use crate::interrupts::InterruptIndex;
+    use crate::pit;
+
+    let counts_per_second = interrupts::without_interrupts(|| unsafe {
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+
+        pit::wait_ms(TIMER_CALIBRATION_MS);
+
+        let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT_COUNT);
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+        elapsed as u64 * (1000 / TIMER_CALIBRATION_MS) as u64
+    });
+
+    let vector: u8 = InterruptIndex::Timer.into();
+    let initial_count = (counts_per_second / frequency_hz as u64).max(1) as u32;
+
+    interrupts::without_interrupts(|| unsafe {
+        mask_isa_irq(InterruptIndex::Timer.irq());
+        lapic_write(LAPIC_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+    });
+}
+
+// Route the given ISA IRQ line through the IO-APIC to vector
+// `PIC_1_OFFSET + irq` on the current CPU, the vector the PIC would use.
+pub fn route_isa_irq(irq: u8) {
+    let gsi = isa_irq_to_gsi(irq);
+    let vector = PIC_1_OFFSET + irq;
+
+    // Fixed delivery, physical destination. ISA lines are edge triggered and
+    // active high unless the MADT says otherwise.
+    let mut low = vector as u32;
+    if let Some(over) = acpi::madt().and_then(|madt| madt.isa_override(irq)) {
+        if over.active_low() {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if over.level_triggered() {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+    }
+    let high = (local_apic_id() as u32) << 24;
+
+    interrupts::without_interrupts(|| unsafe {
+        io_apic_write(IO_APIC_REDIRECTION_TABLE + 2 * gsi + 1, high);
+        io_apic_write(IO_APIC_REDIRECTION_TABLE + 2 * gsi, low);
+    });
+}
+
+// Return the address and data a device writes to deliver a message signalled
+// interrupt on `vector` to the current CPU.
+//
+// Fixed delivery, physical destination, edge triggered.
+pub fn msi_message(vector: u8) -> (u64, u32) {
+    let address = MSI_ADDRESS_BASE | (local_apic_id() as u64) << 12;
+    (address, vector as u32)
+}
+
+// Return whether the given ISA IRQ line is unmasked in the IO-APIC and
+// delivers to the vector `route_isa_irq` picks.
+pub fn is_routed(irq: u8) -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    let gsi = isa_irq_to_gsi(irq);
+    let low = interrupts::without_interrupts(|| unsafe {
+        io_apic_read(IO_APIC_REDIRECTION_TABLE + 2 * gsi)
+    });
+    low & REDIRECTION_MASKED == 0 && low & 0xFF == (PIC_1_OFFSET + irq) as u32
+}
+
+// Mask the given ISA IRQ line in the IO-APIC.
+pub fn mask_isa_irq(irq: u8) {
+    let gsi = isa_irq_to_gsi(irq);
+
+    interrupts::without_interrupts(|| unsafe {
+        io_apic_write(IO_APIC_REDIRECTION_TABLE + 2 * gsi, REDIRECTION_MASKED);
+    });
+}
+
+// Map an ISA IRQ to its IO-APIC input (global system interrupt).
+//
+// The interrupt source overrides of the ACPI MADT list the lines that aren't
+// wired to the input of the same number. Without a MADT, the PIT is assumed
+// to be on input 2 instead of 0, as on PC-compatible chipsets.
+fn isa_irq_to_gsi(irq: u8) -> u32 {
+    if let Some(madt) = acpi::madt() {
+        return madt.isa_override(irq).map_or(irq as u32, |over| over.gsi);
+    }
+    match irq {
+        0 => 2,
+        irq => irq as u32,
+    }
+}
+
+// Return the number of redirection entries of the IO-APIC.
+unsafe fn io_apic_redirection_entries() -> u32 {
+    ((io_apic_read(IO_APIC_VERSION) >> 16) & 0xFF) + 1
+}
+
+unsafe fn lapic_read(offset: usize) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed) as usize;
+    core::ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn lapic_write(offset: usize, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed) as usize;
+    core::ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+unsafe fn io_apic_read(register: u32) -> u32 {
+    let base = IO_APIC_BASE.load(Ordering::Relaxed) as usize;
+    let _guard = IO_APIC_LOCK.lock();
+    core::ptr::write_volatile(base as *mut u32, register);
+    core::ptr::read_volatile((base + 0x10) as *const u32)
+}
+
+unsafe fn io_apic_write(register: u32, value: u32) {
+    let base = IO_APIC_BASE.load(Ordering::Relaxed) as usize;
+    let _guard = IO_APIC_LOCK.lock();
+    core::ptr::write_volatile(base as *mut u32, register);
+    core::ptr::write_volatile((base + 0x10) as *mut u32, value);
+}
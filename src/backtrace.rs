@@ -0,0 +1,156 @@
+// Stack traces from the frame pointer chain.
+//
+// The kernel is built with frame pointers (see the target specification),
+// so every function saves the caller's RBP next to its return address:
+//
+//     [rbp + 8]  return address into the caller
+//     [rbp]      the caller's rbp
+//
+// Following the chain gives the return address of every active call. The
+// walk stops at a null or misaligned frame pointer, or one that doesn't
+// move up the stack, or one whose frame isn't mapped (once `memory::init`
+// made the page tables readable), so a corrupted stack ends the trace
+// instead of faulting in the panic handler. Return addresses are printed
+// with the function they belong to when the kernel has a symbol table
+// (`symbols`).
+
+use crate::memory;
+use core::arch::asm;
+use core::fmt;
+use x86_64::VirtAddr;
+
+// Maximum number of frames printed
+pub const MAX_FRAMES: usize = 32;
+
+// Largest distance between two frames that is still believed; a bigger jump
+// means the chain left the stack
+const MAX_FRAME_SIZE: u64 = 1024 * 1024;
+
+// A stack frame of an active call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_pointer: u64,
+    pub return_address: u64,
+}
+
+// Iterates over the frames, innermost first
+pub struct Frames {
+    rbp: u64,
+    remaining: usize,
+}
+
+impl Iterator for Frames {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.remaining == 0 || self.rbp == 0 || self.rbp % 8 != 0 {
+            return None;
+        }
+        if !is_mapped(self.rbp) || !is_mapped(self.rbp + 8) {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let frame_pointer = self.rbp;
+        let (caller_rbp, return_address) = unsafe {
+            let slot = frame_pointer as *const u64;
+            (slot.read(), slot.add(1).read())
+        };
+        if return_address == 0 {
+            return None;
+        }
+
+        // The caller's frame must be further up the stack
+        self.rbp = if caller_rbp > frame_pointer && caller_rbp - frame_pointer <= MAX_FRAME_SIZE {
+            caller_rbp
+        } else {
+            0
+        };
+
+        Some(Frame {
+            frame_pointer,
+            return_address,
+        })
+    }
+}
+
+// Return whether the 8 bytes at `addr` can be read. Always true before
+// `memory::init`, when the page tables can't be walked; the checks on the
+// chain itself have to do then.
+fn is_mapped(addr: u64) -> bool {
+    if !memory::is_initialized() {
+        return true;
+    }
+    VirtAddr::try_new(addr).map_or(false, |addr| memory::translate(addr).is_some())
+}
+
+// Return the frames of the calls leading to this one, starting with the
+// caller of `trace`
+#[inline(never)]
+pub fn trace() -> Frames {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    // `trace` is never inlined, so this is its own frame, which holds the
+    // return address into the caller
+    unsafe { frames_from(rbp) }
+}
+
+// Return the frames starting at the given frame pointer, e.g. one saved
+// by an exception.
+//
+// Unsafe because `rbp` must point to a valid frame on a mapped stack.
+pub unsafe fn frames_from(rbp: u64) -> Frames {
+    Frames {
+        rbp,
+        remaining: MAX_FRAMES,
+    }
+}
+
+// Print a stack trace of the calls leading here
+pub fn print() {
+    crate::println!("{}", Backtrace);
+}
+
+// Formats a stack trace of the calls leading to where it is formatted
+pub struct Backtrace;
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stack backtrace:")?;
+        for (i, frame) in trace().enumerate() {
+            write!(f, "\n  {:>2}: {:#018x}", i, frame.return_address)?;
+            // The return address may already be the next function's first
+            // byte after a call at the very end of a function
+            if let Some(symbol) = crate::symbols::lookup(frame.return_address - 1) {
+                write!(f, " {}+{:#x}", symbol.name, symbol.offset + 1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_trace_has_frames() {
+    let mut frames = trace();
+    let first = frames.next().expect("no stack frames");
+    assert!(first.return_address != 0);
+
+    // Frame pointers move up the stack
+    let mut previous = first.frame_pointer;
+    for frame in frames {
+        assert!(frame.frame_pointer > previous);
+        previous = frame.frame_pointer;
+    }
+}
+
+#[test_case]
+fn test_unmapped_frame_pointer() {
+    // The lib tests call `memory::init`, so the frame is looked up first
+    let mut frames = unsafe { frames_from(0x_3333_0000_0000) };
+    assert_eq!(frames.next(), None);
+    // Non-canonical
+    let mut frames = unsafe { frames_from(0x_8000_0000_0000) };
+    assert_eq!(frames.next(), None);
+}
@@ -0,0 +1,153 @@
+// Block devices: disks and anything else that stores fixed-size blocks.
+//
+// Drivers implement `BlockDevice` and add their devices with `register`
+// under a name like "sda"; filesystems look them up with `find`. Requests
+// always cover whole blocks, and the buffer length picks how many. Disks
+// are registered with `register_cached`, so their blocks are cached (see
+// `cache`).
+
+pub mod cache;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The block size of disks
+pub const SECTOR_SIZE: usize = 512;
+
+// Errors of block requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // The request goes past the end of the device
+    OutOfRange,
+    // The buffer isn't a multiple of the block size
+    BadBufferSize,
+    // The device can't be written
+    ReadOnly,
+    // The device reported an error
+    Io,
+    // The device didn't answer in time
+    Timeout,
+}
+
+// A device storing `block_count` blocks of `block_size` bytes
+pub trait BlockDevice: Send + Sync {
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    // Read the blocks starting at `lba` into `buffer`
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    // Write `buffer` to the blocks starting at `lba`
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    // Make the written blocks persistent, e.g. by flushing the disk's cache
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    // The size of the device in bytes
+    fn size(&self) -> u64 {
+        self.block_count() * self.block_size() as u64
+    }
+}
+
+// Check a request of `len` bytes at `lba` against a device and return the
+// number of blocks it covers. Drivers call this first thing, which is also
+// where block faults are injected.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    if crate::fault::should_fail(crate::fault::Site::Block) {
+        return Err(BlockError::Io);
+    }
+    if len % device.block_size() != 0 {
+        return Err(BlockError::BadBufferSize);
+    }
+    let count = (len / device.block_size()) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// Returned by `register` when a device with the name exists already
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyRegistered;
+
+// The registered devices with their names
+static DEVICES: Mutex<Vec<(String, Arc<dyn BlockDevice>)>> = Mutex::new(Vec::new());
+
+// Make a device available under the given name
+pub fn register(name: String, device: Arc<dyn BlockDevice>) -> Result<(), AlreadyRegistered> {
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        if devices.iter().any(|(n, _)| *n == name) {
+            return Err(AlreadyRegistered);
+        }
+        log::info!(
+            "block device {}: {} blocks of {} bytes",
+            name,
+            device.block_count(),
+            device.block_size()
+        );
+        devices.push((name, device));
+        Ok(())
+    })
+}
+
+// Make a disk available under the given name, with its blocks going
+// through the cache
+pub fn register_cached(
+    name: String,
+    device: Arc<dyn BlockDevice>,
+) -> Result<(), AlreadyRegistered> {
+    register(name, Arc::new(cache::CachedDevice::new(device)))
+}
+
+// Return the device with the given name
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    interrupts::without_interrupts(|| {
+        DEVICES
+            .lock()
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, device)| device.clone())
+    })
+}
+
+// Return the registered devices in the order they were added
+pub fn devices() -> Vec<(String, Arc<dyn BlockDevice>)> {
+    interrupts::without_interrupts(|| DEVICES.lock().clone())
+}
+
+#[test_case]
+fn test_check_request() {
+    struct Disk;
+
+    impl BlockDevice for Disk {
+        fn block_size(&self) -> usize {
+            SECTOR_SIZE
+        }
+
+        fn block_count(&self) -> u64 {
+            8
+        }
+
+        fn read_blocks(&self, _lba: u64, _buffer: &mut [u8]) -> Result<(), BlockError> {
+            Ok(())
+        }
+
+        fn write_blocks(&self, _lba: u64, _buffer: &[u8]) -> Result<(), BlockError> {
+            Ok(())
+        }
+    }
+
+    assert_eq!(check_request(&Disk, 0, 2 * SECTOR_SIZE), Ok(2));
+    assert_eq!(check_request(&Disk, 6, 2 * SECTOR_SIZE), Ok(2));
+    assert_eq!(check_request(&Disk, 7, 2 * SECTOR_SIZE), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(&Disk, u64::MAX, SECTOR_SIZE), Err(BlockError::OutOfRange));
+    assert_eq!(check_request(&Disk, 0, 100), Err(BlockError::BadBufferSize));
+    assert_eq!(Disk.size(), 8 * SECTOR_SIZE as u64);
+}
@@ -0,0 +1,272 @@
+// A write-back cache of disk blocks, shared by all cached devices.
+//
+// Disk drivers register their disks with `block::register_cached`, which
+// wraps them in a `CachedDevice`; partitions and filesystems on a disk go
+// through the cache without knowing. Blocks are kept by device and block
+// number, and when the cache is full the least recently used block makes
+// room. A write only changes the cached block and marks it dirty. It
+// reaches the disk when the block is dropped from the cache, when the
+// device is flushed, or on `sync`, which runs on an orderly shutdown; a
+// crash loses it.
+//
+// Only devices with 512 byte blocks are cached, so that any slot can hold
+// any block; requests to others are passed through. Slots are allocated as
+// the cache fills up and are never freed.
+//
+// The cache is locked for the whole of a request, including the disk
+// accesses of misses, so it must not be used by interrupt handlers.
+
+use super::{BlockDevice, BlockError, SECTOR_SIZE};
+use crate::power;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// The number of blocks kept, 64 KiB
+pub const CACHE_BLOCKS: usize = 128;
+
+struct Slot {
+    // The device number and block, `None` if reading the block failed
+    key: Option<(usize, u64)>,
+    data: Box<[u8; SECTOR_SIZE]>,
+    dirty: bool,
+    last_used: u64, // The value of `Cache::clock` at the last access
+}
+
+struct Cache {
+    slots: Vec<Slot>,
+    // The cached devices, numbered by their index
+    devices: Vec<Arc<dyn BlockDevice>>,
+    clock: u64, // Counts accesses
+    hits: u64,
+    misses: u64,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    slots: Vec::new(),
+    devices: Vec::new(),
+    clock: 0,
+    hits: 0,
+    misses: 0,
+});
+
+impl Cache {
+    // Return the index of the slot holding a block, reading it from the
+    // device first if it isn't cached and `read` is set. Without `read`,
+    // the slot's data is left as it is, to be overwritten by the caller.
+    fn get(&mut self, device: usize, lba: u64, read: bool) -> Result<usize, BlockError> {
+        self.clock += 1;
+        let key = Some((device, lba));
+        if let Some(index) = self.slots.iter().position(|slot| slot.key == key) {
+            self.hits += 1;
+            self.slots[index].last_used = self.clock;
+            return Ok(index);
+        }
+
+        self.misses += 1;
+        let index = self.free_slot()?;
+        let slot = &mut self.slots[index];
+        slot.key = None;
+        if read {
+            self.devices[device].read_blocks(lba, &mut slot.data[..])?;
+        }
+        slot.key = key;
+        slot.last_used = self.clock;
+        Ok(index)
+    }
+
+    // Return the index of a slot that can be reused: a new one while the
+    // cache isn't full, the least recently used one otherwise, which is
+    // written back first if it is dirty. A block that can't be written back
+    // stays cached and dirty, and the next least recently used one is
+    // tried; the error is only returned if no slot could be freed.
+    fn free_slot(&mut self) -> Result<usize, BlockError> {
+        if self.slots.len() < CACHE_BLOCKS {
+            self.slots.push(Slot {
+                key: None,
+                data: Box::new([0; SECTOR_SIZE]),
+                dirty: false,
+                last_used: 0,
+            });
+            return Ok(self.slots.len() - 1);
+        }
+        let mut order = [0; CACHE_BLOCKS];
+        for (i, index) in order.iter_mut().enumerate() {
+            *index = i;
+        }
+        order.sort_unstable_by_key(|&index| self.slots[index].last_used);
+
+        let mut error = None;
+        for index in order {
+            let slot = &mut self.slots[index];
+            if !slot.dirty {
+                return Ok(index);
+            }
+            let (device, lba) = slot.key.expect("dirty block cache slot without a block");
+            match self.devices[device].write_blocks(lba, &slot.data[..]) {
+                Ok(()) => {
+                    slot.dirty = false;
+                    return Ok(index);
+                }
+                Err(err) => {
+                    // Tried last on the next miss, so a bad block doesn't
+                    // cost a failed write every time
+                    slot.last_used = self.clock;
+                    error.get_or_insert(err);
+                }
+            }
+        }
+        Err(error.expect("empty block cache"))
+    }
+
+    // Write the dirty blocks of the devices `filter` selects back. Returns
+    // the number of blocks written, or the first error; the blocks that
+    // couldn't be written stay dirty.
+    fn write_back<F: Fn(usize) -> bool>(&mut self, filter: F) -> Result<usize, BlockError> {
+        let mut written = 0;
+        let mut result = Ok(());
+        for slot in self.slots.iter_mut().filter(|slot| slot.dirty) {
+            let (device, lba) = slot.key.expect("dirty block cache slot without a block");
+            if !filter(device) {
+                continue;
+            }
+            match self.devices[device].write_blocks(lba, &slot.data[..]) {
+                Ok(()) => {
+                    slot.dirty = false;
+                    written += 1;
+                }
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result.map(|()| written)
+    }
+}
+
+// A device whose blocks go through the cache
+pub struct CachedDevice {
+    device: Arc<dyn BlockDevice>,
+    number: Option<usize>, // The index in `Cache::devices`, `None` if not cached
+}
+
+impl CachedDevice {
+    pub fn new(device: Arc<dyn BlockDevice>) -> CachedDevice {
+        let number = if device.block_size() == SECTOR_SIZE {
+            let mut cache = CACHE.lock();
+            cache.devices.push(device.clone());
+            Some(cache.devices.len() - 1)
+        } else {
+            None
+        };
+        CachedDevice { device, number }
+    }
+
+    // Check the range of a request of `len` bytes at `lba` and return the
+    // number of blocks it covers. Faults are injected by the device, when
+    // a request reaches it.
+    fn check(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        if len % SECTOR_SIZE != 0 {
+            return Err(BlockError::BadBufferSize);
+        }
+        let count = (len / SECTOR_SIZE) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.block_count() => Ok(count),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for CachedDevice {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.device.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let number = match self.number {
+            Some(number) => number,
+            None => return self.device.read_blocks(lba, buffer),
+        };
+        self.check(lba, buffer.len())?;
+        let mut cache = CACHE.lock();
+        for (i, block) in buffer.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let index = cache.get(number, lba + i as u64, true)?;
+            block.copy_from_slice(&cache.slots[index].data[..]);
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let number = match self.number {
+            Some(number) => number,
+            None => return self.device.write_blocks(lba, buffer),
+        };
+        self.check(lba, buffer.len())?;
+        let mut cache = CACHE.lock();
+        for (i, block) in buffer.chunks_exact(SECTOR_SIZE).enumerate() {
+            let index = cache.get(number, lba + i as u64, false)?;
+            let slot = &mut cache.slots[index];
+            slot.data.copy_from_slice(block);
+            slot.dirty = true;
+        }
+        Ok(())
+    }
+
+    // Writes the device's dirty blocks back before flushing it
+    fn flush(&self) -> Result<(), BlockError> {
+        if let Some(number) = self.number {
+            CACHE.lock().write_back(|device| device == number)?;
+        }
+        self.device.flush()
+    }
+}
+
+// Numbers describing how well the cache works
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub blocks: usize, // Blocks in the cache
+    pub dirty: usize,  // Of those, the ones not written back yet
+    pub hits: u64,
+    pub misses: u64,
+}
+
+pub fn stats() -> Stats {
+    let cache = CACHE.lock();
+    Stats {
+        blocks: cache.slots.iter().filter(|slot| slot.key.is_some()).count(),
+        dirty: cache.slots.iter().filter(|slot| slot.dirty).count(),
+        hits: cache.hits,
+        misses: cache.misses,
+    }
+}
+
+// Whether the cache is locked, i.e. a request is in progress. Doesn't wait,
+// so it can be used for diagnostics in interrupt handlers.
+pub fn is_locked() -> bool {
+    CACHE.try_lock().is_none()
+}
+
+// Write all dirty blocks back and flush the cached devices. Returns the
+// number of blocks written, or the first error.
+pub fn sync() -> Result<usize, BlockError> {
+    let mut cache = CACHE.lock();
+    let mut result = cache.write_back(|_| true);
+    for device in &cache.devices {
+        if let Err(err) = device.flush() {
+            result = result.and(Err(err));
+        }
+    }
+    result
+}
+
+// Write the cache back when the system is shut down
+pub fn init() {
+    power::register_teardown("block cache", || {
+        if let Err(err) = sync() {
+            log::error!("block cache: can't write back: {:?}", err);
+        }
+    });
+}
@@ -0,0 +1,222 @@
+// The kernel clipboard.
+//
+// Text selected on the console (see `console`) is copied here and pasted
+// with Shift+Insert as if it was typed. A terminal on the serial console can
+// paste into it too: with bracketed paste mode enabled, the terminal wraps
+// pasted text in `ESC [200~` and `ESC [201~`, and the text between them
+// replaces the clipboard contents on its way to the input subsystem.
+
+use crate::serial;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The maximum amount of text the clipboard holds, in bytes
+pub const CLIPBOARD_SIZE: usize = 4096;
+
+// Sent to a terminal to enable and disable bracketed paste mode
+pub const BRACKETED_PASTE_ENABLE: &str = "\x1b[?2004h";
+pub const BRACKETED_PASTE_DISABLE: &str = "\x1b[?2004l";
+
+// The markers a terminal puts around pasted text
+const PASTE_START: &[u8] = b"\x1b[200~";
+const PASTE_END: &[u8] = b"\x1b[201~";
+
+// The length of the paste markers
+const MARKER_LEN: usize = 6;
+
+struct Clipboard {
+    data: [u8; CLIPBOARD_SIZE],
+    len: usize,
+}
+
+impl Clipboard {
+    const fn new() -> Self {
+        Clipboard {
+            data: [0; CLIPBOARD_SIZE],
+            len: 0,
+        }
+    }
+
+    // Append a byte; returns `false` if the clipboard is full
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == CLIPBOARD_SIZE {
+            return false;
+        }
+        self.data[self.len] = byte;
+        self.len += 1;
+        true
+    }
+}
+
+static CLIPBOARD: Mutex<Clipboard> = Mutex::new(Clipboard::new());
+
+// Replace the clipboard contents. Text beyond `CLIPBOARD_SIZE` is cut off;
+// returns the number of bytes stored.
+pub fn set(text: &[u8]) -> usize {
+    interrupts::without_interrupts(|| {
+        let mut clipboard = CLIPBOARD.lock();
+        clipboard.len = 0;
+        text.iter().take_while(|&&byte| clipboard.push(byte)).count()
+    })
+}
+
+// Empty the clipboard
+pub fn clear() {
+    interrupts::without_interrupts(|| CLIPBOARD.lock().len = 0);
+}
+
+// Append a byte to the clipboard; returns `false` if it is full
+pub fn push(byte: u8) -> bool {
+    interrupts::without_interrupts(|| CLIPBOARD.lock().push(byte))
+}
+
+// Return the number of bytes in the clipboard
+pub fn len() -> usize {
+    interrupts::without_interrupts(|| CLIPBOARD.lock().len)
+}
+
+// Return whether the clipboard is empty
+pub fn is_empty() -> bool {
+    len() == 0
+}
+
+// Run `f` with the clipboard contents. The clipboard stays locked, so `f`
+// must not change it.
+pub fn with_contents<F, R>(f: F) -> R
+where
+    F: FnOnce(&[u8]) -> R,
+{
+    interrupts::without_interrupts(|| {
+        let clipboard = CLIPBOARD.lock();
+        f(&clipboard.data[..clipboard.len])
+    })
+}
+
+// Ask the terminal on the serial console to mark pasted text
+pub fn enable_serial_bracketed_paste() {
+    serial::_print(format_args!("{}", BRACKETED_PASTE_ENABLE));
+}
+
+// What to do with a byte received from a terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paste {
+    // Ordinary input
+    Input(u8),
+    // Pasted input, to be copied to the clipboard as well
+    Pasted(u8),
+}
+
+// Finds the bracketed paste markers in the bytes received from a terminal.
+// Bytes that might start a marker are held back until it is clear whether
+// they do.
+pub struct BracketedPaste {
+    held: [u8; MARKER_LEN],
+    held_len: usize,
+    pasting: bool,
+}
+
+impl BracketedPaste {
+    pub const fn new() -> Self {
+        BracketedPaste {
+            held: [0; MARKER_LEN],
+            held_len: 0,
+            pasting: false,
+        }
+    }
+
+    // Return whether a paste is in progress
+    pub fn is_pasting(&self) -> bool {
+        self.pasting
+    }
+
+    // Feed a received byte and pass on the bytes that are now known not to
+    // be part of a marker
+    pub fn feed(&mut self, byte: u8, mut output: impl FnMut(Paste)) {
+        let marker = if self.pasting { PASTE_END } else { PASTE_START };
+
+        if marker[self.held_len] == byte {
+            self.held[self.held_len] = byte;
+            self.held_len += 1;
+            if self.held_len == MARKER_LEN {
+                self.held_len = 0;
+                self.pasting = !self.pasting;
+            }
+            return;
+        }
+
+        // Not a marker after all; release what was held back
+        for &held in &self.held[..self.held_len] {
+            output(self.classify(held));
+        }
+        self.held_len = 0;
+
+        // The byte may start a new marker
+        if byte == marker[0] {
+            self.held[0] = byte;
+            self.held_len = 1;
+        } else {
+            output(self.classify(byte));
+        }
+    }
+
+    fn classify(&self, byte: u8) -> Paste {
+        if self.pasting {
+            Paste::Pasted(byte)
+        } else {
+            Paste::Input(byte)
+        }
+    }
+}
+
+impl Default for BracketedPaste {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Handle a byte from the serial console: bytes pasted by the terminal
+// replace the clipboard contents, and everything but the paste markers is
+// returned as input. Runs in interrupt context.
+pub(crate) fn filter_serial_byte(byte: u8, mut input: impl FnMut(u8)) {
+    static SERIAL_PASTE: Mutex<BracketedPaste> = Mutex::new(BracketedPaste::new());
+
+    let mut paste = SERIAL_PASTE.lock();
+    let was_pasting = paste.is_pasting();
+    paste.feed(byte, |result| match result {
+        Paste::Input(byte) => input(byte),
+        Paste::Pasted(byte) => {
+            push(byte);
+            input(byte);
+        }
+    });
+    // A new paste replaces the old clipboard contents
+    if paste.is_pasting() && !was_pasting {
+        clear();
+    }
+}
+
+#[test_case]
+fn test_bracketed_paste() {
+    let mut paste = BracketedPaste::new();
+    let mut output = [None; 8];
+    let mut count = 0;
+
+    for &byte in b"a\x1b[200~b\x1b[A\x1b[201~" {
+        paste.feed(byte, |result| {
+            output[count] = Some(result);
+            count += 1;
+        });
+    }
+
+    assert!(!paste.is_pasting());
+    assert_eq!(
+        &output[..count],
+        &[
+            Some(Paste::Input(b'a')),
+            Some(Paste::Pasted(b'b')),
+            Some(Paste::Pasted(0x1b)),
+            Some(Paste::Pasted(b'[')),
+            Some(Paste::Pasted(b'A')),
+        ]
+    );
+}
@@ -0,0 +1,308 @@
+// Streaming base64 and hex codecs.
+//
+// The encoders write to any `core::fmt::Write` (a serial port, the VGA
+// writer, a `String`) and the decoders pass every decoded byte to a closure,
+// so data of any size can be converted in pieces without allocating.
+
+use core::fmt::{self, Write};
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Errors that can occur while decoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input contained a byte that is not part of the encoding.
+    InvalidByte(u8),
+    /// Data followed the base64 padding.
+    TrailingData,
+    /// The input ended in the middle of an encoded group.
+    Truncated,
+}
+
+/// Encodes bytes as standard base64 with padding.
+pub struct Base64Encoder<W: Write> {
+    writer: W,
+    pending: [u8; 3],
+    pending_len: usize,
+}
+
+impl<W: Write> Base64Encoder<W> {
+    /// Creates an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        Base64Encoder {
+            writer,
+            pending: [0; 3],
+            pending_len: 0,
+        }
+    }
+
+    /// Encodes `bytes`. Up to two bytes are held back until the next call or
+    /// `finish`, since base64 encodes groups of three.
+    pub fn write(&mut self, bytes: &[u8]) -> fmt::Result {
+        for &byte in bytes {
+            self.pending[self.pending_len] = byte;
+            self.pending_len += 1;
+            if self.pending_len == 3 {
+                self.flush_group()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the remaining bytes with padding and returns the writer.
+    pub fn finish(mut self) -> Result<W, fmt::Error> {
+        if self.pending_len > 0 {
+            self.flush_group()?;
+        }
+        Ok(self.writer)
+    }
+
+    // Write the pending group as four characters, padding missing bytes
+    fn flush_group(&mut self) -> fmt::Result {
+        let [a, b, c] = self.pending;
+        let group = (a as u32) << 16 | (b as u32) << 8 | c as u32;
+
+        for i in 0..4 {
+            let ch = if i <= self.pending_len {
+                BASE64_ALPHABET[((group >> (18 - 6 * i)) & 0x3F) as usize]
+            } else {
+                b'='
+            };
+            self.writer.write_char(ch as char)?;
+        }
+
+        self.pending = [0; 3];
+        self.pending_len = 0;
+        Ok(())
+    }
+}
+
+/// Decodes standard base64. Whitespace is skipped, so line wrapped input
+/// can be fed directly.
+#[derive(Default)]
+pub struct Base64Decoder {
+    group: u32,
+    group_len: usize,
+    padding: usize,
+}
+
+impl Base64Decoder {
+    /// Creates a new decoder.
+    pub const fn new() -> Self {
+        Base64Decoder {
+            group: 0,
+            group_len: 0,
+            padding: 0,
+        }
+    }
+
+    /// Decodes `input`, passing every decoded byte to `sink`.
+    pub fn decode(&mut self, input: &[u8], mut sink: impl FnMut(u8)) -> Result<(), DecodeError> {
+        for &byte in input {
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+
+            if byte == b'=' {
+                // Padding may only replace the last one or two characters
+                if self.group_len < 2 || self.group_len + self.padding >= 4 {
+                    return Err(DecodeError::InvalidByte(byte));
+                }
+                self.padding += 1;
+                if self.group_len + self.padding == 4 {
+                    self.emit_group(&mut sink);
+                }
+                continue;
+            }
+
+            if self.padding > 0 {
+                return Err(DecodeError::TrailingData);
+            }
+
+            let value = base64_value(byte).ok_or(DecodeError::InvalidByte(byte))?;
+            self.group = self.group << 6 | value as u32;
+            self.group_len += 1;
+            if self.group_len == 4 {
+                self.emit_group(&mut sink);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the input ended on a group boundary.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        match self.group_len {
+            0 => Ok(()),
+            _ => Err(DecodeError::Truncated),
+        }
+    }
+
+    // Pass the bytes of a complete group to the sink
+    fn emit_group(&mut self, sink: &mut impl FnMut(u8)) {
+        let group = self.group << (6 * self.padding);
+        let bytes = self.group_len - 1;
+        for i in 0..bytes {
+            sink((group >> (16 - 8 * i)) as u8);
+        }
+
+        self.group = 0;
+        self.group_len = 0;
+        // A padded group is the last one; keep `padding` to reject more data
+    }
+}
+
+/// Encodes bytes as lowercase hex.
+pub struct HexEncoder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> HexEncoder<W> {
+    /// Creates an encoder writing to `writer`.
+    pub fn new(writer: W) -> Self {
+        HexEncoder { writer }
+    }
+
+    /// Encodes `bytes`.
+    pub fn write(&mut self, bytes: &[u8]) -> fmt::Result {
+        for &byte in bytes {
+            self.writer.write_char(HEX_DIGITS[(byte >> 4) as usize] as char)?;
+            self.writer.write_char(HEX_DIGITS[(byte & 0x0F) as usize] as char)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the writer.
+    pub fn finish(self) -> W {
+        self.writer
+    }
+}
+
+/// Decodes hex in upper or lower case. Whitespace is skipped.
+#[derive(Default)]
+pub struct HexDecoder {
+    high: Option<u8>,
+}
+
+impl HexDecoder {
+    /// Creates a new decoder.
+    pub const fn new() -> Self {
+        HexDecoder { high: None }
+    }
+
+    /// Decodes `input`, passing every decoded byte to `sink`.
+    pub fn decode(&mut self, input: &[u8], mut sink: impl FnMut(u8)) -> Result<(), DecodeError> {
+        for &byte in input {
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+
+            let value = (byte as char).to_digit(16).ok_or(DecodeError::InvalidByte(byte))? as u8;
+            match self.high.take() {
+                Some(high) => sink(high << 4 | value),
+                None => self.high = Some(value),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that no half byte is left over.
+    pub fn finish(self) -> Result<(), DecodeError> {
+        match self.high {
+            Some(_) => Err(DecodeError::Truncated),
+            None => Ok(()),
+        }
+    }
+}
+
+// Return the 6-bit value of a base64 character
+fn base64_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'A'..=b'Z' => Some(byte - b'A'),
+        b'a'..=b'z' => Some(byte - b'a' + 26),
+        b'0'..=b'9' => Some(byte - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+// A fixed size `fmt::Write` target, since unit tests run without a heap
+#[cfg(test)]
+struct TestBuffer {
+    data: [u8; 32],
+    len: usize,
+}
+
+#[cfg(test)]
+impl TestBuffer {
+    fn new() -> Self {
+        TestBuffer { data: [0; 32], len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.data[self.len] = byte;
+        self.len += 1;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+#[cfg(test)]
+impl Write for TestBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_base64_encode() {
+    let cases: [(&[u8], &[u8]); 4] = [
+        (b"", b""),
+        (b"f", b"Zg=="),
+        (b"fo", b"Zm8="),
+        (b"foobar", b"Zm9vYmFy"),
+    ];
+    for (input, expected) in cases.iter() {
+        // Feed one byte at a time to exercise the streaming path
+        let mut encoder = Base64Encoder::new(TestBuffer::new());
+        for byte in input.iter() {
+            encoder.write(&[*byte]).unwrap();
+        }
+        assert_eq!(encoder.finish().unwrap().as_bytes(), *expected);
+    }
+}
+
+#[test_case]
+fn test_base64_decode() {
+    let mut decoder = Base64Decoder::new();
+    let mut output = TestBuffer::new();
+    decoder.decode(b"Zm9v\nYm", |b| output.push(b)).unwrap();
+    decoder.decode(b"E=", |b| output.push(b)).unwrap();
+    decoder.finish().unwrap();
+    assert_eq!(output.as_bytes(), b"foob");
+
+    let mut decoder = Base64Decoder::new();
+    assert_eq!(decoder.decode(b"Zg==Zg", |_| {}), Err(DecodeError::TrailingData));
+}
+
+#[test_case]
+fn test_hex_roundtrip() {
+    let mut encoder = HexEncoder::new(TestBuffer::new());
+    encoder.write(&[0x00, 0xAB, 0x7F]).unwrap();
+    assert_eq!(encoder.finish().as_bytes(), b"00ab7f");
+
+    let mut decoder = HexDecoder::new();
+    let mut output = TestBuffer::new();
+    decoder.decode(b"00AB 7", |b| output.push(b)).unwrap();
+    decoder.decode(b"f", |b| output.push(b)).unwrap();
+    decoder.finish().unwrap();
+    assert_eq!(output.as_bytes(), &[0x00, 0xAB, 0x7F]);
+}
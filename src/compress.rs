@@ -0,0 +1,200 @@
+// Decompressors for compressed images (initrd, crash logs, screenshots).
+//
+// Currently supports LZ4, both raw blocks and the LZ4 frame format produced
+// by the `lz4` command line tool.
+
+use alloc::vec::Vec;
+
+/// Errors that can occur while decompressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The input ended in the middle of a sequence or frame.
+    Truncated,
+    /// A match referenced data before the start of the output.
+    InvalidOffset,
+    /// The input doesn't start with the LZ4 frame magic number.
+    BadMagic,
+    /// The frame uses a feature that isn't supported (e.g. dictionaries).
+    Unsupported,
+    /// The output would exceed the given size limit.
+    OutputTooLarge,
+}
+
+/// The magic number at the start of every LZ4 frame.
+const LZ4_FRAME_MAGIC: u32 = 0x184D_2204;
+
+// Frame descriptor flags
+const FLG_VERSION_MASK: u8 = 0b1100_0000;
+const FLG_VERSION_01: u8 = 0b0100_0000;
+const FLG_BLOCK_CHECKSUM: u8 = 1 << 4;
+const FLG_CONTENT_SIZE: u8 = 1 << 3;
+const FLG_CONTENT_CHECKSUM: u8 = 1 << 2;
+const FLG_DICTIONARY_ID: u8 = 1 << 0;
+
+// Set in a block size if the block is stored uncompressed
+const BLOCK_UNCOMPRESSED: u32 = 1 << 31;
+
+// The shortest match LZ4 encodes
+const MIN_MATCH: usize = 4;
+
+/// Decompresses an LZ4 frame, producing at most `max_output` bytes.
+///
+/// Checksums are skipped rather than verified, since the images we handle
+/// are linked into the kernel or come from trusted storage.
+pub fn decompress_lz4(input: &[u8], max_output: usize) -> Result<Vec<u8>, DecompressError> {
+    let mut reader = Reader { input, pos: 0 };
+
+    if reader.u32()? != LZ4_FRAME_MAGIC {
+        return Err(DecompressError::BadMagic);
+    }
+
+    let flags = reader.u8()?;
+    let _block_descriptor = reader.u8()?;
+    if flags & FLG_VERSION_MASK != FLG_VERSION_01 {
+        return Err(DecompressError::Unsupported);
+    }
+    if flags & FLG_DICTIONARY_ID != 0 {
+        return Err(DecompressError::Unsupported);
+    }
+
+    let mut output = Vec::new();
+    if flags & FLG_CONTENT_SIZE != 0 {
+        let content_size = reader.u64()? as usize;
+        if content_size > max_output {
+            return Err(DecompressError::OutputTooLarge);
+        }
+        output.reserve_exact(content_size);
+    }
+    let _header_checksum = reader.u8()?;
+
+    loop {
+        let block_size = reader.u32()?;
+        if block_size == 0 {
+            break; // end mark
+        }
+
+        let length = (block_size & !BLOCK_UNCOMPRESSED) as usize;
+        let block = reader.bytes(length)?;
+        if block_size & BLOCK_UNCOMPRESSED != 0 {
+            if output.len() + block.len() > max_output {
+                return Err(DecompressError::OutputTooLarge);
+            }
+            output.extend_from_slice(block);
+        } else {
+            // Linked blocks may reference data of previous blocks, which works
+            // because all blocks are decompressed into the same buffer
+            decompress_lz4_block(block, &mut output, max_output)?;
+        }
+
+        if flags & FLG_BLOCK_CHECKSUM != 0 {
+            reader.u32()?;
+        }
+    }
+
+    if flags & FLG_CONTENT_CHECKSUM != 0 {
+        reader.u32()?;
+    }
+
+    Ok(output)
+}
+
+/// Decompresses a raw LZ4 block, appending to `output`.
+///
+/// Matches may reference data that was in `output` before the call, which is
+/// how linked blocks and dictionaries work. Fails if `output` would grow
+/// beyond `max_output` bytes.
+pub fn decompress_lz4_block(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    max_output: usize,
+) -> Result<(), DecompressError> {
+    let mut reader = Reader { input, pos: 0 };
+
+    loop {
+        let token = reader.u8()?;
+
+        // Copy the literals
+        let literal_length = reader.length(token >> 4)?;
+        let literals = reader.bytes(literal_length)?;
+        if output.len() + literals.len() > max_output {
+            return Err(DecompressError::OutputTooLarge);
+        }
+        output.extend_from_slice(literals);
+
+        // The last sequence of a block consists of literals only
+        if reader.is_empty() {
+            return Ok(());
+        }
+
+        // Copy the match, which may overlap with the bytes it produces
+        let offset = reader.u16()? as usize;
+        if offset == 0 || offset > output.len() {
+            return Err(DecompressError::InvalidOffset);
+        }
+        let match_length = reader.length(token & 0x0F)? + MIN_MATCH;
+        if output.len() + match_length > max_output {
+            return Err(DecompressError::OutputTooLarge);
+        }
+
+        let start = output.len() - offset;
+        for i in 0..match_length {
+            let byte = output[start + i];
+            output.push(byte);
+        }
+    }
+}
+
+// A cursor over the compressed input
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], DecompressError> {
+        let end = self.pos.checked_add(count).ok_or(DecompressError::Truncated)?;
+        let bytes = self.input.get(self.pos..end).ok_or(DecompressError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecompressError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DecompressError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecompressError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecompressError> {
+        let low = self.u32()? as u64;
+        let high = self.u32()? as u64;
+        Ok(high << 32 | low)
+    }
+
+    // Decode a literal or match length: a value of 15 in the token is
+    // followed by bytes that are added until one is less than 255
+    fn length(&mut self, nibble: u8) -> Result<usize, DecompressError> {
+        let mut length = nibble as usize;
+        if nibble == 0x0F {
+            loop {
+                let byte = self.u8()?;
+                length += byte as usize;
+                if byte != 0xFF {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    }
+}
@@ -0,0 +1,386 @@
+// Virtual terminals sharing the VGA text screen.
+//
+// Every terminal is a `Writer` with its own text and cursor. The visible one
+// writes straight to the VGA memory while the others write to buffers in RAM;
+// switching exchanges the two. Terminal 0 is the global `WRITER` that
+// `print!` uses, so kernel output stays there while e.g. a shell runs on
+// terminal 1. Alt+F1 to Alt+F4 switch between the terminals, and text can
+// be selected and pasted with the keyboard (see `selection`).
+//
+// The screen can also be split into two panes showing different terminals,
+// e.g. a shell next to terminal 0 to follow the kernel log:
+//
+//     Alt+V    side by side         Alt+H    one above the other
+//     Alt+X    back to one terminal Alt+Tab  focus the other pane
+//
+// Alt+F1 to Alt+F4 then change the terminal in the focused pane. Keyboard
+// input goes to the focused terminal, which `active` returns.
+
+use crate::keyboard::KeyEvent;
+use crate::vga_buffer::{self, Color, Viewport, Writer, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+use alloc::vec::Vec;
+use core::fmt::{Arguments, Write};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::KeyCode;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+mod selection;
+pub mod progress;
+
+pub use progress::{ProgressBar, Spinner};
+
+// The number of virtual terminals, including the kernel terminal 0
+pub const TERMINAL_COUNT: usize = 4;
+
+// The terminal shown on the screen, or in the focused pane
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// The characters of the borders between panes
+const VERTICAL_BORDER: u8 = 0xB3;
+const HORIZONTAL_BORDER: u8 = 0xC4;
+
+// How the screen is divided between terminals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    // One terminal on the whole screen
+    Single,
+    // Two terminals next to each other
+    SideBySide,
+    // Two terminals one above the other
+    Stacked,
+}
+
+// The terminals on the screen
+struct Screen {
+    layout: Layout,
+    panes: [usize; 2], // The terminals in the panes; only the first in `Single`
+    focus: usize,      // The pane receiving input
+}
+
+impl Screen {
+    // Return the terminals shown with their viewports
+    fn shown(&self) -> impl Iterator<Item = (usize, Viewport)> {
+        let [first, second] = pane_viewports(self.layout);
+        let panes = self.panes;
+        let first = Some((panes[0], first));
+        let second = second.map(|viewport| (panes[1], viewport));
+        first.into_iter().chain(second)
+    }
+
+    fn focused(&self) -> usize {
+        self.panes[self.focus]
+    }
+}
+
+static SCREEN: Mutex<Screen> = Mutex::new(Screen {
+    layout: Layout::Single,
+    panes: [0, 1],
+    focus: 0,
+});
+
+// A bit per terminal whose typed characters are not echoed, because the
+// program reading them (e.g. the shell) echoes them itself
+static NO_ECHO: AtomicU8 = AtomicU8::new(0);
+
+// Whether the offscreen terminals have been created
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Terminals 1 and up; terminal 0 is `WRITER`
+    static ref TERMINALS: Vec<Mutex<Writer>> = (1..TERMINAL_COUNT)
+        .map(|_| Mutex::new(Writer::offscreen()))
+        .collect();
+}
+
+// Create the offscreen terminals. Must be called after the heap is
+// initialized; until then only terminal 0 exists and switching is ignored.
+pub fn init() {
+    lazy_static::initialize(&TERMINALS);
+    INITIALIZED.store(true, Ordering::Release);
+}
+
+// Return the index of the terminal shown on the screen.
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// Show the terminal with the given index, in the focused pane if the
+// screen is split. A terminal already shown in the other pane gets the
+// focus instead.
+pub fn switch_to(tty: usize) {
+    assert!(tty < TERMINAL_COUNT, "invalid terminal {}", tty);
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut screen = SCREEN.lock();
+        if screen.focused() == tty {
+            return;
+        }
+        let other = 1 - screen.focus;
+        if screen.layout != Layout::Single && screen.panes[other] == tty {
+            screen.focus = other;
+        } else {
+            let focus = screen.focus;
+            screen.panes[focus] = tty;
+        }
+        apply(&screen);
+    });
+}
+
+// Turn echoing typed characters on the given terminal on or off
+pub fn set_echo(tty: usize, enabled: bool) {
+    assert!(tty < TERMINAL_COUNT, "invalid terminal {}", tty);
+    if enabled {
+        NO_ECHO.fetch_and(!(1 << tty), Ordering::Relaxed);
+    } else {
+        NO_ECHO.fetch_or(1 << tty, Ordering::Relaxed);
+    }
+}
+
+// Return whether typed characters are echoed on the given terminal
+pub fn echo_enabled(tty: usize) -> bool {
+    NO_ECHO.load(Ordering::Relaxed) & (1 << tty) == 0
+}
+
+// Return how the screen is divided
+pub fn layout() -> Layout {
+    interrupts::without_interrupts(|| SCREEN.lock().layout)
+}
+
+// Divide the screen. Splitting shows the terminal with the kernel log next
+// to the active one, or terminal 1 if that is terminal 0.
+pub fn set_layout(layout: Layout) {
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut screen = SCREEN.lock();
+        if screen.layout == layout {
+            return;
+        }
+        if screen.layout == Layout::Single {
+            let tty = screen.focused();
+            screen.panes = [tty, if tty == 0 { 1 } else { 0 }];
+        } else if layout == Layout::Single {
+            screen.panes[0] = screen.focused();
+        }
+        screen.focus = 0;
+        screen.layout = layout;
+        apply(&screen);
+    });
+}
+
+// Move the focus to the other pane of a split screen
+pub fn focus_next() {
+    interrupts::without_interrupts(|| {
+        let mut screen = SCREEN.lock();
+        if screen.layout != Layout::Single {
+            screen.focus = 1 - screen.focus;
+            apply(&screen);
+        }
+    });
+}
+
+// Return the viewports of the panes of a layout, leaving room for the
+// border between them
+fn pane_viewports(layout: Layout) -> [Option<Viewport>; 2] {
+    let half_width = BUFFER_WIDTH / 2;
+    let half_height = BUFFER_HEIGHT / 2;
+    match layout {
+        Layout::Single => [Some(Viewport::FULL_SCREEN), None],
+        Layout::SideBySide => [
+            Some(Viewport { width: half_width, ..Viewport::FULL_SCREEN }),
+            Some(Viewport {
+                left: half_width + 1,
+                width: BUFFER_WIDTH - half_width - 1,
+                ..Viewport::FULL_SCREEN
+            }),
+        ],
+        Layout::Stacked => [
+            Some(Viewport { height: half_height, ..Viewport::FULL_SCREEN }),
+            Some(Viewport {
+                top: half_height + 1,
+                height: BUFFER_HEIGHT - half_height - 1,
+                ..Viewport::FULL_SCREEN
+            }),
+        ],
+    }
+}
+
+// Show the terminals of the screen in their panes and hide the others.
+// Terminals are locked one at a time, so this can't deadlock with a print.
+fn apply(screen: &Screen) {
+    let focused = screen.focused();
+    for tty in 0..TERMINAL_COUNT {
+        let viewport = screen.shown().find(|(shown, _)| *shown == tty).map(|(_, v)| v);
+        let mut writer = terminal(tty).lock();
+        match viewport {
+            Some(viewport) => {
+                if writer.viewport() != viewport {
+                    writer.set_viewport(viewport);
+                }
+                writer.set_focused(tty == focused);
+                writer.set_visible(true);
+            }
+            None => writer.set_visible(false),
+        }
+    }
+
+    match screen.layout {
+        Layout::Single => {}
+        Layout::SideBySide => {
+            for row in 0..BUFFER_HEIGHT {
+                vga_buffer::draw_cell(row, BUFFER_WIDTH / 2, VERTICAL_BORDER, Color::DarkGray, Color::Black);
+            }
+        }
+        Layout::Stacked => {
+            for col in 0..BUFFER_WIDTH {
+                vga_buffer::draw_cell(BUFFER_HEIGHT / 2, col, HORIZONTAL_BORDER, Color::DarkGray, Color::Black);
+            }
+        }
+    }
+
+    ACTIVE.store(focused, Ordering::Relaxed);
+}
+
+// Run `f` with the writer of the given terminal locked.
+pub fn with_terminal<F, R>(tty: usize, f: F) -> R
+where
+    F: FnOnce(&mut Writer) -> R,
+{
+    assert!(tty < TERMINAL_COUNT, "invalid terminal {}", tty);
+    interrupts::without_interrupts(|| f(&mut terminal(tty).lock()))
+}
+
+// Called on every timer interrupt to flush pending output of the terminals
+// on the screen, when flushing is deferred.
+pub(crate) fn on_tick() {
+    if !vga_buffer::deferred_flush_enabled() {
+        return;
+    }
+    // The timer may interrupt a print or a layout change; never spin here
+    let panes = match SCREEN.try_lock() {
+        Some(screen) => [
+            Some(screen.panes[0]),
+            Some(screen.panes[1]).filter(|_| screen.layout != Layout::Single),
+        ],
+        None => return,
+    };
+    for &tty in panes.iter().flatten() {
+        if let Some(mut writer) = terminal(tty).try_lock() {
+            if writer.needs_flush() {
+                writer.flush();
+            }
+        }
+    }
+}
+
+// Handle the console hotkeys. Returns `true` if the key was consumed and
+// must not be passed on.
+pub(crate) fn handle_key_event(event: &KeyEvent) -> bool {
+    let pressed = event.pressed;
+    let alt_pressed = event.modifiers.alt || event.modifiers.alt_gr;
+    if selection::handle_key_event(event, alt_pressed) {
+        return true;
+    }
+
+    if alt_pressed {
+        let layout = match event.code {
+            KeyCode::V => Some(Layout::SideBySide),
+            KeyCode::H => Some(Layout::Stacked),
+            KeyCode::X => Some(Layout::Single),
+            _ => None,
+        };
+        if let Some(layout) = layout {
+            if pressed {
+                set_layout(layout);
+            }
+            return true;
+        }
+        if event.code == KeyCode::Tab {
+            if pressed {
+                focus_next();
+            }
+            return true;
+        }
+    }
+
+    let tty = match event.code {
+        KeyCode::F1 => 0,
+        KeyCode::F2 => 1,
+        KeyCode::F3 => 2,
+        KeyCode::F4 => 3,
+        _ => return false,
+    };
+
+    if !alt_pressed {
+        return false;
+    }
+    if pressed {
+        switch_to(tty);
+    }
+    true
+}
+
+// Return the writer of the given terminal.
+fn terminal(tty: usize) -> &'static Mutex<Writer> {
+    match tty {
+        0 => &WRITER,
+        tty => {
+            assert!(INITIALIZED.load(Ordering::Acquire), "console is not initialized");
+            &TERMINALS[tty - 1]
+        }
+    }
+}
+
+// Like `print!`, but to the given terminal
+#[macro_export]
+macro_rules! console_print {
+    ($tty:expr, $($arg:tt)*) => (
+        $crate::console::_print($tty, format_args!($($arg)*))
+    );
+}
+
+// Like `println!`, but to the given terminal
+#[macro_export]
+macro_rules! console_println {
+    ($tty:expr) => ($crate::console_print!($tty, "\n"));
+    ($tty:expr, $($arg:tt)*) => (
+        $crate::console_print!($tty, "{}\n", format_args!($($arg)*))
+    );
+}
+
+// Print the given string on the given terminal.
+#[doc(hidden)]
+pub fn _print(tty: usize, args: Arguments) {
+    if tty != 0 && !INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    let flush = !vga_buffer::flush_is_deferred();
+    with_terminal(tty, |writer| {
+        writer.write_fmt(args).unwrap();
+        if flush {
+            writer.flush();
+        }
+    });
+}
+
+#[test_case]
+fn test_pane_viewports() {
+    let [left, right] = pane_viewports(Layout::SideBySide);
+    let (left, right) = (left.unwrap(), right.unwrap());
+    assert_eq!(left.width + 1 + right.width, BUFFER_WIDTH);
+    assert_eq!(right.left, left.width + 1);
+
+    let [top, bottom] = pane_viewports(Layout::Stacked);
+    let (top, bottom) = (top.unwrap(), bottom.unwrap());
+    assert_eq!(top.height + 1 + bottom.height, BUFFER_HEIGHT);
+    assert_eq!(bottom.top, top.height + 1);
+
+    assert_eq!(pane_viewports(Layout::Single), [Some(Viewport::FULL_SCREEN), None]);
+}
@@ -0,0 +1,277 @@
+// Progress bars and spinners for long operations.
+//
+// A widget takes a row at the bottom of a terminal that is kept out of the
+// scrolling text, so log output continues above it while it updates in
+// place. The row is given back to the text when the widget is dropped.
+//
+//     let mut bar = ProgressBar::new("loading initrd", size);
+//     for chunk in data.chunks(4096) {
+//         ...
+//         bar.inc(chunk.len() as u64);
+//     }
+//     bar.finish();
+
+use super::{with_terminal, TERMINAL_COUNT};
+use crate::vga_buffer::{self, Writer, BUFFER_WIDTH};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Maximum number of widgets per terminal
+const MAX_WIDGETS: usize = 8;
+
+// The animation of a spinner
+const SPINNER_FRAMES: [char; 4] = ['|', '/', '-', '\\'];
+
+// The rows taken by widgets on every terminal, as a bit mask of slots
+// counted from the bottom
+static SLOTS: Mutex<[u8; TERMINAL_COUNT]> = Mutex::new([0; TERMINAL_COUNT]);
+
+// A widget's row on a terminal
+struct Slot {
+    tty: usize,
+    index: usize,
+}
+
+impl Slot {
+    // Take the lowest free row of a terminal, if there is one
+    fn take(tty: usize) -> Option<Slot> {
+        assert!(tty < TERMINAL_COUNT, "invalid terminal {}", tty);
+        interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            let index = (0..MAX_WIDGETS).find(|i| slots[tty] & (1 << i) == 0)?;
+            slots[tty] |= 1 << index;
+            let reserved = reserved_rows(slots[tty]);
+            with_terminal(tty, |writer| writer.set_reserved_rows(reserved));
+            Some(Slot { tty, index })
+        })
+    }
+
+    // Replace the widget's row with `args`, padded with blanks
+    fn draw(&self, args: fmt::Arguments) {
+        let mut line = Line::new();
+        let _ = line.write_fmt(args);
+
+        let flush = !vga_buffer::flush_is_deferred();
+        with_terminal(self.tty, |writer| {
+            let row = self.row(writer);
+            writer.write_at(row, 0, line.as_str());
+            if flush {
+                writer.flush();
+            }
+        });
+    }
+
+    fn row(&self, writer: &Writer) -> usize {
+        writer.viewport().height - 1 - self.index
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mut slots = SLOTS.lock();
+            slots[self.tty] &= !(1 << self.index);
+            let reserved = reserved_rows(slots[self.tty]);
+            with_terminal(self.tty, |writer| {
+                // Blank the row, in case a widget above still keeps it
+                // out of the text
+                let row = self.row(writer);
+                writer.write_at(row, 0, Line::new().as_str());
+                writer.set_reserved_rows(reserved);
+                writer.flush();
+            });
+        });
+    }
+}
+
+// Return the number of rows needed for the slots in use
+fn reserved_rows(slots: u8) -> usize {
+    (u8::BITS - slots.leading_zeros()) as usize
+}
+
+// A line of text as wide as the screen, padded with blanks
+struct Line {
+    text: [u8; BUFFER_WIDTH],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            text: [b' '; BUFFER_WIDTH],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only ASCII is ever written
+        core::str::from_utf8(&self.text).unwrap_or("")
+    }
+}
+
+impl Write for Line {
+    // Text beyond the width of the screen is cut off
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == BUFFER_WIDTH {
+                break;
+            }
+            self.text[self.len] = if byte.is_ascii() { byte } else { b'?' };
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// A bar showing how much of an operation is done
+pub struct ProgressBar {
+    slot: Option<Slot>, // None if the terminal had no row left
+    label: &'static str,
+    total: u64,
+    current: u64,
+    shown_percent: Option<u64>,
+}
+
+impl ProgressBar {
+    // Create a progress bar for `total` units of work on terminal 0
+    pub fn new(label: &'static str, total: u64) -> Self {
+        Self::on_terminal(0, label, total)
+    }
+
+    // Create a progress bar on the given terminal
+    pub fn on_terminal(tty: usize, label: &'static str, total: u64) -> Self {
+        let mut bar = ProgressBar {
+            slot: Slot::take(tty),
+            label,
+            total,
+            current: 0,
+            shown_percent: None,
+        };
+        bar.draw();
+        bar
+    }
+
+    // Set the amount of work done
+    pub fn set(&mut self, current: u64) {
+        self.current = current.min(self.total);
+        self.draw();
+    }
+
+    // Add to the amount of work done
+    pub fn inc(&mut self, delta: u64) {
+        self.set(self.current.saturating_add(delta));
+    }
+
+    // Return the amount of work done
+    pub fn position(&self) -> u64 {
+        self.current
+    }
+
+    // Show the bar as complete and remove it
+    pub fn finish(mut self) {
+        self.set(self.total);
+    }
+
+    fn percent(&self) -> u64 {
+        match self.total {
+            0 => 100,
+            total => self.current * 100 / total,
+        }
+    }
+
+    // Redraw the bar if what it shows changed
+    fn draw(&mut self) {
+        let percent = self.percent();
+        if self.shown_percent == Some(percent) {
+            return;
+        }
+        self.shown_percent = Some(percent);
+
+        if let Some(slot) = &self.slot {
+            let bar = Bar {
+                percent,
+                width: self.bar_width(),
+            };
+            slot.draw(format_args!("{} {} {:>3}%", self.label, bar, percent));
+        }
+    }
+
+    // The number of cells between the brackets
+    fn bar_width(&self) -> usize {
+        let width = match &self.slot {
+            Some(slot) => with_terminal(slot.tty, |writer| writer.viewport().width),
+            None => BUFFER_WIDTH,
+        };
+        width.saturating_sub(self.label.len() + 8)
+    }
+}
+
+// Formats the filled part of a progress bar
+struct Bar {
+    percent: u64,
+    width: usize,
+}
+
+impl fmt::Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filled = self.width * self.percent as usize / 100;
+        f.write_char('[')?;
+        for cell in 0..self.width {
+            f.write_char(if cell < filled { '#' } else { '.' })?;
+        }
+        f.write_char(']')
+    }
+}
+
+// An animation showing that an operation of unknown length is running
+pub struct Spinner {
+    slot: Option<Slot>, // None if the terminal had no row left
+    label: &'static str,
+    frame: usize,
+}
+
+impl Spinner {
+    // Create a spinner on terminal 0
+    pub fn new(label: &'static str) -> Self {
+        Self::on_terminal(0, label)
+    }
+
+    // Create a spinner on the given terminal
+    pub fn on_terminal(tty: usize, label: &'static str) -> Self {
+        let spinner = Spinner {
+            slot: Slot::take(tty),
+            label,
+            frame: 0,
+        };
+        spinner.draw();
+        spinner
+    }
+
+    // Advance the animation
+    pub fn tick(&mut self) {
+        self.frame = (self.frame + 1) % SPINNER_FRAMES.len();
+        self.draw();
+    }
+
+    // Change the text next to the animation
+    pub fn set_label(&mut self, label: &'static str) {
+        self.label = label;
+        self.draw();
+    }
+
+    fn draw(&self) {
+        if let Some(slot) = &self.slot {
+            slot.draw(format_args!("{} {}", SPINNER_FRAMES[self.frame], self.label));
+        }
+    }
+}
+
+#[test_case]
+fn test_bar_format() {
+    let mut line = Line::new();
+    write!(line, "{}", Bar { percent: 50, width: 10 }).unwrap();
+    assert_eq!(&line.as_str()[..12], "[#####.....]");
+    assert_eq!(reserved_rows(0b101), 3);
+    assert_eq!(reserved_rows(0), 0);
+}
@@ -0,0 +1,218 @@
+// Selecting text on the console with the keyboard.
+//
+// Alt+M enters mark mode on the terminal shown. The arrow keys, Home and End
+// move the selection cursor, and holding Shift extends the selection from
+// where it started. Enter copies the highlighted text to the clipboard and
+// Escape leaves mark mode without copying. Shift+Insert pastes the clipboard
+// as if it was typed.
+
+use super::{active, with_terminal};
+use crate::clipboard;
+use crate::input::{self, DeviceId, InputEvent};
+use crate::keyboard::KeyEvent;
+use crate::vga_buffer::Writer;
+use pc_keyboard::KeyCode;
+use spin::Mutex;
+
+// Number of bytes pasted at a time, so the clipboard isn't locked while
+// the terminal is
+const PASTE_CHUNK: usize = 64;
+
+// A position on the screen, ordered by reading order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Position {
+    row: usize,
+    col: usize,
+}
+
+// The text selected in mark mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Selection {
+    tty: usize,
+    height: usize, // The size of the terminal's viewport
+    width: usize,
+    anchor: Position, // Where the selection started
+    cursor: Position, // The end moved by the arrow keys
+}
+
+impl Selection {
+    // Return the selected columns `from..to` of every selected row
+    fn spans(&self) -> impl Iterator<Item = (usize, usize, usize)> {
+        let start = self.anchor.min(self.cursor);
+        let end = self.anchor.max(self.cursor);
+        let width = self.width;
+        (start.row..=end.row).map(move |row| {
+            let from = if row == start.row { start.col } else { 0 };
+            let to = if row == end.row { end.col + 1 } else { width };
+            (row, from, to)
+        })
+    }
+
+    // Toggle the highlighting of the selected cells
+    fn invert(&self, writer: &mut Writer) {
+        for (row, from, to) in self.spans() {
+            writer.invert_cells(row, from, to);
+        }
+    }
+}
+
+// The selection while in mark mode
+static SELECTION: Mutex<Option<Selection>> = Mutex::new(None);
+
+// Handle the selection and paste keys. Returns `true` if the key was
+// consumed; in mark mode every key is.
+pub(super) fn handle_key_event(event: &KeyEvent, alt_pressed: bool) -> bool {
+    let pressed = event.pressed;
+    let shift_pressed = event.modifiers.shift();
+
+    let mut selection = SELECTION.lock();
+    let current = match *selection {
+        Some(current) => current,
+        None => {
+            return match event.code {
+                KeyCode::M if alt_pressed => {
+                    if pressed {
+                        *selection = Some(start());
+                    }
+                    true
+                }
+                KeyCode::Insert if shift_pressed => {
+                    if pressed {
+                        drop(selection);
+                        paste();
+                    }
+                    true
+                }
+                _ => false,
+            };
+        }
+    };
+
+    if !pressed {
+        return true;
+    }
+
+    let mut cursor = current.cursor;
+    match event.code {
+        KeyCode::ArrowUp => cursor.row = cursor.row.saturating_sub(1),
+        KeyCode::ArrowDown => cursor.row = (cursor.row + 1).min(current.height - 1),
+        KeyCode::ArrowLeft => cursor.col = cursor.col.saturating_sub(1),
+        KeyCode::ArrowRight => cursor.col = (cursor.col + 1).min(current.width - 1),
+        KeyCode::Home => cursor.col = 0,
+        KeyCode::End => cursor.col = current.width - 1,
+        KeyCode::Enter => {
+            *selection = None;
+            finish(&current, true);
+            return true;
+        }
+        KeyCode::Escape => {
+            *selection = None;
+            finish(&current, false);
+            return true;
+        }
+        _ => return true,
+    }
+
+    let anchor = if shift_pressed { current.anchor } else { cursor };
+    let next = Selection { anchor, cursor, ..current };
+    with_terminal(current.tty, |writer| {
+        current.invert(writer);
+        next.invert(writer);
+        writer.flush();
+    });
+    *selection = Some(next);
+    true
+}
+
+// Enter mark mode at the cursor of the terminal shown
+fn start() -> Selection {
+    let tty = active();
+    with_terminal(tty, |writer| {
+        let (row, col) = writer.cursor_position();
+        let position = Position { row, col };
+        let viewport = writer.viewport();
+        let selection = Selection {
+            tty,
+            height: viewport.height,
+            width: viewport.width,
+            anchor: position,
+            cursor: position,
+        };
+        selection.invert(writer);
+        writer.flush();
+        selection
+    })
+}
+
+// Leave mark mode, copying the selected text if `copy` is set
+fn finish(selection: &Selection, copy: bool) {
+    with_terminal(selection.tty, |writer| {
+        selection.invert(writer);
+        writer.flush();
+        if copy {
+            copy_selection(selection, writer);
+        }
+    });
+}
+
+// Copy the selected text to the clipboard, one line per row without the
+// trailing blanks
+fn copy_selection(selection: &Selection, writer: &Writer) {
+    clipboard::clear();
+    for (i, (row, from, to)) in selection.spans().enumerate() {
+        if i > 0 {
+            clipboard::push(b'\n');
+        }
+        let end = (from..to)
+            .rev()
+            .find(|&col| writer.char_at(row, col) != b' ')
+            .map_or(from, |col| col + 1);
+        for col in from..end {
+            clipboard::push(writer.char_at(row, col));
+        }
+    }
+}
+
+// Type the clipboard contents on the terminal shown
+fn paste() {
+    let tty = active();
+    let mut chunk = [0; PASTE_CHUNK];
+    let mut offset = 0;
+
+    loop {
+        let len = clipboard::with_contents(|text| {
+            let rest = &text[offset.min(text.len())..];
+            let len = rest.len().min(PASTE_CHUNK);
+            chunk[..len].copy_from_slice(&rest[..len]);
+            len
+        });
+        if len == 0 {
+            break;
+        }
+        offset += len;
+
+        with_terminal(tty, |writer| {
+            for &byte in &chunk[..len] {
+                input::report(DeviceId::KEYBOARD, InputEvent::Char(byte as char));
+                writer.write_byte(byte);
+            }
+            writer.flush();
+        });
+    }
+}
+
+#[test_case]
+fn test_selection_spans() {
+    let selection = Selection {
+        tty: 0,
+        height: 25,
+        width: 40,
+        anchor: Position { row: 3, col: 10 },
+        cursor: Position { row: 1, col: 5 },
+    };
+    let mut spans = selection.spans();
+    assert_eq!(spans.next(), Some((1, 5, 40)));
+    assert_eq!(spans.next(), Some((2, 0, 40)));
+    assert_eq!(spans.next(), Some((3, 0, 11)));
+    assert_eq!(spans.next(), None);
+}
@@ -0,0 +1,158 @@
+use crate::memory::{self, low};
+use core::ptr;
+use x86_64::{structures::paging::frame::PhysFrameRange, PhysAddr, VirtAddr};
+
+/// Devices that can only address the first 16 MiB (ISA DMA).
+pub const DMA_MASK_24BIT: u64 = 0xFF_FFFF;
+
+/// Devices that can only address the first 4 GiB (32-bit PCI devices).
+pub const DMA_MASK_32BIT: u64 = 0xFFFF_FFFF;
+
+/// Devices that can address the complete physical address space.
+pub const DMA_MASK_64BIT: u64 = u64::MAX;
+
+/// The direction in which data is transferred by a DMA operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    /// The device reads from the buffer (e.g. a NIC transmitting a packet).
+    ToDevice,
+    /// The device writes into the buffer (e.g. a disk read).
+    FromDevice,
+    /// The device both reads and writes the buffer.
+    Bidirectional,
+}
+
+/// A kernel buffer made accessible to a device.
+///
+/// If the buffer is physically contiguous and lies completely below the
+/// device's DMA mask, the device accesses it directly. Otherwise the data is
+/// transparently bounced through a buffer in low memory: it is copied there
+/// before the device reads it and copied back after the device wrote it, so
+/// drivers don't have to care about the addressing limits of their hardware.
+///
+/// The mapping is released and, if necessary, the data is copied back into
+/// the original buffer when the `DmaMapping` is dropped.
+pub struct DmaMapping<'a> {
+    buffer: &'a mut [u8],
+    direction: DmaDirection,
+    device_address: PhysAddr,
+    bounce: Option<PhysFrameRange>,
+}
+
+impl<'a> DmaMapping<'a> {
+    /// Maps `buffer` for a device that can only address physical memory up
+    /// to and including `dma_mask`.
+    ///
+    /// Returns `None` if the buffer is empty, not mapped, or if no bounce
+    /// buffer could be allocated.
+    pub fn new(buffer: &'a mut [u8], direction: DmaDirection, dma_mask: u64) -> Option<Self> {
+        if buffer.is_empty() {
+            return None;
+        }
+
+        let start = VirtAddr::from_ptr(buffer.as_ptr());
+        let phys_start = memory::virt_to_phys(start)?;
+
+        let reachable = is_physically_contiguous(start, buffer.len(), phys_start)
+            && phys_start.as_u64() + (buffer.len() as u64 - 1) <= dma_mask;
+
+        let mut mapping = if reachable {
+            DmaMapping {
+                buffer,
+                direction,
+                device_address: phys_start,
+                bounce: None,
+            }
+        } else {
+            // Low memory is reachable by every device we support
+            let frames = (buffer.len() + 4095) / 4096;
+            let bounce = low::allocate(frames)?;
+            DmaMapping {
+                buffer,
+                direction,
+                device_address: bounce.start.start_address(),
+                bounce: Some(bounce),
+            }
+        };
+
+        mapping.sync_for_device();
+        Some(mapping)
+    }
+
+    /// The physical address that should be handed to the device.
+    pub fn device_address(&self) -> PhysAddr {
+        self.device_address
+    }
+
+    /// Returns `true` if the data is bounced through low memory.
+    pub fn is_bounced(&self) -> bool {
+        self.bounce.is_some()
+    }
+
+    /// Makes CPU writes to the buffer visible to the device.
+    ///
+    /// Must be called after modifying the buffer and before starting a
+    /// transfer to the device. This is done automatically by `new`.
+    pub fn sync_for_device(&mut self) {
+        if self.direction == DmaDirection::FromDevice {
+            return;
+        }
+        if self.bounce.is_some() {
+            unsafe {
+                ptr::copy_nonoverlapping(self.buffer.as_ptr(), self.bounce_ptr(), self.buffer.len());
+            }
+        }
+    }
+
+    /// Makes device writes visible to the CPU.
+    ///
+    /// Must be called after a transfer from the device has completed and
+    /// before reading the buffer. This is done automatically on drop.
+    pub fn sync_for_cpu(&mut self) {
+        if self.direction == DmaDirection::ToDevice {
+            return;
+        }
+        if self.bounce.is_some() {
+            unsafe {
+                ptr::copy_nonoverlapping(self.bounce_ptr(), self.buffer.as_mut_ptr(), self.buffer.len());
+            }
+        }
+    }
+
+    /// Gives the CPU access to the mapped buffer.
+    ///
+    /// Call `sync_for_cpu` first if the device may have written to it.
+    pub fn buffer(&mut self) -> &mut [u8] {
+        &mut *self.buffer
+    }
+
+    // The CPU-visible address of the bounce buffer.
+    fn bounce_ptr(&self) -> *mut u8 {
+        memory::phys_to_virt(self.device_address).as_mut_ptr()
+    }
+}
+
+impl Drop for DmaMapping<'_> {
+    fn drop(&mut self) {
+        self.sync_for_cpu();
+        if let Some(bounce) = self.bounce.take() {
+            low::free(bounce);
+        }
+    }
+}
+
+// Check whether the `len` bytes starting at `start` are backed by physically
+// contiguous memory starting at `phys_start`.
+fn is_physically_contiguous(start: VirtAddr, len: usize, phys_start: PhysAddr) -> bool {
+    let end = start + (len as u64 - 1);
+    let mut page = start.align_down(4096u64);
+    while page <= end {
+        let virt = if page < start { start } else { page };
+        match memory::virt_to_phys(virt) {
+            Some(phys) if phys.as_u64().wrapping_sub(phys_start.as_u64()) == virt - start => {}
+            _ => return false,
+        }
+        page += 4096u64;
+    }
+    true
+}
@@ -0,0 +1,458 @@
+// Loading ELF64 executables from the VFS into user space.
+//
+// `load` reads the headers of a statically linked x86_64 executable, maps
+// each PT_LOAD segment at its address with fresh frames, copies the file's
+// bytes in and clears the rest, e.g. the .bss. The pages get the segment's
+// permissions: WRITABLE only with PF_W, and NO_EXECUTE without PF_X if
+// no-execute is enabled (EFER.NXE, which the bootloader sets). x86 has no
+// separate read permission, so read-only data is just not writable.
+// Position independent executables and dynamic linking aren't supported.
+//
+// Segments may not share a page or land on pages that are already mapped,
+// nor, in the address space of a process, under the level 4 entries it
+// shares with the kernel (see `memory::create_address_space`). They are
+// written through the physical memory mapping rather than their user
+// addresses, so the address space doesn't have to be the active one.
+//
+// `Image::setup_stack` maps a stack below `STACK_TOP` and lays out argc,
+// argv and empty environment and auxiliary vectors the way the System V
+// ABI has them at the entry point. `exec` does it all, runs the program
+// with `usermode::run` and unmaps it again when it exits.
+
+use crate::memory;
+use crate::usercopy::USER_SPACE_END;
+use crate::usermode;
+use crate::vfs::{self, VfsError};
+use alloc::vec::Vec;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::mapper::{MapToError, TranslateError};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+    Size4KiB,
+};
+use x86_64::VirtAddr;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const MAX_PROGRAM_HEADERS: u16 = 64;
+
+// Program header types and flags
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+// The user stack, 64 KiB ending at `STACK_TOP`
+pub const STACK_TOP: u64 = 0x7FFF_FFFF_0000;
+pub const STACK_PAGES: u64 = 16;
+// The most bytes the arguments may take on the stack
+const MAX_ARGUMENTS_SIZE: usize = 16 * 1024;
+
+// Errors of loading an executable
+#[derive(Debug)]
+pub enum ElfError {
+    // Reading the file failed
+    Io(VfsError),
+    // The file doesn't start with an ELF header
+    NotElf,
+    // Not a 64 bit little endian x86_64 executable
+    Unsupported,
+    // A header or segment is inconsistent, e.g. lies outside of the file
+    // or of user space
+    Malformed,
+    // A segment lands on a page that is already mapped
+    AddressInUse,
+    // The arguments don't fit on the stack
+    ArgumentsTooLong,
+    // Mapping the pages failed
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<VfsError> for ElfError {
+    fn from(err: VfsError) -> Self {
+        ElfError::Io(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for ElfError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+// The fields of the ELF header the loader needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    entry: u64,
+    program_headers: u64, // The file offset of the program header table
+    program_header_count: u16,
+}
+
+fn parse_header(bytes: &[u8; HEADER_SIZE]) -> Result<Header, ElfError> {
+    if bytes[..4] != ELF_MAGIC {
+        return Err(ElfError::NotElf);
+    }
+    if bytes[4] != CLASS_64 || bytes[5] != DATA_LITTLE_ENDIAN || bytes[6] != VERSION_CURRENT {
+        return Err(ElfError::Unsupported);
+    }
+    if read_u16(bytes, 16) != TYPE_EXEC || read_u16(bytes, 18) != MACHINE_X86_64 {
+        return Err(ElfError::Unsupported);
+    }
+    let header = Header {
+        entry: read_u64(bytes, 24),
+        program_headers: read_u64(bytes, 32),
+        program_header_count: read_u16(bytes, 56),
+    };
+    if read_u16(bytes, 54) as usize != PROGRAM_HEADER_SIZE
+        || header.program_header_count > MAX_PROGRAM_HEADERS
+        || header.entry >= USER_SPACE_END
+    {
+        return Err(ElfError::Malformed);
+    }
+    Ok(header)
+}
+
+// A PT_LOAD program header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Segment {
+    offset: u64, // In the file
+    vaddr: u64,
+    file_size: u64,
+    mem_size: u64,
+    flags: u32,
+}
+
+impl Segment {
+    // Parse a program header; `None` if it isn't a PT_LOAD one
+    fn parse(bytes: &[u8; PROGRAM_HEADER_SIZE]) -> Option<Segment> {
+        if read_u32(bytes, 0) != PT_LOAD {
+            return None;
+        }
+        Some(Segment {
+            flags: read_u32(bytes, 4),
+            offset: read_u64(bytes, 8),
+            vaddr: read_u64(bytes, 16),
+            file_size: read_u64(bytes, 32),
+            mem_size: read_u64(bytes, 40),
+        })
+    }
+
+    // Check that the segment lies in user space and its bytes in a file of
+    // `len` bytes
+    fn check(&self, len: u64) -> Result<(), ElfError> {
+        let in_file = matches!(self.offset.checked_add(self.file_size), Some(end) if end <= len);
+        let in_user_space =
+            matches!(self.vaddr.checked_add(self.mem_size), Some(end) if end <= USER_SPACE_END);
+        if self.file_size > self.mem_size || !in_file || !in_user_space {
+            return Err(ElfError::Malformed);
+        }
+        Ok(())
+    }
+
+    // Must not be called for empty segments
+    fn pages(&self) -> PageRangeInclusive<Size4KiB> {
+        let start = Page::containing_address(VirtAddr::new(self.vaddr));
+        let end = Page::containing_address(VirtAddr::new(self.vaddr + self.mem_size - 1));
+        Page::range_inclusive(start, end)
+    }
+
+    fn page_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        flags.set(PageTableFlags::WRITABLE, self.flags & PF_W != 0);
+        flags.set(PageTableFlags::NO_EXECUTE, self.flags & PF_X == 0 && no_execute_enabled());
+        flags
+    }
+}
+
+fn no_execute_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+// A program loaded into user space
+#[derive(Debug)]
+pub struct Image {
+    pub entry: VirtAddr,
+    // The pages mapped for the program, each range with its own frames
+    regions: Vec<PageRangeInclusive<Size4KiB>>,
+}
+
+impl Image {
+    // Map the user stack and put the arguments on it. Returns the stack
+    // pointer to start the program with, which points at argc.
+    //
+    // This function is unsafe for the same reasons as `load`.
+    pub unsafe fn setup_stack<A>(
+        &mut self,
+        argv: &[&str],
+        mapper: &mut OffsetPageTable,
+        frame_allocator: &mut A,
+    ) -> Result<VirtAddr, ElfError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        let strings: usize = argv.iter().map(|arg| arg.len() + 1).sum();
+        if strings + (argv.len() + 5) * 8 + 15 > MAX_ARGUMENTS_SIZE {
+            return Err(ElfError::ArgumentsTooLong);
+        }
+
+        let bottom = VirtAddr::new(STACK_TOP - STACK_PAGES * Size4KiB::SIZE);
+        let start = Page::containing_address(bottom);
+        let pages = Page::range_inclusive(start, start + (STACK_PAGES - 1));
+        let mut flags = PageTableFlags::WRITABLE;
+        flags.set(PageTableFlags::NO_EXECUTE, no_execute_enabled());
+        map_unused(mapper, pages, flags, frame_allocator)?;
+        self.regions.push(pages);
+        for page in pages {
+            user_page(mapper, page).fill(0);
+        }
+
+        // The strings go at the top, then argc, the argv pointers and a
+        // NULL, a NULL for envp and an AT_NULL entry ending the auxiliary
+        // vector, starting at a 16 byte aligned address
+        let mut sp = STACK_TOP;
+        let mut words = Vec::with_capacity(argv.len() + 5);
+        words.push(argv.len() as u64);
+        for arg in argv {
+            // The stack is cleared, so the terminating NUL is already there
+            sp -= arg.len() as u64 + 1;
+            write_user(mapper, sp, arg.as_bytes());
+            words.push(sp);
+        }
+        words.extend_from_slice(&[0, 0, 0, 0]);
+        sp = (sp - words.len() as u64 * 8) & !15;
+        for (i, word) in words.iter().enumerate() {
+            write_user(mapper, sp + i as u64 * 8, &word.to_ne_bytes());
+        }
+        Ok(VirtAddr::new(sp))
+    }
+
+    // Unmap the program's pages and free their frames.
+    //
+    // This function is unsafe because the program must not run any more.
+    pub unsafe fn unmap(
+        self,
+        mapper: &mut OffsetPageTable,
+        frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+    ) {
+        for pages in self.regions {
+            if let Err(err) = memory::unmap_range(mapper, pages, true, frame_deallocator) {
+                log::error!("elf: can't unmap {:?}: {:?}", pages, err);
+            }
+        }
+    }
+}
+
+// Load the executable at `path` into the user half of the address space
+// `mapper` manages.
+//
+// This function is unsafe because `memory::init` must have been called.
+pub unsafe fn load<A>(
+    path: &str,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<Image, ElfError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let len = vfs::metadata(path)?.size;
+    let mut bytes = [0; HEADER_SIZE];
+    read_exact(path, 0, &mut bytes)?;
+    let header = parse_header(&bytes)?;
+
+    let mut image = Image {
+        entry: VirtAddr::new(header.entry),
+        regions: Vec::new(),
+    };
+    for i in 0..header.program_header_count as u64 {
+        let offset = header.program_headers + i * PROGRAM_HEADER_SIZE as u64;
+        let result = load_segment(path, offset, len, &mut image, mapper, frame_allocator);
+        if let Err(err) = result {
+            image.unmap(mapper, frame_allocator);
+            return Err(err);
+        }
+    }
+    Ok(image)
+}
+
+// Map the segment whose program header is at `offset`, if it is a PT_LOAD
+// one, and fill its pages
+unsafe fn load_segment<A>(
+    path: &str,
+    offset: u64,
+    len: u64,
+    image: &mut Image,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<(), ElfError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let mut bytes = [0; PROGRAM_HEADER_SIZE];
+    read_exact(path, offset, &mut bytes)?;
+    let segment = match Segment::parse(&bytes) {
+        Some(segment) if segment.mem_size > 0 => segment,
+        _ => return Ok(()),
+    };
+    segment.check(len)?;
+
+    let pages = segment.pages();
+    map_unused(mapper, pages, segment.page_flags(), frame_allocator)?;
+    image.regions.push(pages);
+
+    let file_end = segment.vaddr + segment.file_size;
+    for page in pages {
+        let data = user_page(mapper, page);
+        data.fill(0);
+        // The part of the page backed by the file
+        let page_start = page.start_address().as_u64();
+        let start = page_start.max(segment.vaddr);
+        let end = (page_start + Size4KiB::SIZE).min(file_end);
+        if start < end {
+            let buf = &mut data[(start - page_start) as usize..(end - page_start) as usize];
+            read_exact(path, segment.offset + (start - segment.vaddr), buf)?;
+        }
+    }
+    Ok(())
+}
+
+// Load the executable at `path`, run it with the arguments `argv` and
+// return its exit status once it exits. The program is unmapped again
+// afterwards.
+//
+// This function is unsafe because `mapper` must manage the active page
+// table, and `memory::init` must have been called.
+pub unsafe fn exec<A>(
+    path: &str,
+    argv: &[&str],
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut A,
+) -> Result<u64, ElfError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let mut image = load(path, mapper, frame_allocator)?;
+    let result = image
+        .setup_stack(argv, mapper, frame_allocator)
+        .map(|stack_top| usermode::run(image.entry, stack_top));
+    image.unmap(mapper, frame_allocator);
+    result
+}
+
+// Map `pages` for user space if none of them is mapped yet, and none is in
+// a part of the address space shared with the kernel. If mapping fails
+// halfway, the pages mapped so far are unmapped again.
+unsafe fn map_unused<A>(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    flags: PageTableFlags,
+    frame_allocator: &mut A,
+) -> Result<(), ElfError>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    for page in pages {
+        let mapped = !matches!(mapper.translate_page(page), Err(TranslateError::PageNotMapped));
+        if mapped || memory::is_shared_with_kernel(mapper, page) {
+            return Err(ElfError::AddressInUse);
+        }
+    }
+    if let Err(err) = usermode::map_pages(mapper, pages, flags, frame_allocator) {
+        let _ = memory::unmap_range(mapper, pages, true, frame_allocator);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+// Return the contents of a mapped page, through the physical memory mapping
+unsafe fn user_page(mapper: &OffsetPageTable, page: Page<Size4KiB>) -> &'static mut [u8; 4096] {
+    let frame = mapper.translate_page(page).expect("user page not mapped");
+    &mut *memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+// Copy `bytes` to the mapped user address `addr`
+unsafe fn write_user(mapper: &OffsetPageTable, addr: u64, bytes: &[u8]) {
+    let mut done = 0;
+    while done < bytes.len() {
+        let at = addr + done as u64;
+        let page = Page::containing_address(VirtAddr::new(at));
+        let in_page = (at - page.start_address().as_u64()) as usize;
+        let len = (bytes.len() - done).min(Size4KiB::SIZE as usize - in_page);
+        user_page(mapper, page)[in_page..in_page + len].copy_from_slice(&bytes[done..done + len]);
+        done += len;
+    }
+}
+
+// Fill `buf` from the file at `offset`; a file that ends too early is
+// malformed
+fn read_exact(path: &str, mut offset: u64, mut buf: &mut [u8]) -> Result<(), ElfError> {
+    while !buf.is_empty() {
+        let len = vfs::read(path, offset, buf)?;
+        if len == 0 {
+            return Err(ElfError::Malformed);
+        }
+        offset += len as u64;
+        buf = &mut buf[len..];
+    }
+    Ok(())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let b = &bytes[offset..offset + 4];
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let b = &bytes[offset..offset + 8];
+    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+#[test_case]
+fn test_parse_header() {
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[..4].copy_from_slice(&ELF_MAGIC);
+    bytes[4..7].copy_from_slice(&[CLASS_64, DATA_LITTLE_ENDIAN, VERSION_CURRENT]);
+    bytes[16..20].copy_from_slice(&[2, 0, 0x3E, 0]);
+    bytes[24..32].copy_from_slice(&0x40_1000u64.to_le_bytes());
+    bytes[32] = 64;
+    bytes[54] = PROGRAM_HEADER_SIZE as u8;
+    bytes[56] = 2;
+    let header = parse_header(&bytes).unwrap();
+    assert_eq!(header.entry, 0x40_1000);
+    assert_eq!(header.program_headers, 64);
+    assert_eq!(header.program_header_count, 2);
+
+    bytes[18] = 0x28; // ARM
+    assert!(matches!(parse_header(&bytes), Err(ElfError::Unsupported)));
+    bytes[0] = 0;
+    assert!(matches!(parse_header(&bytes), Err(ElfError::NotElf)));
+}
+
+#[test_case]
+fn test_segment_check() {
+    let segment = Segment {
+        offset: 0x1000,
+        vaddr: 0x40_0000,
+        file_size: 0x800,
+        mem_size: 0x2000,
+        flags: PF_W,
+    };
+    assert!(segment.check(0x1800).is_ok());
+    assert_eq!(segment.pages().count(), 2);
+    assert!(segment.page_flags().contains(PageTableFlags::WRITABLE));
+    // Past the end of the file
+    assert!(segment.check(0x17FF).is_err());
+    // Into the kernel half
+    let segment = Segment { vaddr: USER_SPACE_END - 0x1000, ..segment };
+    assert!(segment.check(0x1800).is_err());
+}
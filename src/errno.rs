@@ -0,0 +1,165 @@
+// Error numbers returned to user space.
+//
+// System calls return a non-negative value on success and the negated error
+// number on failure, as on Linux; the numbers are the Linux ones so that
+// existing C libraries can decode them.
+
+use crate::usercopy::UserCopyError;
+use crate::vfs::VfsError;
+use core::fmt;
+
+// An error number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    EPERM = 1, // Operation not permitted
+    ENOENT = 2, // No such file or directory
+    ESRCH = 3, // No such process
+    EINTR = 4, // Interrupted system call
+    EIO = 5, // I/O error
+    EBADF = 9, // Bad file descriptor
+    ECHILD = 10, // No child processes
+    EAGAIN = 11, // Try again
+    ENOMEM = 12, // Out of memory
+    EACCES = 13, // Permission denied
+    EFAULT = 14, // Bad address
+    EBUSY = 16, // Device or resource busy
+    EEXIST = 17, // File exists
+    ENOTDIR = 20, // Not a directory
+    EISDIR = 21, // Is a directory
+    EINVAL = 22, // Invalid argument
+    EMFILE = 24, // Too many open files
+    ENOSPC = 28, // No space left on device
+    ESPIPE = 29, // Illegal seek
+    EROFS = 30, // Read-only file system
+    ERANGE = 34, // Result too large
+    ENAMETOOLONG = 36, // File name too long
+    ENOSYS = 38, // Function not implemented
+    ENOTEMPTY = 39, // Directory not empty
+}
+
+// The errors in order of their numbers
+const ALL: [Errno; 24] = [
+    Errno::EPERM,
+    Errno::ENOENT,
+    Errno::ESRCH,
+    Errno::EINTR,
+    Errno::EIO,
+    Errno::EBADF,
+    Errno::ECHILD,
+    Errno::EAGAIN,
+    Errno::ENOMEM,
+    Errno::EACCES,
+    Errno::EFAULT,
+    Errno::EBUSY,
+    Errno::EEXIST,
+    Errno::ENOTDIR,
+    Errno::EISDIR,
+    Errno::EINVAL,
+    Errno::EMFILE,
+    Errno::ENOSPC,
+    Errno::ESPIPE,
+    Errno::EROFS,
+    Errno::ERANGE,
+    Errno::ENAMETOOLONG,
+    Errno::ENOSYS,
+    Errno::ENOTEMPTY,
+];
+
+impl Errno {
+    // Return the value a system call returns for this error
+    pub fn to_return_value(self) -> i64 {
+        -(self as i64)
+    }
+
+    // Decode a system call return value; `None` for a success value or an
+    // unknown error number
+    pub fn from_return_value(value: i64) -> Option<Errno> {
+        if value >= 0 {
+            return None;
+        }
+        ALL.iter().copied().find(|&errno| errno as i64 == -value)
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Errno::EPERM => "operation not permitted",
+            Errno::ENOENT => "no such file or directory",
+            Errno::ESRCH => "no such process",
+            Errno::EINTR => "interrupted system call",
+            Errno::EIO => "I/O error",
+            Errno::EBADF => "bad file descriptor",
+            Errno::ECHILD => "no child processes",
+            Errno::EAGAIN => "try again",
+            Errno::ENOMEM => "out of memory",
+            Errno::EACCES => "permission denied",
+            Errno::EFAULT => "bad address",
+            Errno::EBUSY => "device or resource busy",
+            Errno::EEXIST => "file exists",
+            Errno::ENOTDIR => "not a directory",
+            Errno::EISDIR => "is a directory",
+            Errno::EINVAL => "invalid argument",
+            Errno::EMFILE => "too many open files",
+            Errno::ENOSPC => "no space left on device",
+            Errno::ESPIPE => "illegal seek",
+            Errno::EROFS => "read-only file system",
+            Errno::ERANGE => "result too large",
+            Errno::ENAMETOOLONG => "file name too long",
+            Errno::ENOSYS => "function not implemented",
+            Errno::ENOTEMPTY => "directory not empty",
+        }
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({})", self, self.description())
+    }
+}
+
+// A bad user pointer is EFAULT, whether it points into the kernel or at
+// unmapped memory
+impl From<UserCopyError> for Errno {
+    fn from(_: UserCopyError) -> Errno {
+        Errno::EFAULT
+    }
+}
+
+impl From<VfsError> for Errno {
+    fn from(err: VfsError) -> Errno {
+        match err {
+            VfsError::NotFound => Errno::ENOENT,
+            VfsError::NotADirectory => Errno::ENOTDIR,
+            VfsError::IsADirectory => Errno::EISDIR,
+            VfsError::Exists => Errno::EEXIST,
+            VfsError::ReadOnly => Errno::EROFS,
+            VfsError::NoSpace => Errno::ENOSPC,
+            VfsError::NotEmpty => Errno::ENOTEMPTY,
+            VfsError::Busy => Errno::EBUSY,
+            VfsError::BadPath | VfsError::Unsupported => Errno::EINVAL,
+            VfsError::Io => Errno::EIO,
+        }
+    }
+}
+
+// Turn a system call result into the value returned to user space
+pub fn to_return_value(result: Result<u64, Errno>) -> i64 {
+    match result {
+        Ok(value) => value as i64,
+        Err(errno) => errno.to_return_value(),
+    }
+}
+
+#[test_case]
+fn test_return_values() {
+    assert_eq!(to_return_value(Ok(3)), 3);
+    assert_eq!(to_return_value(Err(Errno::EBADF)), -9);
+    assert_eq!(Errno::from_return_value(-14), Some(Errno::EFAULT));
+    assert_eq!(Errno::from_return_value(0), None);
+    assert_eq!(Errno::from_return_value(-1000), None);
+    for errno in ALL {
+        assert_eq!(Errno::from_return_value(errno.to_return_value()), Some(errno));
+    }
+    assert_eq!(Errno::from(UserCopyError::BadAddress), Errno::EFAULT);
+    assert_eq!(Errno::from(VfsError::NotFound), Errno::ENOENT);
+}
@@ -0,0 +1,120 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Number of events buffered per subscriber before the oldest ones are dropped
+const SUBSCRIBER_QUEUE_CAPACITY: usize = 32;
+
+// A system event published on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    DeviceAdded { name: &'static str },
+    DeviceRemoved { name: &'static str },
+    LinkUp { interface: &'static str },
+    LinkDown { interface: &'static str },
+    LowMemory { free_bytes: usize },
+    Thermal { celsius: i32 },
+}
+
+// The state shared between the bus and one subscriber
+struct Channel {
+    queue: VecDeque<(u64, Event)>,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+// All live subscriptions
+static SUBSCRIBERS: Mutex<Vec<Arc<Mutex<Channel>>>> = Mutex::new(Vec::new());
+
+// Publish an event to all current subscribers.
+//
+// Safe to call from interrupt handlers: the subscriber queues are allocated
+// up front, and a full queue drops its oldest event instead of growing.
+pub fn publish(event: Event) {
+    let timestamp = crate::time::uptime_ms();
+
+    interrupts::without_interrupts(|| {
+        for subscriber in SUBSCRIBERS.lock().iter() {
+            let mut channel = subscriber.lock();
+            if channel.queue.len() == SUBSCRIBER_QUEUE_CAPACITY {
+                channel.queue.pop_front();
+                channel.dropped += 1;
+            }
+            channel.queue.push_back((timestamp, event));
+            if let Some(waker) = channel.waker.take() {
+                waker.wake();
+            }
+        }
+    });
+}
+
+// Subscribe to all events published from now on.
+pub fn subscribe() -> Subscription {
+    let channel = Arc::new(Mutex::new(Channel {
+        queue: VecDeque::with_capacity(SUBSCRIBER_QUEUE_CAPACITY),
+        dropped: 0,
+        waker: None,
+    }));
+
+    interrupts::without_interrupts(|| SUBSCRIBERS.lock().push(channel.clone()));
+    Subscription { channel }
+}
+
+// A handle for receiving events; unsubscribes when dropped
+pub struct Subscription {
+    channel: Arc<Mutex<Channel>>,
+}
+
+impl Subscription {
+    // Return the next pending event and its timestamp in ms since boot, if any.
+    pub fn try_recv(&self) -> Option<(u64, Event)> {
+        interrupts::without_interrupts(|| self.channel.lock().queue.pop_front())
+    }
+
+    // Wait asynchronously for the next event.
+    pub fn recv(&self) -> Recv<'_> {
+        Recv { subscription: self }
+    }
+
+    // Return the number of events lost because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        interrupts::without_interrupts(|| self.channel.lock().dropped)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            SUBSCRIBERS
+                .lock()
+                .retain(|channel| !Arc::ptr_eq(channel, &self.channel));
+        });
+    }
+}
+
+// Future returned by `Subscription::recv`
+pub struct Recv<'a> {
+    subscription: &'a Subscription,
+}
+
+impl Future for Recv<'_> {
+    type Output = (u64, Event);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<(u64, Event)> {
+        interrupts::without_interrupts(|| {
+            let mut channel = self.subscription.channel.lock();
+            match channel.queue.pop_front() {
+                Some(entry) => Poll::Ready(entry),
+                None => {
+                    channel.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,943 @@
+// The FAT16 and FAT32 filesystems.
+//
+// A `Volume` is mounted on any block device, e.g. a partition of a disk
+// image made on the host with `mkfs.fat`. Paths are separated by '/' and
+// names are matched without regard to ASCII case, against the long name or
+// the 8.3 name. Files can be listed and read, created, overwritten and
+// appended to, and directories created; nothing is deleted yet.
+//
+// The device is read on every access, a sector at a time; disks are cached
+// below the filesystem (see `block::cache`), and what is written reaches
+// them on a flush or sync. Sectors go through buffers on the stack, since
+// heap memory is only reused once all of it is freed. Writes are serialized
+// by the volume, but reads don't wait for them.
+//
+// The data area is divided into clusters of a few sectors. The FAT holds an
+// entry for every cluster: 0 if it is free, the next cluster of the file
+// otherwise, or a value marking the end of the chain. Directories are files
+// of 32 byte entries (see `dir`), except the FAT16 root directory, which has
+// a fixed place and size in front of the data area.
+
+mod dir;
+
+use crate::block::{BlockDevice, BlockError};
+use crate::console::Spinner;
+use crate::rtc::DateTime;
+use crate::task;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use dir::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LONG_NAME, ATTR_VOLUME_ID, ENTRY_SIZE, END, FREE};
+use spin::Mutex;
+
+// Errors of filesystem operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    // Reading or writing the device failed
+    Io(BlockError),
+    // The device doesn't hold a FAT filesystem
+    NotFat,
+    // A FAT12 filesystem, or a sector size other than the device's
+    Unsupported,
+    // The filesystem is inconsistent, e.g. a chain leads to a free cluster
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    // There are no free clusters, or no room in the FAT16 root directory
+    NoSpace,
+    // A name is empty, too long or has characters FAT doesn't allow
+    BadName,
+    // A file would grow past 4 GiB
+    TooLarge,
+}
+
+impl From<BlockError> for FatError {
+    fn from(err: BlockError) -> Self {
+        FatError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat16,
+    Fat32,
+}
+
+// The fewest clusters of FAT16 and FAT32 filesystems; fewer make FAT12
+const MIN_CLUSTERS_FAT16: u32 = 4085;
+const MIN_CLUSTERS_FAT32: u32 = 65525;
+
+// The largest sector size supported
+const MAX_SECTOR_SIZE: usize = 4096;
+
+// FAT entries from which on a chain ends, and the value written to end one
+const END_OF_CHAIN_FAT16: u32 = 0xFFF8;
+const END_OF_CHAIN_FAT32: u32 = 0x0FFF_FFF8;
+// FAT32 entries are 28 bits; the top 4 bits are reserved
+const FAT32_MASK: u32 = 0x0FFF_FFFF;
+
+// How many used clusters the free cluster search passes between spinner
+// updates
+const SPINNER_CLUSTERS: u32 = 4096;
+
+// Signatures of the FSInfo sector of FAT32
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+
+// A file or directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String, // The long name if there is one
+    pub is_dir: bool,
+    pub size: u32,
+    short_name: [u8; 11],
+    cluster: u32, // The first cluster, 0 if there is none
+    // Where the short entry is: its sector and offset. `None` for the root
+    // directory, which has no entry.
+    location: Option<(u64, usize)>,
+}
+
+// A directory to look at or change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    // The root directory of FAT16
+    FixedRoot,
+    // A directory stored in clusters, starting at the given one
+    Chain(u32),
+}
+
+// State that changes when writing
+struct State {
+    next_free: u32,      // Where to start looking for a free cluster
+    fsinfo_stale: bool,  // Whether the FSInfo free count was invalidated
+}
+
+// A mounted FAT filesystem
+pub struct Volume {
+    device: Arc<dyn BlockDevice>,
+    fat_type: FatType,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    root_start: u64,   // The first sector of the FAT16 root directory
+    root_sectors: u64,
+    root_cluster: u32, // The first cluster of the FAT32 root directory
+    data_start: u64,
+    cluster_count: u32,
+    fsinfo: Option<u64>,
+    state: Mutex<State>,
+}
+
+impl Volume {
+    // Mount the filesystem on `device`, checking its boot sector
+    pub fn mount(device: Arc<dyn BlockDevice>) -> Result<Volume, FatError> {
+        let sector_size = device.block_size();
+        if !(512..=MAX_SECTOR_SIZE).contains(&sector_size) {
+            return Err(FatError::Unsupported);
+        }
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let boot = &mut buffer[..sector_size];
+        device.read_blocks(0, boot)?;
+        if boot[510..512] != [0x55, 0xAA] {
+            return Err(FatError::NotFat);
+        }
+
+        let bytes_per_sector = read_u16(boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = read_u16(boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = read_u16(boot, 17) as u64;
+        let total_sectors = match read_u16(boot, 19) {
+            0 => read_u32(boot, 32) as u64,
+            sectors => sectors as u64,
+        };
+        let fat_sectors = match read_u16(boot, 22) {
+            0 => read_u32(boot, 36) as u64,
+            sectors => sectors as u64,
+        };
+        if !matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(FatError::NotFat);
+        }
+        if bytes_per_sector != sector_size {
+            return Err(FatError::Unsupported);
+        }
+        if total_sectors > device.block_count() {
+            return Err(FatError::Corrupt);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64 + sector_size as u64 - 1)
+            / sector_size as u64;
+        let fat_start = reserved;
+        let root_start = fat_start + fat_count * fat_sectors;
+        let data_start = root_start + root_sectors;
+        let cluster_count = total_sectors
+            .checked_sub(data_start)
+            .ok_or(FatError::Corrupt)?
+            / sectors_per_cluster;
+        let cluster_count = cluster_count.min(FAT32_MASK as u64 - 10) as u32;
+        let fat_type = match cluster_count {
+            n if n < MIN_CLUSTERS_FAT16 => return Err(FatError::Unsupported),
+            n if n < MIN_CLUSTERS_FAT32 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let entry_size = match fat_type {
+            FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        };
+        if fat_sectors * sector_size as u64 / entry_size < cluster_count as u64 + 2 {
+            return Err(FatError::Corrupt);
+        }
+
+        let (root_cluster, fsinfo) = match fat_type {
+            FatType::Fat16 => (0, None),
+            FatType::Fat32 => {
+                let fsinfo = match read_u16(boot, 48) {
+                    0 | 0xFFFF => None,
+                    sector => Some(sector as u64),
+                };
+                (read_u32(boot, 44), fsinfo)
+            }
+        };
+
+        let volume = Volume {
+            device,
+            fat_type,
+            sector_size,
+            sectors_per_cluster,
+            fat_start,
+            fat_sectors,
+            fat_count,
+            root_start,
+            root_sectors,
+            root_cluster,
+            data_start,
+            cluster_count,
+            fsinfo,
+            state: Mutex::new(State {
+                next_free: 2,
+                fsinfo_stale: false,
+            }),
+        };
+        if fat_type == FatType::Fat32 && !volume.is_cluster(root_cluster) {
+            return Err(FatError::Corrupt);
+        }
+        // Start looking for free clusters where the FSInfo sector suggests
+        if volume.read_fsinfo(boot)? {
+            let next_free = read_u32(boot, FSINFO_NEXT_FREE);
+            if volume.is_cluster(next_free) {
+                volume.state.lock().next_free = next_free;
+            }
+        }
+        Ok(volume)
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    // The size of a cluster in bytes
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.sector_size
+    }
+
+    // Count the free clusters in the FAT
+    pub fn free_clusters(&self) -> Result<u32, FatError> {
+        let mut free = 0;
+        for cluster in 2..self.cluster_count + 2 {
+            task::maybe_preempt();
+            if self.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        Ok(free)
+    }
+
+    // Return the file or directory at `path`
+    pub fn find(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty() && *name != ".") {
+            if !entry.is_dir {
+                return Err(FatError::NotADirectory);
+            }
+            entry = self
+                .entries(self.dir_of(&entry))?
+                .into_iter()
+                .find(|entry| entry.has_name(name))
+                .ok_or(FatError::NotFound)?;
+        }
+        Ok(entry)
+    }
+
+    // Return the files and directories in the directory at `path`
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FatError> {
+        let dir = self.find(path)?;
+        if !dir.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        let mut entries = self.entries(self.dir_of(&dir))?;
+        entries.retain(|entry| entry.name != "." && entry.name != "..");
+        Ok(entries)
+    }
+
+    // Read the file `entry` from `offset` into `buffer`. Returns how many
+    // bytes were read, which is less than the buffer holds at the end of
+    // the file.
+    pub fn read(
+        &self,
+        entry: &DirEntry,
+        offset: u64,
+        buffer: &mut [u8],
+    ) -> Result<usize, FatError> {
+        if entry.is_dir {
+            return Err(FatError::IsADirectory);
+        }
+        if offset >= entry.size as u64 {
+            return Ok(0);
+        }
+        if !self.is_cluster(entry.cluster) {
+            return Err(FatError::Corrupt);
+        }
+        let len = buffer.len().min((entry.size as u64 - offset) as usize);
+        let cluster_size = self.cluster_size() as u64;
+        let mut cluster = entry.cluster;
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+        }
+
+        let mut sector_buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut sector_buffer[..self.sector_size];
+        let mut done = 0;
+        let mut position = offset;
+        while done < len {
+            let in_cluster = (position % cluster_size) as usize;
+            let sector = self.cluster_sector(cluster) + (in_cluster / self.sector_size) as u64;
+            let start = in_cluster % self.sector_size;
+            let count = (self.sector_size - start).min(len - done);
+            if count == self.sector_size {
+                self.read_sector(sector, &mut buffer[done..done + count])?;
+            } else {
+                self.read_sector(sector, data)?;
+                buffer[done..done + count].copy_from_slice(&data[start..start + count]);
+            }
+            done += count;
+            position += count as u64;
+            if done < len && position % cluster_size == 0 {
+                cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+            }
+        }
+        Ok(len)
+    }
+
+    // Read the whole file at `path`
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FatError> {
+        let entry = self.find(path)?;
+        let mut data = vec![0; entry.size as usize];
+        self.read(&entry, 0, &mut data)?;
+        Ok(data)
+    }
+
+    // Create an empty file at `path`
+    pub fn create(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut state = self.state.lock();
+        self.add_entry(&mut state, path, ATTR_ARCHIVE, 0)
+    }
+
+    // Create a directory at `path`
+    pub fn create_dir(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut state = self.state.lock();
+        let (parent, _) = self.find_parent(path)?;
+        let cluster = self.allocate_cluster(&mut state, None)?;
+        self.zero_cluster(cluster)?;
+
+        // "." and ".." point to the directory itself and its parent, with 0
+        // standing for the root directory
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        let parent_cluster = if parent.location.is_some() { parent.cluster } else { 0 };
+        let (dot, dot_dot) = data[..2 * ENTRY_SIZE].split_at_mut(ENTRY_SIZE);
+        dir::init(dot, b".          ", 0, ATTR_DIRECTORY, cluster);
+        dir::init(dot_dot, b"..         ", 0, ATTR_DIRECTORY, parent_cluster);
+        self.write_sector(self.cluster_sector(cluster), data)?;
+
+        match self.add_entry(&mut state, path, ATTR_DIRECTORY, cluster) {
+            Ok(entry) => Ok(entry),
+            Err(err) => {
+                self.free_chain(&mut state, cluster)?;
+                Err(err)
+            }
+        }
+    }
+
+    // Replace the content of the file at `path` with `data`, creating the
+    // file if it doesn't exist
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<(), FatError> {
+        let mut state = self.state.lock();
+        let mut entry = match self.find(path) {
+            Ok(entry) if entry.is_dir => return Err(FatError::IsADirectory),
+            Ok(entry) => entry,
+            Err(FatError::NotFound) => self.add_entry(&mut state, path, ATTR_ARCHIVE, 0)?,
+            Err(err) => return Err(err),
+        };
+        // Appending to an empty file frees its clusters first
+        entry.size = 0;
+        self.append_to(&mut state, &mut entry, data)
+    }
+
+    // Add `data` to the end of the file at `path`
+    pub fn append(&self, path: &str, data: &[u8]) -> Result<(), FatError> {
+        let mut state = self.state.lock();
+        let mut entry = self.find(path)?;
+        if entry.is_dir {
+            return Err(FatError::IsADirectory);
+        }
+        self.append_to(&mut state, &mut entry, data)
+    }
+
+    // The root directory as an entry
+    fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            is_dir: true,
+            size: 0,
+            short_name: [b' '; 11],
+            cluster: self.root_cluster,
+            location: None,
+        }
+    }
+
+    fn dir_of(&self, entry: &DirEntry) -> Dir {
+        match (self.fat_type, entry.cluster) {
+            (FatType::Fat16, 0) => Dir::FixedRoot,
+            (FatType::Fat32, 0) => Dir::Chain(self.root_cluster),
+            (_, cluster) => Dir::Chain(cluster),
+        }
+    }
+
+    // Return the parent directory of `path` and the name in it
+    fn find_parent<'a>(&self, path: &'a str) -> Result<(DirEntry, &'a str), FatError> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if !dir::is_valid_name(name) {
+            return Err(FatError::BadName);
+        }
+        let parent = self.find(parent)?;
+        if !parent.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        Ok((parent, name))
+    }
+
+    // The sectors of a directory, in order
+    fn dir_sectors(&self, dir: Dir) -> Result<Vec<u64>, FatError> {
+        match dir {
+            Dir::FixedRoot => Ok((self.root_start..self.root_start + self.root_sectors).collect()),
+            Dir::Chain(first) => {
+                let mut sectors = Vec::new();
+                let mut cluster = Some(first);
+                let mut count = 0;
+                while let Some(current) = cluster {
+                    if !self.is_cluster(current) {
+                        return Err(FatError::Corrupt);
+                    }
+                    let start = self.cluster_sector(current);
+                    sectors.extend(start..start + self.sectors_per_cluster);
+                    cluster = self.next_cluster(current)?;
+                    count += 1;
+                    if count > self.cluster_count {
+                        return Err(FatError::Corrupt); // A loop
+                    }
+                }
+                Ok(sectors)
+            }
+        }
+    }
+
+    // Read the entries of a directory, including "." and ".."
+    fn entries(&self, dir: Dir) -> Result<Vec<DirEntry>, FatError> {
+        let mut entries = Vec::new();
+        let mut long_name = dir::LongName::new();
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        for sector in self.dir_sectors(dir)? {
+            self.read_sector(sector, data)?;
+            for (i, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                match raw[0] {
+                    END => return Ok(entries),
+                    FREE => long_name.reset(),
+                    _ if raw[11] & 0x3F == ATTR_LONG_NAME => long_name.push(raw),
+                    _ if raw[11] & ATTR_VOLUME_ID != 0 => long_name.reset(),
+                    _ => {
+                        let mut short_name = [0; 11];
+                        short_name.copy_from_slice(&raw[..11]);
+                        let name = long_name
+                            .take(&short_name)
+                            .unwrap_or_else(|| dir::display_name(&short_name, raw[12]));
+                        entries.push(DirEntry {
+                            name,
+                            is_dir: raw[11] & ATTR_DIRECTORY != 0,
+                            size: dir::size(raw),
+                            short_name,
+                            cluster: dir::cluster(raw) & FAT32_MASK,
+                            location: Some((sector, i * ENTRY_SIZE)),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    // Add an entry named after the last part of `path` to its parent
+    fn add_entry(
+        &self,
+        state: &mut State,
+        path: &str,
+        attributes: u8,
+        cluster: u32,
+    ) -> Result<DirEntry, FatError> {
+        let (parent, name) = self.find_parent(path)?;
+        let dir = self.dir_of(&parent);
+        let existing = self.entries(dir)?;
+        if existing.iter().any(|entry| entry.has_name(name)) {
+            return Err(FatError::Exists);
+        }
+        let taken = |short: &[u8; 11]| existing.iter().any(|entry| entry.short_name == *short);
+
+        // Names that don't fit 8.3 get a long name and a made up short one
+        let (short_name, lower, long_entries) = match dir::short_name(name) {
+            Some((short, lower)) if !taken(&short) => (short, lower, Vec::new()),
+            _ => {
+                let short = (1..1_000_000)
+                    .map(|n| dir::alias(name, n))
+                    .find(|short| !taken(short))
+                    .ok_or(FatError::NoSpace)?;
+                (short, 0, dir::long_entries(name, dir::checksum(&short)))
+            }
+        };
+
+        let (sectors, first) = self.find_free_entries(state, dir, long_entries.len() + 1)?;
+        let per_sector = self.sector_size / ENTRY_SIZE;
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        let mut location = (0, 0);
+        for i in 0..=long_entries.len() {
+            let slot = first + i;
+            let (sector, offset) = (sectors[slot / per_sector], slot % per_sector * ENTRY_SIZE);
+            self.read_sector(sector, data)?;
+            let raw = &mut data[offset..offset + ENTRY_SIZE];
+            match long_entries.get(i) {
+                Some(long) => raw.copy_from_slice(long),
+                None => {
+                    dir::init(raw, &short_name, lower, attributes, cluster);
+                    location = (sector, offset);
+                }
+            }
+            self.write_sector(sector, data)?;
+        }
+
+        Ok(DirEntry {
+            name: String::from(name),
+            is_dir: attributes & ATTR_DIRECTORY != 0,
+            size: 0,
+            short_name,
+            cluster,
+            location: Some(location),
+        })
+    }
+
+    // Find `count` free entries in a row in a directory, growing it if
+    // needed. Returns the sectors of the directory and the index of the
+    // first entry.
+    fn find_free_entries(
+        &self,
+        state: &mut State,
+        dir: Dir,
+        count: usize,
+    ) -> Result<(Vec<u64>, usize), FatError> {
+        let per_sector = self.sector_size / ENTRY_SIZE;
+        let mut sectors = self.dir_sectors(dir)?;
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        let mut first = 0;
+        let mut free = 0;
+        let mut at_end = false;
+        'search: for (i, &sector) in sectors.iter().enumerate() {
+            self.read_sector(sector, data)?;
+            for (j, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                // Everything after the end marker is free
+                at_end |= raw[0] == END;
+                if at_end || raw[0] == FREE {
+                    if free == 0 {
+                        first = i * per_sector + j;
+                    }
+                    free += 1;
+                    if free == count {
+                        break 'search;
+                    }
+                } else {
+                    free = 0;
+                }
+            }
+        }
+
+        while free < count {
+            let last = match dir {
+                Dir::FixedRoot => return Err(FatError::NoSpace),
+                Dir::Chain(start) => self.last_cluster(start)?,
+            };
+            let cluster = self.allocate_cluster(state, Some(last))?;
+            self.zero_cluster(cluster)?;
+            if free == 0 {
+                first = sectors.len() * per_sector;
+            }
+            let start = self.cluster_sector(cluster);
+            sectors.extend(start..start + self.sectors_per_cluster);
+            free += self.cluster_size() / ENTRY_SIZE;
+        }
+        Ok((sectors, first))
+    }
+
+    // Append `data` to the file `entry` and update its directory entry
+    fn append_to(
+        &self,
+        state: &mut State,
+        entry: &mut DirEntry,
+        data: &[u8],
+    ) -> Result<(), FatError> {
+        let new_size = u32::try_from(entry.size as u64 + data.len() as u64)
+            .map_err(|_| FatError::TooLarge)?;
+        if entry.size == 0 && entry.cluster != 0 {
+            // An empty file keeps no clusters. The entry lets go of them
+            // before they are freed, so it never points to free ones.
+            let clusters = entry.cluster;
+            entry.cluster = 0;
+            self.update_entry(entry)?;
+            self.free_chain(state, clusters)?;
+        }
+
+        // The last cluster of the file, and whether it has room left
+        let cluster_size = self.cluster_size();
+        let mut last = None;
+        let mut current = None;
+        if entry.cluster != 0 {
+            let cluster = self.last_cluster(entry.cluster)?;
+            last = Some(cluster);
+            if entry.size as usize % cluster_size != 0 {
+                current = Some(cluster);
+            }
+        }
+
+        let old_size = entry.size;
+        let result = self
+            .write_appended(state, entry, data, last, current)
+            .and_then(|()| {
+                entry.size = new_size;
+                self.update_entry(entry)
+            });
+        if result.is_err() {
+            entry.size = old_size;
+            self.drop_appended(state, entry, last);
+        }
+        result
+    }
+
+    // Write `data` after the end of the file `entry`, in `current`, the last
+    // cluster `last` if it has room left, and then in clusters added to the
+    // chain. Sets `entry.cluster` if the file had none, but leaves the
+    // directory entry to the caller.
+    fn write_appended(
+        &self,
+        state: &mut State,
+        entry: &mut DirEntry,
+        data: &[u8],
+        mut last: Option<u32>,
+        mut current: Option<u32>,
+    ) -> Result<(), FatError> {
+        let cluster_size = self.cluster_size();
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let sector_data = &mut buffer[..self.sector_size];
+        let mut offset = entry.size as usize % cluster_size;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let cluster = match current.take() {
+                Some(cluster) => cluster,
+                None => {
+                    let cluster = self.allocate_cluster(state, last)?;
+                    if entry.cluster == 0 {
+                        entry.cluster = cluster;
+                    }
+                    last = Some(cluster);
+                    cluster
+                }
+            };
+            while offset < cluster_size && !rest.is_empty() {
+                let sector = self.cluster_sector(cluster) + (offset / self.sector_size) as u64;
+                let start = offset % self.sector_size;
+                let count = (self.sector_size - start).min(rest.len());
+                if count == self.sector_size {
+                    self.write_sector(sector, &rest[..count])?;
+                } else {
+                    // Keep what the file has in the sector already, and
+                    // clear the rest
+                    if start == 0 {
+                        sector_data.fill(0);
+                    } else {
+                        self.read_sector(sector, sector_data)?;
+                    }
+                    sector_data[start..start + count].copy_from_slice(&rest[..count]);
+                    self.write_sector(sector, sector_data)?;
+                }
+                offset += count;
+                rest = &rest[count..];
+            }
+            offset = 0;
+        }
+        Ok(())
+    }
+
+    // Free the clusters a failed append added after `last`, the file's last
+    // cluster before it, or all of the file's clusters if it had none.
+    // Nothing on the disk points at them, so they would be lost otherwise.
+    // Errors are ignored; the append's error is the one reported.
+    fn drop_appended(&self, state: &mut State, entry: &mut DirEntry, last: Option<u32>) {
+        let _ = match last {
+            Some(last) => match self.next_cluster(last) {
+                Ok(Some(added)) => self
+                    .set_fat_entry(state, last, self.end_of_chain())
+                    .and_then(|()| self.free_chain(state, added)),
+                _ => Ok(()),
+            },
+            None if entry.cluster != 0 => {
+                let added = entry.cluster;
+                entry.cluster = 0;
+                self.free_chain(state, added)
+            }
+            None => Ok(()),
+        };
+    }
+
+    // Write the cluster and size of `entry` to its directory entry
+    fn update_entry(&self, entry: &DirEntry) -> Result<(), FatError> {
+        let (sector, offset) = entry.location.ok_or(FatError::IsADirectory)?;
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        self.read_sector(sector, data)?;
+        let now = dir::timestamp(DateTime::now());
+        dir::update(&mut data[offset..offset + ENTRY_SIZE], entry.cluster, entry.size, now);
+        self.write_sector(sector, data)
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+
+    // The cluster after `cluster` in its chain, `None` at the end
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        let next = self.fat_entry(cluster)?;
+        let end = match self.fat_type {
+            FatType::Fat16 => END_OF_CHAIN_FAT16,
+            FatType::Fat32 => END_OF_CHAIN_FAT32,
+        };
+        if next >= end {
+            Ok(None)
+        } else if self.is_cluster(next) {
+            Ok(Some(next))
+        } else {
+            // Free, reserved or bad
+            Err(FatError::Corrupt)
+        }
+    }
+
+    fn last_cluster(&self, first: u32) -> Result<u32, FatError> {
+        let mut cluster = first;
+        for _ in 0..self.cluster_count {
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(cluster),
+            }
+        }
+        Err(FatError::Corrupt) // A loop
+    }
+
+    // Where the FAT entry of `cluster` is: the sector in the first FAT and
+    // the offset in it
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        };
+        let sector_size = self.sector_size as u64;
+        (self.fat_start + offset / sector_size, (offset % sector_size) as usize)
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FatError> {
+        if !self.is_cluster(cluster) {
+            return Err(FatError::Corrupt);
+        }
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        self.read_sector(sector, data)?;
+        Ok(match self.fat_type {
+            FatType::Fat16 => read_u16(data, offset) as u32,
+            FatType::Fat32 => read_u32(data, offset) & FAT32_MASK,
+        })
+    }
+
+    // Set the FAT entry of `cluster` in every copy of the FAT
+    fn set_fat_entry(&self, state: &mut State, cluster: u32, value: u32) -> Result<(), FatError> {
+        self.invalidate_fsinfo(state)?;
+        let (sector, offset) = self.fat_position(cluster);
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        for copy in 0..self.fat_count {
+            let sector = sector + copy * self.fat_sectors;
+            self.read_sector(sector, data)?;
+            match self.fat_type {
+                FatType::Fat16 => {
+                    data[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes());
+                }
+                FatType::Fat32 => {
+                    let value = read_u32(data, offset) & !FAT32_MASK | value & FAT32_MASK;
+                    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+            self.write_sector(sector, data)?;
+        }
+        Ok(())
+    }
+
+    // The FAT entry written to end a chain
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => FAT32_MASK,
+        }
+    }
+
+    // Take a free cluster and append it to the chain ending with `previous`
+    fn allocate_cluster(&self, state: &mut State, previous: Option<u32>) -> Result<u32, FatError> {
+        let first = state.next_free;
+        let mut cluster = first;
+        // Shown once the search takes a while, as on a nearly full volume
+        let mut spinner = None;
+        let mut searched = 0u32;
+        loop {
+            task::maybe_preempt(); // A full volume is searched to the end
+            if self.fat_entry(cluster)? == 0 {
+                break;
+            }
+            searched += 1;
+            if searched % SPINNER_CLUSTERS == 0 {
+                spinner.get_or_insert_with(|| Spinner::new("searching for a free cluster")).tick();
+            }
+            cluster = if cluster + 1 < self.cluster_count + 2 { cluster + 1 } else { 2 };
+            if cluster == first {
+                return Err(FatError::NoSpace);
+            }
+        }
+
+        self.set_fat_entry(state, cluster, self.end_of_chain())?;
+        if let Some(previous) = previous {
+            self.set_fat_entry(state, previous, cluster)?;
+        }
+        state.next_free = if cluster + 1 < self.cluster_count + 2 { cluster + 1 } else { 2 };
+        Ok(cluster)
+    }
+
+    // Free the clusters of the chain starting with `first`
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FatError> {
+        let mut cluster = if first == 0 { None } else { Some(first) };
+        let mut count = 0;
+        while let Some(current) = cluster {
+            task::maybe_preempt();
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(state, current, 0)?;
+            state.next_free = state.next_free.min(current);
+            count += 1;
+            if count > self.cluster_count {
+                return Err(FatError::Corrupt); // A loop
+            }
+        }
+        Ok(())
+    }
+
+    // Read the FSInfo sector into `data`. Returns whether the filesystem
+    // has a valid one.
+    fn read_fsinfo(&self, data: &mut [u8]) -> Result<bool, FatError> {
+        let sector = match self.fsinfo {
+            Some(sector) => sector,
+            None => return Ok(false),
+        };
+        self.read_sector(sector, data)?;
+        Ok(read_u32(data, 0) == FSINFO_LEAD_SIGNATURE
+            && read_u32(data, 484) == FSINFO_STRUCT_SIGNATURE)
+    }
+
+    // Mark the free cluster count in the FSInfo sector as unknown before
+    // the FAT first changes, so it is counted again instead of trusted
+    fn invalidate_fsinfo(&self, state: &mut State) -> Result<(), FatError> {
+        if state.fsinfo_stale {
+            return Ok(());
+        }
+        let mut buffer = [0; MAX_SECTOR_SIZE];
+        let data = &mut buffer[..self.sector_size];
+        if let (true, Some(sector)) = (self.read_fsinfo(data)?, self.fsinfo) {
+            data[FSINFO_FREE_COUNT..FSINFO_FREE_COUNT + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            self.write_sector(sector, data)?;
+        }
+        state.fsinfo_stale = true;
+        Ok(())
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster
+    }
+
+    fn zero_cluster(&self, cluster: u32) -> Result<(), FatError> {
+        let zeros = [0; MAX_SECTOR_SIZE];
+        let start = self.cluster_sector(cluster);
+        for sector in start..start + self.sectors_per_cluster {
+            self.write_sector(sector, &zeros[..self.sector_size])?;
+        }
+        Ok(())
+    }
+
+    // Read whole sectors, as many as fit into `buffer`
+    fn read_sector(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FatError> {
+        Ok(self.device.read_blocks(sector, buffer)?)
+    }
+
+    fn write_sector(&self, sector: u64, buffer: &[u8]) -> Result<(), FatError> {
+        Ok(self.device.write_blocks(sector, buffer)?)
+    }
+}
+
+impl DirEntry {
+    // Whether the entry is called `name`, by its long or its short name
+    fn has_name(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || matches!(dir::short_name(name), Some((short, _)) if short == self.short_name)
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let b = &bytes[offset..offset + 4];
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
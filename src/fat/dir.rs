@@ -0,0 +1,325 @@
+// Directory entries.
+//
+// A directory is an array of 32 byte entries. Every file has a short entry
+// holding an 8.3 name, the attributes, the first cluster and the size. A long
+// name is kept in up to 20 more entries placed right before the short one,
+// the end of the name first. Each holds 13 UTF-16 characters and a checksum
+// of the short name, which tells whether the long name still belongs to it.
+
+use crate::rtc::DateTime;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const ENTRY_SIZE: usize = 32;
+
+// Attributes
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+// The first name byte of entries that are free, and of the entry after the
+// last one in use
+pub const FREE: u8 = 0xE5;
+pub const END: u8 = 0x00;
+
+// Flags of the short entry telling that the name or extension is shown in
+// lower case, as written by Windows NT and later
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+// The ordinal flag of the first entry of a long name, which holds its end
+const LONG_LAST: u8 = 0x40;
+const LONG_MAX_ENTRIES: usize = 20;
+const LONG_CHARS: usize = 13;
+// Where the characters are in a long name entry
+const LONG_CHAR_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// The longest file name, in UTF-16 units
+pub const MAX_NAME: usize = 255;
+
+// Characters allowed in short names besides letters and digits
+const SHORT_SPECIAL: &[u8] = b"!#$%&'()-@^_`{}~";
+
+// The first cluster of the short entry in `entry`
+pub fn cluster(entry: &[u8]) -> u32 {
+    let high = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let low = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+    high << 16 | low
+}
+
+pub fn size(entry: &[u8]) -> u32 {
+    u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]])
+}
+
+// Fill `entry` with a new short entry, created now
+pub fn init(entry: &mut [u8], name: &[u8; 11], lower: u8, attributes: u8, cluster: u32) {
+    entry.fill(0);
+    entry[..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[12] = lower;
+    let (date, time) = timestamp(DateTime::now());
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    update(entry, cluster, 0, (date, time));
+}
+
+// Set the first cluster and size of the short entry in `entry`, and its
+// modification and access times to `(date, time)`
+pub fn update(entry: &mut [u8], cluster: u32, size: u32, (date, time): (u16, u16)) {
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+// The FAT date and time of `now`. FAT dates start in 1980 and times have a
+// resolution of two seconds.
+pub fn timestamp(now: DateTime) -> (u16, u16) {
+    if now.year < 1980 {
+        return ((1 << 5) | 1, 0);
+    }
+    let date = (now.year - 1980).min(127) << 9 | (now.month as u16) << 5 | now.day as u16;
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second as u16) / 2;
+    (date, time)
+}
+
+// The short name as shown, e.g. "README.TXT", or "readme.txt" with the
+// lower case flags
+pub fn display_name(name: &[u8; 11], lower: u8) -> String {
+    let mut shown = String::new();
+    let part = |bytes: &[u8], lower_case: bool, shown: &mut String| {
+        for &byte in bytes.iter().take_while(|&&byte| byte != b' ') {
+            let byte = if lower_case { byte.to_ascii_lowercase() } else { byte };
+            // Bytes of other code pages are shown as replacement characters
+            shown.push(if byte.is_ascii() { byte as char } else { '\u{FFFD}' });
+        }
+    };
+    // A first byte of 0x05 stands for 0xE5, which marks free entries
+    let mut base = [0; 8];
+    base.copy_from_slice(&name[..8]);
+    if base[0] == 0x05 {
+        base[0] = FREE;
+    }
+    part(&base, lower & LOWER_BASE != 0, &mut shown);
+    if name[8] != b' ' {
+        shown.push('.');
+        part(&name[8..], lower & LOWER_EXT != 0, &mut shown);
+    }
+    shown
+}
+
+// The checksum of a short name stored in the long name entries
+pub fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| (sum >> 1 | sum << 7).wrapping_add(byte))
+}
+
+// Whether `name` can be given to a file
+pub fn is_valid_name(name: &str) -> bool {
+    name.encode_utf16().count() <= MAX_NAME
+        && !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.ends_with(['.', ' '])
+        && !name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+}
+
+// Return the short name of `name` and its lower case flags if `name` is a
+// valid 8.3 name that needs no long name, like "README.TXT" or "notes.md"
+pub fn short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.split_once('.') {
+        Some((base, ext)) => (base, ext),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    let mut lower = 0;
+    for (part, range, flag) in [(base, 0..8, LOWER_BASE), (ext, 8..11, LOWER_EXT)] {
+        let bytes = part.as_bytes();
+        let allowed = |byte: &u8| byte.is_ascii_alphanumeric() || SHORT_SPECIAL.contains(byte);
+        if !bytes.iter().all(allowed) {
+            return None;
+        }
+        // Each part is shown in one case only
+        let has_upper = bytes.iter().any(u8::is_ascii_uppercase);
+        let has_lower = bytes.iter().any(u8::is_ascii_lowercase);
+        if has_upper && has_lower {
+            return None;
+        }
+        if has_lower {
+            lower |= flag;
+        }
+        for (i, &byte) in range.zip(bytes) {
+            short[i] = byte.to_ascii_uppercase();
+        }
+    }
+    Some((short, lower))
+}
+
+// The short name "BASE~N.EXT" made up for a file with the long name `name`.
+// It is tried with increasing `n`, below 1000000, until it is unique in the
+// directory.
+pub fn alias(name: &str, n: u32) -> [u8; 11] {
+    let convert = |c: char| match c {
+        c if c.is_ascii_alphanumeric() => Some(c.to_ascii_uppercase() as u8),
+        c if c.is_ascii() && SHORT_SPECIAL.contains(&(c as u8)) => Some(c as u8),
+        ' ' | '.' => None,
+        _ => Some(b'_'),
+    };
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((base, ext)) if !base.trim_start_matches('.').is_empty() => (base, ext),
+        _ => (name, ""),
+    };
+
+    let mut short = [b' '; 11];
+    for (slot, byte) in short[8..].iter_mut().zip(ext.chars().filter_map(convert)) {
+        *slot = byte;
+    }
+    let mut digits = [0; 10];
+    let mut len = 0;
+    let mut rest = n;
+    loop {
+        digits[len] = b'0' + (rest % 10) as u8;
+        len += 1;
+        rest /= 10;
+        if rest == 0 {
+            break;
+        }
+    }
+    let mut end = 0;
+    for byte in base.chars().filter_map(convert).take(8usize.saturating_sub(1 + len)) {
+        short[end] = byte;
+        end += 1;
+    }
+    short[end] = b'~';
+    for i in 0..len {
+        short[end + 1 + i] = digits[len - 1 - i];
+    }
+    short
+}
+
+// The long name entries of `name` for the short name with the checksum
+// `checksum`, in the order they are stored
+pub fn long_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = (chars.len() + LONG_CHARS - 1) / LONG_CHARS;
+    // The name ends with a 0 if there is room, and the rest is padding
+    if chars.len() % LONG_CHARS != 0 {
+        chars.push(0);
+    }
+    chars.resize(count * LONG_CHARS, 0xFFFF);
+
+    (0..count)
+        .rev()
+        .map(|i| {
+            let mut entry = [0; ENTRY_SIZE];
+            entry[0] = (i + 1) as u8 | if i + 1 == count { LONG_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            for (offset, &c) in LONG_CHAR_OFFSETS.iter().zip(&chars[i * LONG_CHARS..]) {
+                entry[*offset..*offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            entry
+        })
+        .collect()
+}
+
+// Collects the long name entries before a short entry
+pub struct LongName {
+    chars: [u16; LONG_MAX_ENTRIES * LONG_CHARS],
+    count: u8,    // The number of entries of the name
+    expected: u8, // The ordinal of the next entry, 0 when complete
+    checksum: u8,
+}
+
+impl LongName {
+    pub fn new() -> LongName {
+        LongName {
+            chars: [0; LONG_MAX_ENTRIES * LONG_CHARS],
+            count: 0,
+            expected: 0,
+            checksum: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.expected = 0;
+    }
+
+    // Add the next long name entry of the directory
+    pub fn push(&mut self, entry: &[u8]) {
+        let ordinal = entry[0] & !LONG_LAST;
+        if entry[0] & LONG_LAST != 0 {
+            if ordinal == 0 || ordinal as usize > LONG_MAX_ENTRIES {
+                self.reset();
+                return;
+            }
+            self.count = ordinal;
+            self.checksum = entry[13];
+        } else if self.expected == 0 || ordinal != self.expected || entry[13] != self.checksum {
+            // Out of order, left over from a deleted file
+            self.reset();
+            return;
+        }
+        let start = (ordinal as usize - 1) * LONG_CHARS;
+        for (i, offset) in LONG_CHAR_OFFSETS.iter().enumerate() {
+            self.chars[start + i] = u16::from_le_bytes([entry[*offset], entry[*offset + 1]]);
+        }
+        self.expected = ordinal - 1;
+    }
+
+    // Return the long name if it is complete and belongs to the short entry
+    // with the name `short`, and start over
+    pub fn take(&mut self, short: &[u8; 11]) -> Option<String> {
+        let complete = self.count != 0 && self.expected == 0 && self.checksum == checksum(short);
+        let count = self.count as usize;
+        self.reset();
+        if !complete {
+            return None;
+        }
+        let chars = &self.chars[..count * LONG_CHARS];
+        let len = chars.iter().position(|&c| c == 0).unwrap_or(chars.len());
+        Some(
+            char::decode_utf16(chars[..len].iter().copied())
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+#[test_case]
+fn test_short_name() {
+    assert_eq!(short_name("README.TXT"), Some((*b"README  TXT", 0)));
+    assert_eq!(short_name("notes.md"), Some((*b"NOTES   MD ", LOWER_BASE | LOWER_EXT)));
+    assert_eq!(short_name("KERNEL"), Some((*b"KERNEL     ", 0)));
+    assert_eq!(short_name("Readme.txt"), None);
+    assert_eq!(short_name("a long name"), None);
+    assert_eq!(short_name("archive.tar.gz"), None);
+    assert_eq!(short_name("toolongname.txt"), None);
+
+    assert_eq!(&alias("A long file name.txt", 1), b"ALONGF~1TXT");
+    assert_eq!(&alias("archive.tar.gz", 12), b"ARCHI~12GZ ");
+    assert_eq!(&alias(".profile", 1), b"PROFIL~1   ");
+}
+
+#[test_case]
+fn test_checksum_and_timestamp() {
+    assert_eq!(checksum(b"LONGFI~1TXT"), 0xD4);
+    assert_eq!(checksum(b"           "), 0xF7);
+
+    let now = DateTime {
+        year: 2024,
+        month: 5,
+        day: 17,
+        hour: 13,
+        minute: 45,
+        second: 31,
+    };
+    assert_eq!(timestamp(now), (44 << 9 | 5 << 5 | 17, 13 << 11 | 45 << 5 | 15));
+}
@@ -0,0 +1,128 @@
+// Fault injection, to exercise error paths that are otherwise never run.
+//
+// Code that can fail asks `should_fail` before doing an operation of one of
+// the `Site`s, and fails as if the operation had gone wrong if it returns
+// true. A test or the shell's `fault` command arms a site with `fail_nth`
+// so that its Nth next operation fails, e.g. the frame allocation in the
+// middle of `init_heap`.
+//
+// Only kernels built with the `fault-injection` feature inject faults;
+// otherwise `should_fail` is always false and costs nothing.
+
+#[cfg(feature = "fault-injection")]
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// The kinds of operations faults are injected into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Site {
+    // Allocating a physical frame
+    Frame,
+    // Allocating heap memory
+    Heap,
+    // Reading or writing a block device
+    Block,
+}
+
+impl Site {
+    pub const ALL: [Site; 3] = [Site::Frame, Site::Heap, Site::Block];
+
+    // The name the shell uses for the site
+    pub fn name(self) -> &'static str {
+        match self {
+            Site::Frame => "frame",
+            Site::Heap => "heap",
+            Site::Block => "block",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Site> {
+        Site::ALL.iter().copied().find(|site| site.name() == name)
+    }
+}
+
+// Whether this kernel can inject faults
+pub const ENABLED: bool = cfg!(feature = "fault-injection");
+
+// Per site, the number of operations until the one that fails, counting
+// it; 0 if the site isn't armed
+#[cfg(feature = "fault-injection")]
+static COUNTDOWN: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+// Per site, the number of faults injected so far
+#[cfg(feature = "fault-injection")]
+static INJECTED: [AtomicU64; 3] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+// Return whether the operation about to be done at `site` should fail.
+// Each armed fault fires once.
+#[inline]
+pub fn should_fail(site: Site) -> bool {
+    #[cfg(feature = "fault-injection")]
+    {
+        let countdown = &COUNTDOWN[site as usize];
+        let previous = countdown.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if previous == Ok(1) {
+            INJECTED[site as usize].fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+    #[cfg(not(feature = "fault-injection"))]
+    {
+        let _ = site;
+        false
+    }
+}
+
+// Make the `n`th next operation at `site` fail, 1 being the next one.
+// Replaces a fault armed before; 0 disarms the site.
+#[cfg(feature = "fault-injection")]
+pub fn fail_nth(site: Site, n: u64) {
+    COUNTDOWN[site as usize].store(n, Ordering::Release);
+}
+
+// Disarm every site
+#[cfg(feature = "fault-injection")]
+pub fn clear() {
+    for site in Site::ALL {
+        fail_nth(site, 0);
+    }
+}
+
+// Return how many operations at `site` are left until the armed fault
+// fires, or `None` if the site isn't armed
+#[cfg(feature = "fault-injection")]
+pub fn pending(site: Site) -> Option<u64> {
+    match COUNTDOWN[site as usize].load(Ordering::Acquire) {
+        0 => None,
+        n => Some(n),
+    }
+}
+
+// Return how many faults were injected at `site`
+#[cfg(feature = "fault-injection")]
+pub fn injected(site: Site) -> u64 {
+    INJECTED[site as usize].load(Ordering::Relaxed)
+}
+
+#[test_case]
+fn test_site_names() {
+    for site in Site::ALL {
+        assert_eq!(Site::from_name(site.name()), Some(site));
+    }
+    assert_eq!(Site::from_name("disk"), None);
+}
+
+#[cfg(feature = "fault-injection")]
+#[test_case]
+fn test_fail_nth() {
+    // The block site isn't used by anything in the unit tests
+    let injected = injected(Site::Block);
+    fail_nth(Site::Block, 3);
+    assert!(!should_fail(Site::Block));
+    assert!(!should_fail(Site::Block));
+    assert_eq!(pending(Site::Block), Some(1));
+    assert!(should_fail(Site::Block));
+    assert!(!should_fail(Site::Block));
+    assert_eq!(pending(Site::Block), None);
+    assert_eq!(injected(Site::Block), injected + 1);
+}
@@ -0,0 +1,228 @@
+// File descriptors: small numbers standing for files opened through the VFS.
+//
+// Every process has an `FdTable` of its own; the functions at the end of
+// this module use the kernel's table, which the rest of the kernel shares.
+// Descriptors are numbered like on Unix, a new one getting the lowest free
+// number, and the open flags and seek origins have the Linux values, so
+// that the system calls can pass them through. An open file remembers its
+// path and offset; the file is looked up in the VFS on every access, so a
+// file that is removed while open can't be read any more.
+//
+// The kernel's table is locked during the filesystem accesses, so
+// descriptors must not be used by interrupt handlers.
+
+use crate::errno::Errno;
+use crate::vfs::{self, VfsError};
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// The access mode, one of which is given to `open`
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+const O_ACCMODE: u32 = 3;
+
+// Flags added to the access mode
+pub const O_CREAT: u32 = 0o100; // Create the file if it doesn't exist
+pub const O_EXCL: u32 = 0o200; // With O_CREAT, fail if the file exists
+pub const O_TRUNC: u32 = 0o1000; // Empty the file when opening it for writing
+pub const O_APPEND: u32 = 0o2000; // Write at the end of the file
+
+const KNOWN_FLAGS: u32 = O_ACCMODE | O_CREAT | O_EXCL | O_TRUNC | O_APPEND;
+
+// The number of descriptors that can be open at once
+pub const MAX_FDS: usize = 64;
+
+// A file descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub u32);
+
+// Where `seek` counts the offset from, with the values of SEEK_SET,
+// SEEK_CUR and SEEK_END
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum Whence {
+    Set = 0,
+    Current = 1,
+    End = 2,
+}
+
+impl Whence {
+    pub fn from_u32(value: u32) -> Option<Whence> {
+        match value {
+            0 => Some(Whence::Set),
+            1 => Some(Whence::Current),
+            2 => Some(Whence::End),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct OpenFile {
+    path: String, // Normalized
+    flags: u32,
+    offset: u64,
+}
+
+impl OpenFile {
+    fn readable(&self) -> bool {
+        self.flags & O_ACCMODE != O_WRONLY
+    }
+
+    fn writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+}
+
+// A table of open files, indexed by descriptor. A clone has its own
+// offsets, unlike the descriptors a Unix fork copies.
+#[derive(Clone)]
+pub struct FdTable {
+    files: Vec<Option<OpenFile>>,
+}
+
+impl FdTable {
+    #[allow(clippy::new_without_default)]
+    pub const fn new() -> FdTable {
+        FdTable { files: Vec::new() }
+    }
+
+    // Open a file and return a new descriptor for it, positioned at the
+    // start
+    pub fn open(&mut self, path: &str, flags: u32) -> Result<Fd, Errno> {
+        if flags & !KNOWN_FLAGS != 0 || flags & O_ACCMODE == O_ACCMODE {
+            return Err(Errno::EINVAL);
+        }
+        let path = vfs::normalize(path)?;
+        let writable = flags & O_ACCMODE != O_RDONLY;
+        match vfs::metadata(&path) {
+            Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(Errno::EEXIST),
+            Ok(metadata) if metadata.is_dir() && writable => return Err(Errno::EISDIR),
+            Ok(_) if writable && flags & O_TRUNC != 0 => vfs::create(&path)?,
+            Ok(_) => {}
+            Err(VfsError::NotFound) if flags & O_CREAT != 0 => vfs::create(&path)?,
+            Err(err) => return Err(err.into()),
+        }
+
+        let file = OpenFile {
+            path,
+            flags,
+            offset: 0,
+        };
+        let number = match self.files.iter().position(Option::is_none) {
+            Some(number) => {
+                self.files[number] = Some(file);
+                number
+            }
+            None if self.files.len() < MAX_FDS => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+            None => return Err(Errno::EMFILE),
+        };
+        Ok(Fd(number as u32))
+    }
+
+    // Read from the file's offset into `buf` and advance the offset.
+    // Returns the number of bytes read, 0 at the end of the file.
+    pub fn read(&mut self, fd: Fd, buf: &mut [u8]) -> Result<usize, Errno> {
+        let file = self.file(fd)?;
+        if !file.readable() {
+            return Err(Errno::EBADF);
+        }
+        let len = vfs::read(&file.path, file.offset, buf)?;
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    // Write `data` at the file's offset, or at its end if it was opened
+    // with O_APPEND, and advance the offset. Returns the number of bytes
+    // written, which is less than asked for if the filesystem fills up.
+    pub fn write(&mut self, fd: Fd, data: &[u8]) -> Result<usize, Errno> {
+        let file = self.file(fd)?;
+        if !file.writable() {
+            return Err(Errno::EBADF);
+        }
+        if file.flags & O_APPEND != 0 {
+            file.offset = vfs::metadata(&file.path)?.size;
+        }
+        let len = vfs::write(&file.path, file.offset, data)?;
+        if len == 0 && !data.is_empty() {
+            return Err(Errno::ENOSPC);
+        }
+        file.offset += len as u64;
+        Ok(len)
+    }
+
+    // Move the file's offset and return the new one. The offset may go
+    // past the end of the file; writing there leaves a gap.
+    pub fn seek(&mut self, fd: Fd, offset: i64, whence: Whence) -> Result<u64, Errno> {
+        let file = self.file(fd)?;
+        let base = match whence {
+            Whence::Set => 0,
+            Whence::Current => file.offset,
+            Whence::End => vfs::metadata(&file.path)?.size,
+        };
+        let offset = (base as i64).checked_add(offset).ok_or(Errno::EINVAL)?;
+        if offset < 0 {
+            return Err(Errno::EINVAL);
+        }
+        file.offset = offset as u64;
+        Ok(file.offset)
+    }
+
+    pub fn close(&mut self, fd: Fd) -> Result<(), Errno> {
+        match self.files.get_mut(fd.0 as usize).and_then(Option::take) {
+            Some(_) => Ok(()),
+            None => Err(Errno::EBADF),
+        }
+    }
+
+    // Return the number of open descriptors
+    pub fn open_count(&self) -> usize {
+        self.files.iter().filter(|file| file.is_some()).count()
+    }
+
+    // Return the file a descriptor stands for
+    fn file(&mut self, fd: Fd) -> Result<&mut OpenFile, Errno> {
+        match self.files.get_mut(fd.0 as usize) {
+            Some(Some(file)) => Ok(file),
+            _ => Err(Errno::EBADF),
+        }
+    }
+}
+
+// The kernel's descriptors, which the functions below use
+static TABLE: Mutex<FdTable> = Mutex::new(FdTable::new());
+
+pub fn open(path: &str, flags: u32) -> Result<Fd, Errno> {
+    TABLE.lock().open(path, flags)
+}
+
+pub fn read(fd: Fd, buf: &mut [u8]) -> Result<usize, Errno> {
+    TABLE.lock().read(fd, buf)
+}
+
+pub fn write(fd: Fd, data: &[u8]) -> Result<usize, Errno> {
+    TABLE.lock().write(fd, data)
+}
+
+pub fn seek(fd: Fd, offset: i64, whence: Whence) -> Result<u64, Errno> {
+    TABLE.lock().seek(fd, offset, whence)
+}
+
+pub fn close(fd: Fd) -> Result<(), Errno> {
+    TABLE.lock().close(fd)
+}
+
+// Whether the table is locked, i.e. a descriptor is being used. Doesn't
+// wait, so it can be used for diagnostics in interrupt handlers.
+pub fn is_locked() -> bool {
+    TABLE.try_lock().is_none()
+}
+
+pub fn open_count() -> usize {
+    TABLE.lock().open_count()
+}
@@ -0,0 +1,350 @@
+// Pixel framebuffer graphics and a text console on top of it.
+//
+// The framebuffer is described by a `FrameBufferInfo` handed over by the
+// bootloader. Once `init` is called, `print!` and `println!` render text onto
+// the framebuffer instead of the VGA text buffer. `kernel_main` calls it when
+// built with the `vga-graphics` feature, which has the bootloader switch to
+// VGA mode 13h.
+
+mod font;
+
+use crate::memory;
+use crate::snapshot::{Snapshot, MAX_COLUMNS, MAX_ROWS};
+use crate::vga_buffer::Color;
+use core::fmt::{self, Arguments, Write};
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+pub use font::{GLYPH_HEIGHT, GLYPH_WIDTH};
+
+// The physical address of the framebuffer of VGA mode 13h
+const VGA_MODE_13H_ADDRESS: u64 = 0xA0000;
+
+// The RGB values of the 16 VGA colors, which are also the first 16 entries
+// of the default mode 13h palette
+const VGA_PALETTE: [Rgb; 16] = [
+    Rgb::new(0x00, 0x00, 0x00), Rgb::new(0x00, 0x00, 0xAA),
+    Rgb::new(0x00, 0xAA, 0x00), Rgb::new(0x00, 0xAA, 0xAA),
+    Rgb::new(0xAA, 0x00, 0x00), Rgb::new(0xAA, 0x00, 0xAA),
+    Rgb::new(0xAA, 0x55, 0x00), Rgb::new(0xAA, 0xAA, 0xAA),
+    Rgb::new(0x55, 0x55, 0x55), Rgb::new(0x55, 0x55, 0xFF),
+    Rgb::new(0x55, 0xFF, 0x55), Rgb::new(0x55, 0xFF, 0xFF),
+    Rgb::new(0xFF, 0x55, 0x55), Rgb::new(0xFF, 0x55, 0xFF),
+    Rgb::new(0xFF, 0xFF, 0x55), Rgb::new(0xFF, 0xFF, 0xFF),
+];
+
+// The layout of a single pixel in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb,      // Red in the lowest byte, at least 3 bytes per pixel
+    Bgr,      // Blue in the lowest byte, at least 3 bytes per pixel
+    Indexed,  // One byte per pixel indexing the palette (VGA mode 13h)
+}
+
+// Description of a linear framebuffer
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBufferInfo {
+    pub address: usize,          // Virtual address of the first pixel
+    pub width: usize,            // Visible pixels per line
+    pub height: usize,           // Number of lines
+    pub stride: usize,           // Pixels per line in memory, at least `width`
+    pub bytes_per_pixel: usize,
+    pub format: PixelFormat,
+}
+
+impl FrameBufferInfo {
+    // The 320x200 framebuffer of VGA mode 13h, which the bootloader sets up
+    // when built with its `vga_320x200` feature. The bootloader doesn't map
+    // it, so it is reached through the physical memory mapping, and
+    // `memory::init` must have been called.
+    pub fn vga_mode_13h() -> FrameBufferInfo {
+        let address = memory::phys_to_virt(PhysAddr::new(VGA_MODE_13H_ADDRESS));
+        FrameBufferInfo {
+            address: address.as_u64() as usize,
+            width: 320,
+            height: 200,
+            stride: 320,
+            bytes_per_pixel: 1,
+            format: PixelFormat::Indexed,
+        }
+    }
+
+    // Return the size of the framebuffer in bytes
+    pub fn size(&self) -> usize {
+        self.stride * self.height * self.bytes_per_pixel
+    }
+}
+
+// A color as red, green and blue intensities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+
+    // Return the index of the closest of the 16 VGA colors
+    fn palette_index(self) -> u8 {
+        let distance = |c: &Rgb| {
+            let dr = self.r as i32 - c.r as i32;
+            let dg = self.g as i32 - c.g as i32;
+            let db = self.b as i32 - c.b as i32;
+            dr * dr + dg * dg + db * db
+        };
+
+        let mut best = 0;
+        for (index, color) in VGA_PALETTE.iter().enumerate() {
+            if distance(color) < distance(&VGA_PALETTE[best]) {
+                best = index;
+            }
+        }
+        best as u8
+    }
+}
+
+impl From<Color> for Rgb {
+    fn from(color: Color) -> Rgb {
+        VGA_PALETTE[color as usize]
+    }
+}
+
+// A linear framebuffer with drawing primitives
+pub struct FrameBuffer {
+    info: FrameBufferInfo,
+    buffer: &'static mut [u8],
+}
+
+impl FrameBuffer {
+    // Create a framebuffer from its description.
+    //
+    // Unsafe because the caller must guarantee that `info` describes mapped,
+    // writable memory that isn't used by anything else.
+    pub unsafe fn new(info: FrameBufferInfo) -> FrameBuffer {
+        let buffer = core::slice::from_raw_parts_mut(info.address as *mut u8, info.size());
+        FrameBuffer { info, buffer }
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    pub fn width(&self) -> usize {
+        self.info.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.height
+    }
+
+    // Set a single pixel; pixels outside the screen are ignored
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
+        let offset = (y * self.info.stride + x) * self.info.bytes_per_pixel;
+        let pixel = &mut self.buffer[offset..offset + self.info.bytes_per_pixel];
+        match self.info.format {
+            PixelFormat::Rgb => pixel[..3].copy_from_slice(&[color.r, color.g, color.b]),
+            PixelFormat::Bgr => pixel[..3].copy_from_slice(&[color.b, color.g, color.r]),
+            PixelFormat::Indexed => pixel[0] = color.palette_index(),
+        }
+    }
+
+    // Fill a rectangle, clipped to the screen
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let x_end = (x + width).min(self.info.width);
+        let y_end = (y + height).min(self.info.height);
+        for py in y..y_end {
+            for px in x..x_end {
+                self.set_pixel(px, py, color);
+            }
+        }
+    }
+
+    // Fill the whole screen with one color
+    pub fn clear(&mut self, color: Rgb) {
+        self.fill_rect(0, 0, self.info.width, self.info.height, color);
+    }
+
+    // Draw a character with its top left corner at `x`, `y`
+    pub fn draw_char(&mut self, x: usize, y: usize, ch: u8, foreground: Rgb, background: Rgb) {
+        let glyph = font::glyph(ch);
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                let color = if bits & (0x80 >> col) != 0 { foreground } else { background };
+                self.set_pixel(x + col, y + row, color);
+            }
+        }
+    }
+
+    // Draw a string on a single line, starting at `x`, `y`
+    pub fn draw_str(&mut self, x: usize, y: usize, s: &str, foreground: Rgb, background: Rgb) {
+        for (i, byte) in s.bytes().enumerate() {
+            self.draw_char(x + i * GLYPH_WIDTH, y, byte, foreground, background);
+        }
+    }
+
+    // Move the content up by `lines` pixel lines and clear the freed lines
+    fn scroll_up(&mut self, lines: usize, background: Rgb) {
+        let line_bytes = self.info.stride * self.info.bytes_per_pixel;
+        let lines = lines.min(self.info.height);
+        let moved = (self.info.height - lines) * line_bytes;
+        self.buffer.copy_within(lines * line_bytes..lines * line_bytes + moved, 0);
+        self.fill_rect(0, self.info.height - lines, self.info.width, lines, background);
+    }
+}
+
+// A text console rendering onto a framebuffer, behaving like the VGA `Writer`
+pub struct TextConsole {
+    framebuffer: FrameBuffer,
+    column_position: usize,
+    row_position: usize,
+    foreground: Rgb,
+    background: Rgb,
+    text: [[u8; MAX_COLUMNS]; MAX_ROWS],  // The characters drawn, for snapshots
+}
+
+impl TextConsole {
+    pub fn new(framebuffer: FrameBuffer) -> TextConsole {
+        let mut console = TextConsole {
+            framebuffer,
+            column_position: 0,
+            row_position: 0,
+            foreground: Color::Yellow.into(),
+            background: Color::Black.into(),
+            text: [[b' '; MAX_COLUMNS]; MAX_ROWS],
+        };
+        console.framebuffer.clear(console.background);
+        console
+    }
+
+    // The number of text columns and rows that fit on the screen
+    pub fn columns(&self) -> usize {
+        self.framebuffer.width() / GLYPH_WIDTH
+    }
+
+    pub fn rows(&self) -> usize {
+        self.framebuffer.height() / GLYPH_HEIGHT
+    }
+
+    // Change the colors used for the following text
+    pub fn set_color(&mut self, foreground: Rgb, background: Rgb) {
+        self.foreground = foreground;
+        self.background = background;
+    }
+
+    // Give access to the framebuffer for drawing
+    pub fn framebuffer(&mut self) -> &mut FrameBuffer {
+        &mut self.framebuffer
+    }
+
+    // Write a single byte to the screen
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            byte => {
+                if self.column_position >= self.columns() {
+                    self.new_line();
+                }
+
+                let x = self.column_position * GLYPH_WIDTH;
+                let y = self.row_position * GLYPH_HEIGHT;
+                self.framebuffer.draw_char(x, y, byte, self.foreground, self.background);
+                if self.row_position < MAX_ROWS && self.column_position < MAX_COLUMNS {
+                    self.text[self.row_position][self.column_position] = byte;
+                }
+                self.column_position += 1;
+            }
+        }
+    }
+
+    // Move to a new line, scrolling once the last row is reached
+    fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position + 1 < self.rows() {
+            self.row_position += 1;
+        } else {
+            self.framebuffer.scroll_up(GLYPH_HEIGHT, self.background);
+            let rows = self.rows().min(MAX_ROWS);
+            self.text.copy_within(1..rows, 0);
+            self.text[rows - 1] = [b' '; MAX_COLUMNS];
+        }
+    }
+
+    // Copy the text on the screen
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new(self.columns(), self.rows());
+        for row in 0..snapshot.height() {
+            for col in 0..snapshot.width() {
+                snapshot.set(row, col, self.text[row][col]);
+            }
+        }
+        snapshot
+    }
+}
+
+impl Write for TextConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                // Printable ASCII character or newline
+                0x20..=0x7E | b'\n' => self.write_byte(byte),
+                // Non-printable ASCII character, print the placeholder character
+                _ => self.write_byte(b'*'),
+            }
+        }
+        Ok(())
+    }
+}
+
+// The framebuffer console, if one was set up
+pub static CONSOLE: Mutex<Option<TextConsole>> = Mutex::new(None);
+
+// Take over text output with a console on the given framebuffer.
+//
+// Unsafe for the same reasons as `FrameBuffer::new`.
+pub unsafe fn init(info: FrameBufferInfo) {
+    let console = TextConsole::new(FrameBuffer::new(info));
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *CONSOLE.lock() = Some(console);
+    });
+}
+
+// Return whether text output goes to the framebuffer.
+pub fn is_active() -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| CONSOLE.lock().is_some())
+}
+
+// Print to the framebuffer console. Returns `false` if there is none.
+// Must be called with interrupts disabled.
+#[doc(hidden)]
+pub fn _print(args: Arguments) -> bool {
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            console.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    }
+}
+
+// Like `_print`, but in the given colors.
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, background: Color, args: Arguments) -> bool {
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            let previous = (console.foreground, console.background);
+            console.set_color(foreground.into(), background.into());
+            console.write_fmt(args).unwrap();
+            console.set_color(previous.0, previous.1);
+            true
+        }
+        None => false,
+    }
+}
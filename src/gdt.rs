@@ -6,6 +6,17 @@ use x86_64::VirtAddr;
 // Define the index for the double fault IST (Interrupt Stack Table)
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+// The size of the stack for the double fault IST
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// The size of the stack interrupts from ring 3 switch to
+pub const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
+
+// Written to the lowest word of the static stacks by `init`. The stacks
+// have no guard pages, so an overflow runs into whatever lies below; a
+// changed canary shows that it happened.
+pub const STACK_CANARY: u64 = 0x57AC_CA4A_57AC_CA4A;
+
 // Define a lazy_static block to initialize the Task State Segment (TSS)
 lazy_static! {
     static ref TSS: TaskStateSegment = {
@@ -13,18 +24,25 @@ lazy_static! {
         
         // Set the interrupt stack table entry for the double fault IST
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // Define the size of the stack for the double fault IST
-            const STACK_SIZE: usize = 4096 * 5;
             // Define a static mutable array to represent the stack
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
             
             // Get the virtual address of the stack start
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
             // Calculate the stack end address
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             // Return the stack end address
             stack_end
         };
+
+        // Set the stack the CPU switches to when an interrupt or exception
+        // arrives in ring 3 (RSP0), so the kernel never runs on a user stack
+        tss.privilege_stack_table[0] = {
+            static mut STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            stack_start + PRIVILEGE_STACK_SIZE
+        };
         
         // Return the initialized Task State Segment
         tss
@@ -38,6 +56,12 @@ lazy_static! {
         
         // Add a kernel code segment entry to the GDT and get its selector
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+
+        // Add the user segments, data before code: SYSRET takes the user
+        // selectors from fixed offsets in this order
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         
         // Add a TSS segment entry to the GDT and get its selector
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
@@ -47,6 +71,9 @@ lazy_static! {
             gdt,
             Selectors {
                 code_selector,
+                data_selector,
+                user_code_selector,
+                user_data_selector,
                 tss_selector,
             },
         )
@@ -54,22 +81,71 @@ lazy_static! {
 }
 
 // Define a structure to hold the GDT selectors
-struct Selectors {
-    code_selector: SegmentSelector,
-    tss_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    // With a requested privilege level of 3
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
+    pub tss_selector: SegmentSelector,
+}
+
+// Return the selectors of the segments installed in the GDT
+pub fn selectors() -> Selectors {
+    GDT.1
 }
 
-// Function to initialize the GDT and set CS and TSS registers
+// Return the top (highest address) of the double fault IST stack
+pub fn double_fault_stack_top() -> VirtAddr {
+    TSS.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize]
+}
+
+// Return the top of the stack interrupts from ring 3 run on
+pub fn privilege_stack_top() -> VirtAddr {
+    TSS.privilege_stack_table[0]
+}
+
+// The static stacks, by name, with the address of their canary
+fn stack_canaries() -> [(&'static str, VirtAddr); 2] {
+    [
+        ("double fault", double_fault_stack_top() - DOUBLE_FAULT_STACK_SIZE as u64),
+        ("privilege", privilege_stack_top() - PRIVILEGE_STACK_SIZE as u64),
+    ]
+}
+
+// Return a stack whose canary changed, with the canary's address. The
+// canary is written again, so every overflow is reported once.
+pub fn check_stack_canaries() -> Option<(&'static str, VirtAddr)> {
+    stack_canaries().into_iter().find(|&(_, canary)| {
+        let canary = canary.as_mut_ptr::<u64>();
+        let intact = unsafe { canary.read_unaligned() } == STACK_CANARY;
+        if !intact {
+            unsafe { canary.write_unaligned(STACK_CANARY) };
+        }
+        !intact
+    })
+}
+
+// Function to initialize the GDT and set the segment and TSS registers
 pub fn init() {
-    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
     use x86_64::instructions::tables::load_tss;
 
     GDT.0.load();
     
-    // Set the CS register to the code selector
+    // Set the CS register to the code selector and the others to the data
+    // selector
     unsafe {
         CS::set_reg(GDT.1.code_selector);
+        SS::set_reg(GDT.1.data_selector);
+        DS::set_reg(GDT.1.data_selector);
+        ES::set_reg(GDT.1.data_selector);
         // Load the TSS selector
         load_tss(GDT.1.tss_selector);
     }
+
+    for (_, canary) in stack_canaries() {
+        unsafe { canary.as_mut_ptr::<u64>().write_unaligned(STACK_CANARY) };
+    }
 }
@@ -0,0 +1,117 @@
+// A heartbeat indicator for telling a live kernel from a hung one.
+//
+// The timer interrupt toggles the indicator every half second: a heart in
+// the top right corner of the VGA screen, or the Scroll Lock LED. The VGA
+// cell is written straight to video memory without taking any lock, so it
+// keeps beating as long as timer interrupts arrive, even if the console or
+// the executor is stuck. A frozen heart means interrupts are off or the CPU
+// is halted for good.
+
+use crate::{keyboard, time};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+// Time between two toggles
+const PERIOD_MS: u64 = 500;
+
+// The VGA cell used, the top right corner of the 80x25 text screen
+const VGA_CELL_ADDRESS: usize = 0xb8000 + 2 * 79;
+
+// A heart in code page 437, light red on black
+const VGA_HEART: u16 = 0x0C03;
+const VGA_BLANK: u16 = 0x0720;
+
+// Where the heartbeat is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    Off = 0,
+    Vga = 1,
+    ScrollLock = 2,
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Vga => "vga",
+            Mode::ScrollLock => "led",
+        }
+    }
+
+    // Look a mode up by its name
+    pub fn from_name(name: &str) -> Option<Mode> {
+        [Mode::Off, Mode::Vga, Mode::ScrollLock]
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+
+// Whether the indicator is on, and when it was last toggled
+static LIT: AtomicBool = AtomicBool::new(false);
+static LAST_TOGGLE_MS: AtomicU64 = AtomicU64::new(0);
+
+// Select where the heartbeat is shown. The indicator of the old mode is
+// switched off.
+pub fn set_mode(new: Mode) {
+    let old = mode();
+    MODE.store(new as u8, Ordering::Relaxed);
+    if old != new {
+        show(old, false);
+        LIT.store(false, Ordering::Relaxed);
+    }
+}
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Relaxed) {
+        1 => Mode::Vga,
+        2 => Mode::ScrollLock,
+        _ => Mode::Off,
+    }
+}
+
+// Called on every timer interrupt
+pub(crate) fn on_tick() {
+    let mode = mode();
+    if mode == Mode::Off {
+        return;
+    }
+    let now = time::uptime_ms();
+    if now.wrapping_sub(LAST_TOGGLE_MS.load(Ordering::Relaxed)) < PERIOD_MS {
+        return;
+    }
+    LAST_TOGGLE_MS.store(now, Ordering::Relaxed);
+    let lit = !LIT.load(Ordering::Relaxed);
+    LIT.store(lit, Ordering::Relaxed);
+    show(mode, lit);
+}
+
+// Switch the indicator of a mode on or off
+fn show(mode: Mode, lit: bool) {
+    match mode {
+        Mode::Off => {}
+        Mode::Vga => {
+            let cell = VGA_CELL_ADDRESS as *mut u16;
+            let value = if lit { VGA_HEART } else { VGA_BLANK };
+            unsafe { cell.write_volatile(value) };
+        }
+        Mode::ScrollLock => {
+            // Off shows the real Scroll Lock state again
+            let modifiers = keyboard::modifiers();
+            let scroll_lock = if lit { !modifiers.scroll_lock } else { modifiers.scroll_lock };
+            keyboard::set_leds(modifiers.caps_lock, modifiers.num_lock, scroll_lock);
+        }
+    }
+}
+
+#[test_case]
+fn test_mode_names() {
+    assert_eq!(Mode::from_name("led"), Some(Mode::ScrollLock));
+    assert_eq!(Mode::from_name("vga"), Some(Mode::Vga));
+    assert_eq!(Mode::from_name("on"), None);
+    for mode in [Mode::Off, Mode::Vga, Mode::ScrollLock] {
+        assert_eq!(Mode::from_name(mode.name()), Some(mode));
+    }
+}
@@ -0,0 +1,89 @@
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::PhysAddr;
+
+// The physical address of the HPET on PC-compatible chipsets (and QEMU),
+// used when there is no ACPI HPET table
+pub const HPET_DEFAULT_BASE: u64 = 0xFED0_0000;
+
+// HPET register offsets
+const REG_CAPABILITIES: usize = 0x000;
+const REG_CONFIGURATION: usize = 0x010;
+const REG_MAIN_COUNTER: usize = 0x0F0;
+
+// Configuration register flags
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTING: u64 = 1 << 1;
+
+// The specification limits the counter period to at most 100 ns
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+const FEMTOSECONDS_PER_NANOSECOND: u128 = 1_000_000;
+
+// The virtual address of the HPET registers, 0 if there is no HPET
+static HPET_BASE: AtomicU64 = AtomicU64::new(0);
+
+// The period of the main counter in femtoseconds
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+// Map the HPET registers and start its main counter.
+//
+// The address comes from the ACPI HPET table if there is one. The
+// capabilities register is validated first, so that machines without an
+// HPET keep using the other time sources.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let phys = crate::acpi::hpet().map_or(HPET_DEFAULT_BASE, |hpet| hpet.base_address);
+    let base = memory::map_mmio(PhysAddr::new(phys), mapper, frame_allocator)?;
+    let base = base.as_u64();
+
+    let capabilities = unsafe { read(base, REG_CAPABILITIES) };
+    let period_fs = capabilities >> 32;
+    let vendor_id = (capabilities >> 16) & 0xFFFF;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS || vendor_id == 0xFFFF {
+        log::info!("HPET not present");
+        return Ok(());
+    }
+
+    unsafe {
+        // Stop the counter, reset it and start it again without the legacy
+        // replacement routing, which would steal the PIT and RTC interrupts
+        let config = read(base, REG_CONFIGURATION) & !(CONFIG_ENABLE | CONFIG_LEGACY_ROUTING);
+        write(base, REG_CONFIGURATION, config);
+        write(base, REG_MAIN_COUNTER, 0);
+        write(base, REG_CONFIGURATION, config | CONFIG_ENABLE);
+    }
+
+    PERIOD_FS.store(period_fs, Ordering::Relaxed);
+    HPET_BASE.store(base, Ordering::Release);
+    Ok(())
+}
+
+// Return whether the HPET is available as a time source.
+pub fn is_available() -> bool {
+    HPET_BASE.load(Ordering::Acquire) != 0
+}
+
+// Return the nanoseconds elapsed since the HPET was started, or `None` if
+// there is no HPET.
+pub fn nanoseconds() -> Option<u64> {
+    let base = HPET_BASE.load(Ordering::Acquire);
+    if base == 0 {
+        return None;
+    }
+
+    let counter = unsafe { read(base, REG_MAIN_COUNTER) };
+    let period_fs = PERIOD_FS.load(Ordering::Relaxed);
+    Some((counter as u128 * period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND) as u64)
+}
+
+unsafe fn read(base: u64, offset: usize) -> u64 {
+    core::ptr::read_volatile((base as usize + offset) as *const u64)
+}
+
+unsafe fn write(base: u64, offset: usize, value: u64) {
+    core::ptr::write_volatile((base as usize + offset) as *mut u64, value);
+}
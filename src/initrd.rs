@@ -0,0 +1,123 @@
+// Parsing of initial ramdisk archives.
+//
+// The parsers work on the archive in memory and hand out borrowed entries,
+// so no allocation is needed to list or read the files. An archive in
+// either format can be linked into the kernel by building with
+//
+//     RUST_OS_INITRD=path/to/initrd.tar cargo build
+//
+// and `init` mounts it read-only as the root of the VFS, so its files are
+// there before any disk driver is up.
+
+use crate::console::ProgressBar;
+use alloc::sync::Arc;
+
+pub mod cpio;
+pub mod fs;
+pub mod tar;
+
+pub use fs::InitrdFs;
+
+// Defines `IMAGE`, the archive linked into the kernel; empty if there is none
+include!(concat!(env!("OUT_DIR"), "/initrd.rs"));
+
+// The type of an archive entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+// A file, directory or link stored in an archive
+#[derive(Debug, Clone, Copy)]
+pub struct Entry<'a> {
+    pub name: &'a str,
+    pub prefix: &'a str, // A directory the name is relative to, or ""
+    pub kind: EntryKind,
+    pub mode: u32,     // Permission bits
+    pub data: &'a [u8], // File content or link target
+}
+
+// Errors that can occur while parsing an archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    BadMagic,      // The header doesn't start with the expected magic
+    BadHeader,     // A header field is malformed
+    BadName,       // A file name isn't valid UTF-8 or isn't terminated
+    Truncated,     // The archive ends in the middle of an entry
+    BadChecksum,   // A header checksum doesn't match
+}
+
+// File type bits of a Unix mode
+const S_IFMT: u32 = 0o170000;
+const S_IFREG: u32 = 0o100000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+// Determine the entry kind from the file type bits of a Unix mode
+fn kind_from_mode(mode: u32) -> EntryKind {
+    match mode & S_IFMT {
+        S_IFREG => EntryKind::File,
+        S_IFDIR => EntryKind::Directory,
+        S_IFLNK => EntryKind::Symlink,
+        _ => EntryKind::Other,
+    }
+}
+
+// Iterator over the entries of an archive in any supported format
+pub enum Entries<'a> {
+    Cpio(cpio::Entries<'a>),
+    Tar(tar::Entries<'a>),
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Entries::Cpio(entries) => entries.next(),
+            Entries::Tar(entries) => entries.next(),
+        }
+    }
+}
+
+// Iterate over the entries of an archive, recognizing its format
+pub fn entries(archive: &[u8]) -> Result<Entries<'_>, Error> {
+    if cpio::is_cpio(archive) {
+        Ok(Entries::Cpio(cpio::entries(archive)))
+    } else if tar::is_tar(archive) {
+        Ok(Entries::Tar(tar::entries(archive)))
+    } else {
+        Err(Error::BadMagic)
+    }
+}
+
+// Return the archive linked into the kernel; empty if there is none
+pub fn image() -> &'static [u8] {
+    &IMAGE
+}
+
+// Mount the archive linked into the kernel at "/", if there is one. Must
+// be called after the heap is initialized.
+pub fn init() {
+    let image = image();
+    if image.is_empty() {
+        return;
+    }
+    let mut bar = ProgressBar::new("loading initrd", image.len() as u64);
+    let fs = InitrdFs::with_progress(image, |done| bar.set(done));
+    bar.finish();
+    let fs = match fs {
+        Ok(fs) => fs,
+        Err(err) => {
+            log::error!("initrd: can't read the archive: {:?}", err);
+            return;
+        }
+    };
+    log::info!("initrd: {} entries in {} bytes", fs.entry_count(), image.len());
+    if let Err(err) = crate::vfs::mount("/", Arc::new(fs)) {
+        log::error!("initrd: can't mount the archive: {:?}", err);
+    }
+}
@@ -0,0 +1,149 @@
+// The "newc" cpio format produced by `cpio -H newc` and used by Linux
+// initramfs images.
+//
+// Every entry is a 110 byte ASCII header followed by the NUL-terminated name
+// and the data, both padded to a multiple of 4 bytes. An entry named
+// `TRAILER!!!` ends the archive.
+
+use super::{kind_from_mode, Entry, Error};
+
+const HEADER_SIZE: usize = 110;
+
+// "070701" is newc, "070702" is newc with a checksum we don't verify
+const MAGIC_NEWC: &[u8] = b"070701";
+const MAGIC_NEWC_CRC: &[u8] = b"070702";
+
+const TRAILER: &str = "TRAILER!!!";
+
+// Indexes of the 8 digit hex fields following the magic
+const FIELD_MODE: usize = 1;
+const FIELD_FILESIZE: usize = 6;
+const FIELD_NAMESIZE: usize = 11;
+
+// Return whether `data` starts like a newc cpio archive
+pub fn is_cpio(data: &[u8]) -> bool {
+    data.starts_with(MAGIC_NEWC) || data.starts_with(MAGIC_NEWC_CRC)
+}
+
+// Iterate over the entries of a newc cpio archive
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+// Iterator over the entries of a cpio archive, ending at the trailer or the
+// first error
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.parse_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl<'a> Entries<'a> {
+    // Parse the entry at the current offset; `None` at the trailer
+    fn parse_entry(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        let header = self
+            .archive
+            .get(self.offset..self.offset + HEADER_SIZE)
+            .ok_or(Error::Truncated)?;
+        if !is_cpio(header) {
+            return Err(Error::BadMagic);
+        }
+
+        let mode = field(header, FIELD_MODE)?;
+        let file_size = field(header, FIELD_FILESIZE)? as usize;
+        let name_size = field(header, FIELD_NAMESIZE)? as usize;
+
+        // The name includes its NUL terminator
+        let name_start = self.offset + HEADER_SIZE;
+        let name_bytes = self
+            .archive
+            .get(name_start..name_start + name_size)
+            .ok_or(Error::Truncated)?;
+        let name = match name_bytes.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| Error::BadName)?,
+            _ => return Err(Error::BadName),
+        };
+
+        let data_start = align4(name_start + name_size);
+        let data = self
+            .archive
+            .get(data_start..data_start + file_size)
+            .ok_or(Error::Truncated)?;
+        self.offset = align4(data_start + file_size);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+
+        Ok(Some(Entry {
+            name,
+            prefix: "",
+            kind: kind_from_mode(mode),
+            mode: mode & 0o7777,
+            data,
+        }))
+    }
+}
+
+// Parse the 8 digit hex field with the given index
+fn field(header: &[u8], index: usize) -> Result<u32, Error> {
+    let start = MAGIC_NEWC.len() + index * 8;
+    let digits = core::str::from_utf8(&header[start..start + 8]).map_err(|_| Error::BadHeader)?;
+    u32::from_str_radix(digits, 16).map_err(|_| Error::BadHeader)
+}
+
+// Round up to the next multiple of 4
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[test_case]
+fn test_cpio_entries() {
+    use super::EntryKind;
+
+    const ARCHIVE: &[u8] = b"\
+07070100000001000041ED0000000000000000000000010000000000000000000000000000000000000000000000000000000400000000etc\0\0\0\
+07070100000001000081A40000000000000000000000010000000000000006000000000000000000000000000000000000000900000000etc/motd\0\0hello\n\0\0\
+07070100000001000000000000000000000000000000010000000000000000000000000000000000000000000000000000000B00000000TRAILER!!!\0\0\0\0";
+
+    assert!(is_cpio(ARCHIVE));
+    let mut entries = entries(ARCHIVE);
+
+    let dir = entries.next().unwrap().unwrap();
+    assert_eq!(dir.name, "etc");
+    assert_eq!(dir.kind, EntryKind::Directory);
+    assert_eq!(dir.mode, 0o755);
+
+    let file = entries.next().unwrap().unwrap();
+    assert_eq!(file.name, "etc/motd");
+    assert_eq!(file.kind, EntryKind::File);
+    assert_eq!(file.data, b"hello\n");
+
+    assert!(entries.next().is_none());
+}
@@ -0,0 +1,154 @@
+// A read-only filesystem over an archive in memory, for mounting in the VFS.
+//
+// The archive is indexed once when the filesystem is created; file contents
+// are read straight from it. Directories that only appear in the paths of
+// other entries, which archives don't have to list, are added as well.
+
+use super::{EntryKind, Error};
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Metadata, VfsError};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// The mode of directories that aren't in the archive themselves
+const IMPLICIT_DIR_MODE: u32 = 0o755;
+
+struct Node {
+    path: String, // Normalized, e.g. "/etc/motd"
+    file_type: FileType,
+    mode: u32,
+    data: &'static [u8], // File content or link target
+}
+
+impl Node {
+    fn metadata(&self) -> Metadata {
+        let size = match self.file_type {
+            FileType::Directory => 0,
+            _ => self.data.len() as u64,
+        };
+        Metadata {
+            file_type: self.file_type,
+            size,
+            mode: self.mode,
+        }
+    }
+}
+
+pub struct InitrdFs {
+    nodes: Vec<Node>,
+}
+
+impl InitrdFs {
+    // Index a cpio or tar archive. Entries other than files, directories
+    // and symbolic links are left out.
+    pub fn new(archive: &'static [u8]) -> Result<InitrdFs, Error> {
+        Self::with_progress(archive, |_| {})
+    }
+
+    // Like `new`, calling `progress` after each entry with the number of
+    // bytes of the archive read so far
+    pub fn with_progress(
+        archive: &'static [u8],
+        mut progress: impl FnMut(u64),
+    ) -> Result<InitrdFs, Error> {
+        let mut fs = InitrdFs { nodes: Vec::new() };
+        for entry in super::entries(archive)? {
+            let entry = entry?;
+            let end = entry.data.as_ptr() as usize + entry.data.len();
+            progress((end - archive.as_ptr() as usize) as u64);
+            let file_type = match entry.kind {
+                EntryKind::File => FileType::File,
+                EntryKind::Directory => FileType::Directory,
+                EntryKind::Symlink => FileType::Symlink,
+                EntryKind::Other => continue,
+            };
+            // Names like "./etc/" or "etc" become "/etc"; ".." can't leave the root
+            let path = match vfs::normalize(&format!("/{}/{}", entry.prefix, entry.name)) {
+                Ok(path) if path != "/" => path,
+                _ => continue,
+            };
+            fs.add_parents(&path);
+            // Like when extracting, a later entry replaces an earlier one
+            fs.nodes.retain(|node| node.path != path);
+            fs.nodes.push(Node {
+                path,
+                file_type,
+                mode: entry.mode,
+                data: entry.data,
+            });
+        }
+        Ok(fs)
+    }
+
+    // Return the number of files, directories and links
+    pub fn entry_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // Add the directories above `path` that are missing
+    fn add_parents(&mut self, path: &str) {
+        for (end, _) in path.match_indices('/').skip(1) {
+            let parent = &path[..end];
+            if self.nodes.iter().all(|node| node.path != parent) {
+                self.nodes.push(Node {
+                    path: String::from(parent),
+                    file_type: FileType::Directory,
+                    mode: IMPLICIT_DIR_MODE,
+                    data: &[],
+                });
+            }
+        }
+    }
+
+    fn find(&self, path: &str) -> Result<&Node, VfsError> {
+        self.nodes.iter().find(|node| node.path == path).ok_or(VfsError::NotFound)
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        if path == "/" {
+            return Ok(Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+                mode: IMPLICIT_DIR_MODE,
+            });
+        }
+        Ok(self.find(path)?.metadata())
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let node = self.find(path)?;
+        match node.file_type {
+            FileType::File => {}
+            FileType::Directory => return Err(VfsError::IsADirectory),
+            FileType::Symlink => return Err(VfsError::Unsupported),
+        }
+        let data = node.data.get(offset as usize..).unwrap_or(&[]);
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        if !self.metadata(path)?.is_dir() {
+            return Err(VfsError::NotADirectory);
+        }
+        let entries = self
+            .nodes
+            .iter()
+            .filter_map(|node| match vfs::split_parent(&node.path) {
+                (parent, name) if parent == path => Some(DirEntry {
+                    name: String::from(name),
+                    metadata: node.metadata(),
+                }),
+                _ => None,
+            })
+            .collect();
+        Ok(entries)
+    }
+}
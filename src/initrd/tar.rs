@@ -0,0 +1,197 @@
+// The POSIX ustar format produced by `tar --format=ustar`, also readable in
+// the GNU format most tar implementations write by default.
+//
+// Every entry is a 512 byte header followed by the data, padded to a
+// multiple of 512 bytes. The numeric header fields are octal ASCII. A block
+// of zeros ends the archive.
+
+use super::{Entry, EntryKind, Error};
+
+const BLOCK_SIZE: usize = 512;
+
+// "ustar\0" for POSIX and "ustar " for GNU archives
+const MAGIC: &[u8] = b"ustar";
+
+// Offsets and lengths of the header fields
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 8);
+const SIZE: (usize, usize) = (124, 12);
+const CHECKSUM: (usize, usize) = (148, 8);
+const TYPE_FLAG: usize = 156;
+const LINK_NAME: (usize, usize) = (157, 100);
+const MAGIC_OFFSET: usize = 257;
+const PREFIX: (usize, usize) = (345, 155);
+
+// Return whether `data` starts like a ustar archive
+pub fn is_tar(data: &[u8]) -> bool {
+    data.len() >= BLOCK_SIZE && data[MAGIC_OFFSET..].starts_with(MAGIC)
+}
+
+// Iterate over the entries of a ustar archive
+pub fn entries(archive: &[u8]) -> Entries<'_> {
+    Entries {
+        archive,
+        offset: 0,
+        done: false,
+    }
+}
+
+// Iterator over the entries of a tar archive, ending at the first zero
+// block or the first error
+pub struct Entries<'a> {
+    archive: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.parse_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+impl<'a> Entries<'a> {
+    // Parse the entry at the current offset; `None` at the end
+    fn parse_entry(&mut self) -> Result<Option<Entry<'a>>, Error> {
+        // Some tools leave out the zero blocks at the end
+        if self.offset == self.archive.len() {
+            return Ok(None);
+        }
+        let header = self
+            .archive
+            .get(self.offset..self.offset + BLOCK_SIZE)
+            .ok_or(Error::Truncated)?;
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        if !header[MAGIC_OFFSET..].starts_with(MAGIC) {
+            return Err(Error::BadMagic);
+        }
+        if octal(field(header, CHECKSUM))? != checksum(header) {
+            return Err(Error::BadChecksum);
+        }
+
+        let mode = octal(field(header, MODE))? as u32;
+        let size = octal(field(header, SIZE))? as usize;
+        let data_start = self.offset + BLOCK_SIZE;
+        let data = self
+            .archive
+            .get(data_start..data_start + size)
+            .ok_or(Error::Truncated)?;
+        self.offset = data_start + align_block(size);
+
+        let (kind, data) = match header[TYPE_FLAG] {
+            b'0' | b'\0' | b'7' => (EntryKind::File, data),
+            b'5' => (EntryKind::Directory, data),
+            b'2' => (EntryKind::Symlink, field(header, LINK_NAME)),
+            _ => (EntryKind::Other, data),
+        };
+        Ok(Some(Entry {
+            name: text(field(header, NAME))?,
+            prefix: text(field(header, PREFIX))?,
+            kind,
+            mode: mode & 0o7777,
+            data,
+        }))
+    }
+}
+
+// Return a field up to its first NUL
+fn field(header: &[u8], (offset, len): (usize, usize)) -> &[u8] {
+    let field = &header[offset..offset + len];
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(len);
+    &field[..end]
+}
+
+fn text(field: &[u8]) -> Result<&str, Error> {
+    core::str::from_utf8(field).map_err(|_| Error::BadName)
+}
+
+// Parse an octal number, which may be padded with spaces on both sides
+fn octal(field: &[u8]) -> Result<u64, Error> {
+    let digits = core::str::from_utf8(field).map_err(|_| Error::BadHeader)?.trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| Error::BadHeader)
+}
+
+// The sum of the header bytes, counting the checksum field as spaces
+fn checksum(header: &[u8]) -> u64 {
+    let (offset, len) = CHECKSUM;
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if (offset..offset + len).contains(&i) { b' ' } else { byte })
+        .map(u64::from)
+        .sum()
+}
+
+// Round up to the next multiple of the block size
+fn align_block(offset: usize) -> usize {
+    (offset + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1)
+}
+
+#[test_case]
+fn test_tar_entries() {
+    // Write a header with a valid checksum
+    fn header(block: &mut [u8], name: &str, prefix: &str, kind: u8, mode: &[u8], size: &[u8]) {
+        block[..name.len()].copy_from_slice(name.as_bytes());
+        block[MODE.0..][..mode.len()].copy_from_slice(mode);
+        block[SIZE.0..][..size.len()].copy_from_slice(size);
+        block[TYPE_FLAG] = kind;
+        block[MAGIC_OFFSET..MAGIC_OFFSET + 8].copy_from_slice(b"ustar\x0000");
+        block[PREFIX.0..][..prefix.len()].copy_from_slice(prefix.as_bytes());
+        let sum = checksum(block);
+        for (i, digit) in block[CHECKSUM.0..CHECKSUM.0 + 6].iter_mut().enumerate() {
+            *digit = b'0' + ((sum >> (3 * (5 - i))) & 7) as u8;
+        }
+        block[CHECKSUM.0 + 6] = 0;
+    }
+
+    let mut archive = [0u8; 5 * BLOCK_SIZE];
+    header(&mut archive[..BLOCK_SIZE], "etc/", "", b'5', b"0000755", b"00000000000");
+    header(&mut archive[BLOCK_SIZE..], "motd", "./etc", b'0', b"0000644", b"00000000006");
+    archive[2 * BLOCK_SIZE..][..6].copy_from_slice(b"hello\n");
+    header(&mut archive[3 * BLOCK_SIZE..], "etc/issue", "", b'2', b"0000777", b"0");
+    archive[3 * BLOCK_SIZE + LINK_NAME.0..][..4].copy_from_slice(b"motd");
+
+    assert!(is_tar(&archive));
+    let mut entries = entries(&archive);
+
+    let dir = entries.next().unwrap().unwrap();
+    assert_eq!((dir.prefix, dir.name), ("", "etc/"));
+    assert_eq!(dir.kind, EntryKind::Directory);
+    assert_eq!(dir.mode, 0o755);
+
+    let file = entries.next().unwrap().unwrap();
+    assert_eq!((file.prefix, file.name), ("./etc", "motd"));
+    assert_eq!(file.kind, EntryKind::File);
+    assert_eq!(file.data, b"hello\n");
+
+    let link = entries.next().unwrap().unwrap();
+    assert_eq!(link.kind, EntryKind::Symlink);
+    assert_eq!(link.data, b"motd");
+    assert!(entries.next().is_none());
+
+    archive[BLOCK_SIZE] ^= 1;
+    let mut entries = self::entries(&archive);
+    assert!(entries.next().unwrap().is_ok());
+    assert_eq!(entries.next().unwrap().unwrap_err(), Error::BadChecksum);
+}
@@ -0,0 +1,239 @@
+// The input subsystem.
+//
+// Drivers register their device and report what happens on it as
+// `InputEvent`s; consumers like a TTY or a shell subscribe to the events of
+// all devices, or of one device or kind of device, instead of talking to the
+// drivers. The PS/2 keyboard and the COM1 serial console are built in.
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use crate::keyboard::KeyEvent;
+use futures_util::stream::Stream;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Number of events buffered per subscriber before the oldest ones are dropped
+const SUBSCRIBER_QUEUE_CAPACITY: usize = 64;
+
+// Maximum number of registered input devices
+const MAX_DEVICES: usize = 16;
+
+// The kind of an input device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Keyboard,
+    Mouse,
+    Serial,
+}
+
+// Identifies a registered input device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(usize);
+
+impl DeviceId {
+    // The PS/2 keyboard
+    pub const KEYBOARD: DeviceId = DeviceId(0);
+    // The serial console on COM1
+    pub const SERIAL: DeviceId = DeviceId(1);
+}
+
+// Mouse buttons
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+// Something that happened on an input device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    // A key was pressed or released
+    Key(KeyEvent),
+    // A character was typed (a decoded key press or a byte from a terminal)
+    Char(char),
+    // The mouse moved by the given amount
+    MouseMove { dx: i16, dy: i16 },
+    // A mouse button was pressed or released
+    MouseButton { button: MouseButton, pressed: bool },
+    // The mouse wheel was turned
+    MouseScroll { delta: i8 },
+}
+
+// A registered input device
+#[derive(Debug, Clone, Copy)]
+pub struct Device {
+    pub id: DeviceId,
+    pub name: &'static str,
+    pub kind: DeviceKind,
+}
+
+// Returned when no more devices can be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyDevices;
+
+// Which events a subscriber receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    All,
+    Device(DeviceId),
+    Kind(DeviceKind),
+}
+
+impl Filter {
+    fn matches(self, device: &Device) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Device(id) => device.id == id,
+            Filter::Kind(kind) => device.kind == kind,
+        }
+    }
+}
+
+// The registered devices, indexed by id. Fixed size so drivers can report
+// events before the heap exists.
+static DEVICES: Mutex<[Option<Device>; MAX_DEVICES]> = Mutex::new({
+    let mut devices = [None; MAX_DEVICES];
+    devices[0] = Some(Device {
+        id: DeviceId::KEYBOARD,
+        name: "ps2-keyboard",
+        kind: DeviceKind::Keyboard,
+    });
+    devices[1] = Some(Device {
+        id: DeviceId::SERIAL,
+        name: "com1",
+        kind: DeviceKind::Serial,
+    });
+    devices
+});
+
+// The state shared between the subsystem and one subscriber
+struct Channel {
+    filter: Filter,
+    queue: VecDeque<(DeviceId, InputEvent)>,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+// All live subscriptions
+static SUBSCRIBERS: Mutex<Vec<Arc<Mutex<Channel>>>> = Mutex::new(Vec::new());
+
+// Register an input device and return its id
+pub fn register_device(name: &'static str, kind: DeviceKind) -> Result<DeviceId, TooManyDevices> {
+    interrupts::without_interrupts(|| {
+        let mut devices = DEVICES.lock();
+        let index = devices.iter().position(|d| d.is_none()).ok_or(TooManyDevices)?;
+        let id = DeviceId(index);
+        devices[index] = Some(Device { id, name, kind });
+        Ok(id)
+    })
+}
+
+// Remove a device; its pending events stay queued
+pub fn unregister_device(id: DeviceId) {
+    interrupts::without_interrupts(|| DEVICES.lock()[id.0] = None);
+}
+
+// Return the registered devices
+pub fn devices() -> Vec<Device> {
+    interrupts::without_interrupts(|| DEVICES.lock().iter().flatten().copied().collect())
+}
+
+// Report an event on a device to the matching subscribers.
+//
+// Safe to call from interrupt handlers: nothing is allocated, and a full
+// queue drops its oldest event instead of growing.
+pub fn report(id: DeviceId, event: InputEvent) {
+    interrupts::without_interrupts(|| {
+        let device = match DEVICES.lock().get(id.0).copied().flatten() {
+            Some(device) => device,
+            None => return,
+        };
+
+        for subscriber in SUBSCRIBERS.lock().iter() {
+            let mut channel = subscriber.lock();
+            if !channel.filter.matches(&device) {
+                continue;
+            }
+            if channel.queue.len() == SUBSCRIBER_QUEUE_CAPACITY {
+                channel.queue.pop_front();
+                channel.dropped += 1;
+            }
+            channel.queue.push_back((id, event));
+            if let Some(waker) = channel.waker.take() {
+                waker.wake();
+            }
+        }
+    });
+}
+
+// Subscribe to the events matching `filter` from now on
+pub fn subscribe(filter: Filter) -> InputStream {
+    let channel = Arc::new(Mutex::new(Channel {
+        filter,
+        queue: VecDeque::with_capacity(SUBSCRIBER_QUEUE_CAPACITY),
+        dropped: 0,
+        waker: None,
+    }));
+
+    interrupts::without_interrupts(|| SUBSCRIBERS.lock().push(channel.clone()));
+    InputStream { channel }
+}
+
+// An asynchronous stream of input events and the devices they came from;
+// unsubscribes when dropped
+pub struct InputStream {
+    channel: Arc<Mutex<Channel>>,
+}
+
+impl InputStream {
+    // Return the next pending event, if any
+    pub fn try_next(&self) -> Option<(DeviceId, InputEvent)> {
+        interrupts::without_interrupts(|| self.channel.lock().queue.pop_front())
+    }
+
+    // Return the number of events lost because the queue was full
+    pub fn dropped(&self) -> u64 {
+        interrupts::without_interrupts(|| self.channel.lock().dropped)
+    }
+}
+
+impl Stream for InputStream {
+    type Item = (DeviceId, InputEvent);
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        interrupts::without_interrupts(|| {
+            let mut channel = self.channel.lock();
+            match channel.queue.pop_front() {
+                Some(entry) => Poll::Ready(Some(entry)),
+                None => {
+                    channel.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+    }
+}
+
+impl Drop for InputStream {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            SUBSCRIBERS
+                .lock()
+                .retain(|channel| !Arc::ptr_eq(channel, &self.channel));
+        });
+    }
+}
+
+#[test_case]
+fn test_filter_matches() {
+    let keyboard = interrupts::without_interrupts(|| DEVICES.lock()[DeviceId::KEYBOARD.0].unwrap());
+    assert!(Filter::All.matches(&keyboard));
+    assert!(Filter::Device(DeviceId::KEYBOARD).matches(&keyboard));
+    assert!(!Filter::Device(DeviceId::SERIAL).matches(&keyboard));
+    assert!(Filter::Kind(DeviceKind::Keyboard).matches(&keyboard));
+    assert!(!Filter::Kind(DeviceKind::Mouse).matches(&keyboard));
+}
@@ -1,8 +1,11 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, PageFaultErrorCode, InterruptStackFrame};
-use crate::{gdt, print, println, hault_loop};
+use crate::{apic, console, console_print, gdt, input, keyboard, print, println, serial_println, hault_loop, softirq, telemetry, time};
+use crate::usermode;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::PrivilegeLevel;
 
 
 pub enum InterruptIndex {
@@ -26,6 +29,37 @@ impl From<InterruptIndex> for usize {
     }
 }
 
+impl InterruptIndex {
+    // Return the PIC IRQ line of this interrupt
+    pub fn irq(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+}
+
+// Number of IRQ lines provided by the chained PICs
+pub const IRQ_COUNT: usize = 16;
+
+// Handlers registered at runtime, indexed by IRQ line
+static IRQ_HANDLERS: spin::Mutex<[Option<fn()>; IRQ_COUNT]> = spin::Mutex::new([None; IRQ_COUNT]);
+
+// Number of interrupts received per IRQ line
+#[allow(clippy::declare_interior_mutable_const)]
+const IRQ_COUNT_INIT: AtomicU64 = AtomicU64::new(0);
+static IRQ_COUNTS: [AtomicU64; IRQ_COUNT] = [IRQ_COUNT_INIT; IRQ_COUNT];
+
+// The vectors handed out by `allocate_vector`, e.g. for message signalled
+// interrupts. They sit above the PIC range and below the spurious vector.
+pub const DYNAMIC_VECTOR_BASE: u8 = 0x50;
+pub const DYNAMIC_VECTOR_COUNT: usize = 32;
+
+// Handlers of the dynamically allocated vectors
+static VECTOR_HANDLERS: spin::Mutex<[Option<fn()>; DYNAMIC_VECTOR_COUNT]> =
+    spin::Mutex::new([None; DYNAMIC_VECTOR_COUNT]);
+
+// Error returned when every dynamic vector is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoFreeVector;
+
 // Define a mutex-protected static variable for PICs
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
@@ -35,8 +69,11 @@ lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         
-        // Set the handler function for the breakpoint exception
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        // Set the handler function for the breakpoint exception, which
+        // user code may raise with `int3` as well
+        idt.breakpoint
+            .set_handler_fn(breakpoint_handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
         
         // Set the handler function and stack index for the double fault exception
         unsafe {
@@ -45,14 +82,28 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
-        idt[InterruptIndex::Timer.into()]
-            .set_handler_fn(timer_interrupt_handler);
+        // Route every PIC line through a generic stub that dispatches to the
+        // handler registered with `register_irq`
+        for (irq, &stub) in IRQ_STUBS.iter().enumerate() {
+            idt[PIC_1_OFFSET as usize + irq].set_handler_fn(stub);
+        }
+
+        // Dynamic vectors dispatch to the handler given to `allocate_vector`
+        for (index, &stub) in VECTOR_STUBS.iter().enumerate() {
+            idt[DYNAMIC_VECTOR_BASE as usize + index].set_handler_fn(stub);
+        }
 
-        idt[InterruptIndex::Keyboard.into()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
 
         idt.page_fault.set_handler_fn(page_fault_handler);
 
+        // Set the handler functions for the remaining common CPU exceptions
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+
         // Return the initialized IDT
         idt
     };
@@ -62,6 +113,202 @@ lazy_static! {
 pub fn init_idt() {
     // Load the IDT
     IDT.load();
+
+    register_irq(InterruptIndex::Timer.irq(), timer_interrupt_handler);
+    register_irq(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler);
+}
+
+// Register `handler` to be called whenever the given IRQ line fires.
+//
+// The handler runs in interrupt context with interrupts disabled, so it must
+// be short and must not block. The end of interrupt is sent after it returns.
+// A previously registered handler for the line is replaced.
+pub fn register_irq(irq: u8, handler: fn()) {
+    assert!((irq as usize) < IRQ_COUNT, "invalid IRQ line {}", irq);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = Some(handler);
+    });
+
+    // The IO-APIC starts with every line masked; the PICs keep the masks the
+    // firmware left, which usually don't include devices we add
+    if apic::is_enabled() {
+        apic::route_isa_irq(irq);
+    } else {
+        unmask_pic_irq(irq);
+    }
+}
+
+// Unmask the given IRQ line in the 8259 PICs, including the cascade line for
+// lines of the secondary PIC
+fn unmask_pic_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mut primary, mut secondary] = pics.read_masks();
+            if irq < 8 {
+                primary &= !(1 << irq);
+            } else {
+                primary &= !(1 << 2);
+                secondary &= !(1 << (irq - 8));
+            }
+            pics.write_masks(primary, secondary);
+        }
+    });
+}
+
+// Remove the handler registered for the given IRQ line.
+pub fn unregister_irq(irq: u8) {
+    assert!((irq as usize) < IRQ_COUNT, "invalid IRQ line {}", irq);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = None;
+    });
+}
+
+// Return whether a handler is registered for the given IRQ line.
+pub fn has_irq_handler(irq: u8) -> bool {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize].is_some()
+    })
+}
+
+// Return the number of interrupts received on the given IRQ line since boot.
+pub fn irq_count(irq: u8) -> u64 {
+    IRQ_COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
+// Call the handler registered for `irq` (if any) and acknowledge the interrupt.
+fn dispatch_irq(irq: u8) {
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+
+    // Copy the handler out so the lock isn't held while it runs
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq)
+        }
+    }
+
+    // Run work deferred by the handler now that the interrupt is acknowledged
+    softirq::run_on_irq_exit();
+}
+
+// Allocate an interrupt vector that calls `handler` when it fires and return
+// its number.
+//
+// Unlike IRQ lines, these vectors aren't wired to an interrupt controller
+// input; they are meant for interrupts delivered straight to the local APIC,
+// like MSI, so the end of interrupt goes to the local APIC. The same rules
+// as for `register_irq` handlers apply.
+pub fn allocate_vector(handler: fn()) -> Result<u8, NoFreeVector> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = VECTOR_HANDLERS.lock();
+        let index = handlers.iter().position(Option::is_none).ok_or(NoFreeVector)?;
+        handlers[index] = Some(handler);
+        Ok(DYNAMIC_VECTOR_BASE + index as u8)
+    })
+}
+
+// Give back a vector returned by `allocate_vector`.
+pub fn free_vector(vector: u8) {
+    let index = vector.wrapping_sub(DYNAMIC_VECTOR_BASE) as usize;
+    assert!(index < DYNAMIC_VECTOR_COUNT, "not a dynamic vector: {:#x}", vector);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        VECTOR_HANDLERS.lock()[index] = None;
+    });
+}
+
+// Call the handler of a dynamic vector and acknowledge the interrupt.
+fn dispatch_vector(index: usize) {
+    let handler = VECTOR_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    apic::end_of_interrupt();
+    softirq::run_on_irq_exit();
+}
+
+// Spurious interrupts of the local APIC must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// Generate one interrupt handler per IRQ line or dynamic vector. The
+// x86-interrupt ABI doesn't tell a handler which vector it was invoked for,
+// so each one needs its own stub that forwards its index to the dispatcher.
+macro_rules! interrupt_stubs {
+    ($stubs:ident[$count:expr] => $dispatch:ident; $($name:ident => $index:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                $dispatch($index);
+            }
+        )*
+
+        const $stubs: [extern "x86-interrupt" fn(InterruptStackFrame); $count] = [$($name),*];
+    };
+}
+
+interrupt_stubs! {
+    IRQ_STUBS[IRQ_COUNT] => dispatch_irq;
+    irq0_stub => 0,
+    irq1_stub => 1,
+    irq2_stub => 2,
+    irq3_stub => 3,
+    irq4_stub => 4,
+    irq5_stub => 5,
+    irq6_stub => 6,
+    irq7_stub => 7,
+    irq8_stub => 8,
+    irq9_stub => 9,
+    irq10_stub => 10,
+    irq11_stub => 11,
+    irq12_stub => 12,
+    irq13_stub => 13,
+    irq14_stub => 14,
+    irq15_stub => 15,
+}
+
+interrupt_stubs! {
+    VECTOR_STUBS[DYNAMIC_VECTOR_COUNT] => dispatch_vector;
+    vector0_stub => 0,
+    vector1_stub => 1,
+    vector2_stub => 2,
+    vector3_stub => 3,
+    vector4_stub => 4,
+    vector5_stub => 5,
+    vector6_stub => 6,
+    vector7_stub => 7,
+    vector8_stub => 8,
+    vector9_stub => 9,
+    vector10_stub => 10,
+    vector11_stub => 11,
+    vector12_stub => 12,
+    vector13_stub => 13,
+    vector14_stub => 14,
+    vector15_stub => 15,
+    vector16_stub => 16,
+    vector17_stub => 17,
+    vector18_stub => 18,
+    vector19_stub => 19,
+    vector20_stub => 20,
+    vector21_stub => 21,
+    vector22_stub => 22,
+    vector23_stub => 23,
+    vector24_stub => 24,
+    vector25_stub => 25,
+    vector26_stub => 26,
+    vector27_stub => 27,
+    vector28_stub => 28,
+    vector29_stub => 29,
+    vector30_stub => 30,
+    vector31_stub => 31,
 }
 
 // Interrupt handler for the breakpoint exception
@@ -69,47 +316,88 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+// Number of 64-bit words dumped from the top of the double fault IST stack
+const DOUBLE_FAULT_STACK_DUMP_WORDS: usize = 16;
+
 // Interrupt handler for the double fault exception
+//
+// Besides the stack frame, the control registers, the segment selectors and
+// the top of the IST stack are dumped over serial, so that double faults
+// caused by e.g. a kernel stack overflow can be diagnosed after the fact.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
+    use x86_64::registers::control::{Cr2, Cr3};
+
+    serial_println!("EXCEPTION: DOUBLE FAULT");
+
+    // CR2 still holds the address of the last page fault, which is usually
+    // the guard page hit by an overflowing stack
+    serial_println!("CR2: {:?}", Cr2::read());
+    let (level_4_table_frame, cr3_flags) = Cr3::read();
+    serial_println!("CR3: {:?} {:?}", level_4_table_frame.start_address(), cr3_flags);
+
+    // The currently loaded selectors and the ones installed in our GDT
+    let selectors = gdt::selectors();
+    serial_println!("CS: {:?} SS: {:?}", CS::get_reg(), SS::get_reg());
+    serial_println!(
+        "GDT code selector: {:?} TSS selector: {:?}",
+        selectors.code_selector,
+        selectors.tss_selector
+    );
+
+    dump_double_fault_stack();
+
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.into())
+// Print the topmost words of the double fault IST stack over serial.
+//
+// The IST stack is a static array owned by the `gdt` module, so reading it is
+// always safe, unlike the interrupted stack which may point to a guard page.
+fn dump_double_fault_stack() {
+    let stack_top = gdt::double_fault_stack_top();
+
+    serial_println!("IST stack (top {} words):", DOUBLE_FAULT_STACK_DUMP_WORDS);
+    for i in 1..=DOUBLE_FAULT_STACK_DUMP_WORDS {
+        let addr = stack_top - (i * 8) as u64;
+        let word = unsafe { addr.as_ptr::<u64>().read_volatile() };
+        serial_println!("  {:#018x}: {:#018x}", addr.as_u64(), word);
     }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stakc_frame: InterruptStackFrame) {
-    
-    use x86_64::instructions::port::Port;
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
+fn timer_interrupt_handler() {
+    time::tick();
+    telemetry::on_tick();
+    console::on_tick();
+    crate::heartbeat::on_tick();
+}
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
-        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
-    }
+fn keyboard_interrupt_handler() {
+    use x86_64::instructions::port::Port;
 
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-    
-    let scanCode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scanCode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key), 
-            }
-        }
+    let scancode: u8 = unsafe { port.read() };
+    let event = match keyboard::process_scancode(scancode) {
+        Some(event) => event,
+        None => return,
+    };
+    if crate::sysrq::handle_key_event(&event) || console::handle_key_event(&event) {
+        return;
     }
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.into())
+    input::report(input::DeviceId::KEYBOARD, input::InputEvent::Key(event));
+
+    // Echo typed characters on the terminal that is shown, unless the
+    // program reading them does
+    if let Some(character) = event.character {
+        input::report(input::DeviceId::KEYBOARD, input::InputEvent::Char(character));
+        let tty = console::active();
+        if console::echo_enabled(tty) {
+            console_print!(tty, "{}", character);
+        }
     }
 }
 
@@ -151,12 +439,138 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
-extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode,) {
+#[test_case]
+fn test_allocate_vector() {
+    fn handler() {}
+
+    let first = allocate_vector(handler).unwrap();
+    let second = allocate_vector(handler).unwrap();
+    assert_ne!(first, second);
+    assert!(first >= DYNAMIC_VECTOR_BASE && first < apic::SPURIOUS_VECTOR);
+
+    // A freed vector is handed out again
+    free_vector(first);
+    assert_eq!(allocate_vector(handler), Ok(first));
+    free_vector(first);
+    free_vector(second);
+}
+
+extern "x86-interrupt" fn page_fault_handler(mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode,) {
     use x86_64::registers::control::Cr2;
 
+    // A write to a copy-on-write page gets a copy of its own, also when
+    // the kernel writes to user memory
+    let write_fault =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code.contains(write_fault) && crate::memory::cow::handle_write_fault(Cr2::read()) {
+        return;
+    }
+
+    kill_faulting_program("PAGE FAULT", &stack_frame, usermode::SIGSEGV);
+
+    // A bad user pointer in a user copy fails the copy instead
+    if let Some(fixup) = crate::usercopy::fixup_address(stack_frame.instruction_pointer.as_u64()) {
+        unsafe {
+            stack_frame
+                .as_mut()
+                .update(|frame| frame.instruction_pointer = x86_64::VirtAddr::new(fixup));
+        }
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
     println!("Error Code: {:?}", error_code);
+    print_faulting_function(stack_frame.instruction_pointer.as_u64());
     println!("{:#?}", stack_frame);
     hault_loop();
-}
\ No newline at end of file
+}
+
+// Number of instruction bytes printed before and after the faulting RIP
+const FAULT_CONTEXT_BYTES: u64 = 8;
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    report_fault("DIVIDE ERROR", &stack_frame, None, usermode::SIGFPE);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    report_fault("INVALID OPCODE", &stack_frame, None, usermode::SIGILL);
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    report_fault("SEGMENT NOT PRESENT", &stack_frame, Some(error_code), usermode::SIGBUS);
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    report_fault("STACK SEGMENT FAULT", &stack_frame, Some(error_code), usermode::SIGBUS);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    report_fault("GENERAL PROTECTION FAULT", &stack_frame, Some(error_code), usermode::SIGSEGV);
+}
+
+// End the user program the fault came from, if it came from one started by
+// `usermode::run`, with the status of a program killed by `signal`
+fn kill_faulting_program(name: &str, stack_frame: &InterruptStackFrame, signal: u64) {
+    if stack_frame.code_segment & 3 != 3 || !usermode::is_running() {
+        return;
+    }
+    log::warn!("user program killed: {} at {:?}", name, stack_frame.instruction_pointer);
+    unsafe { usermode::kill(signal) }
+}
+
+// Print the exception name, error code, faulting RIP and the instruction bytes
+// around it, then halt instead of letting the fault escalate to a triple fault.
+// A user program causing it is killed with `signal` instead.
+fn report_fault(
+    name: &str,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<u64>,
+    signal: u64,
+) -> ! {
+    kill_faulting_program(name, stack_frame, signal);
+    let rip = stack_frame.instruction_pointer;
+
+    println!("EXCEPTION: {}", name);
+    if let Some(error_code) = error_code {
+        println!("Error Code: {:#x}", error_code);
+    }
+    println!("Faulting RIP: {:?}", rip);
+    print_faulting_function(rip.as_u64());
+    print!("Code:");
+    for offset in 0..FAULT_CONTEXT_BYTES * 2 {
+        let addr = rip.as_u64().wrapping_sub(FAULT_CONTEXT_BYTES).wrapping_add(offset);
+        if offset == FAULT_CONTEXT_BYTES {
+            print!(" <");
+        }
+        match read_code_byte(addr) {
+            Some(byte) => print!(" {:02x}", byte),
+            None => print!(" ??"),
+        }
+        if offset == FAULT_CONTEXT_BYTES {
+            print!(">");
+        }
+    }
+    println!();
+    println!("{:#?}", stack_frame);
+    hault_loop();
+}
+
+// Print the function containing the faulting instruction, if it is known
+fn print_faulting_function(rip: u64) {
+    if let Some(symbol) = crate::symbols::lookup(rip) {
+        println!("In function: {}", symbol);
+    }
+}
+
+// Read a single byte of code through the physical memory mapping, so that an
+// unmapped or non-canonical RIP doesn't cause a nested fault.
+fn read_code_byte(addr: u64) -> Option<u8> {
+    use crate::memory;
+    use x86_64::VirtAddr;
+
+    let virt = VirtAddr::try_new(addr).ok()?;
+    let phys = memory::virt_to_phys(virt)?;
+    let ptr: *const u8 = memory::phys_to_virt(phys).as_ptr();
+    Some(unsafe { ptr.read_volatile() })
+}
@@ -0,0 +1,439 @@
+// Keyboard decoding: layouts and modifier keys.
+//
+// The interrupt handler feeds every scancode to `process_scancode`, which
+// turns complete key presses and releases into `KeyEvent`s. An event carries
+// the state of the modifier keys at the time and, for keys that type
+// something, the character in the selected layout. Ctrl with a letter types
+// the matching control character, e.g. Ctrl+C gives '\u{3}'.
+//
+// The layout can be changed at any time with `set_layout`.
+//
+// Commands to the keyboard (LEDs, key repeat) are sent one at a time. The
+// keyboard answers every byte with an ACK, which arrives through the
+// interrupt like a scancode; `process_scancode` consumes it and sends the
+// next byte. The lock key LEDs follow the lock state automatically.
+
+use crate::time;
+use core::sync::atomic::{AtomicU8, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout, ScancodeSet1,
+};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// The PS/2 data and status ports
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+// Number of status polls before writing a byte anyway
+const INPUT_POLL_LIMIT: u32 = 100_000;
+
+// Keyboard commands and responses
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_SET_TYPEMATIC: u8 = 0xF3;
+const RESPONSE_ACK: u8 = 0xFA;
+const RESPONSE_RESEND: u8 = 0xFE;
+
+// How often a byte is resent before the command is dropped
+const MAX_RESENDS: u8 = 3;
+
+// How long to wait for an ACK before the command is considered lost
+const COMMAND_TIMEOUT_MS: u64 = 100;
+
+// LED bits of the set LEDs command
+const LED_SCROLL_LOCK: u8 = 1 << 0;
+const LED_NUM_LOCK: u8 = 1 << 1;
+const LED_CAPS_LOCK: u8 = 1 << 2;
+
+// The largest typematic rate and delay values. A rate of 0 repeats 30 times a
+// second, 31 about twice a second; the delay before repeating starts is
+// 250 ms times (delay + 1).
+pub const MAX_TYPEMATIC_RATE: u8 = 0x1F;
+pub const MAX_TYPEMATIC_DELAY: u8 = 0x3;
+
+// A keyboard layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Layout {
+    Us = 0,
+    Uk = 1,
+    De = 2,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 3] = [Layout::Us, Layout::Uk, Layout::De];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us => "us",
+            Layout::Uk => "uk",
+            Layout::De => "de",
+        }
+    }
+
+    // Look a layout up by its name
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().copied().find(|layout| layout.name() == name)
+    }
+
+    // Return what a key types with the given modifiers
+    fn map(self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey {
+        let handle_ctrl = HandleControl::MapLettersToUnicode;
+        match self {
+            Layout::Us => layouts::Us104Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::Uk => layouts::Uk105Key::map_keycode(code, modifiers, handle_ctrl),
+            Layout::De => layouts::De105Key::map_keycode(code, modifiers, handle_ctrl),
+        }
+    }
+}
+
+// The modifier keys held down, and the lock keys switched on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift_left: bool,
+    pub shift_right: bool,
+    pub ctrl_left: bool,
+    pub ctrl_right: bool,
+    pub alt: bool,    // Left Alt
+    pub alt_gr: bool, // Right Alt
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    // No key held down and every lock off
+    pub const NONE: Modifiers = Modifiers {
+        shift_left: false,
+        shift_right: false,
+        ctrl_left: false,
+        ctrl_right: false,
+        alt: false,
+        alt_gr: false,
+        caps_lock: false,
+        num_lock: false,
+        scroll_lock: false,
+    };
+
+    pub fn shift(&self) -> bool {
+        self.shift_left || self.shift_right
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.ctrl_left || self.ctrl_right
+    }
+
+    // Update the state for a key press or release. Returns `true` if the
+    // key is a modifier or lock key.
+    fn update(&mut self, code: KeyCode, pressed: bool) -> bool {
+        match code {
+            KeyCode::ShiftLeft => self.shift_left = pressed,
+            KeyCode::ShiftRight => self.shift_right = pressed,
+            KeyCode::ControlLeft => self.ctrl_left = pressed,
+            KeyCode::ControlRight => self.ctrl_right = pressed,
+            KeyCode::AltLeft => self.alt = pressed,
+            KeyCode::AltRight => self.alt_gr = pressed,
+            // Lock keys toggle when pressed
+            KeyCode::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if pressed => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if pressed => self.scroll_lock = !self.scroll_lock,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => {}
+            _ => return false,
+        }
+        true
+    }
+
+    // The same state in the form the layouts take
+    fn to_pc_keyboard(self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.shift_left,
+            rshift: self.shift_right,
+            lctrl: self.ctrl_left,
+            rctrl: self.ctrl_right,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+}
+
+// Error returned by `set_typematic` for a rate or delay out of range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTypematic;
+
+// A command waiting for the keyboard to acknowledge one of its bytes
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+    bytes: [u8; 2], // The command and its data byte
+    next: usize,    // Index of the byte waiting for an ACK
+    resends: u8,
+    sent_ms: u64,
+}
+
+// The commands for the keyboard. Only the latest LED state and typematic
+// setting are kept; older ones that weren't sent yet are superseded.
+struct Commands {
+    leds: Option<u8>,
+    typematic: Option<u8>,
+    in_flight: Option<InFlight>,
+}
+
+impl Commands {
+    const fn new() -> Commands {
+        Commands {
+            leds: None,
+            typematic: None,
+            in_flight: None,
+        }
+    }
+
+    fn set_leds(&mut self, leds: u8) {
+        self.leds = Some(leds);
+        self.send_next();
+    }
+
+    fn set_typematic(&mut self, typematic: u8) {
+        self.typematic = Some(typematic);
+        self.send_next();
+    }
+
+    // Start sending the next command unless one is still waiting for its ACK
+    fn send_next(&mut self) {
+        if let Some(in_flight) = self.in_flight {
+            if time::uptime_ms().saturating_sub(in_flight.sent_ms) < COMMAND_TIMEOUT_MS {
+                return;
+            }
+            // The keyboard never answered
+            self.in_flight = None;
+        }
+
+        let bytes = if let Some(leds) = self.leds.take() {
+            [COMMAND_SET_LEDS, leds]
+        } else if let Some(typematic) = self.typematic.take() {
+            [COMMAND_SET_TYPEMATIC, typematic]
+        } else {
+            return;
+        };
+        self.in_flight = Some(InFlight {
+            bytes,
+            next: 0,
+            resends: 0,
+            sent_ms: time::uptime_ms(),
+        });
+        write_data(bytes[0]);
+    }
+
+    // Handle a byte from the keyboard. Returns `true` if it answered a
+    // command and is no scancode.
+    fn handle_response(&mut self, byte: u8) -> bool {
+        let in_flight = match self.in_flight.as_mut() {
+            Some(in_flight) => in_flight,
+            None => return false,
+        };
+
+        match byte {
+            RESPONSE_ACK => {
+                in_flight.next += 1;
+                if in_flight.next < in_flight.bytes.len() {
+                    in_flight.sent_ms = time::uptime_ms();
+                    write_data(in_flight.bytes[in_flight.next]);
+                    return true;
+                }
+            }
+            RESPONSE_RESEND if in_flight.resends < MAX_RESENDS => {
+                in_flight.resends += 1;
+                in_flight.sent_ms = time::uptime_ms();
+                write_data(in_flight.bytes[in_flight.next]);
+                return true;
+            }
+            // Out of resends, give up on this command
+            RESPONSE_RESEND => {}
+            _ => return false,
+        }
+
+        self.in_flight = None;
+        self.send_next();
+        true
+    }
+}
+
+// Write a byte to the keyboard once the controller accepts it
+fn write_data(byte: u8) {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        for _ in 0..INPUT_POLL_LIMIT {
+            if status.read() & STATUS_INPUT_FULL == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        data.write(byte);
+    }
+}
+
+// The data byte of the set LEDs command
+fn led_byte(caps_lock: bool, num_lock: bool, scroll_lock: bool) -> u8 {
+    let mut leds = 0;
+    if caps_lock {
+        leds |= LED_CAPS_LOCK;
+    }
+    if num_lock {
+        leds |= LED_NUM_LOCK;
+    }
+    if scroll_lock {
+        leds |= LED_SCROLL_LOCK;
+    }
+    leds
+}
+
+// The data byte of the set typematic command
+fn typematic_byte(rate: u8, delay: u8) -> Result<u8, InvalidTypematic> {
+    if rate > MAX_TYPEMATIC_RATE || delay > MAX_TYPEMATIC_DELAY {
+        return Err(InvalidTypematic);
+    }
+    Ok(delay << 5 | rate)
+}
+
+// A key being pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,           // False when the key is released
+    pub modifiers: Modifiers,    // Including this key's change
+    pub character: Option<char>, // What the key types, on presses only
+}
+
+// The layout used to decode keys
+static LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us as u8);
+
+lazy_static! {
+    // Only turns scancodes into key codes; the layout passed here is unused
+    static ref DECODER: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
+}
+
+// The current modifier state
+static MODIFIERS: Mutex<Modifiers> = Mutex::new(Modifiers::NONE);
+
+// The commands waiting to be sent to the keyboard
+static COMMANDS: Mutex<Commands> = Mutex::new(Commands::new());
+
+// Select the layout keys are decoded with
+pub fn set_layout(layout: Layout) {
+    LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+pub fn layout() -> Layout {
+    match LAYOUT.load(Ordering::Relaxed) {
+        1 => Layout::Uk,
+        2 => Layout::De,
+        _ => Layout::Us,
+    }
+}
+
+// Return the current modifier state
+pub fn modifiers() -> Modifiers {
+    x86_64::instructions::interrupts::without_interrupts(|| *MODIFIERS.lock())
+}
+
+// Switch the keyboard LEDs on or off.
+//
+// The LEDs are also set whenever a lock key is toggled, so this only lasts
+// until the next lock key press.
+pub fn set_leds(caps_lock: bool, num_lock: bool, scroll_lock: bool) {
+    let leds = led_byte(caps_lock, num_lock, scroll_lock);
+    x86_64::instructions::interrupts::without_interrupts(|| COMMANDS.lock().set_leds(leds));
+}
+
+// Set how fast a held key repeats and how long it takes until it starts
+// repeating, see `MAX_TYPEMATIC_RATE` and `MAX_TYPEMATIC_DELAY`.
+pub fn set_typematic(rate: u8, delay: u8) -> Result<(), InvalidTypematic> {
+    let typematic = typematic_byte(rate, delay)?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        COMMANDS.lock().set_typematic(typematic)
+    });
+    Ok(())
+}
+
+// Feed a byte read from the keyboard controller. Returns an event once the
+// byte completes a key press or release. Called from the keyboard interrupt.
+pub(crate) fn process_scancode(scancode: u8) -> Option<KeyEvent> {
+    if COMMANDS.lock().handle_response(scancode) {
+        return None;
+    }
+    let event = DECODER.lock().add_byte(scancode).ok()??;
+    let pressed = event.state == KeyState::Down;
+
+    let mut modifiers = MODIFIERS.lock();
+    let locks = |modifiers: &Modifiers| (modifiers.caps_lock, modifiers.num_lock, modifiers.scroll_lock);
+    let old_locks = locks(&modifiers);
+    let is_modifier = modifiers.update(event.code, pressed);
+    if locks(&modifiers) != old_locks {
+        let (caps_lock, num_lock, scroll_lock) = locks(&modifiers);
+        COMMANDS.lock().set_leds(led_byte(caps_lock, num_lock, scroll_lock));
+    }
+    Some(decode(event.code, pressed, *modifiers, is_modifier, layout()))
+}
+
+// Build the event for a key, looking up its character
+fn decode(
+    code: KeyCode,
+    pressed: bool,
+    modifiers: Modifiers,
+    is_modifier: bool,
+    layout: Layout,
+) -> KeyEvent {
+    let character = if pressed && !is_modifier {
+        match layout.map(code, &modifiers.to_pc_keyboard()) {
+            DecodedKey::Unicode(character) => Some(character),
+            DecodedKey::RawKey(_) => None,
+        }
+    } else {
+        None
+    };
+
+    KeyEvent {
+        code,
+        pressed,
+        modifiers,
+        character,
+    }
+}
+
+#[test_case]
+fn test_decode() {
+    let mut modifiers = Modifiers::NONE;
+    let typed = |code, modifiers, layout| decode(code, true, modifiers, false, layout).character;
+
+    assert_eq!(typed(KeyCode::A, modifiers, Layout::Us), Some('a'));
+    // QWERTZ
+    assert_eq!(typed(KeyCode::Y, modifiers, Layout::De), Some('z'));
+
+    assert!(modifiers.update(KeyCode::ShiftLeft, true));
+    assert_eq!(typed(KeyCode::A, modifiers, Layout::Us), Some('A'));
+    modifiers.update(KeyCode::ShiftLeft, false);
+
+    modifiers.update(KeyCode::ControlRight, true);
+    assert_eq!(typed(KeyCode::C, modifiers, Layout::Us), Some('\u{3}'));
+    modifiers.update(KeyCode::ControlRight, false);
+
+    // Caps Lock toggles on presses only
+    modifiers.update(KeyCode::CapsLock, true);
+    modifiers.update(KeyCode::CapsLock, false);
+    assert!(modifiers.caps_lock);
+    assert!(!modifiers.update(KeyCode::A, true));
+}
+
+#[test_case]
+fn test_command_bytes() {
+    assert_eq!(led_byte(true, false, true), LED_CAPS_LOCK | LED_SCROLL_LOCK);
+    assert_eq!(led_byte(false, false, false), 0);
+
+    assert_eq!(typematic_byte(0, 0), Ok(0));
+    assert_eq!(typematic_byte(MAX_TYPEMATIC_RATE, MAX_TYPEMATIC_DELAY), Ok(0x7F));
+    assert_eq!(typematic_byte(MAX_TYPEMATIC_RATE + 1, 0), Err(InvalidTypematic));
+    assert_eq!(typematic_byte(0, MAX_TYPEMATIC_DELAY + 1), Err(InvalidTypematic));
+}
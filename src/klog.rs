@@ -0,0 +1,134 @@
+// The kernel log buffer, like `dmesg`.
+//
+// Every log record that passes the logger's level filter is stored with its
+// timestamp in a fixed size ring buffer, registered as a logger sink by
+// `logger::init`. That is Info and above by default (`logger::DEFAULT_LEVEL`);
+// debug and trace records are only kept for modules whose level is lowered
+// with `logger::set_module_level`. When the buffer is full the oldest lines
+// are overwritten. `dump` replays the buffer, e.g. to recover
+// the messages leading up to a panic after they scrolled off the screen.
+
+use crate::logger::Sink;
+use core::fmt::{self, Write};
+use log::Record;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The size of the kernel log buffer in bytes
+pub const KLOG_SIZE: usize = 16 * 1024;
+
+// A ring buffer of text lines
+struct Ring<const N: usize> {
+    buffer: [u8; N],
+    written: usize, // Total number of bytes ever written
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Ring {
+            buffer: [0; N],
+            written: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.buffer[self.written % N] = byte;
+        self.written += 1;
+    }
+
+    // Write the complete lines in the buffer, oldest first. Non-ASCII bytes
+    // are replaced, since a multi-byte character may have been cut in half.
+    fn dump_to(&self, writer: &mut impl Write) -> fmt::Result {
+        let start = self.written.saturating_sub(N);
+        let mut bytes = (start..self.written).map(|i| self.buffer[i % N]);
+
+        // The oldest line is incomplete once the buffer wrapped around
+        if start > 0 {
+            bytes.by_ref().find(|&byte| byte == b'\n');
+        }
+
+        for byte in bytes {
+            writer.write_char(if byte.is_ascii() { byte as char } else { '?' })?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Write for Ring<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+static KLOG: Mutex<Ring<KLOG_SIZE>> = Mutex::new(Ring::new());
+
+// The logger sink recording into the kernel log buffer
+pub struct KlogSink;
+
+impl Sink for KlogSink {
+    fn write(&self, record: &Record) {
+        let uptime = crate::time::uptime_ms();
+        interrupts::without_interrupts(|| {
+            let _ = writeln!(
+                KLOG.lock(),
+                "[{:>5}.{:03}] {:<5} {}: {}",
+                uptime / 1000,
+                uptime % 1000,
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        });
+    }
+}
+
+// Print the kernel log buffer on the console
+pub fn dump() {
+    interrupts::without_interrupts(|| {
+        let _ = KLOG.lock().dump_to(&mut ConsoleWriter);
+    });
+}
+
+// Write the kernel log buffer to `writer`, e.g. a serial port
+pub fn dump_to(writer: &mut impl Write) -> fmt::Result {
+    interrupts::without_interrupts(|| KLOG.lock().dump_to(writer))
+}
+
+// Forwards to `print!`
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_ring_drops_oldest_lines() {
+    // Collects the dump for comparison, since unit tests run without a heap
+    struct Output {
+        data: [u8; 16],
+        len: usize,
+    }
+
+    impl Write for Output {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            for byte in s.bytes() {
+                self.data[self.len] = byte;
+                self.len += 1;
+            }
+            Ok(())
+        }
+    }
+
+    let mut ring: Ring<12> = Ring::new();
+    write!(ring, "first\nsecond\nthird\n").unwrap();
+
+    let mut output = Output { data: [0; 16], len: 0 };
+    ring.dump_to(&mut output).unwrap();
+    assert_eq!(&output.data[..output.len], b"third\n");
+}
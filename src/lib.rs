@@ -19,26 +19,87 @@ pub mod serial;
 pub mod vga_buffer;
 pub mod memory;
 pub mod allocator;
+pub mod dma;
+pub mod apic;
+pub mod pit;
+pub mod time;
+pub mod softirq;
+pub mod rtc;
+pub mod hpet;
+pub mod events;
+pub mod power;
+pub mod telemetry;
+pub mod compress;
+pub mod console;
+pub mod codec;
+pub mod framebuffer;
+pub mod initrd;
+pub mod version;
+pub mod logger;
+pub mod klog;
+pub mod input;
+pub mod keyboard;
+pub mod clipboard;
+pub mod backtrace;
+pub mod symbols;
+pub mod pci;
+pub mod acpi;
+pub mod membench;
+pub mod task;
+pub mod shell;
+pub mod usercopy;
+pub mod errno;
+pub mod sysrq;
+pub mod heartbeat;
+pub mod snapshot;
+pub mod block;
+pub mod ahci;
+pub mod fault;
+pub mod virtio;
+pub mod ramdisk;
+pub mod partition;
+pub mod fat;
+pub mod snake;
+pub mod vfs;
+pub mod xmodem;
+pub mod tmpfs;
+pub mod fd;
+pub mod usermode;
+pub mod syscall;
+pub mod elf;
+pub mod memcheck;
+pub mod process;
 
 extern crate alloc;
 
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
     init();
+    // No heap, but page translation for the tests that look at mappings
+    let phys_mem_offset = x86_64::VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) }.expect("memory initialization failed");
     test_main();
     hault_loop();
 }
 
 pub fn hault_loop() -> ! {
     loop {
+        // Catch up on deferred interrupt work before going idle. Exception
+        // handlers halt with interrupts disabled and must not run it.
+        if x86_64::instructions::interrupts::are_enabled() {
+            softirq::run_pending();
+        }
         x86_64::instructions::hlt();
     }
 }
 
 pub fn init() {
+    logger::init();
     gdt::init();
+    syscall::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    serial::init_input();
     x86_64::instructions::interrupts::enable();
 }
 pub trait Testable {
@@ -0,0 +1,304 @@
+// The kernel logger, a backend for the `log` crate's macros.
+//
+// Records are filtered by level, with optional overrides for individual
+// modules, and handed to every registered sink. The VGA console and the
+// serial port are registered by `init`; further sinks can be added with
+// `add_sink`. Neither filtering nor the sinks allocate, so logging works
+// before the heap is set up.
+//
+// On the VGA console every subsystem logs in its own color, taken from a
+// theme that maps module paths to colors (see `set_module_color`). Errors
+// and warnings always stand out in red and yellow.
+//
+//     log::info!("APIC timer running at {} Hz", hz);
+//     logger::set_module_level("rust_os::apic", LevelFilter::Trace);
+
+use crate::vga_buffer::{self, Color};
+use crate::{klog, serial};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The level used for modules without an override
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+// Maximum number of registered sinks
+const MAX_SINKS: usize = 8;
+
+// Maximum number of per-module level overrides
+const MAX_MODULE_FILTERS: usize = 16;
+
+// Maximum number of module colors in the theme
+const MAX_THEME_ENTRIES: usize = 16;
+
+// The colors subsystems log in unless changed with `set_module_color`
+const DEFAULT_THEME: [(&str, Color); 9] = [
+    ("rust_os::net", Color::Cyan),
+    ("rust_os::memory", Color::Green),
+    ("rust_os::allocator", Color::Green),
+    ("rust_os::dma", Color::Green),
+    ("rust_os::interrupts", Color::Magenta),
+    ("rust_os::apic", Color::Magenta),
+    ("rust_os::softirq", Color::Magenta),
+    ("rust_os::time", Color::LightBlue),
+    ("rust_os::hpet", Color::LightBlue),
+];
+
+// A destination for log records
+pub trait Sink: Sync {
+    fn write(&self, record: &Record);
+}
+
+// Returned when no more sinks or module filters can be added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableFull;
+
+// A registered sink with the most verbose level it receives
+#[derive(Clone, Copy)]
+struct SinkEntry {
+    sink: &'static dyn Sink,
+    level: LevelFilter,
+}
+
+// Logging configuration
+struct Config {
+    default_level: LevelFilter,
+    modules: [Option<(&'static str, LevelFilter)>; MAX_MODULE_FILTERS],
+    theme: [Option<(&'static str, Color)>; MAX_THEME_ENTRIES],
+    sinks: [Option<SinkEntry>; MAX_SINKS],
+}
+
+impl Config {
+    // Return the level for the given target (a module path)
+    fn level_for(&self, target: &str) -> LevelFilter {
+        lookup(&self.modules, target).unwrap_or(self.default_level)
+    }
+
+    // Return the theme color for the given target, if it has one
+    fn color_for(&self, target: &str) -> Option<Color> {
+        lookup(&self.theme, target)
+    }
+
+    // Return the most verbose level any record can pass with
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .map(|(_, level)| *level)
+            .fold(self.default_level, |a, b| a.max(b))
+    }
+}
+
+static CONFIG: Mutex<Config> = Mutex::new(Config {
+    default_level: DEFAULT_LEVEL,
+    modules: [None; MAX_MODULE_FILTERS],
+    theme: [None; MAX_THEME_ENTRIES],
+    sinks: [None; MAX_SINKS],
+});
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        interrupts::without_interrupts(|| {
+            metadata.level() <= CONFIG.lock().level_for(metadata.target())
+        })
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Copy the sinks out so the lock isn't held while they run; a sink
+        // may log itself or change the configuration
+        let sinks = interrupts::without_interrupts(|| CONFIG.lock().sinks);
+        for entry in sinks.iter().flatten() {
+            if record.level() <= entry.level {
+                entry.sink.write(record);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Install the kernel logger with the VGA console, the serial port (COM1
+// unless routed elsewhere, see `serial::Manager`) and the kernel log buffer
+// as sinks. Called by `rust_os::init`.
+pub fn init() {
+    // Fails only if a logger is already installed, which is then ours
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+
+    for (module, color) in DEFAULT_THEME.iter() {
+        set_module_color(module, *color).expect("no room for the default theme");
+    }
+
+    add_sink(&VgaSink, LevelFilter::Info).expect("no room for the VGA log sink");
+    add_sink(&SerialSink, LevelFilter::Trace).expect("no room for the serial log sink");
+    add_sink(&klog::KlogSink, LevelFilter::Trace).expect("no room for the kernel log sink");
+    update_max_level();
+}
+
+// Register a sink receiving records up to the given level
+pub fn add_sink(sink: &'static dyn Sink, level: LevelFilter) -> Result<(), TableFull> {
+    interrupts::without_interrupts(|| {
+        let mut config = CONFIG.lock();
+        let slot = config.sinks.iter_mut().find(|s| s.is_none()).ok_or(TableFull)?;
+        *slot = Some(SinkEntry { sink, level });
+        Ok(())
+    })
+}
+
+// Set the level for modules without an override
+pub fn set_level(level: LevelFilter) {
+    interrupts::without_interrupts(|| CONFIG.lock().default_level = level);
+    update_max_level();
+}
+
+// Set the level for a module and its submodules, e.g. "rust_os::apic"
+pub fn set_module_level(module: &'static str, level: LevelFilter) -> Result<(), TableFull> {
+    interrupts::without_interrupts(|| insert(&mut CONFIG.lock().modules, module, level))?;
+    update_max_level();
+    Ok(())
+}
+
+// Set the color a module and its submodules log in on the VGA console
+pub fn set_module_color(module: &'static str, color: Color) -> Result<(), TableFull> {
+    interrupts::without_interrupts(|| insert(&mut CONFIG.lock().theme, module, color))
+}
+
+// Return the color a module logs in on the VGA console, if it has one
+pub fn module_color(module: &str) -> Option<Color> {
+    interrupts::without_interrupts(|| CONFIG.lock().color_for(module))
+}
+
+// Let the `log` macros skip records no module can pass, before formatting
+fn update_max_level() {
+    let max = interrupts::without_interrupts(|| CONFIG.lock().max_level());
+    log::set_max_level(max);
+}
+
+// Set the value for `module` in a table of per-module settings
+fn insert<T>(
+    table: &mut [Option<(&'static str, T)>],
+    module: &'static str,
+    value: T,
+) -> Result<(), TableFull> {
+    let existing = table
+        .iter()
+        .position(|entry| matches!(entry, Some((m, _)) if *m == module));
+    let index = match existing {
+        Some(index) => index,
+        None => table.iter().position(|entry| entry.is_none()).ok_or(TableFull)?,
+    };
+    table[index] = Some((module, value));
+    Ok(())
+}
+
+// Look up the setting for a target in a table of per-module settings. The
+// entry with the longest module prefix of the target wins.
+fn lookup<T: Copy>(table: &[Option<(&'static str, T)>], target: &str) -> Option<T> {
+    let mut best: Option<(&str, T)> = None;
+    for (module, value) in table.iter().flatten() {
+        if is_module_prefix(module, target)
+            && best.map_or(true, |(best_module, _)| module.len() > best_module.len())
+        {
+            best = Some((module, *value));
+        }
+    }
+    best.map(|(_, value)| value)
+}
+
+// Return whether `module` is `target` or one of its parent modules
+fn is_module_prefix(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+// Return the fixed width label of a level
+fn level_label(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+// Logs to the VGA console, colored by level and theme
+struct VgaSink;
+
+impl Sink for VgaSink {
+    fn write(&self, record: &Record) {
+        let label = level_label(record.level());
+        let color = match record.level() {
+            Level::Error => Some(Color::LightRed),
+            Level::Warn => Some(Color::Yellow),
+            _ => module_color(record.target()),
+        };
+        match color {
+            Some(color) => vga_buffer::_print_colored(
+                color,
+                Color::Black,
+                format_args!("[{}] {}\n", label, record.args()),
+            ),
+            None => vga_buffer::_print(format_args!("[{}] {}\n", label, record.args())),
+        }
+    }
+}
+
+// Logs to the serial port routed for logging, with the module and uptime
+struct SerialSink;
+
+impl Sink for SerialSink {
+    fn write(&self, record: &Record) {
+        serial::_print_to(serial::Role::Log, format_args!(
+            "[{:>8}ms {} {}] {}\n",
+            crate::time::uptime_ms(),
+            level_label(record.level()),
+            record.target(),
+            record.args()
+        ));
+    }
+}
+
+#[test_case]
+fn test_module_filters() {
+    let mut config = Config {
+        default_level: LevelFilter::Info,
+        modules: [None; MAX_MODULE_FILTERS],
+        theme: [None; MAX_THEME_ENTRIES],
+        sinks: [None; MAX_SINKS],
+    };
+    config.modules[0] = Some(("rust_os::apic", LevelFilter::Trace));
+    config.modules[1] = Some(("rust_os", LevelFilter::Warn));
+
+    assert_eq!(config.level_for("rust_os::apic"), LevelFilter::Trace);
+    assert_eq!(config.level_for("rust_os::apic::timer"), LevelFilter::Trace);
+    assert_eq!(config.level_for("rust_os::apicx"), LevelFilter::Warn);
+    assert_eq!(config.level_for("other"), LevelFilter::Info);
+    assert_eq!(config.max_level(), LevelFilter::Trace);
+}
+
+#[test_case]
+fn test_module_colors() {
+    let mut config = Config {
+        default_level: LevelFilter::Info,
+        modules: [None; MAX_MODULE_FILTERS],
+        theme: [None; MAX_THEME_ENTRIES],
+        sinks: [None; MAX_SINKS],
+    };
+    insert(&mut config.theme, "rust_os::memory", Color::Green).unwrap();
+    insert(&mut config.theme, "rust_os::memory", Color::LightGreen).unwrap();
+
+    assert_eq!(config.color_for("rust_os::memory::low"), Some(Color::LightGreen));
+    assert_eq!(config.color_for("rust_os::apic"), None);
+}
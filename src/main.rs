@@ -7,6 +7,7 @@
 extern crate alloc;
 
 use rust_os::{memory::BootInfoFrameAllocator, println};
+use rust_os::task::{executor::Executor, Task};
 use core::panic::{AssertUnwindSafe, PanicInfo};
 use bootloader::{BootInfo, entry_point};
 use x86_64::structures::paging::PageTable;
@@ -19,10 +20,14 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use rust_os::memory;
     use rust_os::memory::translate_addr;
     use rust_os::allocator;
+    use rust_os::apic;
     use x86_64::{ structures::paging::{ Page, Translate}, VirtAddr };
 
-    println!("Hello World{}", "!");
+    println!("{}", rust_os::version::Banner);
     rust_os::init();
+    if rust_os::symbols::is_stale() {
+        log::warn!("the symbol table is from another build; build again with RUST_OS_SYMBOLS");
+    }
 
     fn stack_overflow() {
         stack_overflow(); // for each recursion, the return address is pushed
@@ -33,7 +38,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("Memory initialization failed");
 
     // let addresses = [
     //     // the identity-mapped vga buffer page
@@ -55,6 +61,15 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut frame_allocator = unsafe {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
+    unsafe { memory::low::init(&boot_info.memory_map) };
+    // The bootloader left the screen in graphics mode, where the text
+    // buffer isn't shown, so the banner is printed again on the framebuffer
+    #[cfg(feature = "vga-graphics")]
+    {
+        use rust_os::framebuffer::{self, FrameBufferInfo};
+        unsafe { framebuffer::init(FrameBufferInfo::vga_mode_13h()) };
+        println!("{}", rust_os::version::Banner);
+    }
 
     let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
     memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
@@ -91,6 +106,28 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // println!("write worked");
 
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    rust_os::console::init();
+    rust_os::initrd::init();
+    rust_os::tmpfs::init();
+    // Bootloader 0.9 doesn't pass the RSDP, so it is searched for
+    if let Err(err) = rust_os::acpi::init(None) {
+        log::warn!("ACPI tables not found: {:?}", err);
+    }
+    rust_os::pci::init();
+    if let Some(&mcfg) = rust_os::acpi::mcfg().iter().find(|entry| entry.segment == 0) {
+        rust_os::pci::ecam::init(mcfg, &mut mapper, &mut frame_allocator)
+            .expect("ECAM initialization failed");
+    }
+    apic::init(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+    rust_os::hpet::init(&mut mapper, &mut frame_allocator).expect("HPET initialization failed");
+    rust_os::time::init(rust_os::time::DEFAULT_FREQUENCY_HZ);
+    rust_os::block::cache::init();
+    rust_os::ahci::init(&mut mapper, &mut frame_allocator).expect("AHCI initialization failed");
+    rust_os::virtio::init(&mut mapper, &mut frame_allocator).expect("virtio initialization failed");
+    rust_os::ramdisk::init();
+    rust_os::partition::scan();
+    rust_os::heartbeat::set_mode(rust_os::heartbeat::Mode::Vga);
+    rust_os::vga_buffer::enable_deferred_flush();
 
     let heap_value = Box::new(7);
     println!("heap_value at {:p}", heap_value);
@@ -113,7 +150,12 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("It did not crash!");
-    rust_os::hault_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(rust_os::shell::run()));
+    executor.spawn(Task::new(rust_os::snake::run()));
+    executor.spawn(Task::new(rust_os::memcheck::run()));
+    executor.run();
 }
 
 /// This function is called on panic.
@@ -121,6 +163,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    rust_os::backtrace::print();
+    println!("{}", rust_os::version::Banner);
+    rust_os::vga_buffer::flush();
     rust_os::hault_loop();
 }
 
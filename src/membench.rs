@@ -0,0 +1,276 @@
+// Memory bandwidth and latency micro-benchmarks.
+//
+// `run` measures buffers of growing size, so the numbers step down where a
+// buffer stops fitting in a cache level. `print` shows them as a table.
+//
+// Every pass is timed with the TSC. Bandwidth is measured with 64 bit
+// volatile accesses, latency by chasing pointers through the cache lines of
+// the buffer in random order, which defeats the prefetchers. A heap that was
+// accidentally mapped uncached shows up as latencies in the hundreds of
+// nanoseconds even for the smallest buffer.
+//
+// The buffers come from the kernel heap, which limits the largest size.
+
+use crate::time;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::x86_64::{_mm_sfence, _mm_stream_si64};
+use core::fmt;
+
+// Size of a cache line, the unit of the latency and random write tests
+const LINE_SIZE: usize = 64;
+const WORDS_PER_LINE: usize = LINE_SIZE / 8;
+
+// The smallest buffer measured
+const MIN_SIZE: usize = 4 * 1024;
+
+// The largest buffer measured by default; the heap has to fit it
+pub const DEFAULT_MAX_SIZE: usize = 256 * 1024;
+
+// How many bytes every measurement moves at least, so that small buffers are
+// timed over many passes
+const MIN_BYTES_PER_TEST: usize = 16 * 1024 * 1024;
+
+// Seed of the random order of the cache lines
+const SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+// What to measure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub max_size: usize,    // Rounded down to a power of two
+    pub non_temporal: bool, // Also measure stores that bypass the caches
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            max_size: DEFAULT_MAX_SIZE,
+            non_temporal: true,
+        }
+    }
+}
+
+// Errors from running the benchmark
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MembenchError {
+    NoTsc,        // The TSC isn't calibrated or not invariant
+    SizeTooSmall, // `max_size` is below the smallest buffer
+}
+
+// The results for one buffer size. Bandwidths are in MiB/s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Measurement {
+    pub size: usize,
+    pub seq_read: u64,
+    pub seq_write: u64,
+    pub nt_write: Option<u64>,
+    pub random_write: u64,
+    pub latency_ps: u64, // Time of one dependent load, in picoseconds
+}
+
+impl Measurement {
+    // The header line matching the `Display` output
+    pub const HEADER: &'static str =
+        "size       seq read    seq write   nt write    rand write  latency";
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:>6} KiB ", self.size / 1024)?;
+        write!(f, "{:>6} MiB/s {:>6} MiB/s ", self.seq_read, self.seq_write)?;
+        match self.nt_write {
+            Some(nt_write) => write!(f, "{:>6} MiB/s ", nt_write)?,
+            None => write!(f, "{:>12}", "-")?,
+        }
+        write!(
+            f,
+            "{:>6} MiB/s {:>4}.{} ns",
+            self.random_write,
+            self.latency_ps / 1000,
+            self.latency_ps % 1000 / 100
+        )
+    }
+}
+
+// Measure every power of two buffer size from 4 KiB up to `max_size`.
+//
+// Runs with interrupts enabled, so a busy system adds noise to the numbers.
+pub fn run(options: Options) -> Result<Vec<Measurement>, MembenchError> {
+    let khz = time::tsc_khz();
+    if khz == 0 || !time::has_invariant_tsc() {
+        return Err(MembenchError::NoTsc);
+    }
+    if options.max_size < MIN_SIZE {
+        return Err(MembenchError::SizeTooSmall);
+    }
+    let max_size = 1 << (usize::BITS - 1 - options.max_size.leading_zeros());
+
+    let mut buffer: Vec<u64> = vec![0; max_size / 8];
+    let mut results = Vec::new();
+    let mut size = MIN_SIZE;
+    while size <= max_size {
+        let words = &mut buffer[..size / 8];
+        let passes = (MIN_BYTES_PER_TEST / size).max(1);
+        let bytes = (size * passes) as u64;
+
+        let seq_read = bandwidth(bytes, khz, time_passes(passes, || seq_read(words)));
+        let seq_write = bandwidth(bytes, khz, time_passes(passes, || seq_write(words)));
+        let nt_write = if options.non_temporal {
+            let cycles = time_passes(passes, || nt_write(words));
+            Some(bandwidth(bytes, khz, cycles))
+        } else {
+            None
+        };
+
+        let random_write = bandwidth(bytes, khz, time_passes(passes, || random_write(words)));
+
+        link_lines(words, SEED);
+        let accesses = (size / LINE_SIZE * passes) as u64;
+        let cycles = time_passes(1, || {
+            chase(words, accesses);
+        });
+        let latency_ps =
+            (cycles as u128 * 1_000_000_000 / khz as u128 / accesses.max(1) as u128) as u64;
+
+        results.push(Measurement {
+            size,
+            seq_read,
+            seq_write,
+            nt_write,
+            random_write,
+            latency_ps,
+        });
+        size *= 2;
+    }
+
+    Ok(results)
+}
+
+// Run the benchmark with the default options and print a table
+pub fn print() {
+    match run(Options::default()) {
+        Ok(results) => {
+            crate::println!("{}", Measurement::HEADER);
+            for measurement in results {
+                crate::println!("{}", measurement);
+            }
+        }
+        Err(err) => crate::println!("membench: {:?}", err),
+    }
+}
+
+// Return the TSC cycles taken by calling `pass` `passes` times
+fn time_passes(passes: usize, mut pass: impl FnMut()) -> u64 {
+    // Warm up the caches and the TLB first
+    pass();
+    let start = time::rdtsc();
+    for _ in 0..passes {
+        pass();
+    }
+    time::rdtsc() - start
+}
+
+// Return the bandwidth in MiB/s of moving `bytes` in `cycles` TSC cycles
+fn bandwidth(bytes: u64, khz: u64, cycles: u64) -> u64 {
+    let bytes_per_second = bytes as u128 * khz as u128 * 1000 / cycles.max(1) as u128;
+    (bytes_per_second / (1024 * 1024)) as u64
+}
+
+fn seq_read(words: &[u64]) {
+    let mut sum = 0u64;
+    for word in words {
+        sum = sum.wrapping_add(unsafe { core::ptr::read_volatile(word) });
+    }
+    core::hint::black_box(sum);
+}
+
+fn seq_write(words: &mut [u64]) {
+    for (i, word) in words.iter_mut().enumerate() {
+        unsafe { core::ptr::write_volatile(word, i as u64) };
+    }
+}
+
+// Write with non-temporal stores, which go to memory without filling the
+// caches
+fn nt_write(words: &mut [u64]) {
+    for (i, word) in words.iter_mut().enumerate() {
+        unsafe { _mm_stream_si64(word as *mut u64 as *mut i64, i as i64) };
+    }
+    unsafe { _mm_sfence() };
+}
+
+// Write every cache line once, in a scattered order
+fn random_write(words: &mut [u64]) {
+    let lines = words.len() / WORDS_PER_LINE;
+    // Stepping by an odd number visits every line of a power of two sized
+    // buffer before coming back to the first
+    let step = (lines / 2 + 1) | 1;
+    let mut line = 0;
+    for _ in 0..lines {
+        for word in &mut words[line * WORDS_PER_LINE..(line + 1) * WORDS_PER_LINE] {
+            unsafe { core::ptr::write_volatile(word, line as u64) };
+        }
+        line = (line + step) % lines;
+    }
+}
+
+// Link the cache lines of `words` into a single cycle in random order: the
+// first word of every line holds the index of the next line's first word
+fn link_lines(words: &mut [u64], seed: u64) {
+    let lines = words.len() / WORDS_PER_LINE;
+    for line in 0..lines {
+        words[line * WORDS_PER_LINE] = line as u64;
+    }
+
+    // Shuffle the order, kept in the first words, with a xorshift generator
+    let mut state = seed;
+    for i in (1..lines).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let j = (state % (i as u64 + 1)) as usize;
+        words.swap(i * WORDS_PER_LINE, j * WORDS_PER_LINE);
+    }
+
+    // Turn the order into links from every line to the one after it
+    let first = words[0] as usize;
+    let mut current = first;
+    for position in 1..lines {
+        let next = words[position * WORDS_PER_LINE] as usize;
+        words[current * WORDS_PER_LINE + 1] = (next * WORDS_PER_LINE) as u64;
+        current = next;
+    }
+    words[current * WORDS_PER_LINE + 1] = (first * WORDS_PER_LINE) as u64;
+    for line in 0..lines {
+        words[line * WORDS_PER_LINE] = words[line * WORDS_PER_LINE + 1];
+    }
+}
+
+// Follow the links made by `link_lines` `accesses` times and return where it
+// ended up. Every load depends on the previous one.
+fn chase(words: &[u64], accesses: u64) -> usize {
+    let mut index = 0;
+    for _ in 0..accesses {
+        index = unsafe { core::ptr::read_volatile(&words[index]) } as usize;
+    }
+    core::hint::black_box(index)
+}
+
+#[test_case]
+fn test_link_lines_single_cycle() {
+    const LINES: usize = 32;
+    let mut words = [0u64; LINES * WORDS_PER_LINE];
+    link_lines(&mut words, SEED);
+
+    // Every line is visited once before the chase comes back to the start
+    let mut visited = [false; LINES];
+    let mut index = 0;
+    for _ in 0..LINES {
+        let line = index / WORDS_PER_LINE;
+        assert!(!visited[line]);
+        visited[line] = true;
+        index = words[index] as usize;
+    }
+    assert_eq!(index, 0);
+    assert_eq!(bandwidth(1024 * 1024, 1000, 1_000_000), 1);
+}
@@ -0,0 +1,134 @@
+// Background checks for memory corruption.
+//
+// `run` is a task that looks for damage while the system is idle, so that
+// a stray write is reported with its address soon after it happens, not
+// when it makes something crash far from the cause. It
+//
+// - fills the free part of the heap with `POISON`, a little at a time, and
+//   checks that the poisoned bytes keep it. The bump allocator hands out
+//   memory from the bottom of the free part, so only a stray write changes
+//   them: one past the end of the last allocation, or through a pointer
+//   kept after the heap was reset.
+// - checks that the allocator's bump pointer lies in the heap, with
+//   `allocator::is_consistent`. This is not a free-list integrity check:
+//   the bump allocator has no free list, only that pointer and a count.
+// - checks the canaries at the bottom of the static stacks (see `gdt`).
+//
+// Freed frames can be poisoned too, with `memcheck scrub on` in the shell;
+// the frame allocator checks them when it hands them out again, which
+// finds writes through mappings that outlived their frames. It is off by
+// default since it costs a page write per free.
+//
+// The task does one small step every `STEP_MS`, and only if the executor
+// has halted for lack of work since the previous step. Corruption is
+// logged as an error, once per damaged spot; `memcheck` in the shell runs
+// a whole pass right away and shows the counts.
+
+use crate::allocator::{self, HEAP_SIZE, POISON};
+use crate::task::{self, executor};
+use crate::time::Timer;
+use crate::{gdt, memory, shell, shell_println};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Time between two steps
+const STEP_MS: u64 = 100;
+// Heap bytes poisoned and checked in one step
+const SCRUB_BYTES: usize = 4096;
+const CHECK_BYTES: usize = 16 * 1024;
+
+static PASSES: AtomicU64 = AtomicU64::new(0); // Complete passes over the heap
+static CORRUPTIONS: AtomicU64 = AtomicU64::new(0);
+// Set once the bad bump pointer was reported
+static HEAP_STATE_REPORTED: AtomicBool = AtomicBool::new(false);
+
+// Report `bytes` changed bytes starting at `address` in `what`
+pub(crate) fn report(what: &str, address: u64, bytes: usize) {
+    CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+    log::error!("memcheck: {} changed at {:#x} ({} bytes)", what, address, bytes);
+}
+
+// Poison some free heap and check the `CHECK_BYTES` at `*cursor`, moving
+// it on. After the last part of the heap, the allocator and the stacks are
+// checked and the pass starts over.
+fn step(cursor: &mut usize) {
+    allocator::scrub_free(SCRUB_BYTES);
+    if let Some(corruption) = allocator::check_free(*cursor, CHECK_BYTES) {
+        report("free heap", corruption.address as u64, corruption.bytes);
+    }
+    *cursor += CHECK_BYTES;
+    if *cursor < HEAP_SIZE {
+        return;
+    }
+    *cursor = 0;
+
+    if !allocator::is_consistent() && !HEAP_STATE_REPORTED.swap(true, Ordering::Relaxed) {
+        CORRUPTIONS.fetch_add(1, Ordering::Relaxed);
+        log::error!("memcheck: the heap's bump pointer is out of place");
+    }
+    if let Some((stack, canary)) = gdt::check_stack_canaries() {
+        report(stack, canary.as_u64(), 8);
+    }
+    PASSES.fetch_add(1, Ordering::Relaxed);
+}
+
+// Poison all of the free heap and check everything once
+pub fn check_all() {
+    while allocator::scrub_free(SCRUB_BYTES) > 0 {
+        task::maybe_preempt();
+    }
+    let mut cursor = 0;
+    let passes = PASSES.load(Ordering::Relaxed);
+    while PASSES.load(Ordering::Relaxed) == passes {
+        task::maybe_preempt();
+        step(&mut cursor);
+    }
+}
+
+// The number of complete passes and the corruptions found since boot
+pub fn stats() -> (u64, u64) {
+    (PASSES.load(Ordering::Relaxed), CORRUPTIONS.load(Ordering::Relaxed))
+}
+
+fn command(args: &[&str]) {
+    match args {
+        [] => {
+            check_all();
+            let (passes, corruptions) = stats();
+            shell_println!("memcheck: {} passes, {} corruptions found", passes, corruptions);
+        }
+        ["scrub"] => {
+            let state = if memory::scrub_freed_frames() { "on" } else { "off" };
+            shell_println!("memcheck: scrubbing freed frames is {}", state);
+        }
+        ["scrub", "on"] => memory::set_scrub_freed_frames(true),
+        ["scrub", "off"] => memory::set_scrub_freed_frames(false),
+        _ => shell_println!("usage: memcheck [scrub [on|off]]"),
+    }
+}
+
+// Check memory whenever the system is idle. Spawn this on the executor
+// once; it never returns.
+pub async fn run() {
+    if shell::register("memcheck", command).is_err() {
+        log::warn!("memcheck: the shell command exists already");
+        return;
+    }
+    let mut cursor = 0;
+    let mut idle = executor::stats().idle;
+    loop {
+        Timer::after_ms(STEP_MS).await;
+        let now = executor::stats().idle;
+        if now != idle {
+            idle = now;
+            step(&mut cursor);
+        }
+    }
+}
+
+#[test_case]
+fn test_clean_pass() {
+    let (_, corruptions) = stats();
+    check_all();
+    assert_eq!(stats().1, corruptions);
+    assert_eq!(gdt::check_stack_canaries(), None);
+}
@@ -1,17 +1,73 @@
 use x86_64::{ structures::paging::PageTable, VirtAddr, };
 use x86_64::PhysAddr;
-use x86_64::structures::paging::{ OffsetPageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator };
+use x86_64::structures::paging::{ OffsetPageTable, Page, PageTableFlags, PhysFrame, Mapper, Size4KiB, FrameAllocator, FrameDeallocator };
+use x86_64::structures::paging::mapper::{MapToError, UnmapError};
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::registers::control::{Cr0, Cr0Flags};
 use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::{allocator, memcheck};
+
+pub mod cow;
+pub mod low;
+
+// The virtual address at which the bootloader mapped the complete physical
+// memory. Stored by `init` so that other modules can access physical frames.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// The physical address of the level 4 table of the kernel's address space,
+// the one active when `init` is called
+static KERNEL_LEVEL_4_TABLE: AtomicU64 = AtomicU64::new(0);
+
+// Set by the first call to `init`
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Frame allocator counters
+static FRAMES_USABLE: AtomicU64 = AtomicU64::new(0);
+static FRAMES_ALLOCATED: AtomicU64 = AtomicU64::new(0);
+static FRAMES_FREED: AtomicU64 = AtomicU64::new(0);
+
+// Whether freed frames are filled with `allocator::POISON`, to be checked for
+// stray writes when they are handed out again
+static SCRUB_FREED_FRAMES: AtomicBool = AtomicBool::new(false);
+
+// Follows the free list link in a frame that `deallocate_frame` poisoned,
+// so that frames freed while scrubbing was off aren't checked
+const POISONED_FRAME_MARK: u64 = u64::from_le_bytes(*b"POISONED");
+
+// Error returned when a subsystem that must only be set up once is
+// initialized again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+// Whether `init` was called, so that `translate` works
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire)
+}
 
 // Intialize a new OffsetPageTable.
 //
 // This function is unsafe because the caller must guarantee that the complete
 // physical memory is mapped to virtual memory at the passed 
-// `physical_memory_offset`. Also, this function must be only called once to 
-// avoid alising `&mut` references (which is undefined behaviour).
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+// `physical_memory_offset`. A second call would alias the `&mut` reference
+// to the level 4 table (which is undefined behaviour), so it fails with
+// `AlreadyInitialized` instead.
+pub unsafe fn init(
+    physical_memory_offset: VirtAddr,
+) -> Result<OffsetPageTable<'static>, AlreadyInitialized> {
+    if INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(AlreadyInitialized);
+    }
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let (level_4_table_frame, _) = x86_64::registers::control::Cr3::read();
+    KERNEL_LEVEL_4_TABLE.store(level_4_table_frame.start_address().as_u64(), Ordering::Relaxed);
+    // Make kernel writes to read-only pages fault, like user writes do, so
+    // that copy-on-write pages (see `cow`) are copied whoever writes them
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    Ok(OffsetPageTable::new(level_4_table, physical_memory_offset))
 }
 
 // This function operates on raw pointers (*mut PageTable) and performs 
@@ -50,23 +106,77 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr 
 }
 
-// Translate the given virtual address to the mapped physical address, or
-// `None` if the address is not mapped.
+// The size of the page an address is mapped with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl PageSize {
+    pub fn bytes(self) -> u64 {
+        match self {
+            PageSize::Size4KiB => 4096,
+            PageSize::Size2MiB => 2 * 1024 * 1024,
+            PageSize::Size1GiB => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+// How a virtual address is mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    // The flags of the mapping. WRITABLE and USER_ACCESSIBLE are only set if
+    // every level of the page table allows it, NO_EXECUTE if any level sets
+    // it; the other flags are the ones of the last level.
+    pub flags: PageTableFlags,
+    pub page_size: PageSize,
+}
+
+// Translate the given virtual address to its mapping, or `None` if the
+// address is not mapped.
 //
 // This function is unsafe cause the caller must guareantee that the complete
 // physical memory is mapped to virtual memory at the passed 
 // `physical_memory_offset`.
-pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
     translate_addr_inner(addr, physical_memory_offset)
 }
 
+// Translate the given virtual address in the active page table to its
+// mapping, or `None` if the address is not mapped or `init` was not called
+// yet.
+pub fn translate(addr: VirtAddr) -> Option<Translation> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return None;
+    }
+    translate_addr_inner(addr, VirtAddr::new(offset))
+}
+
+// Return the virtual address through which the given physical address can be
+// accessed, using the physical memory mapping set up by the bootloader.
+//
+// Must only be called after `init`.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+// Translate the given virtual address to the mapped physical address in the
+// active page table, or `None` if the address is not mapped or `init` was not
+// called yet.
+pub fn virt_to_phys(addr: VirtAddr) -> Option<PhysAddr> {
+    translate(addr).map(|translation| translation.phys)
+}
+
 // Private function that is called by `transalate_addr`.
 //
 // This function is safe to limit the scope of `unsafe` because Rust treats
 // the whole body of unsafe functions as an unsafe block. This function must
 // only be readable through `unsafe fn` from outside of this module.
-fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
-    use x86_64::structures::paging::page_table::FrameError;
+fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
     use x86_64::registers::control::Cr3;
 
     // Read the ative level 4 frame from the CR3 register.
@@ -75,26 +185,532 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
     let table_indexes = [
         addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
     ];
-    let mut frame = level_4_table_frame;
+    let mut table_addr = level_4_table_frame.start_address();
+
+    // The permissions of all levels combined
+    let mut writable = true;
+    let mut user_accessible = true;
+    let mut no_execute = false;
 
     // Translate the multi-level page table
-    for &index in &table_indexes {
+    for (level, &index) in table_indexes.iter().enumerate() {
         // convert the frame into a page table reference.
-        let virt = physical_memory_offset + frame.start_address().as_u64();
+        let virt = physical_memory_offset + table_addr.as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
         let table = unsafe {&*table_ptr};
 
-        // Read the page table entry and update `frame`
+        // Read the page table entry
         let entry = &table[index];
-        frame = match entry.frame() {
-            Ok(frame) => frame,
-            Err(FrameError::FrameNotPresent) => return None,
-            Err(FrameError::HugeFrame) => panic!("huge pages not supported"),
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        writable &= flags.contains(PageTableFlags::WRITABLE);
+        user_accessible &= flags.contains(PageTableFlags::USER_ACCESSIBLE);
+        no_execute |= flags.contains(PageTableFlags::NO_EXECUTE);
+
+        // Level 3 and 2 entries may map a huge page instead of a table
+        let page_size = match level {
+            1 if flags.contains(PageTableFlags::HUGE_PAGE) => PageSize::Size1GiB,
+            2 if flags.contains(PageTableFlags::HUGE_PAGE) => PageSize::Size2MiB,
+            3 => PageSize::Size4KiB,
+            _ => {
+                table_addr = entry.addr();
+                continue;
+            }
         };
+
+        let mut flags = flags;
+        flags.set(PageTableFlags::WRITABLE, writable);
+        flags.set(PageTableFlags::USER_ACCESSIBLE, user_accessible);
+        flags.set(PageTableFlags::NO_EXECUTE, no_execute);
+
+        // Calculate the physical address by adding the offset into the page
+        let mask = page_size.bytes() - 1;
+        let page_start = entry.addr().as_u64() & !mask;
+        return Some(Translation {
+            phys: PhysAddr::new(page_start + (addr.as_u64() & mask)),
+            flags,
+            page_size,
+        });
+    }
+
+    None
+}
+
+// Identity map the page containing the memory-mapped I/O register at `phys` as
+// uncached and writable, and return the virtual address of the register.
+//
+// Device registers usually live outside of the RAM covered by the physical
+// memory mapping of the bootloader, so they have to be mapped explicitly.
+// Mapping the same page twice is allowed.
+pub fn map_mmio(
+    phys: PhysAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let frame = PhysFrame::containing_address(phys);
+    let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_CACHE;
+
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(err) => return Err(err),
+    }
+
+    Ok(page.start_address() + (phys - frame.start_address()))
+}
+
+// Where `map_range` takes the frames to map from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frames {
+    // A fresh frame from the frame allocator for every page
+    Allocate,
+    // Consecutive frames starting at the given one, e.g. for device memory
+    Contiguous(PhysFrame),
+}
+
+// Map a range of pages at once.
+//
+// Unlike calling `map_to` for every page, the missing level 3, 2 and 1 tables
+// are all allocated before the first page gets mapped, the tables are walked
+// once per level 1 table instead of once per page, and the TLB is flushed once
+// at the end. `PRESENT` is added to `flags`. Fails if one of the pages is
+// already mapped; the pages before it stay mapped.
+//
+// This function is unsafe because the caller must guarantee that the mapped
+// frames are not in use elsewhere, and `init` must have been called.
+pub unsafe fn map_range(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    frames: Frames,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+    let result = map_range_inner(mapper.level_4_table(), pages, frames, flags, frame_allocator);
+    // New parent entries may have widened the permissions of existing ones
+    x86_64::instructions::tlb::flush_all();
+    result
+}
+
+fn map_range_inner(
+    level_4_table: &mut PageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    frames: Frames,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    let flags = flags | PageTableFlags::PRESENT;
+    // The leaf entries decide about the permissions, so the parents allow
+    // everything the leaves need
+    let parent_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+
+    // Allocate all the tables first, so that running out of frames doesn't
+    // leave a half mapped range behind
+    for (start, _) in level_1_chunks(pages) {
+        level_1_table(level_4_table, start, offset, parent_flags, frame_allocator)?;
+    }
+
+    for (start, end) in level_1_chunks(pages) {
+        let table = level_1_table(level_4_table, start, offset, parent_flags, frame_allocator)?;
+        for page in Page::range_inclusive(start, end) {
+            let entry = &mut table[page.p1_index()];
+            if !entry.is_unused() {
+                return Err(MapToError::PageAlreadyMapped(PhysFrame::containing_address(entry.addr())));
+            }
+            let frame = match frames {
+                Frames::Allocate => frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?,
+                Frames::Contiguous(first) => first + (page - pages.start),
+            };
+            entry.set_frame(frame, flags);
+        }
     }
 
-    // Calculate the physical address by adding the page offset
-    Some(frame.start_address() + u64::from(addr.page_offset()))
+    Ok(())
+}
+
+// Split a page range into the parts covered by one level 1 table each
+fn level_1_chunks(
+    pages: PageRangeInclusive<Size4KiB>,
+) -> impl Iterator<Item = (Page<Size4KiB>, Page<Size4KiB>)> {
+    let mut next = Some(pages.start);
+    core::iter::from_fn(move || {
+        let start = next?;
+        // The last page of the level 1 table, which covers 2 MiB
+        let table_end = Page::containing_address(VirtAddr::new(start.start_address().as_u64() | 0x1F_FFFF));
+        let end = core::cmp::min(table_end, pages.end);
+        next = if end < pages.end { Some(end + 1) } else { None };
+        Some((start, end))
+    })
+}
+
+// Walk down to the level 1 table of `page`, creating missing tables
+fn level_1_table<'a>(
+    level_4_table: &'a mut PageTable,
+    page: Page<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+    parent_flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<&'a mut PageTable, MapToError<Size4KiB>> {
+    let level_3_table = next_table(&mut level_4_table[page.p4_index()], physical_memory_offset, parent_flags, frame_allocator)?;
+    let level_2_table = next_table(&mut level_3_table[page.p3_index()], physical_memory_offset, parent_flags, frame_allocator)?;
+    next_table(&mut level_2_table[page.p2_index()], physical_memory_offset, parent_flags, frame_allocator)
+}
+
+// Return the table the entry points to, allocating a zeroed one if the entry
+// is unused
+fn next_table<'a>(
+    entry: &'a mut PageTableEntry,
+    physical_memory_offset: VirtAddr,
+    parent_flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<&'a mut PageTable, MapToError<Size4KiB>> {
+    if entry.is_unused() {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let table: *mut PageTable = (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe { (*table).zero() };
+        entry.set_frame(frame, parent_flags);
+    } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(MapToError::ParentEntryHugePage);
+    } else if !entry.flags().contains(parent_flags) {
+        entry.set_flags(entry.flags() | parent_flags);
+    }
+
+    let table: *mut PageTable = (physical_memory_offset + entry.addr().as_u64()).as_mut_ptr();
+    Ok(unsafe { &mut *table })
+}
+
+// Unmap a range of pages and free the level 1 and level 2 tables that are
+// left empty.
+//
+// Pages in the range that aren't mapped are skipped. With `free_frames`, the
+// frames the pages were mapped to are freed too, e.g. for ranges mapped with
+// `Frames::Allocate`. Level 3 tables are kept, since they may be shared by
+// several address spaces. The TLB is flushed once at the end.
+//
+// This function is unsafe because the caller must guarantee that the pages
+// (and, with `free_frames`, the frames) are not used anymore.
+pub unsafe fn unmap_range(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    free_frames: bool,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    if pages.is_empty() {
+        return Ok(());
+    }
+    let result = unmap_range_inner(mapper.level_4_table(), pages, free_frames, frame_deallocator);
+    x86_64::instructions::tlb::flush_all();
+    result
+}
+
+fn unmap_range_inner(
+    level_4_table: &mut PageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    free_frames: bool,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> Result<(), UnmapError> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+
+    for (start, end) in level_1_chunks(pages) {
+        let level_3_table = match existing_table(&mut level_4_table[start.p4_index()], offset)? {
+            Some(table) => table,
+            None => continue,
+        };
+        let level_2_table = match existing_table(&mut level_3_table[start.p3_index()], offset)? {
+            Some(table) => table,
+            None => continue,
+        };
+        let level_1_table = match existing_table(&mut level_2_table[start.p2_index()], offset)? {
+            Some(table) => table,
+            None => continue,
+        };
+
+        for page in Page::range_inclusive(start, end) {
+            let entry = &mut level_1_table[page.p1_index()];
+            if entry.is_unused() {
+                continue;
+            }
+            if free_frames {
+                let frame = PhysFrame::containing_address(entry.addr());
+                unsafe { cow::release(frame, entry.flags(), frame_deallocator) };
+            }
+            entry.set_unused();
+        }
+
+        // Free the tables this left empty, bottom up
+        if is_empty(level_1_table) {
+            free_table(&mut level_2_table[start.p2_index()], frame_deallocator);
+            if is_empty(level_2_table) {
+                free_table(&mut level_3_table[start.p3_index()], frame_deallocator);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Return the table the entry points to, or `None` if the entry is unused
+fn existing_table<'a>(
+    entry: &'a mut PageTableEntry,
+    physical_memory_offset: VirtAddr,
+) -> Result<Option<&'a mut PageTable>, UnmapError> {
+    if entry.is_unused() {
+        return Ok(None);
+    }
+    if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+        return Err(UnmapError::ParentEntryHugePage);
+    }
+    let table: *mut PageTable = (physical_memory_offset + entry.addr().as_u64()).as_mut_ptr();
+    Ok(Some(unsafe { &mut *table }))
+}
+
+fn is_empty(table: &PageTable) -> bool {
+    table.iter().all(|entry| entry.is_unused())
+}
+
+// Clear the entry pointing to an empty table and free the table's frame
+fn free_table(entry: &mut PageTableEntry, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) {
+    let frame = PhysFrame::containing_address(entry.addr());
+    entry.set_unused();
+    unsafe { frame_deallocator.deallocate_frame(frame) };
+}
+
+// Return the number of frames used by the page tables of the address space
+// with the given level 4 table, including the level 4 table itself. The
+// active one is `Cr3::read().0`.
+//
+// Returns 0 if `init` was not called yet.
+pub fn page_table_frames(level_4_frame: PhysFrame) -> usize {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return 0;
+    }
+    count_tables(level_4_frame.start_address(), 4, VirtAddr::new(offset))
+}
+
+fn count_tables(table_addr: PhysAddr, level: u8, physical_memory_offset: VirtAddr) -> usize {
+    if level == 1 {
+        return 1;
+    }
+    let table_ptr: *const PageTable = (physical_memory_offset + table_addr.as_u64()).as_ptr();
+    let table = unsafe { &*table_ptr };
+
+    let children: usize = table
+        .iter()
+        .filter(|entry| {
+            let flags = entry.flags();
+            flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+        })
+        .map(|entry| count_tables(entry.addr(), level - 1, physical_memory_offset))
+        .sum();
+    1 + children
+}
+
+// Address spaces for user programs.
+//
+// A new address space starts out with the present level 4 entries of the
+// kernel's table, so the kernel's code, heap, stacks and the physical
+// memory mapping are all there, through shared lower level tables. The
+// kernel doesn't live in the higher half, so its entries are spread over
+// the whole table, and user pages can only go under the ones it left
+// unused. Kernel mappings added later under new level 4 entries don't
+// show up in existing address spaces.
+
+// Create an address space sharing the kernel's mappings. Returns the frame
+// of its level 4 table, or `None` if there is no frame left. Must only be
+// called after `init`.
+pub fn create_address_space(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<PhysFrame> {
+    let frame = frame_allocator.allocate_frame()?;
+    let kernel = unsafe { kernel_level_4_table() };
+    let table = unsafe { table_mut(frame.start_address()) };
+    table.zero();
+    for (entry, kernel_entry) in table.iter_mut().zip(kernel.iter()) {
+        if !kernel_entry.is_unused() {
+            *entry = kernel_entry.clone();
+        }
+    }
+    Some(frame)
+}
+
+// Return a mapper for the address space with the given level 4 table.
+//
+// This function is unsafe because the frame must hold a level 4 table,
+// and there must be no other mapper for it, which would alias the table.
+pub unsafe fn address_space_mapper(level_4_frame: PhysFrame) -> OffsetPageTable<'static> {
+    let offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    OffsetPageTable::new(table_mut(level_4_frame.start_address()), offset)
+}
+
+// Whether `page` lies under a level 4 entry that the address space of
+// `mapper` shares with the kernel's, so that mapping it would change the
+// kernel's tables too. Always `false` for the kernel's address space.
+pub fn is_shared_with_kernel(mapper: &mut OffsetPageTable, page: Page<Size4KiB>) -> bool {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    let table = mapper.level_4_table();
+    let table_addr = table as *const PageTable as u64 - offset;
+    if table_addr == KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let kernel_entry = unsafe { &kernel_level_4_table()[page.p4_index()] };
+    !kernel_entry.is_unused() && table[page.p4_index()].addr() == kernel_entry.addr()
+}
+
+// Copy an address space made by `create_address_space` or by this
+// function, sharing its user pages copy-on-write (see `cow`). Returns the
+// frame of the copy's level 4 table, or `None` if there are no frames
+// left.
+//
+// This function is unsafe because there must be no mapper for the
+// original, which would alias its tables.
+pub unsafe fn clone_address_space<A>(
+    level_4_frame: PhysFrame,
+    frame_allocator: &mut A,
+) -> Option<PhysFrame>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let clone = create_address_space(frame_allocator)?;
+    let kernel = kernel_level_4_table();
+    let table = table_mut(level_4_frame.start_address());
+    let clone_table = table_mut(clone.start_address());
+    for (i, entry) in table.iter().enumerate() {
+        let shared = !kernel[i].is_unused() && entry.addr() == kernel[i].addr();
+        if entry.is_unused() || shared {
+            continue;
+        }
+        match cow::share_tables(entry.addr(), 3, frame_allocator) {
+            Some(copy) => clone_table[i].set_frame(copy, entry.flags()),
+            None => {
+                free_address_space(clone, frame_allocator);
+                return None;
+            }
+        }
+    }
+    // The original's writable pages are read-only now
+    x86_64::instructions::tlb::flush_all();
+    Some(clone)
+}
+
+// Free an address space made by `create_address_space`: the frames mapped
+// in the parts it doesn't share with the kernel, which must have been
+// mapped with `Frames::Allocate` or shared by `clone_address_space`, the
+// page tables there, and the level 4 table. Shared frames are only freed
+// with their last mapping. Returns the number of frames freed, counting
+// shared ones.
+//
+// This function is unsafe because the address space must not be active,
+// and nothing may use its frames any more.
+pub unsafe fn free_address_space(
+    level_4_frame: PhysFrame,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> usize {
+    let kernel = kernel_level_4_table();
+    let table = table_mut(level_4_frame.start_address());
+    let mut freed = 0;
+    for (entry, kernel_entry) in table.iter().zip(kernel.iter()) {
+        let shared = !kernel_entry.is_unused() && entry.addr() == kernel_entry.addr();
+        if !entry.is_unused() && !shared {
+            freed += free_tables(entry.addr(), 3, frame_deallocator);
+        }
+    }
+    frame_deallocator.deallocate_frame(level_4_frame);
+    freed + 1
+}
+
+// Free the table of the given level at `table_addr`, the tables below it
+// and the frames its level 1 tables map. Huge pages are left alone.
+unsafe fn free_tables(
+    table_addr: PhysAddr,
+    level: u8,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) -> usize {
+    let table = table_mut(table_addr);
+    let mut freed = 0;
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        if level == 1 {
+            let frame = PhysFrame::containing_address(entry.addr());
+            cow::release(frame, entry.flags(), frame_deallocator);
+            freed += 1;
+        } else if !entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            freed += free_tables(entry.addr(), level - 1, frame_deallocator);
+        }
+    }
+    frame_deallocator.deallocate_frame(PhysFrame::containing_address(table_addr));
+    freed + 1
+}
+
+unsafe fn kernel_level_4_table() -> &'static PageTable {
+    table_mut(PhysAddr::new(KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed)))
+}
+
+// Return the page table at the given physical address
+unsafe fn table_mut(addr: PhysAddr) -> &'static mut PageTable {
+    &mut *phys_to_virt(addr).as_mut_ptr()
+}
+
+// A snapshot of the frame allocator counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub usable: u64,      // Frames the bootloader reported usable, above 1 MiB
+    pub allocated: u64,   // Frames allocated since boot
+    pub freed: u64,       // Frames freed since boot
+    pub page_tables: u64, // Frames used by the active page tables
+}
+
+impl FrameStats {
+    // Frames currently in use
+    pub fn in_use(&self) -> u64 {
+        self.allocated - self.freed
+    }
+}
+
+impl fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frames: {} usable, {} in use ({} allocated, {} freed), {} page tables",
+            self.usable,
+            self.in_use(),
+            self.allocated,
+            self.freed,
+            self.page_tables,
+        )
+    }
+}
+
+// Return the frame allocator counters
+pub fn frame_stats() -> FrameStats {
+    use x86_64::registers::control::Cr3;
+
+    FrameStats {
+        usable: FRAMES_USABLE.load(Ordering::Relaxed),
+        allocated: FRAMES_ALLOCATED.load(Ordering::Relaxed),
+        freed: FRAMES_FREED.load(Ordering::Relaxed),
+        page_tables: page_table_frames(Cr3::read().0) as u64,
+    }
+}
+
+// Print the frame and heap statistics. Only reads counters and page tables,
+// so it can be used from panic handlers.
+pub fn dump_stats() {
+    crate::println!("{}", frame_stats());
+    crate::println!("{}", crate::allocator::stats());
 }
 
 // This is a example mapping for the given page to frame `0xb8000`.
@@ -119,16 +735,81 @@ pub struct EmptyFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if crate::fault::should_fail(crate::fault::Site::Frame) {
+            return None;
+        }
+
+        // Reuse freed frames first
+        if let Some(frame) = self.free_list {
+            let next: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+            self.free_list = match unsafe { next.read() } {
+                0 => None,
+                next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+            };
+            check_poisoned_frame(frame);
+            FRAMES_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        if frame.is_some() {
+            FRAMES_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    // Freed frames are kept in a list threaded through the frames
+    // themselves, so freeing needs no memory. Must only be called after
+    // `init`.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        // The allocator only hands out frames above 1 MiB, so 0 can mark the
+        // end of the list
+        next.write(self.free_list.map_or(0, |frame| frame.start_address().as_u64()));
+        if SCRUB_FREED_FRAMES.load(Ordering::Relaxed) {
+            next.add(1).write(POISONED_FRAME_MARK);
+            next.add(2).cast::<u8>().write_bytes(allocator::POISON, 4096 - 16);
+        }
+        self.free_list = Some(frame);
+        FRAMES_FREED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Fill frames with poison when they are freed, or stop doing so
+pub fn set_scrub_freed_frames(enabled: bool) {
+    SCRUB_FREED_FRAMES.store(enabled, Ordering::Relaxed);
+}
+
+pub fn scrub_freed_frames() -> bool {
+    SCRUB_FREED_FRAMES.load(Ordering::Relaxed)
+}
+
+// Report stray writes to a frame taken from the free list, if it was
+// poisoned when it was freed
+fn check_poisoned_frame(frame: PhysFrame) {
+    let mark: *mut u64 = phys_to_virt(frame.start_address() + 8u64).as_mut_ptr();
+    unsafe {
+        if mark.read() != POISONED_FRAME_MARK {
+            return;
+        }
+        mark.write(0);
+        let poison = core::slice::from_raw_parts(mark.add(1).cast::<u8>(), 4096 - 16);
+        if let Some(first) = poison.iter().position(|&byte| byte != allocator::POISON) {
+            let changed = poison[first..].iter().filter(|&&byte| byte != allocator::POISON).count();
+            let address = frame.start_address().as_u64() + 16 + first as u64;
+            memcheck::report("freed frame (physical)", address, changed);
+        }
+    }
+}
+
 // A FrameAllocator that returns usable frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: Option<PhysFrame>, // The most recently freed frame
 }
 
 impl BootInfoFrameAllocator {
@@ -138,10 +819,13 @@ impl BootInfoFrameAllocator {
     // memory map is valid. The main requirement is that all frames that are marked
     // as `USABLE` in it are really unused.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
             next: 0,
-        }
+            free_list: None,
+        };
+        FRAMES_USABLE.store(allocator.usable_frames().count() as u64, Ordering::Relaxed);
+        allocator
     }
 
     /// Converts the memory map into an iterator of usable physical frames.
@@ -162,6 +846,9 @@ impl BootInfoFrameAllocator {
         // Convert address ranges into frame start addresses, choosing every 4096th address
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         
+        // Leave the frames below 1 MiB to the low memory allocator
+        let frame_addresses = frame_addresses.filter(|&addr| addr >= low::LOW_MEMORY_END);
+
         // Convert frame start addresses into `PhysFrame` instances
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
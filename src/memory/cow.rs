@@ -0,0 +1,264 @@
+// Copy-on-write sharing of frames between address spaces.
+//
+// `memory::clone_address_space` gives the clone new page tables for the
+// user part, pointing at the same frames as the original. Writable pages
+// are made read-only in both and marked with `COW`; the first write to one
+// faults, and `handle_write_fault` gives the writer a copy of the frame,
+// or, if no other address space maps it any more, just makes the page
+// writable again.
+//
+// A frame mapped by more than one address space has a share count here;
+// frames that aren't in the table are mapped once. `release` frees a
+// frame only when its last mapping goes away.
+//
+// The page fault handler has no frame allocator to copy into, so cloning
+// sets aside a spare frame for every copy-on-write page it shares: a
+// frame mapped by n address spaces is copied at most n - 1 times. The
+// spares are given back as the sharing ends without a copy, so the pool
+// always holds as many frames as copies may still be needed.
+//
+// Neither uses the heap, which is small and, with the bump allocator, never
+// gets back what a growing map or vector frees. The share counts are in a
+// fixed table, so at most `MAX_SHARED_FRAMES` frames are shared at a time
+// and cloning fails beyond that, and the spares are chained through their
+// own first bytes.
+
+use super::{free_tables, kernel_level_4_table, phys_to_virt, table_mut, KERNEL_LEVEL_4_TABLE};
+use core::ptr;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Marks a page that is copied on the first write; one of the page table
+// entry bits the CPU leaves to the OS
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+// The most frames shared at a time
+pub const MAX_SHARED_FRAMES: usize = 4096;
+
+// The number of address spaces mapping each shared frame, at least 2
+static SHARES: Mutex<Shares> = Mutex::new(Shares {
+    entries: [(0, 0); MAX_SHARED_FRAMES],
+    len: 0,
+});
+
+// Frames set aside for the copies
+static SPARES: Mutex<Spares> = Mutex::new(Spares { head: None, len: 0 });
+
+// Return the number of shared frames and of spare frames
+pub fn stats() -> (usize, usize) {
+    (SHARES.lock().len, SPARES.lock().len)
+}
+
+// Share counts, as the start addresses of the frames with their counts,
+// sorted by address
+struct Shares {
+    entries: [(u64, usize); MAX_SHARED_FRAMES],
+    len: usize,
+}
+
+impl Shares {
+    fn search(&self, frame: PhysFrame) -> Result<usize, usize> {
+        let address = frame.start_address().as_u64();
+        self.entries[..self.len].binary_search_by_key(&address, |&(address, _)| address)
+    }
+
+    fn get_mut(&mut self, frame: PhysFrame) -> Option<&mut usize> {
+        let index = self.search(frame).ok()?;
+        Some(&mut self.entries[index].1)
+    }
+
+    // Count one more mapping of `frame`. Returns `false` if the table is
+    // full.
+    fn share(&mut self, frame: PhysFrame) -> bool {
+        match self.search(frame) {
+            Ok(index) => self.entries[index].1 += 1,
+            Err(_) if self.len == MAX_SHARED_FRAMES => return false,
+            Err(index) => {
+                self.entries.copy_within(index..self.len, index + 1);
+                self.entries[index] = (frame.start_address().as_u64(), 2);
+                self.len += 1;
+            }
+        }
+        true
+    }
+
+    fn remove(&mut self, frame: PhysFrame) {
+        if let Ok(index) = self.search(frame) {
+            self.entries.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+}
+
+// The spare frames, each holding the address of the next one in its first
+// bytes
+struct Spares {
+    head: Option<PhysFrame>,
+    len: usize,
+}
+
+// The link of the last spare, which isn't a frame address
+const NO_SPARE: u64 = u64::MAX;
+
+impl Spares {
+    fn push(&mut self, frame: PhysFrame) {
+        let next = self.head.map_or(NO_SPARE, |next| next.start_address().as_u64());
+        unsafe { phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(next) };
+        self.head = Some(frame);
+        self.len += 1;
+    }
+
+    fn pop(&mut self) -> Option<PhysFrame> {
+        let frame = self.head?;
+        let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+        self.head = match next {
+            NO_SPARE => None,
+            next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+        };
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
+// Copy the table of the given level at `table_addr` and the tables below
+// it, sharing the frames its level 1 tables map. Returns the frame of the
+// copy, or `None` if there are no frames left or too many frames are
+// shared, after freeing what was copied.
+pub(super) unsafe fn share_tables<A>(
+    table_addr: PhysAddr,
+    level: u8,
+    frame_allocator: &mut A,
+) -> Option<PhysFrame>
+where
+    A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+{
+    let frame = frame_allocator.allocate_frame()?;
+    let copy = table_mut(frame.start_address());
+    copy.zero();
+    let table = table_mut(table_addr);
+    for (entry, copy_entry) in table.iter_mut().zip(copy.iter_mut()) {
+        if entry.is_unused() {
+            continue;
+        }
+        let flags = entry.flags();
+        if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            match share_tables(entry.addr(), level - 1, frame_allocator) {
+                Some(table) => copy_entry.set_frame(table, flags),
+                None => {
+                    free_tables(frame.start_address(), level, frame_allocator);
+                    return None;
+                }
+            }
+        } else if level > 1 {
+            // Huge pages are only mapped by the kernel, and never freed
+            copy_entry.set_addr(entry.addr(), flags);
+        } else {
+            let cow = flags.intersects(PageTableFlags::WRITABLE | COW);
+            let spare = if cow { frame_allocator.allocate_frame() } else { None };
+            let shared = PhysFrame::containing_address(entry.addr());
+            if (cow && spare.is_none()) || !SHARES.lock().share(shared) {
+                if let Some(spare) = spare {
+                    frame_allocator.deallocate_frame(spare);
+                }
+                free_tables(frame.start_address(), level, frame_allocator);
+                return None;
+            }
+            let flags = match spare {
+                Some(spare) => {
+                    SPARES.lock().push(spare);
+                    (flags - PageTableFlags::WRITABLE) | COW
+                }
+                None => flags,
+            };
+            entry.set_flags(flags);
+            copy_entry.set_addr(entry.addr(), flags);
+        }
+    }
+    Some(frame)
+}
+
+// Give up one mapping of `frame`, mapped with `flags`: free the frame if
+// it was the last one, or else a spare that is no longer needed
+pub(super) unsafe fn release(
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_deallocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let mut shares = SHARES.lock();
+    let count = match shares.get_mut(frame) {
+        Some(count) => count,
+        None => return frame_deallocator.deallocate_frame(frame),
+    };
+    *count -= 1;
+    if *count == 1 {
+        shares.remove(frame);
+    }
+    if flags.contains(COW) {
+        if let Some(spare) = SPARES.lock().pop() {
+            frame_deallocator.deallocate_frame(spare);
+        }
+    }
+}
+
+// Resolve a write fault at `addr` in the active address space if it hit a
+// copy-on-write page. Returns `false` if it didn't, and the fault is an
+// error. Called by the page fault handler.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let (level_4_frame, _) = Cr3::read();
+    if level_4_frame.start_address().as_u64() == KERNEL_LEVEL_4_TABLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut table = unsafe { table_mut(level_4_frame.start_address()) };
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    for (level, &index) in indexes.iter().enumerate() {
+        let entry = &table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        }
+        // The kernel's tables are never copy-on-write
+        if level == 0 && entry.addr() == unsafe { kernel_level_4_table() }[index].addr() {
+            return false;
+        }
+        table = unsafe { table_mut(entry.addr()) };
+    }
+    let entry = &mut table[addr.p1_index()];
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) || !flags.contains(COW) {
+        return false;
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let flags = (flags - COW) | PageTableFlags::WRITABLE;
+    let mut shares = SHARES.lock();
+    match shares.get_mut(frame) {
+        // Still shared: write to a copy
+        Some(count) => {
+            let copy = match SPARES.lock().pop() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                    phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+                    4096,
+                );
+            }
+            *count -= 1;
+            if *count == 1 {
+                shares.remove(frame);
+            }
+            entry.set_frame(copy, flags);
+        }
+        // The others copied it already
+        None => entry.set_flags(flags),
+    }
+    x86_64::instructions::tlb::flush(addr);
+    true
+}
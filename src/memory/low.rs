@@ -0,0 +1,189 @@
+// Management of the conventional memory below 1 MiB.
+//
+// Legacy users such as the SMP trampoline, real-mode BIOS helpers and ISA
+// DMA need physical memory below 1 MiB that is identity mapped. These frames
+// are never handed out by the `BootInfoFrameAllocator`; instead they are
+// tracked by the `LowMemoryAllocator` defined here, which hands out
+// physically contiguous runs of frames.
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, mapper::MapToError, FrameAllocator, Mapper, Page,
+        PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// The first physical address that is not part of the low memory region.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// The number of 4 KiB frames in the low memory region.
+const LOW_MEMORY_FRAMES: usize = (LOW_MEMORY_END / 4096) as usize;
+
+/// The number of `u64` words needed to track every low memory frame.
+const BITMAP_WORDS: usize = LOW_MEMORY_FRAMES / 64;
+
+/// A bitmap allocator for the frames below 1 MiB.
+///
+/// A set bit means that the corresponding frame is free.
+pub struct LowMemoryAllocator {
+    free: [u64; BITMAP_WORDS],
+}
+
+impl LowMemoryAllocator {
+    /// Creates an allocator in which every low frame is reserved.
+    pub const fn new() -> Self {
+        LowMemoryAllocator {
+            free: [0; BITMAP_WORDS],
+        }
+    }
+
+    /// Marks all frames below 1 MiB that the memory map reports as usable as
+    /// free.
+    ///
+    /// Frame 0 is always kept reserved because it holds the real mode
+    /// interrupt vector table and the BIOS data area.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the memory map is valid and that the
+    /// usable low frames are not in use by anything else.
+    pub unsafe fn init(&mut self, memory_map: &MemoryMap) {
+        let usable_regions = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable);
+
+        for region in usable_regions {
+            let start = region.range.start_addr();
+            let end = region.range.end_addr().min(LOW_MEMORY_END);
+            for addr in (start..end).step_by(4096) {
+                self.set_free((addr / 4096) as usize, true);
+            }
+        }
+
+        self.set_free(0, false);
+    }
+
+    /// Returns the number of free low memory frames.
+    pub fn free_frames(&self) -> usize {
+        self.free.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Allocates `count` physically contiguous frames below 1 MiB.
+    pub fn allocate(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_below(count, PhysAddr::new(LOW_MEMORY_END))
+    }
+
+    /// Allocates `count` physically contiguous frames that end at or below
+    /// the physical address `limit`.
+    pub fn allocate_below(&mut self, count: usize, limit: PhysAddr) -> Option<PhysFrameRange> {
+        let max_frame = ((limit.as_u64() / 4096) as usize).min(LOW_MEMORY_FRAMES);
+        if count == 0 || count > max_frame {
+            return None;
+        }
+
+        let mut run_start = 0;
+        let mut run_length = 0;
+        for index in 0..max_frame {
+            if self.is_free(index) {
+                if run_length == 0 {
+                    run_start = index;
+                }
+                run_length += 1;
+                if run_length == count {
+                    for i in run_start..run_start + count {
+                        self.set_free(i, false);
+                    }
+                    return Some(frame_range(run_start, count));
+                }
+            } else {
+                run_length = 0;
+            }
+        }
+
+        None
+    }
+
+    /// Returns a range previously obtained from `allocate` to the allocator.
+    pub fn free(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            let index = (frame.start_address().as_u64() / 4096) as usize;
+            assert!(index < LOW_MEMORY_FRAMES, "frame is not in low memory");
+            self.set_free(index, true);
+        }
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.free[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_free(&mut self, index: usize, free: bool) {
+        if free {
+            self.free[index / 64] |= 1 << (index % 64);
+        } else {
+            self.free[index / 64] &= !(1 << (index % 64));
+        }
+    }
+}
+
+// Build the frame range of `count` frames starting at frame number `index`.
+fn frame_range(index: usize, count: usize) -> PhysFrameRange {
+    let start = PhysFrame::containing_address(PhysAddr::new(index as u64 * 4096));
+    PhysFrame::range(start, start + count as u64)
+}
+
+/// The global allocator for memory below 1 MiB.
+pub static LOW_MEMORY: Mutex<LowMemoryAllocator> = Mutex::new(LowMemoryAllocator::new());
+
+/// Initializes the global low memory allocator from the bootloader's memory map.
+///
+/// # Safety
+/// The caller must guarantee that the memory map is valid. This function must
+/// only be called once.
+pub unsafe fn init(memory_map: &MemoryMap) {
+    LOW_MEMORY.lock().init(memory_map);
+}
+
+/// Allocates `count` contiguous frames below 1 MiB from the global allocator.
+pub fn allocate(count: usize) -> Option<PhysFrameRange> {
+    LOW_MEMORY.lock().allocate(count)
+}
+
+/// Returns frames to the global low memory allocator.
+pub fn free(range: PhysFrameRange) {
+    LOW_MEMORY.lock().free(range)
+}
+
+/// Identity maps the given low memory frames, so that their virtual address
+/// equals their physical address.
+///
+/// Pages that are already identity mapped (e.g. the VGA buffer) are skipped.
+pub fn identity_map(
+    range: PhysFrameRange,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    for frame in range {
+        assert!(
+            frame.start_address().as_u64() < LOW_MEMORY_END,
+            "identity_map called for a frame above 1 MiB"
+        );
+
+        let page = Page::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        if let Ok(mapped) = mapper.translate_page(page) {
+            if mapped == frame {
+                continue;
+            }
+        }
+
+        unsafe {
+            mapper
+                .map_to(page, frame, flags | PageTableFlags::PRESENT, frame_allocator)?
+                .flush();
+        }
+    }
+
+    Ok(())
+}
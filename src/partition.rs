@@ -0,0 +1,457 @@
+// Partition tables: MBR and GPT.
+//
+// `read_table` reads the table of any block device, and every partition
+// found can be wrapped in a `Partition`, a block device of its own whose
+// block 0 is the first block of the partition. `scan` does this for all
+// registered disks and registers their partitions like Linux names them:
+// "sda1", "sda2", or "ram0p1" for disks whose name ends in a digit.
+//
+// Of an MBR, only the four primary entries are read; logical partitions
+// inside an extended partition are not. A GPT is used when the MBR is a
+// protective one. Its header and entry array are checked against their
+// CRCs, and if the primary copy at block 1 is broken the backup copy in the
+// last block is used instead.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::console::Spinner;
+use crate::task;
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+// The MBR signature at the end of sector 0
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_ENTRIES_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+// MBR partition types
+const TYPE_EMPTY: u8 = 0x00;
+const TYPE_EXTENDED_CHS: u8 = 0x05;
+const TYPE_EXTENDED_LBA: u8 = 0x0F;
+const TYPE_EXTENDED_LINUX: u8 = 0x85;
+const TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+// The size of the header fields of GPT revision 1.0
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_HEADER_CRC_OFFSET: usize = 16;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+// More than enough for the usual 128 entries of 128 bytes
+const GPT_ENTRIES_MAX_SIZE: usize = 64 * 1024;
+
+// Errors of reading a partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionError {
+    // Reading the disk failed
+    Io(BlockError),
+    // A GPT header is malformed, e.g. points past the end of the disk
+    Invalid,
+    // A GPT header or entry array doesn't match its CRC
+    BadChecksum,
+}
+
+impl From<BlockError> for PartitionError {
+    fn from(err: BlockError) -> Self {
+        PartitionError::Io(err)
+    }
+}
+
+// The kind of partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scheme {
+    Mbr,
+    Gpt,
+}
+
+// A GUID as stored on disk, with the first three fields little endian
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-{:02x}{:02x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        for byte in &b[10..] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Guid({})", self)
+    }
+}
+
+// What a partition holds, as given by the table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Mbr(u8),
+    Gpt(Guid),
+}
+
+// An entry of a partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionInfo {
+    pub number: usize, // Starting at 1, by the position in the table
+    pub start: u64,    // The first block
+    pub count: u64,    // The number of blocks
+    pub kind: Kind,
+}
+
+// The partitions of a disk, in the order of the table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    pub scheme: Scheme,
+    pub partitions: Vec<PartitionInfo>,
+}
+
+// Read the partition table of `device`. Returns `None` if it has none.
+pub fn read_table(device: &dyn BlockDevice) -> Result<Option<Table>, PartitionError> {
+    if device.block_size() < SECTOR_SIZE || device.block_count() == 0 {
+        return Ok(None);
+    }
+    let mut block = vec![0; device.block_size()];
+    device.read_blocks(0, &mut block)?;
+    let entries = match mbr_entries(&block) {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    if entries.iter().any(|entry| entry.kind == TYPE_GPT_PROTECTIVE) {
+        let partitions = read_gpt(device)?;
+        return Ok(Some(Table {
+            scheme: Scheme::Gpt,
+            partitions,
+        }));
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.count == 0 || is_extended(entry.kind) {
+            continue;
+        }
+        let info = PartitionInfo {
+            number: i + 1,
+            start: entry.start as u64,
+            count: entry.count as u64,
+            kind: Kind::Mbr(entry.kind),
+        };
+        if info.start + info.count > device.block_count() {
+            log::warn!("partition {} goes past the end of the disk", info.number);
+            continue;
+        }
+        partitions.push(info);
+    }
+    Ok(Some(Table {
+        scheme: Scheme::Mbr,
+        partitions,
+    }))
+}
+
+// A primary entry of an MBR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MbrEntry {
+    kind: u8,
+    start: u32,
+    count: u32,
+}
+
+// Return the entries of the MBR in `sector`, or `None` if it doesn't hold
+// one. Besides the signature, which the boot sectors of FAT volumes have as
+// well, every entry must be marked as bootable or not.
+fn mbr_entries(sector: &[u8]) -> Option<[MbrEntry; 4]> {
+    if sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = [MbrEntry {
+        kind: TYPE_EMPTY,
+        start: 0,
+        count: 0,
+    }; 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let bytes = &sector[MBR_ENTRIES_OFFSET + i * MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+        if bytes[0] != 0x00 && bytes[0] != 0x80 {
+            return None;
+        }
+        *entry = MbrEntry {
+            kind: bytes[4],
+            start: read_u32(bytes, 8),
+            count: read_u32(bytes, 12),
+        };
+    }
+    // A table without a single partition is a boot sector of something else
+    if entries.iter().all(|entry| entry.kind == TYPE_EMPTY) {
+        return None;
+    }
+    Some(entries)
+}
+
+fn is_extended(kind: u8) -> bool {
+    matches!(kind, TYPE_EXTENDED_CHS | TYPE_EXTENDED_LBA | TYPE_EXTENDED_LINUX)
+}
+
+// Read the GPT, falling back to the backup copy if the primary one is broken
+fn read_gpt(device: &dyn BlockDevice) -> Result<Vec<PartitionInfo>, PartitionError> {
+    match read_gpt_at(device, 1) {
+        Err(PartitionError::Invalid) | Err(PartitionError::BadChecksum) => {
+            log::warn!("the primary GPT is broken, using the backup");
+            read_gpt_at(device, device.block_count() - 1)
+        }
+        result => result,
+    }
+}
+
+// The fields of a GPT header used here
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct GptHeader {
+    first_usable: u64,
+    last_usable: u64,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+impl GptHeader {
+    // Parse and check the header in `block`, which was read from `lba`. The
+    // CRC field is zeroed to check it.
+    fn parse(block: &mut [u8], lba: u64) -> Result<GptHeader, PartitionError> {
+        if !block.starts_with(GPT_SIGNATURE) {
+            return Err(PartitionError::Invalid);
+        }
+        let size = read_u32(block, 12) as usize;
+        if size < GPT_HEADER_MIN_SIZE || size > block.len() {
+            return Err(PartitionError::Invalid);
+        }
+        let crc = read_u32(block, GPT_HEADER_CRC_OFFSET);
+        block[GPT_HEADER_CRC_OFFSET..GPT_HEADER_CRC_OFFSET + 4].fill(0);
+        if crc32(&block[..size]) != crc {
+            return Err(PartitionError::BadChecksum);
+        }
+        if read_u64(block, 24) != lba {
+            return Err(PartitionError::Invalid);
+        }
+
+        let header = GptHeader {
+            first_usable: read_u64(block, 40),
+            last_usable: read_u64(block, 48),
+            entries_lba: read_u64(block, 72),
+            entry_count: read_u32(block, 80),
+            entry_size: read_u32(block, 84),
+            entries_crc: read_u32(block, 88),
+        };
+        let entry_size = header.entry_size as usize;
+        if entry_size < GPT_ENTRY_MIN_SIZE
+            || entry_size % 8 != 0
+            || header.entry_count as usize * entry_size > GPT_ENTRIES_MAX_SIZE
+            || header.first_usable > header.last_usable
+        {
+            return Err(PartitionError::Invalid);
+        }
+        Ok(header)
+    }
+}
+
+// Read and check the GPT header at `lba` and its entry array
+fn read_gpt_at(device: &dyn BlockDevice, lba: u64) -> Result<Vec<PartitionInfo>, PartitionError> {
+    let block_size = device.block_size();
+    let mut block = vec![0; block_size];
+    device.read_blocks(lba, &mut block)?;
+    let header = GptHeader::parse(&mut block, lba)?;
+
+    let bytes = header.entry_count as usize * header.entry_size as usize;
+    let blocks = ((bytes + block_size - 1) / block_size) as u64;
+    let entries_fit = matches!(
+        header.entries_lba.checked_add(blocks),
+        Some(end) if end <= device.block_count()
+    );
+    if !entries_fit || header.last_usable >= device.block_count() {
+        return Err(PartitionError::Invalid);
+    }
+    let mut entries = vec![0; blocks as usize * block_size];
+    device.read_blocks(header.entries_lba, &mut entries)?;
+    if crc32(&entries[..bytes]) != header.entries_crc {
+        return Err(PartitionError::BadChecksum);
+    }
+
+    let mut partitions = Vec::new();
+    let entries = entries[..bytes].chunks_exact(header.entry_size as usize);
+    for (i, entry) in entries.enumerate() {
+        let mut kind = Guid([0; 16]);
+        kind.0.copy_from_slice(&entry[..16]);
+        if kind.is_nil() {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        let last = read_u64(entry, 40);
+        if first > last || first < header.first_usable || last > header.last_usable {
+            log::warn!("partition {} lies outside the usable blocks", i + 1);
+            continue;
+        }
+        partitions.push(PartitionInfo {
+            number: i + 1,
+            start: first,
+            count: last - first + 1,
+            kind: Kind::Gpt(kind),
+        });
+    }
+    Ok(partitions)
+}
+
+// A partition of a disk, as a block device of its own
+pub struct Partition {
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    count: u64,
+}
+
+impl Partition {
+    pub fn new(disk: Arc<dyn BlockDevice>, info: &PartitionInfo) -> Partition {
+        Partition {
+            disk,
+            start: info.start,
+            count: info.count,
+        }
+    }
+
+    // Return the block of the disk for block `lba` of a request of `len`
+    // bytes. Only the range is checked; the disk checks the rest of the
+    // request, so faults are injected into it just once.
+    fn translate(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let count = (len / self.block_size()) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.count => Ok(self.start + lba),
+            _ => Err(BlockError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for Partition {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.count
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let lba = self.translate(lba, buffer.len())?;
+        self.disk.read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let lba = self.translate(lba, buffer.len())?;
+        self.disk.write_blocks(lba, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+}
+
+// Register the partitions of every registered disk. Call once, after the
+// drivers have registered their disks; a partition holding a table of its
+// own would be scanned again otherwise.
+pub fn scan() {
+    let mut spinner = Spinner::new("scanning partition tables");
+    for (name, disk) in block::devices() {
+        spinner.tick();
+        let table = match read_table(&*disk) {
+            Ok(Some(table)) => table,
+            Ok(None) => continue,
+            Err(err) => {
+                log::warn!("{}: can't read the partition table: {:?}", name, err);
+                continue;
+            }
+        };
+        let separator = if name.ends_with(|c: char| c.is_ascii_digit()) { "p" } else { "" };
+        for info in &table.partitions {
+            let partition = Partition::new(disk.clone(), info);
+            let _ = block::register(
+                format!("{}{}{}", name, separator, info.number),
+                Arc::new(partition),
+            );
+        }
+    }
+}
+
+// The CRC-32 used by GPT, which is the one of zlib and Ethernet
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for chunk in bytes.chunks(4096) {
+        task::maybe_preempt(); // For large buffers
+        for &byte in chunk {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+    !crc
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let b = &bytes[offset..offset + 4];
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let b = &bytes[offset..offset + 8];
+    u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+}
+
+#[test_case]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test_case]
+fn test_mbr_entries() {
+    let mut sector = [0u8; SECTOR_SIZE];
+    assert_eq!(mbr_entries(&sector), None);
+    sector[MBR_SIGNATURE_OFFSET..].copy_from_slice(&MBR_SIGNATURE);
+    // A signature alone isn't enough
+    assert_eq!(mbr_entries(&sector), None);
+
+    let entry = &mut sector[MBR_ENTRIES_OFFSET + MBR_ENTRY_SIZE..][..MBR_ENTRY_SIZE];
+    entry[0] = 0x80;
+    entry[4] = 0x83;
+    entry[8..12].copy_from_slice(&2048u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&4096u32.to_le_bytes());
+    let entries = mbr_entries(&sector).unwrap();
+    assert_eq!(entries[0].kind, TYPE_EMPTY);
+    assert_eq!(
+        entries[1],
+        MbrEntry {
+            kind: 0x83,
+            start: 2048,
+            count: 4096
+        }
+    );
+
+    // Boot code where the entries are, like in a FAT boot sector
+    sector[MBR_ENTRIES_OFFSET] = 0x31;
+    assert_eq!(mbr_entries(&sector), None);
+}
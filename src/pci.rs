@@ -0,0 +1,616 @@
+// PCI bus enumeration.
+//
+// `init` scans every bus, device and function through the legacy
+// configuration mechanism (the 0xCF8 address and 0xCFC data ports) and
+// records what it finds. Drivers look their devices up afterwards:
+//
+//     for device in pci::find_by_class(pci::CLASS_MASS_STORAGE, 0x06) {
+//         // An AHCI controller
+//     }
+//
+// Devices that support it can signal interrupts by message instead of an
+// INTx line, see `msi`.
+//
+// Once the ACPI MCFG table is known, `ecam::init` switches configuration
+// accesses to the memory-mapped ECAM region, which also reaches the 4 KiB
+// extended configuration space of PCI Express functions.
+
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+pub mod ecam;
+pub mod ids;
+pub mod msi;
+
+// The configuration address and data ports
+const CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const CONFIG_DATA_PORT: u16 = 0xCFC;
+
+// Bit in the configuration address enabling the access
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+// Offsets of configuration space registers
+pub const VENDOR_ID: u16 = 0x00;
+pub const DEVICE_ID: u16 = 0x02;
+pub const COMMAND: u16 = 0x04;
+pub const STATUS: u16 = 0x06;
+pub const REVISION_ID: u16 = 0x08;
+pub const HEADER_TYPE: u16 = 0x0E;
+pub const BAR0: u16 = 0x10;
+pub const INTERRUPT_LINE: u16 = 0x3C;
+pub const INTERRUPT_PIN: u16 = 0x3D;
+pub const CAPABILITIES_POINTER: u16 = 0x34;
+
+// Size of the configuration space reachable through the legacy ports, and
+// through ECAM
+const LEGACY_CONFIG_SIZE: u16 = 0x100;
+pub const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+// Status register bit set if the device has a capability list
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+// Command register bits
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+// The vendor ID read from a slot without a device
+const NO_VENDOR: u16 = 0xFFFF;
+
+// Header type bit set on multi-function devices
+const HEADER_MULTI_FUNCTION: u8 = 0x80;
+
+// Header type of regular devices, the only ones with six BARs
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+
+// Number of BARs of a general device
+const BAR_COUNT: usize = 6;
+
+// Class codes
+pub const CLASS_MASS_STORAGE: u8 = 0x01;
+pub const CLASS_NETWORK: u8 = 0x02;
+pub const CLASS_DISPLAY: u8 = 0x03;
+pub const CLASS_BRIDGE: u8 = 0x06;
+pub const CLASS_SERIAL_BUS: u8 = 0x0C;
+
+// Serializes accesses through the address and data ports
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+// The devices found by `init`
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+// The location of a function on the PCI bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,   // 0 to 31
+    pub function: u8, // 0 to 7
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        assert!(device < 32 && function < 8, "invalid PCI address");
+        PciAddress { bus, device, function }
+    }
+
+    // The value for the configuration address port
+    fn config_address(self, offset: u16) -> u32 {
+        debug_assert!(offset < LEGACY_CONFIG_SIZE);
+        CONFIG_ENABLE
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    // Read a 32 bit configuration register; `offset` is rounded down to a
+    // multiple of 4. Registers beyond the first 256 bytes read as all ones
+    // unless the function is reachable through ECAM.
+    pub fn read_u32(self, offset: u16) -> u32 {
+        if let Some(register) = ecam::register(self, offset) {
+            return unsafe { register.read_volatile() };
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return 0xFFFF_FFFF;
+        }
+        interrupts::without_interrupts(|| {
+            let _lock = CONFIG_LOCK.lock();
+            let mut address = Port::<u32>::new(CONFIG_ADDRESS_PORT);
+            let mut data = Port::<u32>::new(CONFIG_DATA_PORT);
+            unsafe {
+                address.write(self.config_address(offset));
+                data.read()
+            }
+        })
+    }
+
+    // Write a 32 bit configuration register.
+    //
+    // Unsafe because it can change where the device decodes memory or
+    // what it does with it.
+    pub unsafe fn write_u32(self, offset: u16, value: u32) {
+        if let Some(register) = ecam::register(self, offset) {
+            return register.write_volatile(value);
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        interrupts::without_interrupts(|| {
+            let _lock = CONFIG_LOCK.lock();
+            let mut address = Port::<u32>::new(CONFIG_ADDRESS_PORT);
+            let mut data = Port::<u32>::new(CONFIG_DATA_PORT);
+            address.write(self.config_address(offset));
+            data.write(value);
+        });
+    }
+
+    pub fn read_u16(self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    // Write a 16 bit configuration register, keeping the other half of its
+    // 32 bit word. Unsafe like `write_u32`.
+    pub unsafe fn write_u16(self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let word = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, word | (value as u32) << shift);
+    }
+
+    // Return the capabilities in the standard configuration space
+    pub fn capabilities(self) -> Capabilities {
+        let next = if self.read_u16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_u8(CAPABILITIES_POINTER) as u16 & !0x3
+        } else {
+            0
+        };
+        Capabilities {
+            address: self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    // Return the offset of the capability with the given ID
+    pub fn find_capability(self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|capability| capability.id == id)
+            .map(|capability| capability.offset)
+    }
+
+    // Return the capabilities in the extended configuration space, only
+    // reachable through ECAM
+    pub fn extended_capabilities(self) -> ExtendedCapabilities {
+        let next = if ecam::register(self, LEGACY_CONFIG_SIZE).is_some() {
+            LEGACY_CONFIG_SIZE
+        } else {
+            0
+        };
+        ExtendedCapabilities {
+            address: self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+}
+
+// Capability IDs
+pub const CAP_POWER_MANAGEMENT: u8 = 0x01;
+pub const CAP_MSI: u8 = 0x05;
+pub const CAP_VENDOR_SPECIFIC: u8 = 0x09;
+pub const CAP_PCI_EXPRESS: u8 = 0x10;
+pub const CAP_MSIX: u8 = 0x11;
+
+// Extended capability IDs
+pub const EXT_CAP_ADVANCED_ERROR_REPORTING: u16 = 0x0001;
+pub const EXT_CAP_SERIAL_NUMBER: u16 = 0x0003;
+
+// Upper bound on the length of a capability list, in case it loops
+const MAX_CAPABILITIES: usize = 64;
+
+// An entry of a capability list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability<Id> {
+    pub id: Id,
+    pub offset: u16, // Where the capability's registers start
+}
+
+// Iterates over the standard capability list of a function
+pub struct Capabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = Capability<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        let header = self.address.read_u16(offset);
+        self.next = (header >> 8) & !0x3;
+        Some(Capability {
+            id: header as u8,
+            offset,
+        })
+    }
+}
+
+// Iterates over the extended capability list of a PCI Express function
+pub struct ExtendedCapabilities {
+    address: PciAddress,
+    next: u16,
+    remaining: usize,
+}
+
+impl Iterator for ExtendedCapabilities {
+    type Item = Capability<u16>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next < LEGACY_CONFIG_SIZE || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = self.next;
+        let header = self.address.read_u32(offset);
+        // An empty list has a header of zero, a missing one of all ones
+        if header == 0 || header == 0xFFFF_FFFF {
+            return None;
+        }
+        self.next = (header >> 20) as u16 & !0x3;
+        Some(Capability {
+            id: header as u16,
+            offset,
+        })
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// A base address register: where a device's registers are mapped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+// A function found on the bus
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8, // Without the multi-function bit
+    pub bars: [Option<Bar>; BAR_COUNT],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8, // 0 if none, 1 to 4 for INTA# to INTD#
+}
+
+impl PciDevice {
+    // Read a function's configuration; `None` if there is no device
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = address.read_u16(VENDOR_ID);
+        if vendor_id == NO_VENDOR {
+            return None;
+        }
+
+        let class_word = address.read_u32(REVISION_ID);
+        let header_type = address.read_u8(HEADER_TYPE) & !HEADER_MULTI_FUNCTION;
+        let bars = if header_type == HEADER_TYPE_GENERAL {
+            read_bars(address)
+        } else {
+            [None; BAR_COUNT]
+        };
+
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: address.read_u16(DEVICE_ID),
+            class: (class_word >> 24) as u8,
+            subclass: (class_word >> 16) as u8,
+            prog_if: (class_word >> 8) as u8,
+            revision: class_word as u8,
+            header_type,
+            bars,
+            interrupt_line: address.read_u8(INTERRUPT_LINE),
+            interrupt_pin: address.read_u8(INTERRUPT_PIN),
+        })
+    }
+
+    // Let the device access memory on its own, e.g. for DMA
+    pub fn enable_bus_master(&self) {
+        let command = self.address.read_u16(COMMAND);
+        unsafe { self.address.write_u16(COMMAND, command | COMMAND_BUS_MASTER) };
+    }
+
+    // Let the device decode its memory and I/O BARs
+    pub fn enable_decoding(&self) {
+        let command = self.address.read_u16(COMMAND);
+        unsafe {
+            self.address
+                .write_u16(COMMAND, command | COMMAND_MEMORY_SPACE | COMMAND_IO_SPACE)
+        };
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if
+        )
+    }
+}
+
+// A device with its names and BARs, as `lspci` shows it
+pub struct Listing<'a>(&'a PciDevice);
+
+impl PciDevice {
+    // Return the device in the form `dump` prints it
+    pub fn listing(&self) -> Listing {
+        Listing(self)
+    }
+}
+
+impl fmt::Display for Listing<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let device = self.0;
+        write!(
+            f,
+            "{} {:04x}:{:04x} {}: ",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            ids::class_name(device.class, device.subclass)
+        )?;
+        match ids::vendor_name(device.vendor_id) {
+            Some(vendor) => write!(f, "{}", vendor)?,
+            None => write!(f, "Vendor {:04x}", device.vendor_id)?,
+        }
+        match ids::device_name(device.vendor_id, device.device_id) {
+            Some(name) => write!(f, " {}", name)?,
+            None => write!(f, " Device {:04x}", device.device_id)?,
+        }
+        if device.revision != 0 {
+            write!(f, " (rev {:02x})", device.revision)?;
+        }
+
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(Bar::Memory {
+                    address,
+                    size,
+                    prefetchable,
+                    is_64bit,
+                }) => write!(
+                    f,
+                    "\n        BAR{}: memory at {:#x} ({}-bit, {}prefetchable, size {:#x})",
+                    index,
+                    address,
+                    if *is_64bit { 64 } else { 32 },
+                    if *prefetchable { "" } else { "non-" },
+                    size
+                )?,
+                Some(Bar::Io { port, size }) => write!(
+                    f,
+                    "\n        BAR{}: I/O ports at {:#x} (size {:#x})",
+                    index, port, size
+                )?,
+                None => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read and size the BARs of a general device. Decoding is switched off
+// while a BAR holds the all-ones sizing pattern.
+fn read_bars(address: PciAddress) -> [Option<Bar>; BAR_COUNT] {
+    let mut bars = [None; BAR_COUNT];
+    let command = address.read_u16(COMMAND);
+    unsafe {
+        address.write_u16(COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+    }
+
+    let mut index = 0;
+    while index < BAR_COUNT {
+        let offset = BAR0 + 4 * index as u16;
+        let low = address.read_u32(offset);
+        let low_mask = size_bar(address, offset, low);
+
+        let is_64bit = low & 0x1 == 0 && (low >> 1) & 0x3 == 0x2 && index + 1 < BAR_COUNT;
+        let (high, high_mask) = if is_64bit {
+            let high = address.read_u32(offset + 4);
+            (high, size_bar(address, offset + 4, high))
+        } else {
+            (0, 0)
+        };
+
+        bars[index] = decode_bar(low, low_mask, high, high_mask);
+        index += if is_64bit { 2 } else { 1 };
+    }
+
+    unsafe { address.write_u16(COMMAND, command) };
+    bars
+}
+
+// Return the value a BAR reads back after writing all ones, and restore it
+fn size_bar(address: PciAddress, offset: u16, original: u32) -> u32 {
+    unsafe {
+        address.write_u32(offset, 0xFFFF_FFFF);
+        let mask = address.read_u32(offset);
+        address.write_u32(offset, original);
+        mask
+    }
+}
+
+// Decode a BAR from its value and the value read back after writing all
+// ones; `high` and `high_mask` are the upper half of a 64 bit BAR
+fn decode_bar(low: u32, low_mask: u32, high: u32, high_mask: u32) -> Option<Bar> {
+    if low & 0x1 == 1 {
+        let mask = low_mask & !0x3;
+        if mask == 0 {
+            return None;
+        }
+        // Only the low 16 bits of an I/O BAR are decoded
+        let size = !(mask | 0xFFFF_0000) + 1;
+        return Some(Bar::Io {
+            port: (low & !0x3) as u16,
+            size,
+        });
+    }
+
+    let is_64bit = (low >> 1) & 0x3 == 0x2;
+    let mask = if is_64bit {
+        (high_mask as u64) << 32 | (low_mask & !0xF) as u64
+    } else {
+        0xFFFF_FFFF_0000_0000 | (low_mask & !0xF) as u64
+    };
+    if mask & 0xFFFF_FFFF == 0 && (!is_64bit || high_mask == 0) {
+        return None;
+    }
+
+    Some(Bar::Memory {
+        address: (high as u64) << 32 | (low & !0xF) as u64,
+        size: (!mask).wrapping_add(1),
+        prefetchable: low & 0x8 != 0,
+        is_64bit,
+    })
+}
+
+// Scan the bus and record the devices. Requires the heap; calling it again
+// does nothing.
+pub fn init() {
+    DEVICES.init_once(|| {
+        let mut devices = Vec::new();
+        for bus in 0..=255 {
+            for device in 0..32 {
+                scan_device(bus, device, &mut devices);
+            }
+        }
+        log::info!("found {} PCI functions", devices.len());
+        devices
+    });
+}
+
+// Add the functions of a device to `devices`
+fn scan_device(bus: u8, device: u8, devices: &mut Vec<PciDevice>) {
+    let first = match PciDevice::probe(PciAddress::new(bus, device, 0)) {
+        Some(first) => first,
+        None => return,
+    };
+    let multi_function =
+        PciAddress::new(bus, device, 0).read_u8(HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0;
+    devices.push(first);
+
+    if multi_function {
+        for function in 1..8 {
+            if let Some(found) = PciDevice::probe(PciAddress::new(bus, device, function)) {
+                devices.push(found);
+            }
+        }
+    }
+}
+
+// Return the devices found by `init`; empty before it ran
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.get().map_or(&[], |devices| devices.as_slice())
+}
+
+// Log the devices found by `init` with their names and BARs
+pub fn dump() {
+    for device in devices() {
+        log::info!("{}", device.listing());
+    }
+}
+
+// Return the devices with the given class and subclass
+pub fn find_by_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.class == class && device.subclass == subclass)
+}
+
+// Return the devices with the given vendor and device ID
+pub fn find_by_id(vendor_id: u16, device_id: u16) -> impl Iterator<Item = &'static PciDevice> {
+    devices()
+        .iter()
+        .filter(move |device| device.vendor_id == vendor_id && device.device_id == device_id)
+}
+
+#[test_case]
+fn test_decode_bar() {
+    // A 4 KiB 32 bit memory BAR
+    assert_eq!(
+        decode_bar(0xFEBF_0000, 0xFFFF_F000, 0, 0),
+        Some(Bar::Memory {
+            address: 0xFEBF_0000,
+            size: 0x1000,
+            prefetchable: false,
+            is_64bit: false,
+        })
+    );
+    // A 16 KiB prefetchable 64 bit memory BAR
+    assert_eq!(
+        decode_bar(0x0000_000C, 0xFFFF_C00C, 0x1, 0xFFFF_FFFF),
+        Some(Bar::Memory {
+            address: 0x1_0000_0000,
+            size: 0x4000,
+            prefetchable: true,
+            is_64bit: true,
+        })
+    );
+    // A 32 byte I/O BAR
+    assert_eq!(
+        decode_bar(0xC041, 0xFFFF_FFE1, 0, 0),
+        Some(Bar::Io { port: 0xC040, size: 32 })
+    );
+    // An unimplemented BAR
+    assert_eq!(decode_bar(0, 0, 0, 0), None);
+}
+
+#[test_case]
+fn test_capabilities_terminate() {
+    // Walking every function's list must end, even on odd hardware
+    for device in 0..32 {
+        let address = PciAddress::new(0, device, 0);
+        assert!(address.capabilities().count() < MAX_CAPABILITIES);
+    }
+}
+
+#[test_case]
+fn test_config_space() {
+    // The host bridge of every supported machine sits at 00:00.0
+    let host_bridge = PciAddress::new(0, 0, 0);
+    assert_ne!(host_bridge.read_u16(VENDOR_ID), NO_VENDOR);
+    assert_eq!(host_bridge.read_u8(0x0B), CLASS_BRIDGE);
+}
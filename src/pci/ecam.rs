@@ -0,0 +1,191 @@
+// Memory-mapped PCI Express configuration space (ECAM).
+//
+// The ACPI MCFG table says where the configuration space of a range of
+// buses is mapped into physical memory: every function gets 4 KiB at
+//
+//     base + ((bus - start_bus) << 20 | device << 15 | function << 12)
+//
+// Unlike the legacy ports, this reaches the extended configuration space
+// and its capabilities. `init` maps the pages of the functions found by
+// `pci::init`; until then, and for anything not mapped, configuration
+// accesses fall back to the legacy ports.
+
+use super::{devices, PciAddress, EXTENDED_CONFIG_SIZE};
+use crate::memory;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::structures::paging::{mapper::MapToError, FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Size of the ACPI table header and of the MCFG fields before the entries
+const SDT_HEADER_SIZE: usize = 36;
+const MCFG_ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+
+// Size of an MCFG configuration space entry
+const MCFG_ENTRY_SIZE: usize = 16;
+
+// The physical base of the ECAM region, 0 if there is none
+static ECAM_BASE: AtomicU64 = AtomicU64::new(0);
+
+// The buses covered by the region
+static START_BUS: AtomicU8 = AtomicU8::new(0);
+static END_BUS: AtomicU8 = AtomicU8::new(0);
+
+// A region of the MCFG table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base: u64, // The physical address of `start_bus`'s configuration space
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+// Errors from parsing an MCFG table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum McfgError {
+    BadSignature,
+    BadLength,
+    BadChecksum,
+}
+
+// Iterates over the entries of an MCFG table
+pub struct McfgEntries<'a> {
+    entries: core::slice::ChunksExact<'a, u8>,
+}
+
+impl Iterator for McfgEntries<'_> {
+    type Item = McfgEntry;
+
+    fn next(&mut self) -> Option<McfgEntry> {
+        let entry = self.entries.next()?;
+        Some(McfgEntry {
+            base: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            segment: u16::from_le_bytes([entry[8], entry[9]]),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+    }
+}
+
+// Validate an MCFG table, including its ACPI header, and return its entries
+pub fn parse_mcfg(table: &[u8]) -> Result<McfgEntries<'_>, McfgError> {
+    if table.len() < MCFG_ENTRIES_OFFSET {
+        return Err(McfgError::BadLength);
+    }
+    if &table[0..4] != b"MCFG" {
+        return Err(McfgError::BadSignature);
+    }
+    let length = u32::from_le_bytes(table[4..8].try_into().unwrap()) as usize;
+    if length < MCFG_ENTRIES_OFFSET || length > table.len() {
+        return Err(McfgError::BadLength);
+    }
+    let table = &table[..length];
+    if table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err(McfgError::BadChecksum);
+    }
+
+    Ok(McfgEntries {
+        entries: table[MCFG_ENTRIES_OFFSET..].chunks_exact(MCFG_ENTRY_SIZE),
+    })
+}
+
+// Use the given MCFG region for configuration accesses and map the
+// configuration space of every function `pci::init` found in it. Only
+// segment 0 is supported; other regions are ignored.
+pub fn init(
+    entry: McfgEntry,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    if entry.segment != 0 || entry.start_bus > entry.end_bus {
+        log::info!("ignoring ECAM region for segment {}", entry.segment);
+        return Ok(());
+    }
+
+    for device in devices() {
+        if let Some(phys) = function_base(entry, device.address) {
+            memory::map_mmio(PhysAddr::new(phys), mapper, frame_allocator)?;
+        }
+    }
+
+    START_BUS.store(entry.start_bus, Ordering::Relaxed);
+    END_BUS.store(entry.end_bus, Ordering::Relaxed);
+    ECAM_BASE.store(entry.base, Ordering::Release);
+    log::info!(
+        "PCI ECAM at {:#x} for buses {}-{}",
+        entry.base,
+        entry.start_bus,
+        entry.end_bus
+    );
+    Ok(())
+}
+
+// Return whether configuration accesses go through ECAM
+pub fn is_enabled() -> bool {
+    ECAM_BASE.load(Ordering::Acquire) != 0
+}
+
+// Return the physical address of a function's configuration space
+fn function_base(entry: McfgEntry, address: PciAddress) -> Option<u64> {
+    if address.bus < entry.start_bus || address.bus > entry.end_bus {
+        return None;
+    }
+    let bus = (address.bus - entry.start_bus) as u64;
+    Some(entry.base + (bus << 20 | (address.device as u64) << 15 | (address.function as u64) << 12))
+}
+
+// Return a pointer to a 32 bit configuration register through ECAM, if the
+// function's configuration space is mapped
+pub(super) fn register(address: PciAddress, offset: u16) -> Option<*mut u32> {
+    let base = ECAM_BASE.load(Ordering::Acquire);
+    if base == 0 || offset >= EXTENDED_CONFIG_SIZE {
+        return None;
+    }
+    let entry = McfgEntry {
+        base,
+        segment: 0,
+        start_bus: START_BUS.load(Ordering::Relaxed),
+        end_bus: END_BUS.load(Ordering::Relaxed),
+    };
+    let phys = function_base(entry, address)? + (offset & !0x3) as u64;
+
+    // Only the functions found by the scan are mapped (identity mapped,
+    // like all device memory)
+    let virt = VirtAddr::try_new(phys).ok()?;
+    if memory::virt_to_phys(virt) != Some(PhysAddr::new(phys)) {
+        return None;
+    }
+    Some(virt.as_mut_ptr())
+}
+
+#[test_case]
+fn test_parse_mcfg() {
+    let mut table = [0u8; MCFG_ENTRIES_OFFSET + MCFG_ENTRY_SIZE];
+    table[0..4].copy_from_slice(b"MCFG");
+    table[4..8].copy_from_slice(&(table.len() as u32).to_le_bytes());
+    let entry = &mut table[MCFG_ENTRIES_OFFSET..];
+    entry[0..8].copy_from_slice(&0xB000_0000u64.to_le_bytes());
+    entry[10] = 0;
+    entry[11] = 0xFF;
+    let sum = table.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    table[9] = 0u8.wrapping_sub(sum);
+
+    let mut entries = parse_mcfg(&table).unwrap();
+    let entry = entries.next().unwrap();
+    assert_eq!(
+        entry,
+        McfgEntry {
+            base: 0xB000_0000,
+            segment: 0,
+            start_bus: 0,
+            end_bus: 0xFF,
+        }
+    );
+    assert_eq!(entries.next(), None);
+    assert_eq!(
+        function_base(entry, PciAddress::new(1, 2, 3)),
+        Some(0xB000_0000 + (1 << 20) + (2 << 15) + (3 << 12))
+    );
+
+    table[9] = table[9].wrapping_add(1);
+    assert!(matches!(parse_mcfg(&table), Err(McfgError::BadChecksum)));
+}
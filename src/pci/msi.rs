@@ -0,0 +1,279 @@
+// Message signalled interrupts (MSI and MSI-X).
+//
+// Instead of raising an INTx line that is shared and routed through the
+// IO-APIC, a device using MSI writes a message straight to the local APIC,
+// which names the vector to raise. Every vector comes from
+// `interrupts::allocate_vector`, so it needs the APIC to be enabled.
+//
+// MSI gives a function one vector, programmed in its capability:
+//
+//     let vector = device.enable_msi(handle_completion)?;
+//
+// MSI-X gives it a table of vectors in one of its memory BARs, e.g. one
+// per queue:
+//
+//     let mut msix = device.enable_msix(&mut mapper, &mut frame_allocator)?;
+//     msix.set_handler(0, handle_config_change)?;
+//     msix.set_handler(1, handle_queue)?;
+
+use super::{Bar, PciAddress, PciDevice, CAP_MSI, CAP_MSIX, COMMAND, COMMAND_INTERRUPT_DISABLE};
+use crate::{apic, interrupts, memory};
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+// MSI capability registers, relative to the capability
+const MSI_CONTROL: u16 = 0x02;
+const MSI_ADDRESS_LOW: u16 = 0x04;
+const MSI_ADDRESS_HIGH: u16 = 0x08; // Only with 64 bit addresses
+const MSI_DATA_32: u16 = 0x08;
+const MSI_DATA_64: u16 = 0x0C;
+
+// MSI message control bits
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE: u16 = 0x7 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+
+// MSI-X capability registers, relative to the capability
+const MSIX_CONTROL: u16 = 0x02;
+const MSIX_TABLE: u16 = 0x04;
+
+// MSI-X message control bits
+const MSIX_TABLE_SIZE: u16 = 0x7FF; // Number of entries minus one
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+
+// Bits of the table register selecting the BAR; the rest is the offset
+const MSIX_TABLE_BIR: u32 = 0x7;
+
+// Layout of an MSI-X table entry
+const MSIX_ENTRY_SIZE: usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW: usize = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: usize = 0x4;
+const MSIX_ENTRY_DATA: usize = 0x8;
+const MSIX_ENTRY_CONTROL: usize = 0xC;
+
+// Vector control bit masking an MSI-X entry
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+// Errors from setting up message signalled interrupts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    ApicDisabled, // Messages are delivered to the local APIC
+    NotSupported, // The function has no MSI or MSI-X capability
+    NoFreeVector,
+    BadTable,     // The MSI-X table isn't in a memory BAR
+    MapFailed,
+    InvalidEntry(u16),
+}
+
+impl From<interrupts::NoFreeVector> for MsiError {
+    fn from(_: interrupts::NoFreeVector) -> Self {
+        MsiError::NoFreeVector
+    }
+}
+
+impl PciDevice {
+    // Deliver the function's interrupts through MSI on a newly allocated
+    // vector that calls `handler`, and return the vector. The INTx line is
+    // disabled.
+    pub fn enable_msi(&self, handler: fn()) -> Result<u8, MsiError> {
+        if !apic::is_enabled() {
+            return Err(MsiError::ApicDisabled);
+        }
+        let capability = self.address.find_capability(CAP_MSI).ok_or(MsiError::NotSupported)?;
+        let vector = interrupts::allocate_vector(handler)?;
+
+        let (address, data) = apic::msi_message(vector);
+        let control = self.address.read_u16(capability + MSI_CONTROL);
+        unsafe {
+            self.address.write_u32(capability + MSI_ADDRESS_LOW, address as u32);
+            if control & MSI_64BIT != 0 {
+                self.address
+                    .write_u32(capability + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+                self.address.write_u16(capability + MSI_DATA_64, data as u16);
+            } else {
+                self.address.write_u16(capability + MSI_DATA_32, data as u16);
+            }
+
+            // A single message, so the vector is used as is
+            let control = control & !MSI_MULTIPLE_MESSAGE_ENABLE | MSI_ENABLE;
+            self.address.write_u16(capability + MSI_CONTROL, control);
+        }
+        disable_intx(self.address);
+        self.enable_bus_master();
+
+        Ok(vector)
+    }
+
+    // Stop delivering interrupts through MSI and free the vector
+    pub fn disable_msi(&self) {
+        let capability = match self.address.find_capability(CAP_MSI) {
+            Some(capability) => capability,
+            None => return,
+        };
+        let control = self.address.read_u16(capability + MSI_CONTROL);
+        if control & MSI_ENABLE == 0 {
+            return;
+        }
+
+        unsafe {
+            self.address
+                .write_u16(capability + MSI_CONTROL, control & !MSI_ENABLE)
+        };
+        let data = if control & MSI_64BIT != 0 {
+            self.address.read_u16(capability + MSI_DATA_64)
+        } else {
+            self.address.read_u16(capability + MSI_DATA_32)
+        };
+        interrupts::free_vector(data as u8);
+    }
+
+    // Deliver the function's interrupts through MSI-X. The table is mapped
+    // and every entry starts masked; entries get a vector with
+    // `MsiX::set_handler`. The INTx line is disabled.
+    pub fn enable_msix(
+        &self,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<MsiX, MsiError> {
+        if !apic::is_enabled() {
+            return Err(MsiError::ApicDisabled);
+        }
+        let capability = self.address.find_capability(CAP_MSIX).ok_or(MsiError::NotSupported)?;
+
+        let control = self.address.read_u16(capability + MSIX_CONTROL);
+        let size = (control & MSIX_TABLE_SIZE) + 1;
+        let table_register = self.address.read_u32(capability + MSIX_TABLE);
+        let bar = self.bars.get((table_register & MSIX_TABLE_BIR) as usize).copied().flatten();
+        let bar_address = match bar {
+            Some(Bar::Memory { address, .. }) => address,
+            _ => return Err(MsiError::BadTable),
+        };
+        let table_phys = bar_address + (table_register & !MSIX_TABLE_BIR) as u64;
+
+        // The table may span several pages
+        let table_end = table_phys + (size as usize * MSIX_ENTRY_SIZE) as u64;
+        let mut page = table_phys & !0xFFF;
+        while page < table_end {
+            memory::map_mmio(PhysAddr::new(page), mapper, frame_allocator)
+                .map_err(|_| MsiError::MapFailed)?;
+            page += 0x1000;
+        }
+
+        let msix = MsiX {
+            address: self.address,
+            capability,
+            table: VirtAddr::new(table_phys),
+            vectors: vec![None; size as usize],
+        };
+
+        // Mask the whole function while the table is set up
+        self.enable_decoding();
+        unsafe {
+            self.address.write_u16(
+                capability + MSIX_CONTROL,
+                control | MSIX_FUNCTION_MASK | MSIX_ENABLE,
+            );
+            for entry in 0..size {
+                msix.write_entry(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+            }
+            self.address.write_u16(
+                capability + MSIX_CONTROL,
+                (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+            );
+        }
+        disable_intx(self.address);
+        self.enable_bus_master();
+
+        Ok(msix)
+    }
+}
+
+// The MSI-X table of a function
+pub struct MsiX {
+    address: PciAddress,
+    capability: u16,
+    table: VirtAddr, // Identity mapped
+    vectors: Vec<Option<u8>>, // The vector of every entry
+}
+
+impl MsiX {
+    // Return the number of entries of the table
+    pub fn size(&self) -> u16 {
+        self.vectors.len() as u16
+    }
+
+    // Return the vector of an entry, if it has one
+    pub fn vector(&self, entry: u16) -> Option<u8> {
+        self.vectors.get(entry as usize).copied().flatten()
+    }
+
+    // Allocate a vector calling `handler` for an entry, program it and
+    // unmask the entry. Returns the vector; a previous one is freed.
+    pub fn set_handler(&mut self, entry: u16, handler: fn()) -> Result<u8, MsiError> {
+        if entry >= self.size() {
+            return Err(MsiError::InvalidEntry(entry));
+        }
+        self.clear(entry);
+        let vector = interrupts::allocate_vector(handler)?;
+        self.vectors[entry as usize] = Some(vector);
+
+        let (address, data) = apic::msi_message(vector);
+        unsafe {
+            self.write_entry(entry, MSIX_ENTRY_ADDRESS_LOW, address as u32);
+            self.write_entry(entry, MSIX_ENTRY_ADDRESS_HIGH, (address >> 32) as u32);
+            self.write_entry(entry, MSIX_ENTRY_DATA, data);
+            self.write_entry(entry, MSIX_ENTRY_CONTROL, 0);
+        }
+        Ok(vector)
+    }
+
+    // Mask an entry and free its vector
+    pub fn clear(&mut self, entry: u16) {
+        if let Some(vector) = self.vector(entry) {
+            self.mask(entry);
+            interrupts::free_vector(vector);
+            self.vectors[entry as usize] = None;
+        }
+    }
+
+    // Stop an entry from signalling; the device remembers pending messages
+    pub fn mask(&self, entry: u16) {
+        if entry < self.size() {
+            unsafe { self.write_entry(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED) };
+        }
+    }
+
+    // Let a masked entry signal again
+    pub fn unmask(&self, entry: u16) {
+        if self.vector(entry).is_some() {
+            unsafe { self.write_entry(entry, MSIX_ENTRY_CONTROL, 0) };
+        }
+    }
+
+    // Turn MSI-X off and free every vector
+    pub fn disable(mut self) {
+        for entry in 0..self.size() {
+            self.clear(entry);
+        }
+        let control = self.address.read_u16(self.capability + MSIX_CONTROL);
+        unsafe {
+            self.address
+                .write_u16(self.capability + MSIX_CONTROL, control & !MSIX_ENABLE)
+        };
+    }
+
+    unsafe fn write_entry(&self, entry: u16, register: usize, value: u32) {
+        let offset = entry as usize * MSIX_ENTRY_SIZE + register;
+        let register = (self.table + offset as u64).as_mut_ptr::<u32>();
+        core::ptr::write_volatile(register, value);
+    }
+}
+
+// Keep the function from asserting its INTx line
+fn disable_intx(address: PciAddress) {
+    let command = address.read_u16(COMMAND);
+    unsafe { address.write_u16(COMMAND, command | COMMAND_INTERRUPT_DISABLE) };
+}
@@ -0,0 +1,70 @@
+use x86_64::instructions::port::Port;
+
+// The input clock of the programmable interval timer in Hz
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+// I/O ports of the PIT
+const CHANNEL_0_PORT: u16 = 0x40;
+const CHANNEL_2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+
+// The keyboard controller port B, which gates PIT channel 2
+const PORT_B: u16 = 0x61;
+
+// The longest delay a single channel 2 countdown can measure
+const MAX_WAIT_MS: u32 = 50;
+
+// Program channel 0 to raise IRQ 0 at (approximately) the given frequency.
+pub fn set_frequency(frequency_hz: u32) {
+    let divisor = (PIT_FREQUENCY / frequency_hz).clamp(1, 0xFFFF) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        // Channel 0, low/high byte access, mode 3 (square wave generator)
+        command.write(0x36);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+// Busy-wait for the given number of milliseconds using PIT channel 2.
+//
+// This doesn't depend on interrupts, so it can be used to calibrate other
+// time sources (the APIC timer and the TSC) before they are running.
+pub fn wait_ms(ms: u32) {
+    let mut remaining = ms;
+    while remaining > 0 {
+        let chunk = remaining.min(MAX_WAIT_MS);
+        countdown(PIT_FREQUENCY * chunk / 1000);
+        remaining -= chunk;
+    }
+}
+
+// Let channel 2 count down from `count` and wait until it reaches zero.
+fn countdown(count: u32) {
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_2: Port<u8> = Port::new(CHANNEL_2_PORT);
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+
+    unsafe {
+        // Disable the speaker and close the gate of channel 2
+        let value = port_b.read() & !0x03;
+        port_b.write(value);
+
+        // Channel 2, low/high byte access, mode 0 (interrupt on terminal count)
+        command.write(0xB0);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        // Opening the gate starts the countdown
+        port_b.write(value | 0x01);
+
+        // Bit 5 reflects the output of channel 2, which goes high at zero
+        while port_b.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+
+        port_b.write(value);
+    }
+}
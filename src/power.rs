@@ -0,0 +1,207 @@
+use crate::{acpi, pit, println};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+// The ACPI shutdown ports of QEMU's default machine and of Bochs (and older
+// QEMU versions), and the value that requests the S5 (soft off) state
+const QEMU_SHUTDOWN_PORT: u16 = 0x604;
+const BOCHS_SHUTDOWN_PORT: u16 = 0xB004;
+const QEMU_SHUTDOWN_VALUE: u16 = 0x2000;
+
+// PM1 control register bits
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+// How long the firmware gets to switch to ACPI mode
+const ACPI_ENABLE_TIMEOUT_MS: u32 = 300;
+
+// How long each reset method gets before the next one is tried
+const RESET_WAIT_MS: u32 = 50;
+
+// The 8042 keyboard controller status/command port and its reset command
+const KBC_COMMAND_PORT: u16 = 0x64;
+const KBC_INPUT_BUFFER_FULL: u8 = 0x02;
+const KBC_RESET_CPU: u8 = 0xFE;
+
+// Number of status polls before giving up on the keyboard controller
+const KBC_POLL_LIMIT: u32 = 100_000;
+
+// The chipset reset control register and the values requesting a reset
+// (first selecting the type, then triggering it)
+const RESET_CONTROL_PORT: u16 = 0xCF9;
+const RESET_CONTROL_HARD: u8 = 0x02;
+const RESET_CONTROL_TRIGGER: u8 = 0x04;
+
+// A teardown hook run on orderly shutdown
+struct Teardown {
+    name: &'static str,
+    hook: fn(),
+}
+
+// Registered teardown hooks, in registration (i.e. init) order
+static TEARDOWN_HOOKS: Mutex<Vec<Teardown>> = Mutex::new(Vec::new());
+
+// Register a hook that is run when the system is shut down or rebooted in an
+// orderly way.
+//
+// Subsystems should register their hook when they are initialized; hooks run
+// in reverse registration order, so a subsystem is torn down before the
+// subsystems it depends on.
+pub fn register_teardown(name: &'static str, hook: fn()) {
+    interrupts::without_interrupts(|| {
+        TEARDOWN_HOOKS.lock().push(Teardown { name, hook });
+    });
+}
+
+// Run all teardown hooks and power off the machine.
+pub fn shutdown_orderly() -> ! {
+    run_teardown_hooks();
+    println!("power: powering off");
+    shutdown();
+}
+
+// Run all teardown hooks and reset the machine.
+pub fn reboot_orderly() -> ! {
+    run_teardown_hooks();
+    println!("power: rebooting");
+    reboot();
+}
+
+// Reset the machine immediately without running any teardown hooks.
+//
+// This is the fast path (`reboot -f`) for when the system is wedged and the
+// hooks themselves might hang.
+pub fn reboot_force() -> ! {
+    reboot();
+}
+
+// Run the registered teardown hooks in reverse init order.
+fn run_teardown_hooks() {
+    // Take the hooks out of the list, so that a hook registering or running
+    // another hook can't deadlock, and so that they only run once
+    let hooks = interrupts::without_interrupts(|| core::mem::take(&mut *TEARDOWN_HOOKS.lock()));
+
+    for teardown in hooks.iter().rev() {
+        println!("power: stopping {}", teardown.name);
+        (teardown.hook)();
+    }
+}
+
+// Switch the machine off right away, without running the teardown hooks.
+//
+// Enters the ACPI S5 state through the PM1 control registers of the FADT,
+// with the sleep type from the DSDT. Without ACPI tables, the shutdown ports
+// of QEMU and Bochs are tried. Halts if the machine is still running.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    match acpi_shutdown() {
+        // Powering off takes a moment
+        Ok(()) => pit::wait_ms(RESET_WAIT_MS),
+        Err(err) => println!("power: ACPI shutdown unavailable: {}", err),
+    }
+
+    for port in [QEMU_SHUTDOWN_PORT, BOCHS_SHUTDOWN_PORT] {
+        let mut port: Port<u16> = Port::new(port);
+        unsafe { port.write(QEMU_SHUTDOWN_VALUE) };
+    }
+
+    // Not running on a machine we know how to switch off
+    println!("power: poweroff failed, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Request the S5 sleep state through the FADT's PM1 control registers
+fn acpi_shutdown() -> Result<(), &'static str> {
+    let fadt = acpi::fadt().ok_or("no FADT")?;
+    if fadt.pm1a_control_block == 0 {
+        return Err("no PM1a control block");
+    }
+    let (sleep_type_a, sleep_type_b) = acpi::s5_sleep_types().ok_or("no \\_S5 object")?;
+
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    unsafe {
+        // The sleep registers only work in ACPI mode, which the firmware
+        // enables when asked through the SMI command port
+        if pm1a.read() & PM1_SCI_ENABLE == 0 && fadt.smi_command != 0 && fadt.acpi_enable != 0 {
+            Port::<u8>::new(fadt.smi_command as u16).write(fadt.acpi_enable);
+            for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+                if pm1a.read() & PM1_SCI_ENABLE != 0 {
+                    break;
+                }
+                pit::wait_ms(1);
+            }
+        }
+
+        let value = pm1a.read() & !(0x7 << PM1_SLEEP_TYPE_SHIFT);
+        pm1a.write(value | (sleep_type_a as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+            let value = pm1b.read() & !(0x7 << PM1_SLEEP_TYPE_SHIFT);
+            pm1b.write(value | (sleep_type_b as u16) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+        }
+    }
+    Ok(())
+}
+
+// Reset the machine right away, without running the teardown hooks.
+//
+// Tries the ACPI reset register, the 8042 keyboard controller and the
+// chipset's reset control register in turn, and finally forces a triple
+// fault.
+pub fn reboot() -> ! {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    interrupts::disable();
+
+    // The reset register only counts if it is an I/O port; memory-mapped
+    // ones aren't mapped this late
+    if let Some(fadt) = acpi::fadt() {
+        if let Some(register) = fadt.reset_register {
+            if register.address_space == acpi::ADDRESS_SPACE_IO {
+                unsafe { Port::<u8>::new(register.address as u16).write(fadt.reset_value) };
+                pit::wait_ms(RESET_WAIT_MS);
+            }
+        }
+    }
+
+    if acpi::fadt().map_or(true, |fadt| fadt.has_8042()) {
+        let mut command: Port<u8> = Port::new(KBC_COMMAND_PORT);
+        unsafe {
+            // Wait until the controller accepts a command
+            for _ in 0..KBC_POLL_LIMIT {
+                if command.read() & KBC_INPUT_BUFFER_FULL == 0 {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+            command.write(KBC_RESET_CPU);
+        }
+        pit::wait_ms(RESET_WAIT_MS);
+    }
+
+    let mut reset_control: Port<u8> = Port::new(RESET_CONTROL_PORT);
+    unsafe {
+        reset_control.write(RESET_CONTROL_HARD);
+        reset_control.write(RESET_CONTROL_HARD | RESET_CONTROL_TRIGGER);
+    }
+    pit::wait_ms(RESET_WAIT_MS);
+
+    // Loading an empty IDT turns the next exception into a triple fault
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe { lidt(&empty_idt) };
+    x86_64::instructions::interrupts::int3();
+
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
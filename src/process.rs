@@ -0,0 +1,154 @@
+// Processes: user programs with an address space of their own.
+//
+// A process owns a level 4 page table made by
+// `memory::create_address_space`, which shares the kernel's mappings, and
+// everything mapped in the rest of it: its program, loaded with `elf`, its
+// stack and the page tables holding them. It has a PID, a table of open
+// files and its threads. There is no scheduler yet, so a thread is only
+// the user context it starts with, and `run` runs the first one to its
+// exit system call with the process's address space active.
+//
+// `fork` copies a process, sharing its pages copy-on-write (see
+// `memory::cow`). There is no fork system call yet: the copy starts its
+// threads where the original started them, not where it is, since
+// `usermode` can't resume user code from saved registers.
+//
+// A process must be torn down with `destroy`, which gives every frame back
+// to the frame allocator; dropping it leaks them.
+
+use crate::elf::{self, ElfError};
+use crate::fd::FdTable;
+use crate::memory;
+use crate::usermode;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+// A process ID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+// The PID of the process `run` is running, 0 if none
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+// Return the process whose code is running, if any
+pub fn current() -> Option<Pid> {
+    match CURRENT.load(Ordering::Relaxed) {
+        0 => None,
+        pid => Some(Pid(pid)),
+    }
+}
+
+// Where a thread starts in user mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thread {
+    pub entry: VirtAddr,
+    pub stack_top: VirtAddr,
+}
+
+pub struct Process {
+    pid: Pid,
+    level_4_frame: PhysFrame,
+    files: FdTable,
+    threads: Vec<Thread>,
+}
+
+impl Process {
+    // Create a process running the executable at `path` with the
+    // arguments `argv`, in a new address space.
+    //
+    // This function is unsafe because `memory::init` must have been called.
+    pub unsafe fn create_from_elf<A>(
+        path: &str,
+        argv: &[&str],
+        frame_allocator: &mut A,
+    ) -> Result<Process, ElfError>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        let level_4_frame = memory::create_address_space(frame_allocator)
+            .ok_or(ElfError::Map(MapToError::FrameAllocationFailed))?;
+        let mut mapper = memory::address_space_mapper(level_4_frame);
+        let thread = elf::load(path, &mut mapper, frame_allocator).and_then(|mut image| {
+            let stack_top = image.setup_stack(argv, &mut mapper, frame_allocator)?;
+            Ok(Thread {
+                entry: image.entry,
+                stack_top,
+            })
+        });
+        match thread {
+            Ok(thread) => Ok(Process {
+                pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+                level_4_frame,
+                files: FdTable::new(),
+                threads: vec![thread],
+            }),
+            Err(err) => {
+                memory::free_address_space(level_4_frame, frame_allocator);
+                Err(err)
+            }
+        }
+    }
+
+    // Return a copy of the process with a new PID. It has the same threads
+    // and open files, and shares its pages until one of the two writes to
+    // them. Returns `None` if there are no frames left.
+    //
+    // This function is unsafe because the process must not be running.
+    pub unsafe fn fork<A>(&self, frame_allocator: &mut A) -> Option<Process>
+    where
+        A: FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>,
+    {
+        let level_4_frame = memory::clone_address_space(self.level_4_frame, frame_allocator)?;
+        Some(Process {
+            pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            level_4_frame,
+            files: self.files.clone(),
+            threads: self.threads.clone(),
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn files(&mut self) -> &mut FdTable {
+        &mut self.files
+    }
+
+    pub fn threads(&self) -> &[Thread] {
+        &self.threads
+    }
+
+    // Switch to the process's address space and run its first thread until
+    // it exits; returns the exit status. The kernel's address space is
+    // active again afterwards.
+    //
+    // This function is unsafe because it must be called from the kernel's
+    // address space, and only once: the thread's stack isn't set up again.
+    // A fork made before it runs can run the thread once more.
+    pub unsafe fn run(&mut self) -> u64 {
+        let thread = self.threads[0];
+        let (kernel, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+        CURRENT.store(self.pid.0, Ordering::Relaxed);
+        let status = usermode::run(thread.entry, thread.stack_top);
+        CURRENT.store(0, Ordering::Relaxed);
+        Cr3::write(kernel, flags);
+        status
+    }
+
+    // Free the process's frames: its pages, their page tables and its level
+    // 4 table. Returns the number of frames freed.
+    //
+    // This function is unsafe because the process must not be running.
+    pub unsafe fn destroy(self, frame_deallocator: &mut impl FrameDeallocator<Size4KiB>) -> usize {
+        memory::free_address_space(self.level_4_frame, frame_deallocator)
+    }
+}
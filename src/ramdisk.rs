@@ -0,0 +1,190 @@
+// Block devices kept in memory.
+//
+// A `RamDisk` stores its blocks either in a heap buffer, or, for disks
+// larger than the heap, in frames taken from the frame allocator. It can
+// start out blank or with a copy of an image, e.g. a filesystem made on the
+// host. An image can also be linked into the kernel by building with
+//
+//     RUST_OS_RAMDISK=path/to/disk.img cargo build
+//
+// It is linked into `.data`, so `init` can register it as "ram0" in place,
+// without copying it to the heap.
+
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memory;
+use crate::task;
+use alloc::string::ToString;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+
+const FRAME_SIZE: usize = 4096;
+
+// Defines `IMAGE`, the image linked into the kernel; empty if there is none
+include!(concat!(env!("OUT_DIR"), "/ramdisk.rs"));
+
+// Whether `IMAGE` has been handed out
+static IMAGE_TAKEN: AtomicBool = AtomicBool::new(false);
+
+// Where the blocks are stored
+enum Storage {
+    Heap(Vec<u8>),
+    Static(&'static mut [u8]),
+    // Frames in order, each holding `FRAME_SIZE / SECTOR_SIZE` blocks
+    Frames(Vec<PhysFrame>),
+}
+
+impl Storage {
+    // Call `f` with the pieces of the `len` bytes at `offset`, each
+    // contiguous in memory, and their offset into the request
+    fn for_each_piece(&mut self, offset: usize, len: usize, mut f: impl FnMut(&mut [u8], usize)) {
+        match self {
+            Storage::Heap(data) => f(&mut data[offset..offset + len], 0),
+            Storage::Static(data) => f(&mut data[offset..offset + len], 0),
+            Storage::Frames(frames) => {
+                let mut done = 0;
+                while done < len {
+                    let position = offset + done;
+                    let frame = frames[position / FRAME_SIZE];
+                    let start = position % FRAME_SIZE;
+                    let piece_len = (FRAME_SIZE - start).min(len - done);
+                    let piece = unsafe {
+                        let frame: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+                        core::slice::from_raw_parts_mut(frame.add(start), piece_len)
+                    };
+                    f(piece, done);
+                    done += piece_len;
+                }
+            }
+        }
+    }
+}
+
+// A block device in memory
+pub struct RamDisk {
+    storage: Mutex<Storage>,
+    blocks: u64,
+    read_only: bool,
+}
+
+impl RamDisk {
+    // Create a zeroed disk of `blocks` blocks on the heap
+    pub fn new(blocks: u64) -> RamDisk {
+        RamDisk {
+            storage: Mutex::new(Storage::Heap(vec![0; blocks as usize * SECTOR_SIZE])),
+            blocks,
+            read_only: false,
+        }
+    }
+
+    // Create a disk on the heap holding a copy of `image`, padded with zeros
+    // to a whole block
+    pub fn from_image(image: &[u8]) -> RamDisk {
+        let blocks = (image.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        let mut data = vec![0; blocks * SECTOR_SIZE];
+        data[..image.len()].copy_from_slice(image);
+        RamDisk {
+            storage: Mutex::new(Storage::Heap(data)),
+            blocks: blocks as u64,
+            read_only: false,
+        }
+    }
+
+    // Create a disk using `data` in place. A partial block at the end is
+    // left out.
+    pub fn from_static(data: &'static mut [u8]) -> RamDisk {
+        let blocks = (data.len() / SECTOR_SIZE) as u64;
+        RamDisk {
+            storage: Mutex::new(Storage::Static(data)),
+            blocks,
+            read_only: false,
+        }
+    }
+
+    // Create a zeroed disk of `blocks` blocks in frames from the frame
+    // allocator. Returns `None` if there aren't enough frames; the frames
+    // are never given back.
+    pub fn with_frames(
+        blocks: u64,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Option<RamDisk> {
+        let bytes = blocks as usize * SECTOR_SIZE;
+        let mut frames = Vec::with_capacity((bytes + FRAME_SIZE - 1) / FRAME_SIZE);
+        for _ in 0..frames.capacity() {
+            task::maybe_preempt();
+            let frame = frame_allocator.allocate_frame()?;
+            let start: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+            unsafe { core::ptr::write_bytes(start, 0, FRAME_SIZE) };
+            frames.push(frame);
+        }
+        Some(RamDisk {
+            storage: Mutex::new(Storage::Frames(frames)),
+            blocks,
+            read_only: false,
+        })
+    }
+
+    // Refuse writes to the disk
+    pub fn read_only(mut self) -> RamDisk {
+        self.read_only = true;
+        self
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let offset = lba as usize * SECTOR_SIZE;
+        self.storage.lock().for_each_piece(offset, buffer.len(), |piece, done| {
+            buffer[done..done + piece.len()].copy_from_slice(piece);
+        });
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let offset = lba as usize * SECTOR_SIZE;
+        self.storage.lock().for_each_piece(offset, buffer.len(), |piece, done| {
+            piece.copy_from_slice(&buffer[done..done + piece.len()]);
+        });
+        Ok(())
+    }
+}
+
+// Return the image linked into the kernel, if there is one. It is handed
+// out only once, since the caller may write to it.
+pub fn take_linked_image() -> Option<&'static mut [u8]> {
+    if IMAGE_TAKEN.swap(true, Ordering::AcqRel) {
+        return None;
+    }
+    // Only reached once, so this is the only reference
+    let image: &'static mut [u8] = unsafe { &mut *addr_of_mut!(IMAGE) };
+    if image.is_empty() {
+        None
+    } else {
+        Some(image)
+    }
+}
+
+// Register the image linked into the kernel as "ram0", if there is one.
+// Requires the heap.
+pub fn init() {
+    if let Some(image) = take_linked_image() {
+        let _ = block::register("ram0".to_string(), Arc::new(RamDisk::from_static(image)));
+    }
+}
@@ -0,0 +1,177 @@
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+// CMOS index and data ports
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// RTC registers
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0A;
+const REG_STATUS_B: u8 = 0x0B;
+
+// Status register flags
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+
+// Set in the hours register for PM times in 12 hour mode
+const HOUR_PM: u8 = 0x80;
+
+// Serializes accesses to the CMOS index/data port pair
+static CMOS: Mutex<()> = Mutex::new(());
+
+// A wall-clock date and time as reported by the RTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+// The raw register values of one RTC read
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+impl DateTime {
+    // Read the current date and time from the RTC.
+    //
+    // The RTC is updated once per second and reading it during an update can
+    // return inconsistent values, so the registers are read until two
+    // consecutive reads outside of an update agree.
+    pub fn now() -> DateTime {
+        interrupts::without_interrupts(|| {
+            let _guard = CMOS.lock();
+
+            let mut last = read_raw();
+            loop {
+                let current = read_raw();
+                if current == last {
+                    break;
+                }
+                last = current;
+            }
+
+            let status_b = read_register(REG_STATUS_B);
+            convert(last, status_b)
+        })
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// Read all time registers once no update is in progress.
+fn read_raw() -> RawTime {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    RawTime {
+        second: read_register(REG_SECONDS),
+        minute: read_register(REG_MINUTES),
+        hour: read_register(REG_HOURS),
+        day: read_register(REG_DAY),
+        month: read_register(REG_MONTH),
+        year: read_register(REG_YEAR),
+        century: read_register(REG_CENTURY),
+    }
+}
+
+// Convert raw register values to a `DateTime`, honoring the BCD and 12 hour
+// modes selected in status register B.
+fn convert(raw: RawTime, status_b: u8) -> DateTime {
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = decode(raw.hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is hour 0 and 12 PM is hour 12
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // Not every RTC has a century register; assume the 21st century then
+    let century = match decode(raw.century) {
+        0 => 20,
+        century => century as u16,
+    };
+
+    DateTime {
+        year: century * 100 + decode(raw.year) as u16,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+// Convert a binary-coded decimal value to binary.
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+#[test_case]
+fn test_bcd_to_binary() {
+    assert_eq!(bcd_to_binary(0x00), 0);
+    assert_eq!(bcd_to_binary(0x09), 9);
+    assert_eq!(bcd_to_binary(0x59), 59);
+}
+
+#[test_case]
+fn test_convert_12_hour_bcd() {
+    let raw = RawTime {
+        second: 0x30,
+        minute: 0x15,
+        hour: HOUR_PM | 0x12,
+        day: 0x31,
+        month: 0x12,
+        year: 0x23,
+        century: 0x20,
+    };
+    let time = convert(raw, 0);
+    assert_eq!(time.hour, 12);
+    assert_eq!(time.year, 2023);
+    assert_eq!(time.second, 30);
+
+    let midnight = RawTime { hour: 0x12, ..raw };
+    assert_eq!(convert(midnight, 0).hour, 0);
+}
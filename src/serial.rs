@@ -1,13 +1,53 @@
 use uart_16550::SerialPort; // Import the SerialPort trait from the uart_16550 crate.
 use spin::Mutex; // Import the Mutex type from the spin crate.
 use lazy_static::lazy_static; // Import the lazy_static macro from the lazy_static crate.
+use conquer_once::spin::OnceCell;
+use core::{pin::Pin, sync::atomic::{AtomicBool, AtomicU8, Ordering}, task::{Context, Poll}};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::instructions::{interrupts, port::Port};
+
+// The I/O base port and IRQ line of COM1
+const COM1_BASE: u16 = 0x3F8;
+pub const COM1_IRQ: u8 = 4;
+
+// Offsets of the receive buffer and line status registers
+const DATA_REGISTER: u16 = 0;
+const LINE_STATUS_REGISTER: u16 = 5;
+
+// Line status bit set while a received byte is waiting
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+// Line status bit set if the waiting byte is the NUL of a break
+const LINE_STATUS_BREAK: u8 = 1 << 4;
+
+// Number of received bytes buffered until they are read
+const INPUT_QUEUE_SIZE: usize = 256;
+
+// Bytes received on COM1, filled by the interrupt handler
+static INPUT_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+// Wakes the task waiting for serial input
+static INPUT_WAKER: AtomicWaker = AtomicWaker::new();
+
+// Number of bytes buffered for a `RawInput`, enough for an XMODEM-1K block
+const RAW_QUEUE_SIZE: usize = 2048;
+
+// Bytes received on COM1 while a `RawInput` exists
+static RAW_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+// Whether a `RawInput` exists
+static RAW_MODE: AtomicBool = AtomicBool::new(false);
+
+// Whether a break was received, making the next byte a SysRq key. This is
+// how the host asks for the `sysrq` state dump (see there).
+static SYSRQ_ARMED: AtomicBool = AtomicBool::new(false);
 
 // Define a lazy static global variable named SERIAL1, which is a Mutex wrapping a SerialPort.
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
         // Create a new SerialPort instance at I/O port 0x3F8.
         let mut serial_port = unsafe {
-            SerialPort::new(0x3F8)
+            SerialPort::new(COM1_BASE)
         };
         // Initialize the serial port.
         serial_port.init();
@@ -16,12 +56,229 @@ lazy_static! {
     };
 }
 
+// Define a second serial port at I/O port 0x2F8 (COM2), used as a dedicated
+// machine-readable channel (e.g. for telemetry) next to the console on COM1.
+lazy_static! {
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe {
+            SerialPort::new(ComPort::Com2.base())
+        };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+// The third and fourth serial ports, only usable if `Manager::probe` found
+// them. They share IRQs 4 and 3 with COM1 and COM2.
+lazy_static! {
+    pub static ref SERIAL3: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(ComPort::Com3.base()) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+lazy_static! {
+    pub static ref SERIAL4: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(ComPort::Com4.base()) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+// Offsets of the line control, divisor latch and scratch registers
+const LINE_CONTROL_REGISTER: u16 = 3;
+const INTERRUPT_ENABLE_REGISTER: u16 = 1; // The divisor's high byte while DLAB is set
+const SCRATCH_REGISTER: u16 = 7;
+
+// Line control bit selecting the divisor latch registers
+const LINE_CONTROL_DLAB: u8 = 1 << 7;
+
+// The UART's input clock divided by 16; the fastest possible baud rate
+const MAX_BAUD_RATE: u32 = 115_200;
+
+// The baud rate ports are initialized with
+pub const DEFAULT_BAUD_RATE: u32 = 38_400;
+
+// The standard PC serial ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    pub const ALL: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+    // Return the I/O base port
+    pub fn base(self) -> u16 {
+        match self {
+            ComPort::Com1 => COM1_BASE,
+            ComPort::Com2 => 0x2F8,
+            ComPort::Com3 => 0x3E8,
+            ComPort::Com4 => 0x2E8,
+        }
+    }
+
+    // Return the port driver, initializing the port on first use
+    fn port(self) -> &'static Mutex<SerialPort> {
+        match self {
+            ComPort::Com1 => &SERIAL1,
+            ComPort::Com2 => &SERIAL2,
+            ComPort::Com3 => &SERIAL3,
+            ComPort::Com4 => &SERIAL4,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// What a serial port is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    // Kernel log output
+    Log,
+    // A GDB remote debugging stub
+    Gdb,
+}
+
+const ROLE_COUNT: usize = 2;
+
+// Errors from opening or routing serial ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerialError {
+    // No UART responded at the port's address
+    NotPresent,
+    // The baud rate can't be derived from the UART clock
+    InvalidBaudRate(u32),
+    // The port has to be opened first
+    NotOpen,
+}
+
+// Keeps track of the serial ports: which exist, which are open at what baud
+// rate, and which one each role uses.
+//
+//     let mut serial = serial::MANAGER.lock();
+//     serial.probe();
+//     serial.open(ComPort::Com2, 115_200)?;
+//     serial.route(Role::Gdb, ComPort::Com2)?;
+pub struct Manager {
+    present: [Option<bool>; 4], // None until probed
+    baud_rates: [Option<u32>; 4], // Some once opened
+}
+
+impl Manager {
+    const fn new() -> Self {
+        Manager {
+            // COM1 is opened as the console before the manager exists
+            present: [Some(true), None, None, None],
+            baud_rates: [Some(DEFAULT_BAUD_RATE), None, None, None],
+        }
+    }
+
+    // Check which ports exist and return them
+    pub fn probe(&mut self) -> impl Iterator<Item = ComPort> + '_ {
+        for port in ComPort::ALL {
+            if self.present[port.index()].is_none() {
+                self.present[port.index()] = Some(probe(port));
+            }
+        }
+        ComPort::ALL.into_iter().filter(move |port| self.is_present(*port))
+    }
+
+    // Return whether a UART was found at the port's address
+    pub fn is_present(&mut self, port: ComPort) -> bool {
+        *self.present[port.index()].get_or_insert_with(|| probe(port))
+    }
+
+    // Open a port with the given baud rate, or change the baud rate of an
+    // open port
+    pub fn open(&mut self, port: ComPort, baud_rate: u32) -> Result<&'static Mutex<SerialPort>, SerialError> {
+        let divisor = baud_divisor(baud_rate).ok_or(SerialError::InvalidBaudRate(baud_rate))?;
+        if !self.is_present(port) {
+            return Err(SerialError::NotPresent);
+        }
+
+        let serial_port = port.port();
+        interrupts::without_interrupts(|| {
+            // Hold the lock so nothing is sent while the divisor changes
+            let _guard = serial_port.lock();
+            unsafe { set_divisor(port.base(), divisor) };
+        });
+        self.baud_rates[port.index()] = Some(baud_rate);
+        Ok(serial_port)
+    }
+
+    // Return the baud rate of an open port
+    pub fn baud_rate(&self, port: ComPort) -> Option<u32> {
+        self.baud_rates[port.index()]
+    }
+
+    // Use an open port for the given role
+    pub fn route(&mut self, role: Role, port: ComPort) -> Result<(), SerialError> {
+        if self.baud_rates[port.index()].is_none() {
+            return Err(SerialError::NotOpen);
+        }
+        ROUTES[role as usize].store(port as u8, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+pub static MANAGER: Mutex<Manager> = Mutex::new(Manager::new());
+
+// The port index used for each role. Kept outside the manager so logging
+// works while its lock is held.
+static ROUTES: [AtomicU8; ROLE_COUNT] = [AtomicU8::new(0), AtomicU8::new(0)];
+
+// Return the port used for the given role
+pub fn routed(role: Role) -> ComPort {
+    ComPort::ALL[ROUTES[role as usize].load(Ordering::Relaxed) as usize]
+}
+
+// Return the driver of the port used for the given role
+pub fn port_for(role: Role) -> &'static Mutex<SerialPort> {
+    routed(role).port()
+}
+
+// Check for a UART by writing and reading back its scratch register
+fn probe(port: ComPort) -> bool {
+    let mut scratch: Port<u8> = Port::new(port.base() + SCRATCH_REGISTER);
+    [0x55, 0xAA].iter().all(|&pattern| unsafe {
+        scratch.write(pattern);
+        scratch.read() == pattern
+    })
+}
+
+// Return the divisor latch value for a baud rate
+fn baud_divisor(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || MAX_BAUD_RATE % baud_rate != 0 {
+        return None;
+    }
+    u16::try_from(MAX_BAUD_RATE / baud_rate).ok()
+}
+
+// Program the baud rate divisor, keeping the line settings
+unsafe fn set_divisor(base: u16, divisor: u16) {
+    let mut line_control: Port<u8> = Port::new(base + LINE_CONTROL_REGISTER);
+    let mut divisor_low: Port<u8> = Port::new(base + DATA_REGISTER);
+    let mut divisor_high: Port<u8> = Port::new(base + INTERRUPT_ENABLE_REGISTER);
+
+    let settings = line_control.read();
+    line_control.write(settings | LINE_CONTROL_DLAB);
+    divisor_low.write(divisor as u8);
+    divisor_high.write((divisor >> 8) as u8);
+    line_control.write(settings & !LINE_CONTROL_DLAB);
+}
+
 // Define a hidden function _print that takes a formatting argument and writes it to SERIAL1.
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     // Import the Write trait from core::fmt and write the formatted arguments to SERIAL1.
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
         // Lock the SERIAL1 Mutex and write the formatted arguments.
@@ -29,6 +286,16 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+// Write formatted arguments to the port used for the given role
+#[doc(hidden)]
+pub fn _print_to(role: Role, args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+
+    interrupts::without_interrupts(|| {
+        let _ = port_for(role).lock().write_fmt(args);
+    });
+}
+
 // Define a macro serial_print that prints formatted arguments to the serial port.
 #[macro_export]
 macro_rules! serial_print {
@@ -50,3 +317,168 @@ macro_rules! serial_println {
         concat!($fmt, "\n"), $($arg)* 
     ));
 }
+
+// Start receiving on COM1. The port's receive interrupt is enabled when it
+// is initialized; this registers the handler for its IRQ line and asks the
+// terminal to mark pasted text, which the handler copies to the clipboard.
+pub fn init_input() {
+    lazy_static::initialize(&SERIAL1);
+    crate::interrupts::register_irq(COM1_IRQ, serial_interrupt_handler);
+    crate::clipboard::enable_serial_bracketed_paste();
+}
+
+// Called on IRQ 4; moves the received bytes into the input queue
+fn serial_interrupt_handler() {
+    // Hold the port lock so output isn't interleaved with the register reads
+    let port = SERIAL1.lock();
+    let mut line_status: Port<u8> = Port::new(COM1_BASE + LINE_STATUS_REGISTER);
+    let mut data: Port<u8> = Port::new(COM1_BASE + DATA_REGISTER);
+    let mut sysrq_key = None;
+
+    loop {
+        let status = unsafe { line_status.read() };
+        if status & LINE_STATUS_DATA_READY == 0 {
+            break;
+        }
+        let byte = unsafe { data.read() };
+        if RAW_MODE.load(Ordering::Acquire) {
+            if let Ok(queue) = RAW_QUEUE.try_get() {
+                // A full queue loses the byte; the transfer protocol retries
+                let _ = queue.push(byte);
+            }
+            continue;
+        }
+        if status & LINE_STATUS_BREAK != 0 {
+            SYSRQ_ARMED.store(true, Ordering::Relaxed);
+            continue;
+        }
+        if SYSRQ_ARMED.swap(false, Ordering::Relaxed) {
+            sysrq_key = Some(byte);
+            continue;
+        }
+        // Bytes pasted by the terminal go to the clipboard on the way
+        crate::clipboard::filter_serial_byte(byte, add_byte);
+    }
+
+    // The action may write to the port
+    drop(port);
+    if let Some(byte) = sysrq_key {
+        crate::sysrq::handle_serial_key(byte);
+    }
+}
+
+// Queue a received byte and wake the reader. Must not block or allocate,
+// since it runs in interrupt context.
+fn add_byte(byte: u8) {
+    crate::input::report(crate::input::DeviceId::SERIAL, crate::input::InputEvent::Char(byte as char));
+
+    if let Ok(queue) = INPUT_QUEUE.try_get() {
+        if queue.push(byte).is_err() {
+            // Can't log here: the serial log sink would deadlock on the port
+            crate::println!("WARNING: serial input queue full; dropping input");
+        } else {
+            INPUT_WAKER.wake();
+        }
+    }
+    // Input arriving before anyone reads it is dropped
+}
+
+// An asynchronous stream of the bytes received on COM1
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    // Create the stream. There can only be one, since every byte is
+    // delivered once. Requires the heap.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        INPUT_QUEUE
+            .try_init_once(|| ArrayQueue::new(INPUT_QUEUE_SIZE))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = INPUT_QUEUE.try_get().expect("input queue not initialized");
+
+        // Fast path
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        // Register before checking again, so a byte arriving in between
+        // isn't missed
+        INPUT_WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                INPUT_WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+// Exclusive access to the bytes received on COM1, for binary transfers.
+//
+// While it exists, received bytes bypass the clipboard and aren't reported
+// as input events, so neither the shell nor anything else sees them.
+pub struct RawInput {
+    _private: (),
+}
+
+impl RawInput {
+    // Take over the received bytes; `None` if someone else has them.
+    // Requires the heap.
+    pub fn take() -> Option<RawInput> {
+        let _ = RAW_QUEUE.try_init_once(|| ArrayQueue::new(RAW_QUEUE_SIZE));
+        if RAW_MODE.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let input = RawInput { _private: () };
+        input.purge();
+        Some(input)
+    }
+
+    // Return the next received byte, if any
+    pub fn read_byte(&self) -> Option<u8> {
+        RAW_QUEUE.try_get().ok().and_then(|queue| queue.pop())
+    }
+
+    // Drop the bytes received so far
+    pub fn purge(&self) {
+        while self.read_byte().is_some() {}
+    }
+
+    // Send bytes on COM1 as they are
+    pub fn write(&self, bytes: &[u8]) {
+        interrupts::without_interrupts(|| {
+            let mut port = SERIAL1.lock();
+            for &byte in bytes {
+                port.send_raw(byte);
+            }
+        });
+    }
+}
+
+impl Drop for RawInput {
+    fn drop(&mut self) {
+        RAW_MODE.store(false, Ordering::Release);
+    }
+}
+
+#[test_case]
+fn test_baud_divisor() {
+    assert_eq!(baud_divisor(115_200), Some(1));
+    assert_eq!(baud_divisor(38_400), Some(3));
+    assert_eq!(baud_divisor(9_600), Some(12));
+    assert_eq!(baud_divisor(50), Some(2304));
+    assert_eq!(baud_divisor(0), None);
+    assert_eq!(baud_divisor(230_400), None);
+    assert_eq!(baud_divisor(100_000), None);
+}
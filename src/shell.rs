@@ -0,0 +1,241 @@
+// An interactive shell on terminal 1.
+//
+// The shell runs as a task on the executor. It reads characters from the
+// keyboard, while its terminal is focused, and from the serial console, and
+// runs a command for every line. Commands are looked up among the built-in
+// ones and the ones other modules add with `register`; a handler gets the
+// words of the line after the command name and prints with `shell_print!`
+// and `shell_println!`.
+
+use crate::console;
+use crate::input::{self, DeviceId, Filter, InputEvent};
+use crate::task;
+use alloc::vec::Vec;
+use core::fmt;
+use futures_util::stream::StreamExt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+mod commands;
+mod line;
+
+pub use line::{HISTORY_SIZE, MAX_LINE};
+use line::{LineBuffer, LineEditor, Outcome};
+
+// Like `print!`, but to the shell's terminal
+#[macro_export]
+macro_rules! shell_print {
+    ($($arg:tt)*) => ($crate::console_print!($crate::shell::TTY, $($arg)*));
+}
+
+// Like `println!`, but to the shell's terminal
+#[macro_export]
+macro_rules! shell_println {
+    () => ($crate::shell_print!("\n"));
+    ($($arg:tt)*) => ($crate::console_println!($crate::shell::TTY, $($arg)*));
+}
+
+// The terminal the shell runs on
+pub const TTY: usize = 1;
+
+// The most arguments a command gets; the rest of the line is dropped
+const MAX_ARGS: usize = 16;
+
+const PROMPT: &str = "> ";
+
+// A command handler, called with the arguments after the command name
+pub type Handler = fn(args: &[&str]);
+
+// Returned by `register` when a command with the name exists already
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyRegistered;
+
+// The commands every shell has
+const BUILTINS: &[(&str, Handler)] = &[
+    ("help", help),
+    ("clear", clear),
+    ("echo", echo),
+    ("panic", panic),
+    ("meminfo", commands::meminfo),
+    ("lspci", commands::lspci),
+    ("heartbeat", commands::heartbeat),
+    ("telemetry", commands::telemetry),
+    ("fault", commands::fault),
+    ("fat", commands::fat),
+    ("ls", commands::ls),
+    ("cat", commands::cat),
+    ("mkdir", commands::mkdir),
+    ("rm", commands::rm),
+    ("sync", commands::sync),
+    ("rx", commands::rx),
+];
+
+// The commands added by other modules
+static COMMANDS: Mutex<Vec<(&'static str, Handler)>> = Mutex::new(Vec::new());
+
+// Add a command to the shell
+pub fn register(name: &'static str, handler: Handler) -> Result<(), AlreadyRegistered> {
+    interrupts::without_interrupts(|| {
+        let mut commands = COMMANDS.lock();
+        if find_builtin(name).is_some() || commands.iter().any(|&(n, _)| n == name) {
+            return Err(AlreadyRegistered);
+        }
+        commands.push((name, handler));
+        Ok(())
+    })
+}
+
+fn find_builtin(name: &str) -> Option<Handler> {
+    BUILTINS.iter().find(|&&(n, _)| n == name).map(|&(_, handler)| handler)
+}
+
+// Return the handler of the command with the given name
+fn find(name: &str) -> Option<Handler> {
+    find_builtin(name).or_else(|| {
+        interrupts::without_interrupts(|| {
+            COMMANDS.lock().iter().find(|&&(n, _)| n == name).map(|&(_, handler)| handler)
+        })
+    })
+}
+
+// Run the shell. Spawn this on the executor once; it never returns.
+pub async fn run() {
+    // The shell echoes what it accepts itself
+    console::set_echo(TTY, false);
+    console::switch_to(TTY);
+
+    let mut events = input::subscribe(Filter::All);
+    let mut editor = LineEditor::new();
+    shell_print!("{}", PROMPT);
+
+    while let Some((device, event)) = events.next().await {
+        // Input arrives in bursts when text is pasted
+        task::maybe_yield().await;
+        // Keys typed on other terminals aren't meant for the shell
+        if device == DeviceId::KEYBOARD && console::active() != TTY {
+            continue;
+        }
+        let edit = match event {
+            InputEvent::Char(character) => editor.edit_for_char(character, device == DeviceId::SERIAL),
+            // Keys that type nothing, like the arrows
+            InputEvent::Key(key) if key.pressed && key.character.is_none() => {
+                line::edit_for_key(key.code)
+            }
+            _ => None,
+        };
+        let edit = match edit {
+            Some(edit) => edit,
+            None => continue,
+        };
+
+        let old_cursor = editor.line().cursor();
+        match editor.apply(edit) {
+            Outcome::Unchanged => {}
+            Outcome::Changed => redraw(editor.line(), old_cursor),
+            Outcome::Submit => {
+                shell_println!();
+                execute(editor.line().as_str());
+                editor.clear_line();
+                shell_print!("{}", PROMPT);
+            }
+            Outcome::Cancel => {
+                shell_println!("^C");
+                shell_print!("{}", PROMPT);
+            }
+        }
+    }
+}
+
+// Redraw the line after the prompt, with the cursor at `old_cursor`
+// characters into it
+fn redraw(line: &LineBuffer, old_cursor: usize) {
+    if old_cursor > 0 {
+        shell_print!("\x1b[{}D", old_cursor);
+    }
+    shell_print!("{}\x1b[K", line.as_str());
+    let back = line.len() - line.cursor();
+    if back > 0 {
+        shell_print!("\x1b[{}D", back);
+    }
+}
+
+// Run the command on a line
+fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return,
+    };
+
+    let mut args = [""; MAX_ARGS];
+    let mut count = 0;
+    for word in words.take(MAX_ARGS) {
+        args[count] = word;
+        count += 1;
+    }
+
+    match find(name) {
+        Some(handler) => handler(&args[..count]),
+        None => shell_println!("{}: command not found", name),
+    }
+}
+
+fn help(_args: &[&str]) {
+    let mut names: Vec<&str> = BUILTINS.iter().map(|&(name, _)| name).collect();
+    interrupts::without_interrupts(|| {
+        names.extend(COMMANDS.lock().iter().map(|&(name, _)| name));
+    });
+    names.sort_unstable();
+
+    shell_println!("commands:");
+    for name in names {
+        shell_println!("  {}", name);
+    }
+}
+
+fn clear(_args: &[&str]) {
+    // Erase the terminal and move the cursor to the top left
+    shell_print!("\x1b[2J\x1b[H");
+}
+
+fn echo(args: &[&str]) {
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            shell_print!(" ");
+        }
+        shell_print!("{}", arg);
+    }
+    shell_println!();
+}
+
+fn panic(args: &[&str]) {
+    if args.is_empty() {
+        panic!("panic requested from the shell");
+    }
+    // Rebuild the message from the words
+    let mut message = alloc::string::String::new();
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            message.push(' ');
+        }
+        message.push_str(arg);
+    }
+    panic!("{}", message);
+}
+
+// Writes to the shell's terminal, for functions taking a `fmt::Write`
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::console_print!(TTY, "{}", s);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_find() {
+    assert!(find("help").is_some());
+    assert!(find("no-such-command").is_none());
+    assert_eq!(register("help", help), Err(AlreadyRegistered));
+}
@@ -0,0 +1,278 @@
+// Shell commands showing the state of other subsystems
+
+use crate::fat::{FatError, Volume};
+use crate::serial::RawInput;
+use crate::vfs::{self, FileType, VfsError};
+use crate::xmodem::{self, SerialChannel};
+use crate::{allocator, block, heartbeat, memory, pci, telemetry};
+use alloc::string::String;
+
+// The largest file `fat cat` prints
+const MAX_CAT_SIZE: u32 = 64 * 1024;
+
+pub(super) fn meminfo(_args: &[&str]) {
+    shell_println!("{}", memory::frame_stats());
+    shell_println!("{}", allocator::stats());
+}
+
+pub(super) fn lspci(_args: &[&str]) {
+    for device in pci::devices() {
+        shell_println!("{}", device.listing());
+    }
+}
+
+pub(super) fn heartbeat(args: &[&str]) {
+    match args {
+        [] => shell_println!("heartbeat: {}", heartbeat::mode().name()),
+        [name] => match heartbeat::Mode::from_name(name) {
+            Some(mode) => heartbeat::set_mode(mode),
+            None => shell_println!("usage: heartbeat [off|vga|led]"),
+        },
+        _ => shell_println!("usage: heartbeat [off|vga|led]"),
+    }
+}
+
+pub(super) fn telemetry(args: &[&str]) {
+    const USAGE: &str = "usage: telemetry [off | json|csv [interval_ms]]";
+    let (format, interval) = match *args {
+        [] => {
+            let state = if telemetry::is_enabled() { "on" } else { "off" };
+            shell_println!("telemetry: {}", state);
+            return;
+        }
+        ["off"] => {
+            telemetry::disable();
+            return;
+        }
+        [format] => (format, Ok(1000)),
+        [format, interval] => (format, interval.parse()),
+        _ => ("", Ok(0)),
+    };
+    let format = match format {
+        "json" => telemetry::Format::Json,
+        "csv" => telemetry::Format::Csv,
+        _ => {
+            shell_println!("{}", USAGE);
+            return;
+        }
+    };
+    match interval {
+        Ok(interval) => telemetry::enable(format, interval),
+        Err(_) => shell_println!("{}", USAGE),
+    }
+}
+
+pub(super) fn fault(args: &[&str]) {
+    #[cfg(not(feature = "fault-injection"))]
+    {
+        let _ = args;
+        shell_println!("fault: built without the fault-injection feature");
+    }
+    #[cfg(feature = "fault-injection")]
+    {
+        use crate::fault::{self, Site};
+
+        match args {
+            [] => {
+                for site in Site::ALL {
+                    match fault::pending(site) {
+                        Some(n) => shell_print!("{}: fails in {}", site.name(), n),
+                        None => shell_print!("{}: off", site.name()),
+                    }
+                    shell_println!(", {} injected", fault::injected(site));
+                }
+            }
+            ["off"] => fault::clear(),
+            [name, n] => match (Site::from_name(name), n.parse()) {
+                (Some(site), Ok(n)) => fault::fail_nth(site, n),
+                _ => shell_println!("usage: fault [off | frame|heap|block <n>]"),
+            },
+            _ => shell_println!("usage: fault [off | frame|heap|block <n>]"),
+        }
+    }
+}
+
+pub(super) fn fat(args: &[&str]) {
+    const USAGE: &str = "usage: fat <device> ls [path] | fat <device> cat <path>";
+    let (device, command, path) = match *args {
+        [device, command] => (device, command, "/"),
+        [device, command, path] => (device, command, path),
+        _ => {
+            shell_println!("{}", USAGE);
+            return;
+        }
+    };
+    let volume = match block::find(device).map(Volume::mount) {
+        Some(Ok(volume)) => volume,
+        Some(Err(err)) => {
+            shell_println!("fat: can't mount {}: {:?}", device, err);
+            return;
+        }
+        None => {
+            shell_println!("fat: no block device {}", device);
+            return;
+        }
+    };
+    let result = match command {
+        "ls" => volume.read_dir(path).map(|entries| {
+            for entry in entries {
+                if entry.is_dir {
+                    shell_println!("{:>10} {}/", "", entry.name);
+                } else {
+                    shell_println!("{:>10} {}", entry.size, entry.name);
+                }
+            }
+        }),
+        "cat" => fat_cat(&volume, path),
+        _ => {
+            shell_println!("{}", USAGE);
+            Ok(())
+        }
+    };
+    if let Err(err) = result {
+        shell_println!("fat: {}: {:?}", path, err);
+    }
+}
+
+// Print a file through a buffer on the stack
+fn fat_cat(volume: &Volume, path: &str) -> Result<(), FatError> {
+    let entry = volume.find(path)?;
+    if entry.size > MAX_CAT_SIZE {
+        shell_println!("fat: {} has {} bytes, too many to print", path, entry.size);
+        return Ok(());
+    }
+    let mut buffer = [0; 4096];
+    let mut offset = 0;
+    loop {
+        let len = volume.read(&entry, offset, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        shell_print!("{}", String::from_utf8_lossy(&buffer[..len]));
+        offset += len as u64;
+    }
+    shell_println!();
+    Ok(())
+}
+
+pub(super) fn ls(args: &[&str]) {
+    let path = match *args {
+        [] => "/",
+        [path] => path,
+        _ => {
+            shell_println!("usage: ls [path]");
+            return;
+        }
+    };
+    match vfs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry.metadata.file_type {
+                    FileType::File => shell_println!("{:>10} {}", entry.metadata.size, entry.name),
+                    FileType::Directory => shell_println!("{:>10} {}/", "", entry.name),
+                    FileType::Symlink => shell_println!("{:>10} {}@", "", entry.name),
+                }
+            }
+        }
+        Err(err) => shell_println!("ls: {}: {:?}", path, err),
+    }
+}
+
+pub(super) fn cat(args: &[&str]) {
+    if args.is_empty() {
+        shell_println!("usage: cat <path>...");
+    }
+    for path in args {
+        if let Err(err) = print_file(path) {
+            shell_println!("cat: {}: {:?}", path, err);
+        }
+    }
+}
+
+// Print a file from the VFS through a buffer on the stack
+fn print_file(path: &str) -> Result<(), VfsError> {
+    let mut buffer = [0; 4096];
+    let mut offset = 0;
+    loop {
+        let len = vfs::read(path, offset, &mut buffer)?;
+        if len == 0 {
+            return Ok(());
+        }
+        shell_print!("{}", String::from_utf8_lossy(&buffer[..len]));
+        offset += len as u64;
+    }
+}
+
+pub(super) fn mkdir(args: &[&str]) {
+    if args.is_empty() {
+        shell_println!("usage: mkdir <path>...");
+    }
+    for path in args {
+        if let Err(err) = vfs::create_dir(path) {
+            shell_println!("mkdir: {}: {:?}", path, err);
+        }
+    }
+}
+
+pub(super) fn rm(args: &[&str]) {
+    if args.is_empty() {
+        shell_println!("usage: rm <path>...");
+    }
+    for path in args {
+        if let Err(err) = vfs::remove(path) {
+            shell_println!("rm: {}: {:?}", path, err);
+        }
+    }
+}
+
+// Write the cached disk blocks back
+pub(super) fn sync(_args: &[&str]) {
+    match block::cache::sync() {
+        Ok(written) => shell_println!("sync: {} blocks written", written),
+        Err(err) => shell_println!("sync: {:?}", err),
+    }
+    let stats = block::cache::stats();
+    shell_println!(
+        "block cache: {} blocks, {} dirty, {} hits, {} misses",
+        stats.blocks,
+        stats.dirty,
+        stats.hits,
+        stats.misses
+    );
+}
+
+// Receive a file with XMODEM on COM1
+pub(super) fn rx(args: &[&str]) {
+    let path = match *args {
+        [path] => path,
+        _ => {
+            shell_println!("usage: rx <path>");
+            return;
+        }
+    };
+    if let Err(err) = vfs::create(path) {
+        shell_println!("rx: {}: {:?}", path, err);
+        return;
+    }
+    let input = match RawInput::take() {
+        Some(input) => input,
+        None => {
+            shell_println!("rx: COM1 is in use");
+            return;
+        }
+    };
+    shell_println!("rx: send {} with XMODEM on COM1 now", path);
+
+    let mut offset = 0;
+    let result = xmodem::receive(&mut SerialChannel::new(input), |data| {
+        if vfs::write(path, offset, data)? < data.len() {
+            return Err(VfsError::NoSpace);
+        }
+        offset += data.len() as u64;
+        Ok(())
+    });
+    match result {
+        Ok(size) => shell_println!("rx: received {} bytes", size),
+        Err(err) => shell_println!("rx: {}: {:?}", path, err),
+    }
+}
@@ -0,0 +1,426 @@
+// Line editing and history for the shell.
+//
+// Keys arrive either as characters (typed keys, control characters and the
+// escape sequences a serial terminal sends for its arrow keys) or, for keys
+// that type nothing like the arrows, as key codes from the keyboard. Both
+// are turned into `Edit`s, which the `LineEditor` applies to the line:
+//
+//     Left/Right, Ctrl+B/Ctrl+F  move the cursor
+//     Home/End, Ctrl+A/Ctrl+E    move to the start or end of the line
+//     Backspace, Delete          delete before or under the cursor
+//     Ctrl+K, Ctrl+U             delete to the end or the start of the line
+//     Up/Down, Ctrl+P/Ctrl+N     recall older or newer lines
+//     Ctrl+C                     drop the line
+
+use crate::vga_buffer::BUFFER_WIDTH;
+use pc_keyboard::KeyCode;
+
+// The longest line the shell accepts. The line fits on the row of the
+// prompt, since `redraw` only moves the cursor within one row.
+pub const MAX_LINE: usize = BUFFER_WIDTH - super::PROMPT.len();
+
+// Number of lines kept in the history
+pub const HISTORY_SIZE: usize = 16;
+
+// A change to the line being edited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Edit {
+    Insert(char),
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    KillToEnd,
+    KillToStart,
+    Older,
+    Newer,
+    Enter,
+    Cancel,
+}
+
+// What applying an edit did
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Outcome {
+    Unchanged,
+    // The line or the cursor changed and must be redrawn
+    Changed,
+    // The line is complete
+    Submit,
+    // The line was dropped
+    Cancel,
+}
+
+// Return the edit of a key that types nothing
+pub(super) fn edit_for_key(code: KeyCode) -> Option<Edit> {
+    match code {
+        KeyCode::ArrowLeft => Some(Edit::Left),
+        KeyCode::ArrowRight => Some(Edit::Right),
+        KeyCode::ArrowUp => Some(Edit::Older),
+        KeyCode::ArrowDown => Some(Edit::Newer),
+        KeyCode::Home => Some(Edit::Home),
+        KeyCode::End => Some(Edit::End),
+        _ => None,
+    }
+}
+
+// A line of printable ASCII with a cursor. Fixed size, so it doesn't need
+// the heap.
+#[derive(Clone, Copy)]
+pub(super) struct LineBuffer {
+    bytes: [u8; MAX_LINE],
+    len: usize,
+    cursor: usize,
+}
+
+impl LineBuffer {
+    pub(super) const fn new() -> LineBuffer {
+        LineBuffer {
+            bytes: [0; MAX_LINE],
+            len: 0,
+            cursor: 0,
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub(super) fn as_str(&self) -> &str {
+        // Only printable ASCII is inserted
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("")
+    }
+
+    // Insert a character at the cursor. Returns `false` if it is not
+    // printable ASCII or the line is full.
+    fn insert(&mut self, character: char) -> bool {
+        if !(' '..='~').contains(&character) || self.len == MAX_LINE {
+            return false;
+        }
+        self.bytes.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.bytes[self.cursor] = character as u8;
+        self.len += 1;
+        self.cursor += 1;
+        true
+    }
+
+    // Remove the character before the cursor
+    fn backspace(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.cursor -= 1;
+        self.delete()
+    }
+
+    // Remove the character under the cursor
+    fn delete(&mut self) -> bool {
+        if self.cursor == self.len {
+            return false;
+        }
+        self.bytes.copy_within(self.cursor + 1..self.len, self.cursor);
+        self.len -= 1;
+        true
+    }
+
+    // Move the cursor, returning whether it moved
+    fn move_to(&mut self, cursor: usize) -> bool {
+        let cursor = cursor.min(self.len);
+        let moved = cursor != self.cursor;
+        self.cursor = cursor;
+        moved
+    }
+
+    fn kill_to_end(&mut self) -> bool {
+        let killed = self.cursor != self.len;
+        self.len = self.cursor;
+        killed
+    }
+
+    fn kill_to_start(&mut self) -> bool {
+        let killed = self.cursor;
+        self.bytes.copy_within(self.cursor..self.len, 0);
+        self.len -= killed;
+        self.cursor = 0;
+        killed != 0
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.len = 0;
+        self.cursor = 0;
+    }
+}
+
+// The last lines entered, and the position while going through them
+struct History {
+    entries: [LineBuffer; HISTORY_SIZE],
+    len: usize,
+    newest: usize,           // Index of the newest entry
+    browsing: Option<usize>, // How far back the shown entry is, 0 = newest
+    edited: LineBuffer,      // The line typed before going back
+}
+
+impl History {
+    const fn new() -> History {
+        History {
+            entries: [LineBuffer::new(); HISTORY_SIZE],
+            len: 0,
+            newest: 0,
+            browsing: None,
+            edited: LineBuffer::new(),
+        }
+    }
+
+    // Add an entered line, unless it is empty or repeats the newest entry
+    fn push(&mut self, line: &LineBuffer) {
+        self.browsing = None;
+        if line.len == 0 || (self.len > 0 && self.get(0).as_str() == line.as_str()) {
+            return;
+        }
+        self.newest = (self.newest + 1) % HISTORY_SIZE;
+        self.entries[self.newest] = *line;
+        self.len = (self.len + 1).min(HISTORY_SIZE);
+    }
+
+    // Return the entry `back` lines before the newest
+    fn get(&self, back: usize) -> &LineBuffer {
+        &self.entries[(self.newest + HISTORY_SIZE - back) % HISTORY_SIZE]
+    }
+
+    // Replace `line` with the next older entry
+    fn older(&mut self, line: &mut LineBuffer) -> bool {
+        let back = match self.browsing {
+            Some(back) if back + 1 < self.len => back + 1,
+            Some(_) => return false,
+            None if self.len > 0 => {
+                self.edited = *line;
+                0
+            }
+            None => return false,
+        };
+        self.browsing = Some(back);
+        *line = *self.get(back);
+        line.cursor = line.len;
+        true
+    }
+
+    // Replace `line` with the next newer entry, or the line typed before
+    // going back
+    fn newer(&mut self, line: &mut LineBuffer) -> bool {
+        match self.browsing {
+            Some(0) => {
+                self.browsing = None;
+                *line = self.edited;
+            }
+            Some(back) => {
+                self.browsing = Some(back - 1);
+                *line = *self.get(back - 1);
+                line.cursor = line.len;
+            }
+            None => return false,
+        }
+        true
+    }
+}
+
+// The state of an escape sequence from a serial terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Started, // After ESC
+    Csi(u8), // After ESC [ or ESC O, with the number so far
+}
+
+// The line being typed with its history
+pub(super) struct LineEditor {
+    line: LineBuffer,
+    history: History,
+    escape: Escape,
+}
+
+impl LineEditor {
+    pub(super) const fn new() -> LineEditor {
+        LineEditor {
+            line: LineBuffer::new(),
+            history: History::new(),
+            escape: Escape::None,
+        }
+    }
+
+    pub(super) fn line(&self) -> &LineBuffer {
+        &self.line
+    }
+
+    // Start a new line after the last one was run
+    pub(super) fn clear_line(&mut self) {
+        self.line.clear();
+    }
+
+    // Return the edit of a typed character. `serial` tells whether it came
+    // from a serial terminal, which sends escape sequences for its arrow
+    // keys and DEL for Backspace.
+    pub(super) fn edit_for_char(&mut self, character: char, serial: bool) -> Option<Edit> {
+        match self.escape {
+            Escape::None => {}
+            Escape::Started => {
+                self.escape = match character {
+                    '[' | 'O' => Escape::Csi(0),
+                    _ => Escape::None,
+                };
+                return None;
+            }
+            Escape::Csi(number) => {
+                if let Some(digit) = character.to_digit(10) {
+                    self.escape = Escape::Csi(number.saturating_mul(10).saturating_add(digit as u8));
+                    return None;
+                }
+                self.escape = Escape::None;
+                return match (character, number) {
+                    ('A', _) => Some(Edit::Older),
+                    ('B', _) => Some(Edit::Newer),
+                    ('C', _) => Some(Edit::Right),
+                    ('D', _) => Some(Edit::Left),
+                    ('H', _) | ('~', 1) | ('~', 7) => Some(Edit::Home),
+                    ('F', _) | ('~', 4) | ('~', 8) => Some(Edit::End),
+                    ('~', 3) => Some(Edit::Delete),
+                    _ => None,
+                };
+            }
+        }
+
+        match character {
+            '\u{1b}' if serial => {
+                self.escape = Escape::Started;
+                None
+            }
+            '\n' | '\r' => Some(Edit::Enter),
+            '\u{8}' => Some(Edit::Backspace),
+            // The keyboard's Delete key, but Backspace on most terminals
+            '\u{7f}' if serial => Some(Edit::Backspace),
+            '\u{7f}' => Some(Edit::Delete),
+            '\u{1}' => Some(Edit::Home),
+            '\u{2}' => Some(Edit::Left),
+            '\u{3}' => Some(Edit::Cancel),
+            '\u{5}' => Some(Edit::End),
+            '\u{6}' => Some(Edit::Right),
+            '\u{b}' => Some(Edit::KillToEnd),
+            '\u{e}' => Some(Edit::Newer),
+            '\u{10}' => Some(Edit::Older),
+            '\u{15}' => Some(Edit::KillToStart),
+            ' '..='~' => Some(Edit::Insert(character)),
+            _ => None,
+        }
+    }
+
+    pub(super) fn apply(&mut self, edit: Edit) -> Outcome {
+        let line = &mut self.line;
+        let changed = match edit {
+            Edit::Insert(character) => line.insert(character),
+            Edit::Backspace => line.backspace(),
+            Edit::Delete => line.delete(),
+            Edit::Left => line.move_to(line.cursor.saturating_sub(1)),
+            Edit::Right => line.move_to(line.cursor + 1),
+            Edit::Home => line.move_to(0),
+            Edit::End => line.move_to(line.len),
+            Edit::KillToEnd => line.kill_to_end(),
+            Edit::KillToStart => line.kill_to_start(),
+            Edit::Older => self.history.older(line),
+            Edit::Newer => self.history.newer(line),
+            Edit::Enter => {
+                self.history.push(line);
+                return Outcome::Submit;
+            }
+            Edit::Cancel => {
+                line.clear();
+                self.history.browsing = None;
+                return Outcome::Cancel;
+            }
+        };
+        if changed {
+            Outcome::Changed
+        } else {
+            Outcome::Unchanged
+        }
+    }
+}
+
+#[test_case]
+fn test_line_editing() {
+    fn type_str(editor: &mut LineEditor, text: &str) {
+        for character in text.chars() {
+            editor.apply(Edit::Insert(character));
+        }
+    }
+
+    let mut editor = LineEditor::new();
+
+    type_str(&mut editor, "echo hi");
+    assert_eq!(editor.apply(Edit::Backspace), Outcome::Changed);
+    editor.apply(Edit::Home);
+    assert_eq!(editor.apply(Edit::Left), Outcome::Unchanged);
+    editor.apply(Edit::Delete);
+    type_str(&mut editor, "E");
+    assert_eq!(editor.line().as_str(), "Echo h");
+    assert_eq!(editor.line().cursor(), 1);
+
+    editor.apply(Edit::KillToStart);
+    assert_eq!(editor.line().as_str(), "cho h");
+    editor.apply(Edit::Right);
+    editor.apply(Edit::KillToEnd);
+    assert_eq!(editor.line().as_str(), "c");
+
+    editor.apply(Edit::End);
+    for _ in 0..MAX_LINE {
+        editor.apply(Edit::Insert('x'));
+    }
+    assert_eq!(editor.line().len(), MAX_LINE);
+    assert_eq!(editor.apply(Edit::Insert('x')), Outcome::Unchanged);
+    assert_eq!(editor.apply(Edit::Insert('\u{8}')), Outcome::Unchanged);
+}
+
+#[test_case]
+fn test_history() {
+    let mut editor = LineEditor::new();
+    for line in ["first", "second", "second"] {
+        for character in line.chars() {
+            editor.apply(Edit::Insert(character));
+        }
+        assert_eq!(editor.apply(Edit::Enter), Outcome::Submit);
+        editor.clear_line();
+    }
+
+    editor.apply(Edit::Insert('x'));
+    editor.apply(Edit::Older);
+    assert_eq!(editor.line().as_str(), "second");
+    editor.apply(Edit::Older);
+    assert_eq!(editor.line().as_str(), "first");
+    // The repeated line was only kept once
+    assert_eq!(editor.apply(Edit::Older), Outcome::Unchanged);
+    editor.apply(Edit::Newer);
+    editor.apply(Edit::Newer);
+    assert_eq!(editor.line().as_str(), "x");
+    assert_eq!(editor.apply(Edit::Newer), Outcome::Unchanged);
+}
+
+#[test_case]
+fn test_serial_escapes() {
+    let mut editor = LineEditor::new();
+    let mut feed = |text: &str| {
+        let mut edits = [None; 4];
+        for (i, character) in text.chars().enumerate() {
+            edits[i] = editor.edit_for_char(character, true);
+        }
+        edits
+    };
+
+    assert_eq!(feed("\u{1b}[A"), [None, None, Some(Edit::Older), None]);
+    assert_eq!(feed("\u{1b}[3~"), [None, None, None, Some(Edit::Delete)]);
+    assert_eq!(feed("\u{7f}"), [Some(Edit::Backspace), None, None, None]);
+    assert_eq!(editor.edit_for_char('\u{7f}', false), Some(Edit::Delete));
+    assert_eq!(edit_for_key(KeyCode::ArrowDown), Some(Edit::Newer));
+}
@@ -0,0 +1,530 @@
+// A snake game on terminal 2, started with the shell command `snake`.
+//
+// Besides being a game, it keeps the input, timer and drawing paths busy
+// together for as long as someone plays: the board advances at a fixed
+// rate with `Timer::at_ms`, keys arrive through an input subscription, and
+// every step redraws the board, on the framebuffer when there is one and
+// as text otherwise. The status line shows how late the ticks were and
+// how many input events were lost, so scheduler jitter and input latency
+// regressions show up while playing; both are logged when the game ends.
+//
+// Arrow keys or W/A/S/D steer and Q or Escape quits. The same letters work
+// over the serial console, where they also reach the shell's line.
+
+use crate::console;
+use crate::framebuffer::{self, FrameBuffer, Rgb, GLYPH_HEIGHT};
+use crate::input::{self, DeviceId, Filter, InputEvent};
+use crate::shell;
+use crate::time::{self, Timer};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use futures_util::future;
+use futures_util::stream::StreamExt;
+use futures_util::task::AtomicWaker;
+use pc_keyboard::KeyCode;
+use x86_64::instructions::interrupts;
+
+// The terminal the game is drawn on
+pub const TTY: usize = 2;
+
+// The size of the board in cells, without the walls around it
+pub const WIDTH: usize = 40;
+pub const HEIGHT: usize = 20;
+
+// The time between two steps
+const TICK_MS: u64 = 100;
+
+const START_LENGTH: usize = 3;
+
+// The colors on the framebuffer
+const WALL_COLOR: Rgb = Rgb::new(0x55, 0x55, 0x55);
+const SNAKE_COLOR: Rgb = Rgb::new(0x55, 0xFF, 0x55);
+const FOOD_COLOR: Rgb = Rgb::new(0xFF, 0x55, 0x55);
+const TEXT_COLOR: Rgb = Rgb::new(0xAA, 0xAA, 0xAA);
+const BACKGROUND: Rgb = Rgb::new(0, 0, 0);
+
+// Set by the shell command and taken by the game task
+static START: AtomicBool = AtomicBool::new(false);
+static START_WAKER: AtomicWaker = AtomicWaker::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    fn opposite(self) -> Direction {
+        match self {
+            Direction::Up => Direction::Down,
+            Direction::Down => Direction::Up,
+            Direction::Left => Direction::Right,
+            Direction::Right => Direction::Left,
+        }
+    }
+}
+
+// What happened in a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Moved,
+    Ate,
+    // The snake hit a wall or itself, or fills the whole board
+    Over,
+}
+
+// A cell of the board as column and row
+pub type Cell = (usize, usize);
+
+// The state of a game. The snake is a ring buffer of cell indices, so
+// nothing is allocated while playing.
+pub struct Game {
+    body: [u16; WIDTH * HEIGHT],
+    head: usize, // The index of the head in `body`
+    len: usize,
+    occupied: [bool; WIDTH * HEIGHT],
+    direction: Direction, // The direction of the last step
+    next: Direction,      // The direction of the next step
+    food: Cell,
+    score: u32,
+    over: bool,
+    seed: u64,
+}
+
+impl Game {
+    // Start a game with the snake in the middle, heading right. `seed`
+    // decides where the food appears.
+    pub fn new(seed: u64) -> Game {
+        let mut game = Game {
+            body: [0; WIDTH * HEIGHT],
+            head: START_LENGTH - 1,
+            len: START_LENGTH,
+            occupied: [false; WIDTH * HEIGHT],
+            direction: Direction::Right,
+            next: Direction::Right,
+            food: (0, 0),
+            score: 0,
+            over: false,
+            seed: seed | 1,
+        };
+        for i in 0..START_LENGTH {
+            let cell = (WIDTH / 2 - START_LENGTH + 1 + i, HEIGHT / 2);
+            game.body[i] = index(cell) as u16;
+            game.occupied[index(cell)] = true;
+        }
+        game.place_food();
+        game
+    }
+
+    // Turn before the next step. Turning back onto the snake is ignored.
+    pub fn turn(&mut self, direction: Direction) {
+        if direction != self.direction.opposite() {
+            self.next = direction;
+        }
+    }
+
+    // Move the snake by one cell
+    pub fn step(&mut self) -> Step {
+        if self.over {
+            return Step::Over;
+        }
+        self.direction = self.next;
+        let (col, row) = self.head();
+        let cell = match self.direction {
+            Direction::Up if row > 0 => (col, row - 1),
+            Direction::Down if row + 1 < HEIGHT => (col, row + 1),
+            Direction::Left if col > 0 => (col - 1, row),
+            Direction::Right if col + 1 < WIDTH => (col + 1, row),
+            _ => return self.end(),
+        };
+
+        let ate = cell == self.food;
+        if !ate {
+            // The tail moves out of the way first
+            let tail = (self.head + self.body.len() + 1 - self.len) % self.body.len();
+            self.occupied[self.body[tail] as usize] = false;
+            self.len -= 1;
+        }
+        if self.occupied[index(cell)] {
+            return self.end();
+        }
+        self.head = (self.head + 1) % self.body.len();
+        self.body[self.head] = index(cell) as u16;
+        self.occupied[index(cell)] = true;
+        self.len += 1;
+
+        if !ate {
+            return Step::Moved;
+        }
+        self.score += 1;
+        if self.len == self.body.len() {
+            return self.end();
+        }
+        self.place_food();
+        Step::Ate
+    }
+
+    pub fn head(&self) -> Cell {
+        let index = self.body[self.head] as usize;
+        (index % WIDTH, index / WIDTH)
+    }
+
+    pub fn length(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_snake(&self, cell: Cell) -> bool {
+        self.occupied[index(cell)]
+    }
+
+    pub fn food(&self) -> Cell {
+        self.food
+    }
+
+    pub fn score(&self) -> u32 {
+        self.score
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.over
+    }
+
+    fn end(&mut self) -> Step {
+        self.over = true;
+        Step::Over
+    }
+
+    // Put the food on a random free cell
+    fn place_food(&mut self) {
+        // xorshift64
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        let free = self.occupied.len() - self.len;
+        let mut skip = (self.seed % free as u64) as usize;
+        for (i, &occupied) in self.occupied.iter().enumerate() {
+            if occupied {
+                continue;
+            }
+            if skip == 0 {
+                self.food = (i % WIDTH, i / WIDTH);
+                return;
+            }
+            skip -= 1;
+        }
+    }
+}
+
+fn index((col, row): Cell) -> usize {
+    row * WIDTH + col
+}
+
+// Start a game, from the shell command
+fn start(_args: &[&str]) {
+    START.store(true, Ordering::Relaxed);
+    START_WAKER.wake();
+}
+
+// Wait until a game is started
+async fn started() {
+    future::poll_fn(|cx| {
+        START_WAKER.register(cx.waker());
+        if START.swap(false, Ordering::Relaxed) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// How well the ticks kept up during a game
+#[derive(Debug, Default, Clone, Copy)]
+struct Stats {
+    ticks: u64,
+    max_late_ms: u64,
+    missed: u64, // Ticks skipped because a step came too late for them
+}
+
+// Run games whenever the shell asks for one. Spawn this on the executor
+// once; it never returns.
+pub async fn run() {
+    if shell::register("snake", start).is_err() {
+        log::warn!("snake: the shell command exists already");
+        return;
+    }
+    loop {
+        started().await;
+        play().await;
+    }
+}
+
+async fn play() {
+    let mut events = input::subscribe(Filter::All);
+    let echo = console::echo_enabled(TTY);
+    console::set_echo(TTY, false);
+    console::switch_to(TTY);
+    crate::console_print!(TTY, "\x1b[2J\x1b[H");
+
+    let mut game = Game::new(time::rdtsc());
+    let mut stats = Stats::default();
+    let mut next = time::uptime_ms() + TICK_MS;
+    draw(&game, &stats, events.dropped());
+
+    let mut quit = false;
+    while !game.is_over() && !quit {
+        Timer::at_ms(next).await;
+        let now = time::uptime_ms();
+        let late = now.saturating_sub(next);
+        stats.ticks += 1;
+        stats.max_late_ms = stats.max_late_ms.max(late);
+        // Keep the rate fixed, but don't catch up with a burst of steps
+        next += TICK_MS;
+        if next <= now {
+            let missed = (now - next) / TICK_MS + 1;
+            stats.missed += missed;
+            next += missed * TICK_MS;
+        }
+
+        while let Some((device, event)) = events.try_next() {
+            match command(device, event) {
+                Some(Command::Turn(direction)) => game.turn(direction),
+                Some(Command::Quit) => quit = true,
+                None => {}
+            }
+        }
+        if !quit {
+            game.step();
+        }
+        draw(&game, &stats, events.dropped());
+    }
+
+    log::info!(
+        "snake: score {}, {} ticks, {} missed, at most {} ms late, {} input events dropped",
+        game.score(),
+        stats.ticks,
+        stats.missed,
+        stats.max_late_ms,
+        events.dropped()
+    );
+
+    if !quit {
+        status_line("game over, press any key");
+        while let Some((device, event)) = events.next().await {
+            if command(device, event).is_some() || is_key_press(device, event) {
+                break;
+            }
+        }
+    }
+    console::set_echo(TTY, echo);
+    console::switch_to(shell::TTY);
+}
+
+enum Command {
+    Turn(Direction),
+    Quit,
+}
+
+// Return what an input event asks the game to do
+fn command(device: DeviceId, event: InputEvent) -> Option<Command> {
+    // Keys typed on other terminals aren't meant for the game
+    if device == DeviceId::KEYBOARD && console::active() != TTY {
+        return None;
+    }
+    let command = match event {
+        InputEvent::Key(key) if key.pressed => match key.code {
+            KeyCode::ArrowUp => Command::Turn(Direction::Up),
+            KeyCode::ArrowDown => Command::Turn(Direction::Down),
+            KeyCode::ArrowLeft => Command::Turn(Direction::Left),
+            KeyCode::ArrowRight => Command::Turn(Direction::Right),
+            KeyCode::Escape => Command::Quit,
+            _ => return None,
+        },
+        InputEvent::Char(character) => match character.to_ascii_lowercase() {
+            'w' => Command::Turn(Direction::Up),
+            's' => Command::Turn(Direction::Down),
+            'a' => Command::Turn(Direction::Left),
+            'd' => Command::Turn(Direction::Right),
+            'q' => Command::Quit,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn is_key_press(device: DeviceId, event: InputEvent) -> bool {
+    if device == DeviceId::KEYBOARD && console::active() != TTY {
+        return false;
+    }
+    matches!(event, InputEvent::Key(key) if key.pressed) || matches!(event, InputEvent::Char(_))
+}
+
+// A line of text for the board or the status, padded with blanks
+struct Line {
+    text: [u8; WIDTH + 2],
+    len: usize,
+}
+
+impl Line {
+    fn new() -> Self {
+        Line {
+            text: [b' '; WIDTH + 2],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        // Only ASCII is ever written
+        core::str::from_utf8(&self.text).unwrap_or("")
+    }
+}
+
+impl Write for Line {
+    // Text beyond the width of the board is cut off
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == self.text.len() {
+                break;
+            }
+            self.text[self.len] = if byte.is_ascii() { byte } else { b'?' };
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+fn draw(game: &Game, stats: &Stats, dropped: u64) {
+    let mut status = Line::new();
+    let _ = write!(
+        status,
+        "score {}  late {} ms  missed {}  lost {}",
+        game.score(),
+        stats.max_late_ms,
+        stats.missed,
+        dropped
+    );
+    if framebuffer::is_active() {
+        draw_pixels(game, &status);
+    } else {
+        draw_text(game, &status);
+    }
+}
+
+// Draw the board as text on the game's terminal, one character per cell
+fn draw_text(game: &Game, status: &Line) {
+    console::with_terminal(TTY, |writer| {
+        let rows = writer.text_height();
+        for row in 0..(HEIGHT + 2).min(rows) {
+            let mut line = Line::new();
+            for (col, byte) in line.text.iter_mut().enumerate() {
+                *byte = if row == 0 || row == HEIGHT + 1 || col == 0 || col == WIDTH + 1 {
+                    b'#'
+                } else if (col - 1, row - 1) == game.head() {
+                    b'@'
+                } else if game.is_snake((col - 1, row - 1)) {
+                    b'o'
+                } else if (col - 1, row - 1) == game.food() {
+                    b'*'
+                } else {
+                    b' '
+                };
+            }
+            writer.write_at(row, 0, line.as_str());
+        }
+        if HEIGHT + 2 < rows {
+            writer.write_at(HEIGHT + 2, 0, status.as_str());
+        }
+    });
+}
+
+// Return the size in pixels of the square cells on the framebuffer: as
+// large as fit above the status line
+fn cell_size(framebuffer: &FrameBuffer) -> usize {
+    let height = framebuffer.height().saturating_sub(GLYPH_HEIGHT);
+    (framebuffer.width() / (WIDTH + 2)).min(height / (HEIGHT + 2)).max(1)
+}
+
+// Draw the board on the framebuffer
+fn draw_pixels(game: &Game, status: &Line) {
+    interrupts::without_interrupts(|| {
+        let mut console = framebuffer::CONSOLE.lock();
+        let framebuffer = match console.as_mut() {
+            Some(console) => console.framebuffer(),
+            None => return,
+        };
+        let size = cell_size(framebuffer);
+        framebuffer.fill_rect(0, 0, (WIDTH + 2) * size, (HEIGHT + 2) * size, WALL_COLOR);
+        framebuffer.fill_rect(size, size, WIDTH * size, HEIGHT * size, BACKGROUND);
+        for row in 0..HEIGHT {
+            for col in 0..WIDTH {
+                if game.is_snake((col, row)) {
+                    let (x, y) = ((col + 1) * size, (row + 1) * size);
+                    framebuffer.fill_rect(x, y, size, size, SNAKE_COLOR);
+                }
+            }
+        }
+        let (col, row) = game.food();
+        framebuffer.fill_rect((col + 1) * size, (row + 1) * size, size, size, FOOD_COLOR);
+        let y = (HEIGHT + 2) * size;
+        framebuffer.draw_str(0, y, status.as_str(), TEXT_COLOR, BACKGROUND);
+    });
+}
+
+// Replace the status line with a message
+fn status_line(message: &str) {
+    let mut line = Line::new();
+    let _ = line.write_str(message);
+    if framebuffer::is_active() {
+        interrupts::without_interrupts(|| {
+            if let Some(console) = framebuffer::CONSOLE.lock().as_mut() {
+                let framebuffer = console.framebuffer();
+                let y = (HEIGHT + 2) * cell_size(framebuffer);
+                framebuffer.draw_str(0, y, line.as_str(), TEXT_COLOR, BACKGROUND);
+            }
+        });
+    } else {
+        console::with_terminal(TTY, |writer| {
+            if HEIGHT + 2 < writer.text_height() {
+                writer.write_at(HEIGHT + 2, 0, line.as_str());
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_snake_moves_and_grows() {
+    let mut game = Game::new(1);
+    let (col, row) = game.head();
+    game.food = (col + 2, row);
+
+    assert_eq!(game.step(), Step::Moved);
+    assert_eq!(game.head(), (col + 1, row));
+    assert!(!game.is_snake((col - START_LENGTH + 1, row)));
+    assert_eq!(game.step(), Step::Ate);
+    assert_eq!((game.length(), game.score()), (START_LENGTH + 1, 1));
+    assert!(!game.is_snake(game.food()));
+}
+
+#[test_case]
+fn test_snake_turns_and_dies() {
+    let mut game = Game::new(1);
+    let (col, row) = game.head();
+    game.food = (0, 0);
+
+    // Reversing onto itself is ignored
+    game.turn(Direction::Left);
+    assert_eq!(game.step(), Step::Moved);
+    assert_eq!(game.head(), (col + 1, row));
+
+    game.turn(Direction::Down);
+    assert_eq!(game.step(), Step::Moved);
+    assert_eq!(game.head(), (col + 1, row + 1));
+    while game.step() == Step::Moved {}
+    assert_eq!(game.head(), (col + 1, HEIGHT - 1));
+    assert!(game.is_over());
+    assert_eq!(game.step(), Step::Over);
+}
@@ -0,0 +1,132 @@
+// Snapshots of console output, for tests.
+//
+// A `Snapshot` is a copy of the text on a console: the cells of a VGA writer
+// or the text layer of the framebuffer console. Tests print something and
+// then assert against the snapshot instead of reading the screen buffers
+// directly, e.g.
+//
+//     println!("hello");
+//     assert_eq!(snapshot::capture().last_line(), "hello");
+//
+// Snapshots have a fixed size and don't need the heap. Characters outside of
+// printable ASCII show as '?'.
+
+use crate::console;
+use core::fmt;
+use x86_64::instructions::interrupts;
+
+// The largest console a snapshot holds; bigger ones are cut off
+pub const MAX_COLUMNS: usize = 160;
+pub const MAX_ROWS: usize = 64;
+
+// The text on a console at one point in time
+#[derive(Clone)]
+pub struct Snapshot {
+    cells: [[u8; MAX_COLUMNS]; MAX_ROWS],
+    width: usize,
+    height: usize,
+}
+
+impl Snapshot {
+    // Create a blank snapshot of the given size
+    pub fn new(width: usize, height: usize) -> Snapshot {
+        Snapshot {
+            cells: [[b' '; MAX_COLUMNS]; MAX_ROWS],
+            width: width.min(MAX_COLUMNS),
+            height: height.min(MAX_ROWS),
+        }
+    }
+
+    // Set the character at a position; positions outside are ignored
+    pub fn set(&mut self, row: usize, col: usize, character: u8) {
+        if row < self.height && col < self.width {
+            self.cells[row][col] = match character {
+                0x20..=0x7E => character,
+                _ => b'?',
+            };
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // Return a row without the trailing blanks
+    pub fn row(&self, row: usize) -> &str {
+        let cells = &self.cells[row][..self.width];
+        let len = cells.iter().rposition(|&c| c != b' ').map_or(0, |last| last + 1);
+        // Only printable ASCII is stored
+        core::str::from_utf8(&cells[..len]).unwrap()
+    }
+
+    // Return the rows from top to bottom
+    pub fn rows(&self) -> impl Iterator<Item = &str> {
+        (0..self.height).map(move |row| self.row(row))
+    }
+
+    // Return the last row that isn't blank, or "" if all are. After a
+    // `println!`, this is the line just printed.
+    pub fn last_line(&self) -> &str {
+        self.rows().filter(|row| !row.is_empty()).last().unwrap_or("")
+    }
+
+    // Return whether a row contains the given text
+    pub fn contains(&self, text: &str) -> bool {
+        self.rows().any(|row| row.contains(text))
+    }
+}
+
+// Prints the rows, separated by newlines
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, row) in self.rows().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            f.write_str(row)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Snapshot {}x{}:\n{}", self.width, self.height, self)
+    }
+}
+
+// Capture what `print!` writes to: the framebuffer console if there is one,
+// otherwise the VGA text writer
+pub fn capture() -> Snapshot {
+    interrupts::without_interrupts(|| {
+        if let Some(console) = crate::framebuffer::CONSOLE.lock().as_ref() {
+            return console.snapshot();
+        }
+        crate::vga_buffer::WRITER.lock().snapshot()
+    })
+}
+
+// Capture the text of a virtual terminal
+pub fn capture_terminal(tty: usize) -> Snapshot {
+    console::with_terminal(tty, |writer| writer.snapshot())
+}
+
+#[test_case]
+fn test_snapshot_rows() {
+    let mut snapshot = Snapshot::new(8, 3);
+    for (col, &byte) in b"ab c".iter().enumerate() {
+        snapshot.set(1, col, byte);
+    }
+    snapshot.set(2, 0, 0xC4);
+    snapshot.set(3, 0, b'x');
+
+    assert_eq!(snapshot.row(0), "");
+    assert_eq!(snapshot.row(1), "ab c");
+    assert_eq!(snapshot.last_line(), "?");
+    assert!(snapshot.contains("b c"));
+    assert!(!snapshot.contains("x"));
+}
@@ -0,0 +1,122 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Maximum number of work items that can be pending at once
+const QUEUE_CAPACITY: usize = 64;
+
+// Maximum number of work items run per interrupt. Anything beyond that is
+// left for the idle loop, which bounds the time spent before returning from
+// an interrupt during an interrupt storm.
+const IRQ_EXIT_BUDGET: usize = 8;
+
+// A small piece of work deferred from an interrupt handler
+#[derive(Clone, Copy)]
+struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+// Error returned by `schedule` when the work queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+// A fixed-capacity ring buffer, so that scheduling work never allocates
+struct WorkQueue {
+    items: [Option<Work>; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl WorkQueue {
+    const fn new() -> Self {
+        WorkQueue {
+            items: [None; QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, work: Work) -> Result<(), QueueFull> {
+        if self.len == QUEUE_CAPACITY {
+            return Err(QueueFull);
+        }
+        self.items[(self.head + self.len) % QUEUE_CAPACITY] = Some(work);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        work
+    }
+}
+
+static QUEUE: Mutex<WorkQueue> = Mutex::new(WorkQueue::new());
+
+// Set while deferred work is running, so that nested interrupts don't start
+// another run on top of it
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// Number of work items run so far
+static PROCESSED: AtomicU64 = AtomicU64::new(0);
+
+// Queue `func(arg)` to run with interrupts enabled at the next safe point.
+//
+// Meant to be called from interrupt handlers to keep them short: the work
+// runs right after the interrupt is acknowledged, before returning from it,
+// or from the idle loop if too much work is pending.
+pub fn schedule(func: fn(usize), arg: usize) -> Result<(), QueueFull> {
+    interrupts::without_interrupts(|| QUEUE.lock().push(Work { func, arg }))
+}
+
+// Return the number of pending work items.
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| QUEUE.lock().len)
+}
+
+// Return the number of work items run since boot.
+pub fn processed() -> u64 {
+    PROCESSED.load(Ordering::Relaxed)
+}
+
+// Run pending work on interrupt exit, with interrupts enabled and bounded by
+// `IRQ_EXIT_BUDGET`. Called by the IRQ dispatcher after the end of interrupt.
+pub(crate) fn run_on_irq_exit() {
+    run(IRQ_EXIT_BUDGET);
+}
+
+// Run all pending work. Called from the idle loop.
+pub fn run_pending() {
+    run(usize::MAX);
+}
+
+fn run(budget: usize) {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return;
+    }
+
+    let were_enabled = interrupts::are_enabled();
+    interrupts::enable();
+
+    for _ in 0..budget {
+        let work = interrupts::without_interrupts(|| QUEUE.lock().pop());
+        match work {
+            Some(work) => {
+                (work.func)(work.arg);
+                PROCESSED.fetch_add(1, Ordering::Relaxed);
+            }
+            None => break,
+        }
+    }
+
+    if !were_enabled {
+        interrupts::disable();
+    }
+    RUNNING.store(false, Ordering::Release);
+}
@@ -0,0 +1,149 @@
+// The kernel symbol table, for turning code addresses into function names.
+//
+// The table is generated by the build script from the kernel ELF of a
+// previous build (see `RUST_OS_SYMBOLS` in build.rs); it is empty unless the
+// kernel was built that way, and every lookup then returns `None`. A table
+// from a build of different source is detected with `is_stale`.
+
+use core::fmt;
+
+// A function in the symbol table
+struct Entry {
+    address: u64,
+    size: u64, // 0 if unknown
+    name_offset: u32,
+    name_len: u32,
+}
+
+// The layout of the table, see `symbol_table` in build.rs
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 24;
+
+// Defines `TABLE`, of `TABLE_SIZE` bytes whatever it holds
+include!(concat!(env!("OUT_DIR"), "/symbols.rs"));
+
+// The function an address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64, // Where the function starts
+    pub offset: u64,  // Distance of the looked up address from the start
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+// Return the function containing `address`
+pub fn lookup(address: u64) -> Option<Symbol> {
+    lookup_in(table(), address)
+}
+
+// Return the name of the function containing `address`
+pub fn resolve(address: u64) -> Option<&'static str> {
+    lookup(address).map(|symbol| symbol.name)
+}
+
+// Return the number of functions in the symbol table
+pub fn count() -> usize {
+    count_in(table())
+}
+
+// Return whether the table was taken from a build of different source, so
+// it names the wrong functions. Checked with this function's own address.
+pub fn is_stale() -> bool {
+    count() != 0 && resolve(is_stale as usize as u64) != Some("rust_os::symbols::is_stale")
+}
+
+// The table, through a pointer the optimizer can't see into. The code then
+// doesn't depend on what the table holds, so embedding the symbols doesn't
+// move the functions they describe.
+fn table() -> &'static [u8] {
+    let start = core::hint::black_box(TABLE.as_ptr());
+    unsafe { core::slice::from_raw_parts(start, TABLE_SIZE) }
+}
+
+fn count_in(table: &[u8]) -> usize {
+    let count = table.get(..HEADER_SIZE).map_or([0; 4], |count| count.try_into().unwrap());
+    u32::from_le_bytes(count) as usize
+}
+
+fn entry_in(table: &[u8], index: usize) -> Option<Entry> {
+    let start = HEADER_SIZE + index * ENTRY_SIZE;
+    let bytes = table.get(start..start + ENTRY_SIZE)?;
+    let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    Some(Entry {
+        address: u64_at(0),
+        size: u64_at(8),
+        name_offset: u32_at(16),
+        name_len: u32_at(20),
+    })
+}
+
+fn lookup_in(table: &'static [u8], address: u64) -> Option<Symbol> {
+    // The entries are sorted by address; find the last one at or below it
+    let count = count_in(table);
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry_in(table, middle)?.address <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let entry = entry_in(table, low.checked_sub(1)?)?;
+
+    let offset = address - entry.address;
+    // Without a size, assume the function reaches up to the next one
+    if entry.size != 0 && offset >= entry.size {
+        return None;
+    }
+
+    let start = HEADER_SIZE + count * ENTRY_SIZE + entry.name_offset as usize;
+    let name = table.get(start..start + entry.name_len as usize)?;
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        address: entry.address,
+        offset,
+    })
+}
+
+// Two entries, at 0x1000 with a size and at 0x2000 without, and their names
+const fn test_table() -> [u8; HEADER_SIZE + 2 * ENTRY_SIZE + 7] {
+    let mut table = [0; HEADER_SIZE + 2 * ENTRY_SIZE + 7];
+    table[0] = 2;
+    // Addresses and sizes
+    table[HEADER_SIZE + 1] = 0x10;
+    table[HEADER_SIZE + 8] = 0x10;
+    table[HEADER_SIZE + ENTRY_SIZE + 1] = 0x20;
+    // Name offsets and lengths
+    table[HEADER_SIZE + 20] = 3;
+    table[HEADER_SIZE + ENTRY_SIZE + 16] = 3;
+    table[HEADER_SIZE + ENTRY_SIZE + 20] = 4;
+    let names = b"foobar!";
+    let mut i = 0;
+    while i < names.len() {
+        table[HEADER_SIZE + 2 * ENTRY_SIZE + i] = names[i];
+        i += 1;
+    }
+    table
+}
+
+#[test_case]
+fn test_lookup() {
+    static TEST_TABLE: [u8; HEADER_SIZE + 2 * ENTRY_SIZE + 7] = test_table();
+    let table = &TEST_TABLE[..];
+
+    assert_eq!(count_in(table), 2);
+    assert_eq!(lookup_in(table, 0xfff), None);
+    assert_eq!(
+        lookup_in(table, 0x1004),
+        Some(Symbol { name: "foo", address: 0x1000, offset: 4 })
+    );
+    assert_eq!(lookup_in(table, 0x1010), None);
+    assert_eq!(lookup_in(table, 0x2345).map(|s| s.name), Some("bar!"));
+}
@@ -0,0 +1,221 @@
+// System calls through the `syscall` instruction.
+//
+// `init` programs the MSRs the instruction uses: STAR holds the segments
+// (see the GDT order in `gdt`), LSTAR the address of `syscall_entry`, and
+// SFMASK the RFLAGS bits cleared on entry, so handlers start with
+// interrupts disabled. `syscall` doesn't switch stacks, so the entry stub
+// saves the user stack pointer and moves to the privilege stack from the
+// TSS, the one interrupts from ring 3 use too. Since only one user program
+// runs at a time, that stack and the static the user stack pointer goes
+// through aren't shared.
+//
+// The stub saves the registers the call may change as a `SyscallFrame`,
+// and `dispatch` runs the handler with interrupts enabled. The calling
+// convention is in `abi`.
+
+pub mod abi;
+
+use crate::errno::{self, Errno};
+use crate::gdt;
+use crate::process;
+use crate::time;
+use crate::usercopy::{self, copy_from_user};
+use crate::usermode;
+use abi::Timespec;
+use core::arch::global_asm;
+use core::fmt::{self, Write};
+use core::mem;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+// The stack `syscall_entry` switches to
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+// The user stack pointer, while `syscall_entry` moves to the kernel stack
+static USER_RSP: AtomicU64 = AtomicU64::new(0);
+
+// The registers saved by `syscall_entry`, in the order they are pushed in
+// reverse
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub number: u64, // rax
+    pub args: [u64; 6], // rdi, rsi, rdx, r10, r8, r9
+    pub rflags: u64, // r11
+    pub rip: u64, // rcx
+    pub rsp: u64,
+}
+
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    mov [rip + {user_rsp}], rsp
+    mov rsp, [rip + {kernel_rsp}]
+    // Ten pushes keep the stack aligned for the call
+    and rsp, -16
+    push qword ptr [rip + {user_rsp}]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+    mov rdi, rsp
+    call {handler}
+    // The result is in rax; skip the number
+    add rsp, 8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    pop rsp
+    sysretq
+"#,
+    user_rsp = sym USER_RSP,
+    kernel_rsp = sym KERNEL_RSP,
+    handler = sym syscall_handler,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+// Enable the `syscall` instruction. Must be called after `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    KERNEL_RSP.store(gdt::privilege_stack_top().as_u64(), Ordering::Relaxed);
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+        Star::write(
+            selectors.user_code_selector,
+            selectors.user_data_selector,
+            selectors.code_selector,
+            selectors.data_selector,
+        )
+        .expect("GDT order doesn't suit syscall");
+        LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+        SFMask::write(
+            RFlags::INTERRUPT_FLAG
+                | RFlags::TRAP_FLAG
+                | RFlags::DIRECTION_FLAG
+                | RFlags::ALIGNMENT_CHECK,
+        );
+    }
+}
+
+extern "C" fn syscall_handler(frame: &mut SyscallFrame) -> i64 {
+    interrupts::enable();
+    let result = dispatch(frame.number, frame.args);
+    interrupts::disable();
+    result
+}
+
+// Run system call `number` and return the value for rax
+pub fn dispatch(number: u64, args: [u64; 6]) -> i64 {
+    let result = match number {
+        abi::SYS_WRITE => write(args[0], args[1], args[2]),
+        abi::SYS_NANOSLEEP => nanosleep(args[0]),
+        abi::SYS_GETPID => Ok(getpid()),
+        abi::SYS_EXIT => exit(args[0]),
+        _ => Err(Errno::ENOSYS),
+    };
+    errno::to_return_value(result)
+}
+
+// Bytes shown one character each, whatever their encoding
+struct Bytes<'a>(&'a [u8]);
+
+impl fmt::Display for Bytes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|&byte| f.write_char(byte as char))
+    }
+}
+
+// Standard output and standard error both go to the screen
+fn write(fd: u64, buf: u64, count: u64) -> Result<u64, Errno> {
+    if fd != abi::STDOUT && fd != abi::STDERR {
+        return Err(Errno::EBADF);
+    }
+    if !usercopy::access_ok(buf, count as usize) {
+        return Err(Errno::EFAULT);
+    }
+    let mut chunk = [0; 128];
+    let mut written = 0;
+    while written < count {
+        let len = (count - written).min(chunk.len() as u64) as usize;
+        if let Err(err) = copy_from_user(&mut chunk[..len], buf + written) {
+            // Report the bytes already written, like a short write
+            return if written > 0 { Ok(written) } else { Err(err.into()) };
+        }
+        print!("{}", Bytes(&chunk[..len]));
+        written += len as u64;
+    }
+    Ok(written)
+}
+
+// Sleeps for the whole time, since nothing interrupts a sleep yet; the
+// remaining time is never written
+fn nanosleep(req: u64) -> Result<u64, Errno> {
+    let mut bytes = [0; mem::size_of::<Timespec>()];
+    copy_from_user(&mut bytes, req)?;
+    let (sec, nsec) = bytes.split_at(8);
+    let timespec = Timespec {
+        tv_sec: i64::from_ne_bytes(sec.try_into().unwrap()),
+        tv_nsec: i64::from_ne_bytes(nsec.try_into().unwrap()),
+    };
+    if timespec.tv_sec < 0 || !(0..1_000_000_000).contains(&timespec.tv_nsec) {
+        return Err(Errno::EINVAL);
+    }
+    let ms = (timespec.tv_sec as u64)
+        .saturating_mul(1000)
+        .saturating_add((timespec.tv_nsec as u64 + 999_999) / 1_000_000);
+    time::sleep(ms);
+    Ok(0)
+}
+
+// A program run without a process, by `usermode::run` directly, is
+// process 1
+fn getpid() -> u64 {
+    process::current().map_or(1, |pid| pid.0)
+}
+
+// Returns from the `usermode::run` that started the program, with the low
+// byte of the status as on Linux
+fn exit(status: u64) -> Result<u64, Errno> {
+    interrupts::disable();
+    unsafe { usermode::exit(status & 0xFF) }
+}
+
+#[test_case]
+fn test_dispatch_errors() {
+    assert_eq!(dispatch(abi::SYS_GETPID, [0; 6]), 1);
+    assert_eq!(dispatch(1000, [0; 6]), Errno::ENOSYS.to_return_value());
+
+    // Addresses in the upper half are refused
+    let kernel = usercopy::USER_SPACE_END;
+    let args = [abi::STDOUT, kernel, 4, 0, 0, 0];
+    assert_eq!(dispatch(abi::SYS_WRITE, args), Errno::EFAULT.to_return_value());
+
+    // So is the kernel's memory in the lower half, which user programs
+    // could otherwise print
+    static SECRET: [u8; 4] = *b"key!";
+    let args = [abi::STDOUT, SECRET.as_ptr() as u64, 4, 0, 0, 0];
+    assert_eq!(dispatch(abi::SYS_WRITE, args), Errno::EFAULT.to_return_value());
+    let args = [SECRET.as_ptr() as u64, 0, 0, 0, 0, 0];
+    assert_eq!(dispatch(abi::SYS_NANOSLEEP, args), Errno::EFAULT.to_return_value());
+    let args = [7, kernel, 4, 0, 0, 0];
+    assert_eq!(dispatch(abi::SYS_WRITE, args), Errno::EBADF.to_return_value());
+    assert_eq!(dispatch(abi::SYS_WRITE, [abi::STDOUT, 0, 0, 0, 0, 0]), 0);
+    let args = [kernel, 0, 0, 0, 0, 0];
+    assert_eq!(dispatch(abi::SYS_NANOSLEEP, args), Errno::EFAULT.to_return_value());
+}
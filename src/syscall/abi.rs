@@ -0,0 +1,88 @@
+// The system call interface, as seen from ring 3.
+//
+// The call number goes in rax and up to six arguments in rdi, rsi, rdx, r10,
+// r8 and r9, as on Linux x86_64, and the `syscall` instruction returns the
+// result in rax. It overwrites rcx and r11 and keeps every other register.
+// A result between -4095 and -1 is a negated error number (see `errno`).
+// The numbers are the Linux ones.
+
+use core::arch::asm;
+
+// Write to a file descriptor: (fd, buf, count) -> bytes written
+pub const SYS_WRITE: u64 = 1;
+// Sleep: (const Timespec *req, Timespec *rem) -> 0
+pub const SYS_NANOSLEEP: u64 = 35;
+// () -> the caller's process ID
+pub const SYS_GETPID: u64 = 39;
+// Leave user mode: (status), doesn't return
+pub const SYS_EXIT: u64 = 60;
+
+// The descriptors `write` accepts until processes have file tables
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// A duration, as passed to SYS_NANOSLEEP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct Timespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64, // Below 1_000_000_000
+}
+
+// Make a system call without arguments.
+//
+// These functions are unsafe because they must run in ring 3, and the
+// arguments must be what the call expects.
+pub unsafe fn syscall0(number: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+pub unsafe fn syscall1(number: u64, arg0: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        in("rdi") arg0,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+pub unsafe fn syscall2(number: u64, arg0: u64, arg1: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+pub unsafe fn syscall3(number: u64, arg0: u64, arg1: u64, arg2: u64) -> i64 {
+    let result: i64;
+    asm!(
+        "syscall",
+        inlateout("rax") number as i64 => result,
+        in("rdi") arg0,
+        in("rsi") arg1,
+        in("rdx") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
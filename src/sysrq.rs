@@ -0,0 +1,234 @@
+// Magic SysRq keys.
+//
+// Holding Alt+SysRq (Alt+Print Screen) and pressing a letter runs a
+// diagnostic action right in the keyboard interrupt, so it works even if the
+// shell or the executor is stuck:
+//
+//     p  print the control registers and a stack trace
+//     m  print the frame and heap statistics
+//     d  dump the heap, executor and lock state to the serial port
+//     t  write the running task and the stack trace to the serial port
+//     b  reboot immediately, without the teardown hooks
+//     c  panic, to test the crash path
+//     h  list the actions
+//
+// The output goes to terminal 0, except for `d` and `t`.
+//
+// Tasks are futures polled on the one kernel stack, so a task that isn't
+// running has no stack to trace: what it waits for is in its future's
+// state. `t` shows the running task and the calls of the interrupted code,
+// which is what a task that doesn't yield is stuck in.
+//
+// The host can trigger the actions too, e.g. to look at a kernel that is
+// stuck without having panicked: a break on COM1 followed by the letter of
+// an action, like on Linux. With `-serial mon:stdio`, QEMU sends a break
+// on Ctrl+A b. Only bytes that the serial driver passes on count, so this
+// doesn't work during an XMODEM transfer. `b` and `c` from the serial port
+// are deferred with `softirq`, so they run once the serial interrupt is
+// acknowledged and its handler has let go of the port. The panic handler
+// and the reboot message then only need the VGA and serial writers, which
+// are always locked with interrupts disabled, so the code the interrupt
+// stopped can't be holding them.
+//
+// Neither a write to a debug I/O port nor a virtio-serial control message
+// triggers the dump: QEMU has no device that turns a port write by the host
+// into an interrupt, and there is no virtio-serial driver. The break on
+// COM1 needs the COM1 line routed, which `apic::init` keeps for every line
+// registered before it. The dump also lacks the lock owners: spin locks
+// don't record who holds them, so it shows each lock as held or free only.
+
+use crate::keyboard::KeyEvent;
+use crate::task::executor;
+use crate::{allocator, backtrace, block, fd, memory, power, println, serial_println, softirq, time};
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::KeyCode;
+
+// Whether SysRq is held down together with Alt
+static SYSRQ_HELD: AtomicBool = AtomicBool::new(false);
+
+// A SysRq action
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Registers,
+    Memory,
+    Dump,
+    Tasks,
+    Reboot,
+    Crash,
+    Help,
+}
+
+// Return the action of a key
+fn action_for(code: KeyCode) -> Option<Action> {
+    match code {
+        KeyCode::P => Some(Action::Registers),
+        KeyCode::M => Some(Action::Memory),
+        KeyCode::D => Some(Action::Dump),
+        KeyCode::T => Some(Action::Tasks),
+        KeyCode::B => Some(Action::Reboot),
+        KeyCode::C => Some(Action::Crash),
+        KeyCode::H => Some(Action::Help),
+        _ => None,
+    }
+}
+
+// Handle a key in the keyboard interrupt. Returns `true` if the key was part
+// of a SysRq combination and must not be passed on.
+pub(crate) fn handle_key_event(event: &KeyEvent) -> bool {
+    let alt = event.modifiers.alt || event.modifiers.alt_gr;
+    match event.code {
+        // Alt+Print Screen sends the SysRq code, but not every keyboard does
+        KeyCode::SysRq | KeyCode::PrintScreen => {
+            SYSRQ_HELD.store(event.pressed && alt, Ordering::Relaxed);
+            return alt;
+        }
+        _ => {}
+    }
+
+    if !SYSRQ_HELD.load(Ordering::Relaxed) {
+        return false;
+    }
+    if !alt {
+        // Alt was released first
+        SYSRQ_HELD.store(false, Ordering::Relaxed);
+        return false;
+    }
+    let action = match action_for(event.code) {
+        Some(action) => action,
+        None => return false,
+    };
+    if event.pressed {
+        run(action);
+    }
+    true
+}
+
+// Return the action of a letter received on the serial port
+fn action_for_byte(byte: u8) -> Option<Action> {
+    match byte.to_ascii_lowercase() {
+        b'p' => Some(Action::Registers),
+        b'm' => Some(Action::Memory),
+        b'd' => Some(Action::Dump),
+        b't' => Some(Action::Tasks),
+        b'b' => Some(Action::Reboot),
+        b'c' => Some(Action::Crash),
+        b'h' => Some(Action::Help),
+        _ => None,
+    }
+}
+
+// Handle the byte that followed a break on COM1, in the serial interrupt
+// after the port was unlocked. Other letters are ignored.
+pub(crate) fn handle_serial_key(byte: u8) {
+    let action = match action_for_byte(byte) {
+        Some(action) => action,
+        None => return,
+    };
+    if !is_deferred(action) {
+        run(action);
+        return;
+    }
+    let work: fn(usize) = match action {
+        Action::Reboot => |_| run(Action::Reboot),
+        _ => |_| run(Action::Crash),
+    };
+    if softirq::schedule(work, 0).is_err() {
+        serial_println!("sysrq: deferred work queue full, try again");
+    }
+}
+
+// Whether an action from the serial port waits until the serial interrupt
+// is over (see above)
+fn is_deferred(action: Action) -> bool {
+    matches!(action, Action::Reboot | Action::Crash)
+}
+
+fn run(action: Action) {
+    match action {
+        Action::Registers => print_registers(),
+        Action::Memory => memory::dump_stats(),
+        Action::Dump => dump_state(),
+        Action::Tasks => dump_tasks(),
+        Action::Reboot => {
+            println!("sysrq: rebooting");
+            power::reboot();
+        }
+        Action::Crash => panic!("sysrq: crash triggered"),
+        Action::Help => {
+            println!("sysrq: p=registers m=memory d=dump t=tasks b=reboot c=crash h=help");
+        }
+    }
+}
+
+// Write what the heap, the executor and the locks that are held across
+// slow operations are doing to the serial port. Only reads counters and
+// tries the locks, so it works whatever the interrupted code was doing.
+fn dump_state() {
+    serial_println!("sysrq: state at {} ms", time::uptime_ms());
+    serial_println!("{}", memory::frame_stats());
+    serial_println!("{}", allocator::stats());
+
+    print_task_stats();
+
+    // Spin locks don't record their owners, only whether they are held
+    let locks = [
+        ("heap", allocator::is_locked()),
+        ("block cache", block::cache::is_locked()),
+        ("file descriptors", fd::is_locked()),
+    ];
+    for (name, locked) in locks {
+        serial_println!("lock {}: {}", name, if locked { "held" } else { "free" });
+    }
+}
+
+// Write the executor's state to the serial port
+fn print_task_stats() {
+    let tasks = executor::stats();
+    serial_println!("tasks: {} alive, {} polls", tasks.tasks, tasks.polls);
+    match tasks.running {
+        Some((id, since)) => serial_println!(
+            "tasks: task {} running for {} ms",
+            id,
+            time::uptime_ms().saturating_sub(since)
+        ),
+        None => serial_println!("tasks: none running"),
+    }
+}
+
+// Write the running task and the calls leading to the interrupt to the
+// serial port. The trace goes through the interrupt frame into the code
+// that was interrupted.
+fn dump_tasks() {
+    serial_println!("sysrq: tasks at {} ms", time::uptime_ms());
+    print_task_stats();
+    serial_println!("{}", backtrace::Backtrace);
+}
+
+// Print the control registers and the calls leading to the interrupt, and
+// write the executor's state to the serial port
+fn print_registers() {
+    use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+    use x86_64::registers::rflags;
+
+    let (level_4_table, cr3_flags) = Cr3::read();
+    println!("sysrq: registers");
+    println!("CR0: {:?}", Cr0::read());
+    println!("CR2: {:?}", Cr2::read());
+    println!("CR3: {:?} {:?}", level_4_table.start_address(), cr3_flags);
+    println!("CR4: {:?}", Cr4::read());
+    println!("RFLAGS: {:?}", rflags::read());
+    print_task_stats();
+    backtrace::print();
+}
+
+#[test_case]
+fn test_action_for() {
+    assert_eq!(action_for(KeyCode::M), Some(Action::Memory));
+    assert_eq!(action_for(KeyCode::B), Some(Action::Reboot));
+    assert_eq!(action_for(KeyCode::A), None);
+    assert_eq!(action_for_byte(b'D'), Some(Action::Dump));
+    assert_eq!(action_for_byte(b't'), Some(Action::Tasks));
+    assert_eq!(action_for_byte(b'x'), None);
+    assert!(is_deferred(Action::Crash) && is_deferred(Action::Reboot));
+    assert!(!is_deferred(Action::Dump));
+}
@@ -0,0 +1,144 @@
+// Cooperative multitasking with async/await.
+//
+// A `Task` wraps a future; the `executor` polls the tasks whose wakers were
+// called and halts the CPU when there is nothing to do.
+//
+// Nothing preempts kernel code, so a long loop keeps everything else
+// waiting: the other tasks, and the deferred interrupt work beyond what
+// runs on interrupt exit. Such loops give way once they have run for
+// `TIME_SLICE_NS`: in a task with `maybe_yield().await`, elsewhere with
+// `maybe_preempt`, which only runs the deferred work.
+
+use crate::{softirq, time};
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use x86_64::instructions::interrupts;
+
+pub mod executor;
+
+// A unit of work run by the executor
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+// Identifies a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+// How long code may run before it should give way, 2 ms
+pub const TIME_SLICE_NS: u64 = 2_000_000;
+
+// When the current time slice began, by `time::now_ns`
+static SLICE_START_NS: AtomicU64 = AtomicU64::new(0);
+
+// Start a new time slice; the executor calls this before every poll
+pub(crate) fn start_slice() {
+    SLICE_START_NS.store(time::now_ns(), Ordering::Relaxed);
+}
+
+// Whether the current time slice is used up
+pub fn slice_expired() -> bool {
+    time::now_ns().saturating_sub(SLICE_START_NS.load(Ordering::Relaxed)) >= TIME_SLICE_NS
+}
+
+// Run the pending deferred interrupt work if the time slice is used up,
+// and start a new one. Meant for long loops outside of tasks, or in
+// functions a task calls that can't await.
+//
+// The work also runs on interrupt exit, on top of whatever was
+// interrupted, so it is no less safe to run here with locks held. Does
+// nothing with interrupts disabled, as in interrupt handlers.
+pub fn maybe_preempt() {
+    if !slice_expired() || !interrupts::are_enabled() {
+        return;
+    }
+    softirq::run_pending();
+    start_slice();
+}
+
+// Return to the executor once, letting the other ready tasks and the
+// deferred interrupt work run before the task goes on
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+// Yield if the time slice is used up
+pub async fn maybe_yield() {
+    if slice_expired() {
+        yield_now().await;
+    }
+}
+
+// The future returned by `yield_now`
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        // Queue the task again behind the ones already woken
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_maybe_preempt() {
+    use core::sync::atomic::AtomicBool;
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    start_slice();
+    while !slice_expired() {
+        x86_64::instructions::hlt();
+    }
+    softirq::schedule(|_| RAN.store(true, Ordering::Relaxed), 0).unwrap();
+    maybe_preempt();
+    assert!(RAN.load(Ordering::Relaxed));
+    assert!(!slice_expired());
+}
+
+#[test_case]
+fn test_yield_now() {
+    use core::ptr;
+    use core::task::{RawWaker, RawWakerVTable, Waker};
+
+    fn raw_waker() -> RawWaker {
+        RawWaker::new(ptr::null(), &VTABLE)
+    }
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw_waker(), |_| {}, |_| {}, |_| {});
+    let waker = unsafe { Waker::from_raw(raw_waker()) };
+    let mut context = Context::from_waker(&waker);
+    let mut future = yield_now();
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Pending);
+    assert_eq!(Pin::new(&mut future).poll(&mut context), Poll::Ready(()));
+}
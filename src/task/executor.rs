@@ -0,0 +1,170 @@
+use super::{Task, TaskId};
+use crate::{softirq, time};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use x86_64::instructions::interrupts;
+
+// Maximum number of tasks woken but not polled yet
+const TASK_QUEUE_CAPACITY: usize = 100;
+
+// What the executors are doing, for diagnostics from interrupt handlers
+static TASK_COUNT: AtomicUsize = AtomicUsize::new(0);
+static POLLS: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicU64 = AtomicU64::new(NOT_RUNNING); // The id of the task being polled
+static RUNNING_SINCE_MS: AtomicU64 = AtomicU64::new(0);
+static IDLE: AtomicU64 = AtomicU64::new(0);
+
+const NOT_RUNNING: u64 = u64::MAX;
+
+// A snapshot of the executor counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub tasks: usize, // Tasks spawned and not completed
+    pub polls: u64,   // Polls since boot
+    // The id of the task being polled and the uptime when its poll began;
+    // a task that doesn't return keeps the others from running
+    pub running: Option<(u64, u64)>,
+    // Times the executor halted because no task was ready
+    pub idle: u64,
+}
+
+// Return the executor counters. Only reads atomics, so it can be used from
+// interrupt handlers.
+pub fn stats() -> Stats {
+    let running = match RUNNING.load(Ordering::Relaxed) {
+        NOT_RUNNING => None,
+        id => Some((id, RUNNING_SINCE_MS.load(Ordering::Relaxed))),
+    };
+    Stats {
+        tasks: TASK_COUNT.load(Ordering::Relaxed),
+        polls: POLLS.load(Ordering::Relaxed),
+        running,
+        idle: IDLE.load(Ordering::Relaxed),
+    }
+}
+
+// Runs tasks until they complete, polling each one whenever it is woken
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_CAPACITY)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    // Add a task; it is polled for the first time on the next round
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+        TASK_COUNT.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Run the tasks forever, halting when none of them is ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            // Deferred interrupt work runs between tasks, like in the idle
+            // loop
+            softirq::run_pending();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // Destructure `self` to avoid borrow checker errors
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        // Only the tasks woken before the round began are polled, so that
+        // the deferred work between rounds also runs for a task that keeps
+        // yielding
+        for _ in 0..task_queue.len() {
+            let task_id = match task_queue.pop() {
+                Some(task_id) => task_id,
+                None => break,
+            };
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // The task no longer exists
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            RUNNING_SINCE_MS.store(time::uptime_ms(), Ordering::Relaxed);
+            RUNNING.store(task_id.0, Ordering::Relaxed);
+            super::start_slice();
+            let poll = task.poll(&mut context);
+            RUNNING.store(NOT_RUNNING, Ordering::Relaxed);
+            POLLS.fetch_add(1, Ordering::Relaxed);
+            match poll {
+                Poll::Ready(()) => {
+                    // The task is done; remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                    TASK_COUNT.fetch_sub(1, Ordering::Relaxed);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Interrupts are disabled while checking, so a wakeup in between
+        // can't be missed; `enable_and_hlt` enables them atomically
+        interrupts::disable();
+        if self.task_queue.is_empty() && softirq::pending() == 0 {
+            IDLE.fetch_add(1, Ordering::Relaxed);
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+// Wakes a task by pushing its id to the task queue
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.task_id).expect("task queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
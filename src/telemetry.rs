@@ -0,0 +1,141 @@
+use crate::interrupts::{self, IRQ_COUNT};
+use crate::serial::SERIAL2;
+use crate::{allocator, memory, softirq, time};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+
+// Output format of the telemetry records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Format {
+    // One JSON object per line
+    Json = 0,
+    // Comma separated values, preceded by a header line
+    Csv = 1,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static FORMAT: AtomicU8 = AtomicU8::new(Format::Json as u8);
+static INTERVAL_MS: AtomicU64 = AtomicU64::new(1000);
+static NEXT_EMIT_MS: AtomicU64 = AtomicU64::new(0);
+
+// Start emitting a telemetry record every `interval_ms` milliseconds on COM2.
+//
+// The records are meant for host-side scripts and CI, so they are written to
+// a dedicated serial port instead of the human-readable console.
+pub fn enable(format: Format, interval_ms: u64) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+    INTERVAL_MS.store(interval_ms.max(1), Ordering::Relaxed);
+    NEXT_EMIT_MS.store(time::uptime_ms(), Ordering::Relaxed);
+
+    if format == Format::Csv {
+        write_line(format_args!("{}", csv_header()));
+    }
+    ENABLED.store(true, Ordering::Release);
+}
+
+// Stop emitting telemetry records.
+pub fn disable() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+// Whether records are being emitted
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+// Called from the timer interrupt. Writing a record takes a while at serial
+// speeds, so it is deferred to a softirq instead of done in the handler.
+pub(crate) fn on_tick() {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let now = time::uptime_ms();
+    let next = NEXT_EMIT_MS.load(Ordering::Relaxed);
+    if now >= next {
+        NEXT_EMIT_MS.store(now + INTERVAL_MS.load(Ordering::Relaxed), Ordering::Relaxed);
+        // If the queue is full this record is skipped; the next one follows
+        let _ = softirq::schedule(|_| emit(), 0);
+    }
+}
+
+// Write one telemetry record with the current counters.
+pub fn emit() {
+    let format = if FORMAT.load(Ordering::Relaxed) == Format::Csv as u8 {
+        Format::Csv
+    } else {
+        Format::Json
+    };
+    write_line(format_args!("{}", Record { format }));
+}
+
+// The CSV column names, matching the fields written by `Record`
+fn csv_header() -> &'static str {
+    "uptime_ms,ticks,softirq_processed,softirq_pending,\
+     heap_used,heap_available,heap_allocations,frames_in_use,frames_usable,\
+     irq0,irq1,irq2,irq3,irq4,irq5,irq6,irq7,irq8,irq9,irq10,irq11,irq12,irq13,irq14,irq15"
+}
+
+// A snapshot of the kernel counters, formatted on the fly so that emitting
+// a record doesn't allocate
+struct Record {
+    format: Format,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let uptime = time::uptime_ms();
+        let ticks = time::ticks();
+        let processed = softirq::processed();
+        let pending = softirq::pending();
+        let heap = allocator::stats();
+        // -1 if the heap was locked when the record was taken
+        let heap_available = heap.available.map_or(-1, |bytes| bytes as i64);
+        let frames = memory::frame_stats();
+
+        match self.format {
+            Format::Json => {
+                write!(
+                    f,
+                    "{{\"uptime_ms\":{},\"ticks\":{},\"softirq_processed\":{},\"softirq_pending\":{},",
+                    uptime, ticks, processed, pending
+                )?;
+                write!(
+                    f,
+                    "\"heap_used\":{},\"heap_available\":{},\"heap_allocations\":{},",
+                    heap.used, heap_available, heap.allocations
+                )?;
+                write!(
+                    f,
+                    "\"frames_in_use\":{},\"frames_usable\":{},\"irqs\":[",
+                    frames.in_use(), frames.usable
+                )?;
+                for irq in 0..IRQ_COUNT {
+                    if irq > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", interrupts::irq_count(irq as u8))?;
+                }
+                f.write_str("]}")
+            }
+            Format::Csv => {
+                write!(f, "{},{},{},{}", uptime, ticks, processed, pending)?;
+                write!(f, ",{},{},{}", heap.used, heap_available, heap.allocations)?;
+                write!(f, ",{},{}", frames.in_use(), frames.usable)?;
+                for irq in 0..IRQ_COUNT {
+                    write!(f, ",{}", interrupts::irq_count(irq as u8))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_line(args: fmt::Arguments) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut port = SERIAL2.lock();
+        let _ = port.write_fmt(args);
+        let _ = port.write_str("\n");
+    });
+}
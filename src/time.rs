@@ -0,0 +1,266 @@
+use crate::{apic, hpet, pit};
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod clock;
+
+// The timer frequency used if nothing else is requested
+pub const DEFAULT_FREQUENCY_HZ: u32 = 100;
+
+// The range of supported timer frequencies
+pub const MIN_FREQUENCY_HZ: u32 = 19;
+pub const MAX_FREQUENCY_HZ: u32 = 10_000;
+
+// Number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Frequency of the timer interrupt; the PIT defaults to ~18.2 Hz after reset
+static FREQUENCY_HZ: AtomicU32 = AtomicU32::new(18);
+
+// Nanoseconds since boot, advanced by one timer period on every tick so that
+// changing the frequency doesn't make the clock jump
+static UPTIME_NS: AtomicU64 = AtomicU64::new(0);
+
+// Added to the clock source by `now_ns`, so that it carries on from the
+// mocked time instead of going back when the mock ends
+static NOW_OFFSET_NS: AtomicU64 = AtomicU64::new(0);
+
+// TSC frequency in kHz, 0 if the TSC is not usable as a clock
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+
+// TSC value at calibration time, the epoch of `rdtsc_ns`
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// How long the TSC is measured against the PIT
+const TSC_CALIBRATION_MS: u32 = 10;
+
+// Wakers of pending `Timer` futures together with their deadlines in ms
+static SLEEPERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+// Start the periodic timer interrupt at the given frequency.
+//
+// Uses the local APIC timer, calibrated against the PIT, when the APICs are
+// enabled and falls back to PIT channel 0 otherwise. May be called again to
+// change the frequency.
+pub fn init(frequency_hz: u32) {
+    assert!(
+        (MIN_FREQUENCY_HZ..=MAX_FREQUENCY_HZ).contains(&frequency_hz),
+        "unsupported timer frequency {} Hz",
+        frequency_hz
+    );
+
+    if TSC_KHZ.load(Ordering::Relaxed) == 0 {
+        calibrate_tsc();
+    }
+
+    interrupts::without_interrupts(|| {
+        if apic::is_enabled() {
+            apic::start_timer(frequency_hz);
+        } else {
+            pit::set_frequency(frequency_hz);
+        }
+        FREQUENCY_HZ.store(frequency_hz, Ordering::Relaxed);
+    });
+}
+
+// Return the number of timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Return the number of timer ticks per second.
+pub fn frequency() -> u32 {
+    FREQUENCY_HZ.load(Ordering::Relaxed)
+}
+
+// Return the time since boot in milliseconds.
+//
+// The clock is monotonic and has the resolution of one timer tick.
+pub fn uptime_ms() -> u64 {
+    UPTIME_NS.load(Ordering::Relaxed) / 1_000_000
+}
+
+// Return a high resolution timestamp in nanoseconds since boot.
+//
+// Uses the HPET when available and falls back to the TSC and then to the
+// timer tick clock, which only has the resolution of one tick. While the
+// clock is mocked, this is the mocked time.
+pub fn now_ns() -> u64 {
+    if clock::is_mocked() {
+        return clock::mocked_ns();
+    }
+    source_ns().wrapping_add(NOW_OFFSET_NS.load(Ordering::Relaxed))
+}
+
+// Move `now_ns` forward to `ns` if it is behind, as after the mocked clock
+// ran ahead of the real one
+fn catch_up(ns: u64) {
+    interrupts::without_interrupts(|| {
+        let now = now_ns();
+        if ns > now {
+            NOW_OFFSET_NS.fetch_add(ns - now, Ordering::Relaxed);
+        }
+    });
+}
+
+// The clock `now_ns` reads
+fn source_ns() -> u64 {
+    if let Some(ns) = hpet::nanoseconds() {
+        return ns;
+    }
+    if has_invariant_tsc() && TSC_KHZ.load(Ordering::Relaxed) != 0 {
+        return rdtsc_ns();
+    }
+    UPTIME_NS.load(Ordering::Relaxed)
+}
+
+// Return whether the CPU has an invariant TSC, which ticks at a constant rate
+// regardless of power states (CPUID leaf 0x8000_0007, EDX bit 8).
+pub fn has_invariant_tsc() -> bool {
+    use core::arch::x86_64::__cpuid;
+
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    unsafe { __cpuid(0x8000_0007) }.edx & (1 << 8) != 0
+}
+
+// Return the TSC frequency in kHz as measured at boot, or 0 if it has not
+// been calibrated yet.
+pub fn tsc_khz() -> u64 {
+    TSC_KHZ.load(Ordering::Relaxed)
+}
+
+// Return a cheap nanosecond timestamp based on the TSC.
+//
+// Reading the TSC takes a few cycles, which makes this suitable for profiling
+// and benchmarks. The timestamps are only meaningful if `has_invariant_tsc`
+// returns true; falls back to the tick clock before the TSC is calibrated.
+pub fn rdtsc_ns() -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+    if khz == 0 {
+        return UPTIME_NS.load(Ordering::Relaxed);
+    }
+
+    let cycles = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+    (cycles as u128 * 1_000_000 / khz as u128) as u64
+}
+
+// Read the time stamp counter.
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+// Measure the TSC frequency against the PIT.
+fn calibrate_tsc() {
+    let (start, end) = interrupts::without_interrupts(|| {
+        let start = rdtsc();
+        pit::wait_ms(TSC_CALIBRATION_MS);
+        (start, rdtsc())
+    });
+
+    let khz = (end - start) / TSC_CALIBRATION_MS as u64;
+    TSC_BASE.store(start, Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+}
+
+// Block the CPU for at least the given number of milliseconds.
+//
+// The CPU is halted between timer ticks, so interrupts must be enabled. A
+// mocked clock is advanced instead.
+pub fn sleep(ms: u64) {
+    if clock::is_mocked() {
+        clock::advance_ms(ms);
+        return;
+    }
+    assert!(interrupts::are_enabled(), "sleep called with interrupts disabled");
+
+    let deadline = uptime_ms() + ms;
+    while uptime_ms() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+// A future that completes once the given amount of time has passed.
+//
+// Unlike `sleep` it doesn't block the CPU, so other tasks can run while
+// waiting. The future is woken from the timer interrupt.
+pub struct Timer {
+    deadline_ms: u64,
+}
+
+impl Timer {
+    // Create a timer that expires `ms` milliseconds from now.
+    pub fn after_ms(ms: u64) -> Self {
+        Timer {
+            deadline_ms: uptime_ms() + ms,
+        }
+    }
+
+    // Create a timer that expires once the uptime reaches `deadline_ms`.
+    pub fn at_ms(deadline_ms: u64) -> Self {
+        Timer { deadline_ms }
+    }
+}
+
+impl Future for Timer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let deadline = self.deadline_ms;
+        if uptime_ms() >= deadline {
+            return Poll::Ready(());
+        }
+
+        // Register the waker with interrupts disabled, so that the timer
+        // interrupt can't expire the deadline between the check and the push
+        interrupts::without_interrupts(|| {
+            if uptime_ms() >= deadline {
+                return Poll::Ready(());
+            }
+
+            let mut sleepers = SLEEPERS.lock();
+            let registered = sleepers
+                .iter()
+                .any(|(d, waker)| *d == deadline && waker.will_wake(cx.waker()));
+            if !registered {
+                sleepers.push((deadline, cx.waker().clone()));
+            }
+            Poll::Pending
+        })
+    }
+}
+
+// Called by the timer interrupt handler on every tick.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    if !clock::is_mocked() {
+        advance_uptime(1_000_000_000 / frequency() as u64);
+    }
+    wake_expired_timers();
+}
+
+// Move the uptime forward by the given number of nanoseconds
+fn advance_uptime(ns: u64) {
+    UPTIME_NS.fetch_add(ns, Ordering::Relaxed);
+}
+
+// Wake all `Timer` futures whose deadline has passed.
+fn wake_expired_timers() {
+    let now = uptime_ms();
+    let mut sleepers = SLEEPERS.lock();
+    sleepers.retain(|(deadline, waker)| {
+        if *deadline <= now {
+            waker.wake_by_ref();
+            false
+        } else {
+            true
+        }
+    });
+}
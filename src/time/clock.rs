@@ -0,0 +1,105 @@
+// A mock clock for deterministic tests.
+//
+// While the clock is mocked, timer interrupts no longer advance the uptime.
+// It only moves when a test calls `advance_ms` or `advance_ns`, which also
+// wake the `Timer` futures that expire. Timeouts measured with `uptime_ms`
+// or `now_ns` then behave the same on every run, however fast QEMU happens
+// to be, and `sleep` advances the clock instead of waiting:
+//
+//     let _clock = clock::mock();
+//     let timer = Timer::after_ms(10);
+//     clock::advance_ms(10); // `timer` expires here
+//
+// The real clock takes over again, from the mocked time on, when the guard
+// returned by `mock` is dropped. `now_ns` never goes back: if the mocked
+// clock ran ahead, the real one is moved forward to it.
+//
+// Drivers must not run while the clock is mocked. Their timeouts, like the
+// ones of AHCI and virtio requests, wait for `uptime_ms` to pass a deadline
+// while spinning, and nothing advances the mocked clock then.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+// Whether the clock is mocked
+static MOCKED: AtomicBool = AtomicBool::new(false);
+
+// `now_ns` when the clock was mocked, less the uptime then. The mocked
+// `now_ns` moves with the uptime from there.
+static MOCK_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+// Keeps the clock mocked while alive
+pub struct MockClock {
+    _private: (),
+}
+
+// Stop the timer interrupt from advancing the clock until the returned guard
+// is dropped. Panics if the clock is mocked already.
+pub fn mock() -> MockClock {
+    interrupts::without_interrupts(|| {
+        let now = super::now_ns();
+        assert!(!MOCKED.swap(true, Ordering::AcqRel), "the clock is mocked already");
+        let uptime = super::UPTIME_NS.load(Ordering::Relaxed);
+        MOCK_BASE_NS.store(now.wrapping_sub(uptime), Ordering::Relaxed);
+    });
+    MockClock { _private: () }
+}
+
+impl Drop for MockClock {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            let mocked = mocked_ns();
+            MOCKED.store(false, Ordering::Release);
+            super::catch_up(mocked);
+        });
+    }
+}
+
+// The mocked `now_ns`
+pub(super) fn mocked_ns() -> u64 {
+    super::UPTIME_NS
+        .load(Ordering::Relaxed)
+        .wrapping_add(MOCK_BASE_NS.load(Ordering::Relaxed))
+}
+
+// Return whether the clock is mocked
+pub fn is_mocked() -> bool {
+    MOCKED.load(Ordering::Acquire)
+}
+
+// Advance the mocked clock by the given number of milliseconds
+pub fn advance_ms(ms: u64) {
+    advance_ns(ms * 1_000_000);
+}
+
+// Advance the mocked clock by the given number of nanoseconds and wake the
+// timers that expired. Panics if the clock isn't mocked.
+pub fn advance_ns(ns: u64) {
+    assert!(is_mocked(), "the clock isn't mocked");
+    interrupts::without_interrupts(|| {
+        super::advance_uptime(ns);
+        super::wake_expired_timers();
+    });
+}
+
+#[test_case]
+fn test_mock_clock() {
+    let clock = mock();
+    let start_ms = super::uptime_ms();
+    let start_ns = super::now_ns();
+
+    advance_ms(10);
+    assert_eq!(super::uptime_ms(), start_ms + 10);
+    assert_eq!(super::now_ns(), start_ns + 10_000_000);
+
+    // Sleeping takes no real time, but moves the clock
+    super::sleep(5);
+    assert_eq!(super::uptime_ms(), start_ms + 15);
+
+    // Run far ahead of the real clock, which mustn't go back afterwards
+    advance_ms(60_000);
+    let mocked_ns = super::now_ns();
+    drop(clock);
+    assert!(!is_mocked());
+    assert!(super::now_ns() >= mocked_ns);
+}
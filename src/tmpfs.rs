@@ -0,0 +1,324 @@
+// A writable filesystem kept on the heap, mounted at /tmp.
+//
+// Inodes live in a table indexed by their number, the root directory being
+// inode 0. File contents are stored in separately allocated blocks, so a
+// file grows without moving what it holds already, and the whole
+// filesystem is limited to a number of blocks given when it is created.
+//
+// The heap is small and shared with the rest of the kernel, so the blocks
+// are allocated without panicking when it runs out, and not at all once the
+// allocator can hand out fewer than `HEAP_RESERVE` more bytes; writes then
+// fail with `NoSpace` even if the filesystem has room. Removing files doesn't
+// help with that, since the bump allocator only reuses freed memory once
+// everything on the heap is freed.
+
+use crate::allocator;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Metadata, VfsError};
+use alloc::alloc::{alloc_zeroed, Layout};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const BLOCK_SIZE: usize = 512;
+
+// The size of the filesystem `init` mounts, a tenth of the heap
+pub const DEFAULT_CAPACITY: u64 = 64 * 1024;
+
+// The heap bytes left to the rest of the kernel
+pub const HEAP_RESERVE: usize = 128 * 1024;
+
+const ROOT: usize = 0;
+
+const FILE_MODE: u32 = 0o644;
+const DIR_MODE: u32 = 0o755;
+
+enum Inode {
+    File {
+        blocks: Vec<Box<[u8; BLOCK_SIZE]>>,
+        size: u64,
+    },
+    Directory {
+        entries: Vec<(String, usize)>, // Names with their inode numbers
+    },
+}
+
+impl Inode {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Inode::File { size, .. } => Metadata {
+                file_type: FileType::File,
+                size: *size,
+                mode: FILE_MODE,
+            },
+            Inode::Directory { .. } => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+                mode: DIR_MODE,
+            },
+        }
+    }
+}
+
+struct Inner {
+    inodes: Vec<Option<Inode>>, // `None` for unused numbers
+    used_blocks: usize,
+    max_blocks: usize,
+}
+
+pub struct TmpFs {
+    inner: Mutex<Inner>,
+}
+
+impl TmpFs {
+    // Create an empty filesystem holding up to `capacity` bytes of file
+    // contents
+    pub fn new(capacity: u64) -> TmpFs {
+        let root = Inode::Directory {
+            entries: Vec::new(),
+        };
+        TmpFs {
+            inner: Mutex::new(Inner {
+                inodes: alloc::vec![Some(root)],
+                used_blocks: 0,
+                max_blocks: (capacity as usize + BLOCK_SIZE - 1) / BLOCK_SIZE,
+            }),
+        }
+    }
+
+    // Return the number of bytes of file contents that can still be added
+    pub fn available(&self) -> u64 {
+        let inner = self.inner.lock();
+        ((inner.max_blocks - inner.used_blocks) * BLOCK_SIZE) as u64
+    }
+}
+
+// Allocate a zeroed block, unless the heap is short
+fn allocate_block() -> Option<Box<[u8; BLOCK_SIZE]>> {
+    if allocator::available() < HEAP_RESERVE + BLOCK_SIZE {
+        return None;
+    }
+    // Not `Box::new`, which panics if the allocation fails
+    let block = unsafe { alloc_zeroed(Layout::new::<[u8; BLOCK_SIZE]>()) };
+    if block.is_null() {
+        return None;
+    }
+    Some(unsafe { Box::from_raw(block as *mut [u8; BLOCK_SIZE]) })
+}
+
+impl Inner {
+    // Return the inode number of a path
+    fn lookup(&self, path: &str) -> Result<usize, VfsError> {
+        let mut inode = ROOT;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            inode = match &self.inodes[inode] {
+                Some(Inode::Directory { entries }) => entries
+                    .iter()
+                    .find(|(n, _)| n == name)
+                    .map(|&(_, inode)| inode)
+                    .ok_or(VfsError::NotFound)?,
+                _ => return Err(VfsError::NotADirectory),
+            };
+        }
+        Ok(inode)
+    }
+
+    fn inode(&self, path: &str) -> Result<&Inode, VfsError> {
+        let number = self.lookup(path)?;
+        Ok(self.inodes[number].as_ref().expect("directory entry of a free inode"))
+    }
+
+    fn inode_mut(&mut self, path: &str) -> Result<&mut Inode, VfsError> {
+        let number = self.lookup(path)?;
+        Ok(self.inodes[number].as_mut().expect("directory entry of a free inode"))
+    }
+
+    // Return the entries of the directory a new file or directory at `path`
+    // goes in, together with its name
+    fn parent_entries<'a, 'p>(
+        &'a mut self,
+        path: &'p str,
+    ) -> Result<(&'a mut Vec<(String, usize)>, &'p str), VfsError> {
+        let (parent, name) = vfs::split_parent(path);
+        if name.is_empty() {
+            return Err(VfsError::Exists);
+        }
+        match self.inode_mut(parent)? {
+            Inode::Directory { entries } => Ok((entries, name)),
+            Inode::File { .. } => Err(VfsError::NotADirectory),
+        }
+    }
+
+    // Add an inode to the directory it goes in
+    fn add(&mut self, path: &str, inode: Inode) -> Result<(), VfsError> {
+        // The number the inode will get
+        let number = self.inodes.iter().position(Option::is_none).unwrap_or(self.inodes.len());
+        let (entries, name) = self.parent_entries(path)?;
+        if entries.iter().any(|(n, _)| n == name) {
+            return Err(VfsError::Exists);
+        }
+        entries.push((String::from(name), number));
+        if number == self.inodes.len() {
+            self.inodes.push(Some(inode));
+        } else {
+            self.inodes[number] = Some(inode);
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError> {
+        Ok(self.inner.lock().inode(path)?.metadata())
+    }
+
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+        let inner = self.inner.lock();
+        let (blocks, size) = match inner.inode(path)? {
+            Inode::File { blocks, size } => (blocks, *size),
+            Inode::Directory { .. } => return Err(VfsError::IsADirectory),
+        };
+        let len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let mut done = 0;
+        while done < len {
+            let position = offset as usize + done;
+            let start = position % BLOCK_SIZE;
+            let piece = (BLOCK_SIZE - start).min(len - done);
+            let block = &blocks[position / BLOCK_SIZE];
+            buf[done..done + piece].copy_from_slice(&block[start..start + piece]);
+            done += piece;
+        }
+        Ok(len)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError> {
+        let inner = self.inner.lock();
+        let entries = match inner.inode(path)? {
+            Inode::Directory { entries } => entries,
+            Inode::File { .. } => return Err(VfsError::NotADirectory),
+        };
+        let entries = entries
+            .iter()
+            .map(|(name, number)| DirEntry {
+                name: name.clone(),
+                metadata: inner.inodes[*number].as_ref().expect("free inode").metadata(),
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    fn create(&self, path: &str) -> Result<(), VfsError> {
+        let mut inner = self.inner.lock();
+        let number = match inner.lookup(path) {
+            Ok(number) => number,
+            Err(VfsError::NotFound) => {
+                let file = Inode::File {
+                    blocks: Vec::new(),
+                    size: 0,
+                };
+                return inner.add(path, file);
+            }
+            Err(err) => return Err(err),
+        };
+        let freed = match inner.inodes[number].as_mut() {
+            Some(Inode::File { blocks, size }) => {
+                *size = 0;
+                core::mem::take(blocks).len()
+            }
+            _ => return Err(VfsError::IsADirectory),
+        };
+        inner.used_blocks -= freed;
+        Ok(())
+    }
+
+    // Writes what fits when the filesystem is full; 0 if nothing does.
+    // Fails with `NoSpace` if the heap has no room for any of the blocks.
+    fn write(&self, path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+        let mut inner = self.inner.lock();
+        let available = inner.max_blocks - inner.used_blocks;
+        let (blocks, size) = match inner.inode_mut(path)? {
+            Inode::File { blocks, size } => (blocks, size),
+            Inode::Directory { .. } => return Err(VfsError::IsADirectory),
+        };
+
+        // Nothing fits past the last block the filesystem could hold
+        let limit = ((blocks.len() + available) * BLOCK_SIZE) as u64;
+        if data.is_empty() || offset >= limit {
+            return Ok(0);
+        }
+
+        // Add the blocks up to the end of the write, zeroed for any gap
+        let offset = offset as usize;
+        let end = offset + data.len();
+        let needed = ((end + BLOCK_SIZE - 1) / BLOCK_SIZE).saturating_sub(blocks.len());
+        let wanted = needed.min(available);
+        if blocks.try_reserve(wanted).is_err() {
+            return Err(VfsError::NoSpace);
+        }
+        let mut added = 0;
+        while added < wanted {
+            match allocate_block() {
+                Some(block) => blocks.push(block),
+                None => break,
+            }
+            added += 1;
+        }
+        let len = end.min(blocks.len() * BLOCK_SIZE).saturating_sub(offset);
+        if len == 0 && added < wanted {
+            return Err(VfsError::NoSpace);
+        }
+
+        let mut done = 0;
+        while done < len {
+            let position = offset + done;
+            let start = position % BLOCK_SIZE;
+            let piece = (BLOCK_SIZE - start).min(len - done);
+            let block = &mut blocks[position / BLOCK_SIZE];
+            block[start..start + piece].copy_from_slice(&data[done..done + piece]);
+            done += piece;
+        }
+        *size = (*size).max((offset + len) as u64);
+        inner.used_blocks += added;
+        Ok(len)
+    }
+
+    fn create_dir(&self, path: &str) -> Result<(), VfsError> {
+        let directory = Inode::Directory {
+            entries: Vec::new(),
+        };
+        self.inner.lock().add(path, directory)
+    }
+
+    fn remove(&self, path: &str) -> Result<(), VfsError> {
+        let mut inner = self.inner.lock();
+        let number = inner.lookup(path)?;
+        if number == ROOT {
+            return Err(VfsError::Busy);
+        }
+        let freed = match &inner.inodes[number] {
+            Some(Inode::Directory { entries }) if !entries.is_empty() => {
+                return Err(VfsError::NotEmpty);
+            }
+            Some(Inode::File { blocks, .. }) => blocks.len(),
+            _ => 0,
+        };
+        let (entries, name) = inner.parent_entries(path)?;
+        entries.retain(|(n, _)| n != name);
+        inner.inodes[number] = None;
+        inner.used_blocks -= freed;
+        Ok(())
+    }
+}
+
+// Mount a tmpfs of the default size at /tmp. Must be called after the heap
+// is initialized.
+pub fn init() {
+    if let Err(err) = vfs::mount("/tmp", Arc::new(TmpFs::new(DEFAULT_CAPACITY))) {
+        log::error!("tmpfs: can't mount /tmp: {:?}", err);
+    }
+}
@@ -0,0 +1,238 @@
+// Copying data between the kernel and user space.
+//
+// A pointer passed in by user space may point anywhere: into the kernel, at
+// unmapped memory or at a non-canonical address. The helpers here first
+// check that the whole range lies in the lower, user half of the address
+// space, and that none of its pages is a kernel page. The bootloader loads
+// the kernel in the lower half too, with its heap and device mappings, so
+// the range alone would let user space name kernel memory. Then they copy
+// with small assembly routines whose memory accesses are listed in a fixup
+// table. If one of them page faults, the page fault
+// handler resumes at the routine's fixup code instead of treating it as a
+// kernel bug, and the copy fails with `UserCopyError::Fault`.
+//
+// With SMAP enabled in CR4 the kernel can only touch user pages while
+// RFLAGS.AC is set, so the copies are bracketed with `stac` and `clac`.
+
+use core::arch::{asm, global_asm};
+use crate::memory;
+use core::ptr::addr_of;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+// The end of the user half of the address space
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+// Errors from copying to or from user space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserCopyError {
+    // The range is not entirely in user space
+    BadAddress,
+    // Part of the range is not mapped
+    Fault,
+}
+
+// The copy routines. Each labels the instruction that touches user memory
+// (`*_access`) and the code to continue with if it faults (`*_fixup`).
+global_asm!(
+    r#"
+.global usercopy_bytes
+.global usercopy_bytes_access
+.global usercopy_bytes_fixup
+// Copy rdx bytes from rsi to rdi; returns the number of bytes not copied
+usercopy_bytes:
+    mov rcx, rdx
+usercopy_bytes_access:
+    rep movsb
+usercopy_bytes_fixup:
+    // After a fault, rcx holds the bytes left
+    mov rax, rcx
+    ret
+
+.global usercopy_strncpy
+.global usercopy_strncpy_access
+.global usercopy_strncpy_fixup
+// Copy a NUL terminated string of at most rdx bytes from rsi to rdi; returns
+// its length without the NUL, rdx if there was none, or -1 on a fault
+usercopy_strncpy:
+    xor eax, eax
+usercopy_strncpy_loop:
+    cmp rax, rdx
+    je usercopy_strncpy_done
+usercopy_strncpy_access:
+    mov cl, [rsi + rax]
+    mov [rdi + rax], cl
+    test cl, cl
+    je usercopy_strncpy_done
+    inc rax
+    jmp usercopy_strncpy_loop
+usercopy_strncpy_done:
+    ret
+usercopy_strncpy_fixup:
+    mov rax, -1
+    ret
+"#
+);
+
+extern "C" {
+    fn usercopy_bytes(dst: *mut u8, src: *const u8, count: usize) -> usize;
+    fn usercopy_strncpy(dst: *mut u8, src: *const u8, max: usize) -> isize;
+
+    static usercopy_bytes_access: u8;
+    static usercopy_bytes_fixup: u8;
+    static usercopy_strncpy_access: u8;
+    static usercopy_strncpy_fixup: u8;
+}
+
+// The instructions that may fault on a user address, and where to continue
+// when they do
+fn fixup_table() -> [(u64, u64); 2] {
+    unsafe {
+        [
+            (addr_of!(usercopy_bytes_access) as u64, addr_of!(usercopy_bytes_fixup) as u64),
+            (addr_of!(usercopy_strncpy_access) as u64, addr_of!(usercopy_strncpy_fixup) as u64),
+        ]
+    }
+}
+
+// Return where to continue after a page fault at `rip`, if the faulting
+// instruction is a user space access. Called by the page fault handler.
+pub(crate) fn fixup_address(rip: u64) -> Option<u64> {
+    fixup_table()
+        .iter()
+        .find(|&&(access, _)| access == rip)
+        .map(|&(_, fixup)| fixup)
+}
+
+// Return whether `len` bytes at `addr` lie entirely in user space, in
+// pages that are mapped for user mode or not mapped at all. Unmapped pages
+// are left to the copy, which fails with `Fault` on them. Always `false`
+// before `memory::init`, when the mappings can't be looked up.
+pub fn access_ok(addr: u64, len: usize) -> bool {
+    let end = match addr.checked_add(len as u64) {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    if !memory::is_initialized() {
+        return false;
+    }
+    let mut page = addr & !0xFFF;
+    while page < end {
+        let size = match memory::translate(VirtAddr::new(page)) {
+            Some(translation) if !translation.flags.contains(PageTableFlags::USER_ACCESSIBLE) => {
+                return false;
+            }
+            Some(translation) => translation.page_size.bytes(),
+            None => 4096,
+        };
+        page = (page & !(size - 1)) + size;
+    }
+    true
+}
+
+// Allows access to user pages while alive, if SMAP is enabled
+struct UserAccess {
+    smap: bool,
+}
+
+impl UserAccess {
+    fn new() -> UserAccess {
+        let smap = Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+        if smap {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccess { smap }
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if self.smap {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+// Fill `dst` with the bytes at the user address `src`
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UserCopyError> {
+    if !access_ok(src, dst.len()) {
+        return Err(UserCopyError::BadAddress);
+    }
+    let _access = UserAccess::new();
+    let left = unsafe { usercopy_bytes(dst.as_mut_ptr(), src as *const u8, dst.len()) };
+    if left != 0 {
+        return Err(UserCopyError::Fault);
+    }
+    Ok(())
+}
+
+// Copy `src` to the user address `dst`
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UserCopyError> {
+    if !access_ok(dst, src.len()) {
+        return Err(UserCopyError::BadAddress);
+    }
+    let _access = UserAccess::new();
+    let left = unsafe { usercopy_bytes(dst as *mut u8, src.as_ptr(), src.len()) };
+    if left != 0 {
+        return Err(UserCopyError::Fault);
+    }
+    Ok(())
+}
+
+// Copy the NUL terminated string at the user address `src` into `dst`,
+// including the NUL. Returns the length of the string; if it equals
+// `dst.len()`, the string didn't fit and `dst` isn't NUL terminated.
+//
+// All of the `dst.len()` bytes the string may take must pass `access_ok`,
+// even if it is shorter.
+pub fn strncpy_from_user(dst: &mut [u8], src: u64) -> Result<usize, UserCopyError> {
+    if src >= USER_SPACE_END {
+        return Err(UserCopyError::BadAddress);
+    }
+    // Don't read past the end of user space, even if `dst` is larger
+    let max = dst.len().min((USER_SPACE_END - src) as usize);
+    if !access_ok(src, max) {
+        return Err(UserCopyError::BadAddress);
+    }
+    let _access = UserAccess::new();
+    let len = unsafe { usercopy_strncpy(dst.as_mut_ptr(), src as *const u8, max) };
+    if len < 0 {
+        return Err(UserCopyError::Fault);
+    }
+    if len as usize == max && max < dst.len() {
+        // The string runs into the end of user space
+        return Err(UserCopyError::BadAddress);
+    }
+    Ok(len as usize)
+}
+
+#[test_case]
+fn test_access_ok() {
+    // Unmapped
+    assert!(access_ok(0x_3333_0000_0000, 0x1000));
+    assert!(access_ok(USER_SPACE_END - 8, 8));
+    assert!(!access_ok(USER_SPACE_END - 8, 9));
+    assert!(!access_ok(u64::MAX, 1));
+}
+
+#[test_case]
+fn test_copy_from_user() {
+    // The kernel is loaded in the lower half, but its pages aren't user
+    // pages. Heap memory is refused the same way (see tests/heap_allocation).
+    static SOURCE: [u8; 8] = *b"hi\0there";
+    let kernel = SOURCE.as_ptr() as u64;
+    assert!(kernel < USER_SPACE_END);
+
+    let mut buffer = [0u8; 8];
+    assert_eq!(copy_from_user(&mut buffer, kernel), Err(UserCopyError::BadAddress));
+    assert_eq!(strncpy_from_user(&mut buffer, kernel), Err(UserCopyError::BadAddress));
+    assert_eq!(copy_to_user(buffer.as_mut_ptr() as u64, b"x"), Err(UserCopyError::BadAddress));
+    assert_eq!(buffer, [0; 8]);
+
+    // Unmapped memory faults instead of crashing
+    assert_eq!(copy_from_user(&mut buffer, 0x_3333_0000_0000), Err(UserCopyError::Fault));
+    assert_eq!(strncpy_from_user(&mut buffer, 0x_3333_0000_0000), Err(UserCopyError::Fault));
+    assert_eq!(copy_to_user(0x_3333_0000_0000, &buffer), Err(UserCopyError::Fault));
+    assert_eq!(copy_from_user(&mut buffer, USER_SPACE_END), Err(UserCopyError::BadAddress));
+}
@@ -0,0 +1,189 @@
+// Running code in ring 3.
+//
+// User code runs with the user segments of the GDT and can only touch pages
+// mapped USER_ACCESSIBLE, all of which lie below `USER_SPACE_END`. `enter`
+// drops to ring 3 by building the frame an interrupt from ring 3 would have
+// pushed and returning through it with `iretq`. When an interrupt or an
+// exception arrives in ring 3, the CPU switches to the stack in the TSS
+// (see `gdt::privilege_stack_top`) before running the handler. System calls
+// (see `syscall`) come in on that stack as well.
+//
+// `run` is `enter` for programs that end: it saves the kernel's
+// callee-saved registers and stack pointer first, and the exit system call
+// restores them with `exit`, which makes `run` return the exit status.
+// An exception in such a program ends it the same way (see `kill`), with
+// the status of a program killed by a signal, so a bad user program can't
+// stop the kernel.
+
+use crate::gdt;
+use crate::memory::{self, Frames};
+use crate::usercopy::USER_SPACE_END;
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::segmentation::{Segment, DS, ES};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::page::PageRangeInclusive;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+// RFLAGS for user code: interrupts enabled, plus bit 1, which is always set
+const USER_RFLAGS: u64 = 0x202;
+
+// The signals for the exceptions that end a program, with the numbers
+// they have on Linux. A program killed by one exits with 128 plus the
+// number, as a shell reports it.
+pub const SIGILL: u64 = 4;
+pub const SIGBUS: u64 = 7;
+pub const SIGFPE: u64 = 8;
+pub const SIGSEGV: u64 = 11;
+
+// Map fresh frames at `pages` for user code, with `flags` added to
+// PRESENT and USER_ACCESSIBLE. The frames are cleared, since freed frames
+// come back with whatever the kernel or another program left in them.
+//
+// This function is unsafe because `memory::init` must have been called.
+pub unsafe fn map_pages(
+    mapper: &mut OffsetPageTable,
+    pages: PageRangeInclusive<Size4KiB>,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        pages.end.start_address().as_u64() < USER_SPACE_END,
+        "user pages must be in the lower half"
+    );
+    let flags = flags | PageTableFlags::USER_ACCESSIBLE;
+    memory::map_range(mapper, pages, Frames::Allocate, flags, frame_allocator)?;
+    // Through the physical memory mapping, since `mapper` needn't be the
+    // active address space
+    for page in pages {
+        let frame = mapper.translate_page(page).expect("page just mapped");
+        let start: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        start.write_bytes(0, 4096);
+    }
+    Ok(())
+}
+
+// Switch to ring 3 and jump to `entry`, with the stack pointer at
+// `stack_top`. Doesn't return; the kernel regains control through
+// interrupts.
+//
+// This function is unsafe because `entry` must be user code mapped with
+// `map_pages`, and the stack must be mapped writable for user space.
+pub unsafe fn enter(entry: VirtAddr, stack_top: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64;
+    let data = selectors.user_data_selector.0 as u64;
+    asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        // The frame `iretq` pops: SS, RSP, RFLAGS, CS and RIP
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack_top.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn),
+    );
+}
+
+// The kernel stack pointer in `usermode_run`, 0 when no program was
+// started by `run`
+static KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    r#"
+.global usermode_run
+// Enter ring 3 at rdi with the stack pointer at rsi, using the code segment
+// in rdx and the data segment in rcx. Returns the status `usermode_exit` is
+// called with.
+usermode_run:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rip + {kernel_rsp}], rsp
+    mov ds, cx
+    mov es, cx
+    push rcx
+    push rsi
+    // USER_RFLAGS
+    push 0x202
+    push rdx
+    push rdi
+    iretq
+
+.global usermode_exit
+// Return from `usermode_run` with the status in rdi
+usermode_exit:
+    mov rsp, [rip + {kernel_rsp}]
+    mov rax, rdi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#,
+    kernel_rsp = sym KERNEL_RSP,
+);
+
+extern "C" {
+    fn usermode_run(entry: u64, stack_top: u64, code: u64, data: u64) -> u64;
+    fn usermode_exit(status: u64) -> !;
+}
+
+// Run user code like `enter`, until it makes the exit system call, and
+// return its exit status.
+//
+// This function is unsafe for the same reasons as `enter`.
+pub unsafe fn run(entry: VirtAddr, stack_top: VirtAddr) -> u64 {
+    let selectors = gdt::selectors();
+    let code = selectors.user_code_selector.0 as u64;
+    let data = selectors.user_data_selector.0 as u64;
+    let status = usermode_run(entry.as_u64(), stack_top.as_u64(), code, data);
+    KERNEL_RSP.store(0, Ordering::Relaxed);
+    DS::set_reg(selectors.data_selector);
+    ES::set_reg(selectors.data_selector);
+    status
+}
+
+// Go back to the kernel from the exit system call, making `run` return
+// `status`. The user program's state is dropped.
+//
+// This function is unsafe because it must be called with interrupts
+// disabled, from a system call made by a program started by `run`.
+pub(crate) unsafe fn exit(status: u64) -> ! {
+    assert!(
+        KERNEL_RSP.load(Ordering::Relaxed) != 0,
+        "exit from a program not started by usermode::run"
+    );
+    usermode_exit(status)
+}
+
+// Whether a program started by `run` is running
+pub fn is_running() -> bool {
+    KERNEL_RSP.load(Ordering::Relaxed) != 0
+}
+
+// End the program started by `run` for an exception `signal` stands for,
+// making `run` return 128 plus the signal.
+//
+// This function is unsafe because it must be called from the handler of
+// an exception raised in ring 3 by a program started by `run`.
+pub(crate) unsafe fn kill(signal: u64) -> ! {
+    exit(128 + signal)
+}
@@ -0,0 +1,42 @@
+// Information about the kernel build, collected by `build.rs`.
+//
+// Printed at boot and in panic reports, so that logs can be matched to the
+// exact build that produced them.
+
+use core::fmt;
+
+// The crate version from Cargo.toml
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// The abbreviated git commit, with "-dirty" if there were local changes
+pub const GIT_HASH: &str = env!("RUST_OS_GIT_HASH");
+
+// When the kernel was built, in UTC
+pub const BUILD_TIME: &str = env!("RUST_OS_BUILD_TIME");
+
+// The output of `rustc --version` for the compiler that built the kernel
+pub const RUSTC_VERSION: &str = env!("RUST_OS_RUSTC_VERSION");
+
+// The enabled cargo features, comma separated
+pub const FEATURES: &str = env!("RUST_OS_FEATURES");
+
+// The cargo profile, "debug" or "release"
+pub const PROFILE: &str = env!("RUST_OS_PROFILE");
+
+// Displays the version on one line, like
+// "rust_os 0.1.0 (1a2b3c4, debug, built 2024-01-01 12:00:00 with rustc ...)"
+pub struct Banner;
+
+impl fmt::Display for Banner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rust_os {} ({}, {}, built {} with {}",
+            VERSION, GIT_HASH, PROFILE, BUILD_TIME, RUSTC_VERSION
+        )?;
+        if !FEATURES.is_empty() {
+            write!(f, ", features: {}", FEATURES)?;
+        }
+        write!(f, ")")
+    }
+}
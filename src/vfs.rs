@@ -0,0 +1,295 @@
+// A virtual filesystem joining the mounted filesystems into one tree.
+//
+// A filesystem implements `FileSystem` and is mounted at an absolute path
+// with `mount`. The functions of this module take absolute paths, find the
+// filesystem mounted at the longest prefix of the path and pass it the rest
+// of the path, so every filesystem sees paths relative to its own root, in
+// the form "/" or "/dir/file". Paths are normalized first: empty and "."
+// components are dropped and ".." goes up a level, never above the root.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Errors of filesystem operations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    Exists,
+    ReadOnly,
+    NoSpace,
+    NotEmpty,
+    Busy,        // E.g. removing the root of a filesystem
+    BadPath,     // The path isn't absolute
+    Unsupported, // E.g. reading a symbolic link, which aren't followed
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    pub mode: u32, // Permission bits
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::Directory
+    }
+}
+
+// An entry of a directory listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub metadata: Metadata,
+}
+
+// A filesystem that can be mounted. The paths are normalized and relative
+// to the root of the filesystem.
+pub trait FileSystem: Send + Sync {
+    // The kind of filesystem, e.g. "initrd"
+    fn name(&self) -> &'static str;
+
+    fn metadata(&self, path: &str) -> Result<Metadata, VfsError>;
+
+    // Read from a file at `offset` and return how many bytes were read, 0 at
+    // the end of the file
+    fn read(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError>;
+
+    // Return the entries of a directory, without "." and ".."
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, VfsError>;
+
+    // Create an empty file, or empty the file if it exists. Read-only
+    // filesystems keep the default.
+    fn create(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    // Write to a file at `offset`, growing it as needed, and return how
+    // many bytes were written
+    fn write(&self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    fn create_dir(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    // Remove a file, or a directory if it is empty
+    fn remove(&self, _path: &str) -> Result<(), VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+}
+
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+}
+
+// The mounted filesystems
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+// Mount a filesystem at an absolute path. Fails with `Exists` if another
+// filesystem is mounted there. The path doesn't need to exist in the
+// filesystem below.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        if mounts.iter().any(|mount| mount.path == path) {
+            return Err(VfsError::Exists);
+        }
+        log::info!("vfs: {} mounted at {}", fs.name(), path);
+        mounts.push(Mount { path, fs });
+        Ok(())
+    })
+}
+
+// Unmount the filesystem mounted at `path`
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = normalize(path)?;
+    interrupts::without_interrupts(|| {
+        let mut mounts = MOUNTS.lock();
+        let index = mounts.iter().position(|mount| mount.path == path);
+        mounts.remove(index.ok_or(VfsError::NotFound)?);
+        Ok(())
+    })
+}
+
+// Return the mount points and the kinds of the filesystems mounted there
+pub fn mounts() -> Vec<(String, &'static str)> {
+    interrupts::without_interrupts(|| {
+        MOUNTS.lock().iter().map(|mount| (mount.path.clone(), mount.fs.name())).collect()
+    })
+}
+
+pub fn metadata(path: &str) -> Result<Metadata, VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.metadata(&path)
+}
+
+// Read from a file at `offset` and return how many bytes were read
+pub fn read(path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.read(&path, offset, buf)
+}
+
+// Read a whole file
+pub fn read_file(path: &str) -> Result<Vec<u8>, VfsError> {
+    let (fs, path) = resolve(path)?;
+    let metadata = fs.metadata(&path)?;
+    if metadata.is_dir() {
+        return Err(VfsError::IsADirectory);
+    }
+    let mut data = vec![0; metadata.size as usize];
+    let mut done = 0;
+    while done < data.len() {
+        match fs.read(&path, done as u64, &mut data[done..])? {
+            0 => break,
+            len => done += len,
+        }
+    }
+    data.truncate(done);
+    Ok(data)
+}
+
+// Create an empty file, or empty the file if it exists
+pub fn create(path: &str) -> Result<(), VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.create(&path)
+}
+
+// Write to a file at `offset` and return how many bytes were written
+pub fn write(path: &str, offset: u64, data: &[u8]) -> Result<usize, VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.write(&path, offset, data)
+}
+
+pub fn create_dir(path: &str) -> Result<(), VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.create_dir(&path)
+}
+
+// Remove a file, or a directory if it is empty. Mount points can't be
+// removed.
+pub fn remove(path: &str) -> Result<(), VfsError> {
+    let (fs, path) = resolve(path)?;
+    if path == "/" {
+        return Err(VfsError::Busy);
+    }
+    fs.remove(&path)
+}
+
+// Replace the contents of a file, creating it if needed
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), VfsError> {
+    let (fs, path) = resolve(path)?;
+    fs.create(&path)?;
+    let mut done = 0;
+    while done < data.len() {
+        match fs.write(&path, done as u64, &data[done..])? {
+            0 => return Err(VfsError::NoSpace),
+            len => done += len,
+        }
+    }
+    Ok(())
+}
+
+// Return the entries of a directory, including the filesystems mounted
+// right below it
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let path = normalize(path)?;
+    let mounts = mounts();
+    let mut entries = match resolve(&path) {
+        Ok((fs, relative)) => fs.read_dir(&relative)?,
+        // Mount points may be all there is, e.g. /tmp without a root
+        Err(VfsError::NotFound) if mounts.iter().any(|(mount, _)| is_below(mount, &path)) => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+    for (mount, _) in mounts {
+        let (parent, name) = split_parent(&mount);
+        if parent != path || name.is_empty() || entries.iter().any(|entry| entry.name == name) {
+            continue;
+        }
+        if let Ok(metadata) = metadata(&mount) {
+            entries.push(DirEntry {
+                name: String::from(name),
+                metadata,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// Return the filesystem a path is on, and the path relative to its root
+fn resolve(path: &str) -> Result<(Arc<dyn FileSystem>, String), VfsError> {
+    let path = normalize(path)?;
+    interrupts::without_interrupts(|| {
+        let mounts = MOUNTS.lock();
+        let mount = mounts
+            .iter()
+            .filter(|mount| is_below(&path, &mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .ok_or(VfsError::NotFound)?;
+        let relative = match &path[mount.path.len()..] {
+            _ if mount.path == "/" => path.clone(),
+            "" => String::from("/"),
+            rest => String::from(rest),
+        };
+        Ok((mount.fs.clone(), relative))
+    })
+}
+
+// Whether the normalized `path` is `dir` or below it
+fn is_below(path: &str, dir: &str) -> bool {
+    dir == "/" || path == dir || (path.starts_with(dir) && path[dir.len()..].starts_with('/'))
+}
+
+// Normalize an absolute path
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::BadPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return Ok(String::from("/"));
+    }
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    Ok(normalized)
+}
+
+// Split a normalized path into its parent directory and its last component
+pub fn split_parent(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => ("/", path),
+    }
+}
@@ -23,7 +23,9 @@ pub enum Color {
 
 use volatile::Volatile;
 use core::fmt::{Write, Result, Arguments};
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
+use crate::snapshot::Snapshot;
 use spin::Mutex;
 
 // Struct representing the color code for text
@@ -36,6 +38,89 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    // Return a copy with the foreground color replaced
+    fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode((self.0 & 0xF0) | foreground as u8)
+    }
+
+    // Return a copy with the background color replaced
+    fn with_background(self, background: Color) -> ColorCode {
+        ColorCode((self.0 & 0x0F) | (background as u8) << 4)
+    }
+
+    // Return a copy with the bright variant of the foreground color
+    fn with_bright_foreground(self) -> ColorCode {
+        ColorCode(self.0 | 0x08)
+    }
+}
+
+// The eight ANSI colors, in the order of their SGR codes
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+
+// The bright variants of the ANSI colors (SGR codes 90-97 and 100-107)
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+];
+
+// Maximum number of numeric parameters kept from an escape sequence
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+// State of the ANSI escape sequence parser
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,  // Regular text
+    Escape,  // Received ESC, waiting for '['
+    Csi,     // Inside a control sequence, collecting parameters
+}
+
+// Parser for the subset of ANSI escape sequences the writer understands
+struct EscapeParser {
+    state: EscapeState,
+    params: [u16; MAX_ESCAPE_PARAMS],
+    param_index: usize,
+}
+
+impl EscapeParser {
+    const fn new() -> EscapeParser {
+        EscapeParser {
+            state: EscapeState::Normal,
+            params: [0; MAX_ESCAPE_PARAMS],
+            param_index: 0,
+        }
+    }
+
+    // Start collecting the parameters of a new control sequence
+    fn begin_csi(&mut self) {
+        self.state = EscapeState::Csi;
+        self.params = [0; MAX_ESCAPE_PARAMS];
+        self.param_index = 0;
+    }
+
+    // Append a decimal digit to the current parameter
+    fn push_digit(&mut self, digit: u8) {
+        if self.param_index < MAX_ESCAPE_PARAMS {
+            let param = &mut self.params[self.param_index];
+            *param = param.saturating_mul(10).saturating_add((digit - b'0') as u16);
+        }
+    }
+
+    // Return the parameters received so far; missing ones are 0
+    fn params(&self) -> &[u16] {
+        &self.params[..(self.param_index + 1).min(MAX_ESCAPE_PARAMS)]
+    }
+
+    // Return the parameter at `index`, or `default` if it is missing or 0
+    fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&param) if param != 0 => param,
+            _ => default,
+        }
+    }
 }
 
 // Struct representing a character on the screen
@@ -47,8 +132,28 @@ struct ScreenChar {
 }
 
 // Constants for the VGA buffer size
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub(crate) const BUFFER_HEIGHT: usize = 25;
+pub(crate) const BUFFER_WIDTH: usize = 80;
+
+// CRT controller index and data ports, used to program the hardware cursor
+const CRTC_INDEX_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+
+// CRT controller registers
+const CRTC_CURSOR_START: u8 = 0x0A;
+const CRTC_CURSOR_END: u8 = 0x0B;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0E;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0F;
+
+// Bit in the cursor start register that disables the cursor
+const CURSOR_DISABLE: u8 = 0x20;
+
+// Scanlines of the underline cursor shape
+const CURSOR_SCANLINE_START: u8 = 14;
+const CURSOR_SCANLINE_END: u8 = 15;
+
+// The physical (and identity mapped) address of the VGA text buffer
+const VGA_BUFFER_ADDRESS: usize = 0xb8000;
 
 // Struct representing the VGA buffer
 #[repr(transparent)]
@@ -56,24 +161,244 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// Every row of the screen, as a mask of dirty rows
+const ALL_ROWS: u32 = (1 << BUFFER_HEIGHT) - 1;
+
+// The shadow buffer of the global `WRITER`. Writers modify a copy of the
+// screen in RAM that `flush` copies to the VGA memory, so that e.g. scrolling
+// doesn't tear.
+static mut SHADOW_BUFFER: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] = [[ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0),
+}; BUFFER_WIDTH]; BUFFER_HEIGHT];
+
+// Whether output is flushed by the timer instead of after every print
+static DEFERRED_FLUSH: AtomicBool = AtomicBool::new(false);
+
 // Struct representing a text writer for the VGA buffer
 pub struct Writer {
     column_position: usize,       // Track the current column position in the VGA buffer
+    row_position: usize,          // Track the current row; output starts on the last row
     color_code: ColorCode,        // Store the color information for text
-    buffer: &'static mut Buffer,  // Reference to the VGA buffer
+    default_color: ColorCode,     // The color restored by an SGR reset
+    escape: EscapeParser,         // State of the ANSI escape sequence parser
+    buffer: &'static mut Buffer,  // Reference to the shadow buffer, from the viewport's corner
+    visible: bool,                // Whether `flush` copies to the VGA memory
+    focused: bool,                // Whether this writer controls the hardware cursor
+    dirty_rows: u32,              // Rows changed since the last flush
+    cursor_dirty: bool,           // Whether the cursor moved since the last flush
+    viewport: Viewport,           // The part of the screen this writer is shown in
+    reserved_rows: usize,         // Rows at the bottom of the viewport kept out of the text
+}
+
+// A rectangle on the screen that a writer is shown in. The writer wraps and
+// scrolls its text within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Viewport {
+    pub top: usize,
+    pub left: usize,
+    pub height: usize,
+    pub width: usize,
+}
+
+impl Viewport {
+    // The whole screen
+    pub const FULL_SCREEN: Viewport = Viewport {
+        top: 0,
+        left: 0,
+        height: BUFFER_HEIGHT,
+        width: BUFFER_WIDTH,
+    };
 }
 
 impl Writer {
+    // Create a writer for a screen that isn't shown, backed by a blank buffer
+    // in RAM. Requires the heap.
+    pub(crate) fn offscreen() -> Writer {
+        use alloc::boxed::Box;
+
+        let color_code = ColorCode::new(Color::Yellow, Color::Black);
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        let buffer: &'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] =
+            Box::leak(Box::new([[blank; BUFFER_WIDTH]; BUFFER_HEIGHT]));
+        Writer::hidden(buffer, color_code)
+    }
+
+    // Create a writer that isn't shown, writing to the given cells
+    fn hidden(
+        buffer: &'static mut [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        color_code: ColorCode,
+    ) -> Writer {
+        Writer {
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            color_code,
+            default_color: color_code,
+            escape: EscapeParser::new(),
+            // `Volatile` is a transparent wrapper, so the layouts are identical
+            buffer: unsafe { &mut *(buffer as *mut _ as *mut Buffer) },
+            visible: false,
+            focused: true,
+            dirty_rows: 0,
+            cursor_dirty: false,
+            viewport: Viewport::FULL_SCREEN,
+            reserved_rows: 0,
+        }
+    }
+
+    // Return whether this writer is shown on the screen
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    // Show or hide this writer. A writer that becomes visible redraws the
+    // whole screen; only one writer may be visible at a time.
+    pub(crate) fn set_visible(&mut self, visible: bool) {
+        self.visible = visible;
+        if visible {
+            self.dirty_rows = ALL_ROWS;
+            self.cursor_dirty = true;
+            self.flush();
+        }
+    }
+
+    // Copy the rows that changed since the last flush to the VGA memory and
+    // move the hardware cursor. Does nothing for a hidden writer.
+    pub fn flush(&mut self) {
+        if !self.visible {
+            return;
+        }
+
+        let vga = unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) };
+        let Viewport { top, left, height, width } = self.viewport;
+        for row in 0..height {
+            if self.dirty_rows & (1 << row) != 0 {
+                for col in 0..width {
+                    vga.chars[top + row][left + col].write(self.buffer.chars[row][col].read());
+                }
+            }
+        }
+        self.dirty_rows = 0;
+
+        if self.cursor_dirty && self.focused {
+            self.update_cursor();
+            self.cursor_dirty = false;
+        }
+    }
+
+    // Show this writer in a part of the screen. Text beyond the new size is
+    // cut off, and rows scroll up so the cursor stays inside.
+    pub(crate) fn set_viewport(&mut self, viewport: Viewport) {
+        assert!(
+            viewport.top + viewport.height <= BUFFER_HEIGHT
+                && viewport.left + viewport.width <= BUFFER_WIDTH
+                && viewport.height > 0
+                && viewport.width > 0,
+            "viewport outside of the screen"
+        );
+        self.viewport = viewport;
+        self.reserved_rows = self.reserved_rows.min(viewport.height - 1);
+
+        let text_height = self.text_height();
+        if self.row_position >= text_height {
+            let shift = self.row_position + 1 - text_height;
+            for row in 0..text_height {
+                for col in 0..viewport.width {
+                    let character = self.buffer.chars[row + shift][col].read();
+                    self.buffer.chars[row][col].write(character);
+                }
+            }
+            self.row_position = text_height - 1;
+        }
+        self.column_position = self.column_position.min(viewport.width);
+
+        // Blank everything outside, so growing the viewport later shows no
+        // stale text
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                if row >= viewport.height || col >= viewport.width {
+                    self.buffer.chars[row][col].write(blank);
+                }
+            }
+        }
+        self.dirty_rows = ALL_ROWS;
+        self.cursor_dirty = true;
+    }
+
+    // Keep the given number of rows at the bottom of the viewport out of the
+    // text, e.g. for a progress bar drawn with `write_at`. Text doesn't
+    // scroll through them, and text on newly reserved rows scrolls up.
+    pub(crate) fn set_reserved_rows(&mut self, rows: usize) {
+        let rows = rows.min(self.viewport.height - 1);
+        if rows < self.reserved_rows {
+            // Give the rows back to the text
+            for row in self.viewport.height - self.reserved_rows..self.viewport.height - rows {
+                self.clear_row(row);
+            }
+        }
+        self.reserved_rows = rows;
+        while self.row_position >= self.text_height() {
+            self.scroll_up();
+            self.row_position -= 1;
+        }
+        self.cursor_dirty = true;
+    }
+
+    // Return the number of rows in the viewport that text is written to
+    pub fn text_height(&self) -> usize {
+        self.viewport.height - self.reserved_rows
+    }
+
+    // Write text at a position of the viewport without moving the cursor,
+    // cut off at the right edge
+    pub fn write_at(&mut self, row: usize, col: usize, text: &str) {
+        let color_code = self.default_color;
+        for (col, byte) in (col..self.viewport.width).zip(text.bytes()) {
+            let byte = match byte {
+                0x20..=0x7E => byte,
+                _ => b'*',
+            };
+            self.buffer.chars[row][col].write(ScreenChar {
+                ascii_character: byte,
+                color_code,
+            });
+        }
+        self.dirty_rows |= 1 << row;
+    }
+
+    // Return the part of the screen this writer is shown in
+    pub fn viewport(&self) -> Viewport {
+        self.viewport
+    }
+
+    // Let this writer move the hardware cursor, or stop it from doing so
+    pub(crate) fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+        self.cursor_dirty = focused;
+    }
+
+    // Return whether there are changes that haven't been flushed
+    pub(crate) fn needs_flush(&self) -> bool {
+        self.visible && (self.dirty_rows != 0 || self.cursor_dirty)
+    }
+
     // Write a single byte to the screen
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),  // If the byte is a newline character, move to a new line
             byte => {
-                if self.column_position >= BUFFER_WIDTH {
+                if self.column_position >= self.viewport.width {
                     self.new_line();      // If the current line is full, move to a new line
                 }
 
-                let row = BUFFER_HEIGHT - 1;  // Set the row to the last row of the VGA buffer
+                let row = self.row_position;  // Get the current row position
                 let col = self.column_position;  // Get the current column position
 
                 let color_code = self.color_code;  // Get the color code for the text
@@ -81,6 +406,7 @@ impl Writer {
                     ascii_character: byte,  // Set the ASCII character for the current position
                     color_code,            // Set the color code for the current position
                 });
+                self.dirty_rows |= 1 << row;  // Mark the row for the next flush
                 self.column_position += 1;  // Move to the next column position
             }
         }
@@ -97,25 +423,203 @@ impl Writer {
     // }
 
     // Write a string to the screen
+    //
+    // ANSI escape sequences for colors (SGR), cursor movement and clearing the
+    // screen or the current line are interpreted instead of printed.
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            if byte >= 0x20 && byte <= 0x7E || byte == b'\n' {
-                // Printable ASCII character or newline, print the byte
-                self.write_byte(byte);
-            } else {
-                // Non-printable ASCII character, print the placeholder character
-                self.write_byte(b'*');
+            match self.escape.state {
+                EscapeState::Normal => match byte {
+                    // Start of an escape sequence
+                    0x1B => self.escape.state = EscapeState::Escape,
+                    // Printable ASCII character or newline, print the byte
+                    0x20..=0x7E | b'\n' => self.write_byte(byte),
+                    // Non-printable ASCII character, print the placeholder character
+                    _ => self.write_byte(b'*'),
+                },
+                EscapeState::Escape => match byte {
+                    b'[' => self.escape.begin_csi(),
+                    // Unsupported escape sequence, drop it
+                    _ => self.escape.state = EscapeState::Normal,
+                },
+                EscapeState::Csi => match byte {
+                    b'0'..=b'9' => self.escape.push_digit(byte),
+                    b';' => self.escape.param_index += 1,
+                    // A final byte ends the control sequence
+                    0x40..=0x7E => {
+                        self.escape.state = EscapeState::Normal;
+                        self.execute_csi(byte);
+                    }
+                    // Malformed control sequence, drop it
+                    _ => self.escape.state = EscapeState::Normal,
+                },
+            }
+        }
+    }
+
+    // Execute a complete control sequence with the given final byte
+    fn execute_csi(&mut self, command: u8) {
+        match command {
+            // Select graphic rendition (colors)
+            b'm' => {
+                for i in 0..self.escape.params().len() {
+                    let param = self.escape.params()[i];
+                    self.select_graphic_rendition(param);
+                }
+            }
+            // Cursor position, 1-based row and column
+            b'H' | b'f' => {
+                let row = self.escape.param_or(0, 1) as usize - 1;
+                let col = self.escape.param_or(1, 1) as usize - 1;
+                self.set_position(row, col);
+            }
+            // Cursor up, down, forward and back
+            b'A' => {
+                let n = self.escape.param_or(0, 1) as usize;
+                self.set_position(self.row_position.saturating_sub(n), self.column_position);
+            }
+            b'B' => {
+                let n = self.escape.param_or(0, 1) as usize;
+                self.set_position(self.row_position + n, self.column_position);
+            }
+            b'C' => {
+                let n = self.escape.param_or(0, 1) as usize;
+                self.set_position(self.row_position, self.column_position + n);
+            }
+            b'D' => {
+                let n = self.escape.param_or(0, 1) as usize;
+                self.set_position(self.row_position, self.column_position.saturating_sub(n));
+            }
+            // Erase in display: 0 = to the end, 1 = to the cursor, 2 = everything
+            b'J' => match self.escape.params()[0] {
+                0 => {
+                    self.clear_cells(self.row_position, self.column_position, self.viewport.width);
+                    for row in self.row_position + 1..self.text_height() {
+                        self.clear_row(row);
+                    }
+                }
+                1 => {
+                    for row in 0..self.row_position {
+                        self.clear_row(row);
+                    }
+                    self.clear_cells(self.row_position, 0, self.column_position + 1);
+                }
+                _ => {
+                    for row in 0..self.text_height() {
+                        self.clear_row(row);
+                    }
+                }
+            },
+            // Erase in line: 0 = to the end, 1 = to the cursor, 2 = whole line
+            b'K' => match self.escape.params()[0] {
+                0 => self.clear_cells(self.row_position, self.column_position, self.viewport.width),
+                1 => self.clear_cells(self.row_position, 0, self.column_position + 1),
+                _ => self.clear_row(self.row_position),
+            },
+            // Unsupported control sequence, ignore it
+            _ => {}
+        }
+    }
+
+    // Apply a single SGR parameter to the current color
+    fn select_graphic_rendition(&mut self, param: u16) {
+        let color = self.color_code;
+        self.color_code = match param {
+            0 => self.default_color,
+            1 => color.with_bright_foreground(),
+            30..=37 => color.with_foreground(ANSI_COLORS[param as usize - 30]),
+            39 => ColorCode((color.0 & 0xF0) | (self.default_color.0 & 0x0F)),
+            40..=47 => color.with_background(ANSI_COLORS[param as usize - 40]),
+            49 => ColorCode((color.0 & 0x0F) | (self.default_color.0 & 0xF0)),
+            90..=97 => color.with_foreground(ANSI_BRIGHT_COLORS[param as usize - 90]),
+            100..=107 => color.with_background(ANSI_BRIGHT_COLORS[param as usize - 100]),
+            _ => color,
+        };
+    }
+
+    // Move the cursor, clamping it to the viewport
+    fn set_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(self.text_height() - 1);
+        self.column_position = col.min(self.viewport.width - 1);
+    }
+
+    // Change the color used for the following text
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    // Move the writer and the hardware cursor to the given position
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.set_position(row, col);
+        self.cursor_dirty = true;
+        self.flush();
+    }
+
+    // Return the row and column the next character is written to
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position.min(self.viewport.width - 1))
+    }
+
+    // Return the character shown at the given position
+    pub fn char_at(&self, row: usize, col: usize) -> u8 {
+        self.buffer.chars[row][col].read().ascii_character
+    }
+
+    // Copy the text of the viewport, without the reserved rows. The shadow
+    // buffer holds the viewport's cells from its top left corner on, and
+    // `flush` moves them to the viewport's place on the screen.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new(self.viewport.width, self.text_height());
+        for row in 0..self.text_height() {
+            for col in 0..self.viewport.width {
+                snapshot.set(row, col, self.buffer.chars[row][col].read().ascii_character);
             }
         }
+        snapshot
+    }
+
+    // Swap the foreground and background colors of the columns `from..to`
+    // of a row, e.g. to highlight a selection. Inverting twice restores the
+    // cells.
+    pub(crate) fn invert_cells(&mut self, row: usize, from: usize, to: usize) {
+        for col in from..to.min(self.viewport.width) {
+            let mut cell = self.buffer.chars[row][col].read();
+            cell.color_code = ColorCode(cell.color_code.0.rotate_left(4));
+            self.buffer.chars[row][col].write(cell);
+        }
+        self.dirty_rows |= 1 << row;
+    }
+
+    // Move the blinking hardware cursor to the current writer position
+    fn update_cursor(&mut self) {
+        let row = self.viewport.top + self.row_position;
+        let col = self.viewport.left + self.column_position.min(self.viewport.width - 1);
+        let position = (row * BUFFER_WIDTH + col) as u16;
+
+        write_crtc(CRTC_CURSOR_LOCATION_HIGH, (position >> 8) as u8);
+        write_crtc(CRTC_CURSOR_LOCATION_LOW, position as u8);
     }
 
 
     // Move to a new line in the VGA buffer
     fn new_line(&mut self) {
-        // Loop through each row (except the first one)
-        for row in 1..BUFFER_HEIGHT {
-            // Loop through each column in the buffer
-            for col in 0..BUFFER_WIDTH {
+        // Reset the column position to the beginning of the new line
+        self.column_position = 0;
+
+        // Only scroll once the cursor reached the last row
+        if self.row_position < self.text_height() - 1 {
+            self.row_position += 1;
+            return;
+        }
+        self.scroll_up();
+    }
+
+    // Move the text up by one row, leaving the last text row blank
+    fn scroll_up(&mut self) {
+        // Loop through each row of the text area (except the first one)
+        for row in 1..self.text_height() {
+            // Loop through each column in the viewport
+            for col in 0..self.viewport.width {
                 // Read the character from the current row and column
                 let character = self.buffer.chars[row][col].read();
 
@@ -125,36 +629,103 @@ impl Writer {
         }
 
         // Clear the last row by filling it with empty characters
-        self.clear_row(BUFFER_HEIGHT - 1);
+        self.clear_row(self.text_height() - 1);
 
-        // Reset the column position to the beginning of the new line
-        self.column_position = 0;
+        // Every row moved
+        self.dirty_rows = ALL_ROWS;
     }
 
 
     // Clear a specific row in the VGA buffer
     fn clear_row(&mut self, row: usize) {
+        self.clear_cells(row, 0, self.viewport.width);
+    }
+
+    // Clear the columns `from..to` of a specific row in the VGA buffer
+    fn clear_cells(&mut self, row: usize, from: usize, to: usize) {
         // Create a blank character with a space and the current color
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
 
-        // Loop through each column in the specified row
-        for col in 0..BUFFER_WIDTH {
-            // Write the blank character to clear the row
+        // Loop through each column in the specified range
+        for col in from..to.min(self.viewport.width) {
+            // Write the blank character to clear the cell
             self.buffer.chars[row][col].write(blank);
         }
+        self.dirty_rows |= 1 << row;
     }
 }
 
 impl Write for Writer {
     fn write_str(&mut self, s: &str) -> Result {
         self.write_string(s);
+        self.cursor_dirty = true;
         Ok(())
     }
 }
 
+// Write a character directly to the VGA memory, e.g. for the borders between
+// console panes, which belong to no writer
+pub(crate) fn draw_cell(row: usize, col: usize, character: u8, foreground: Color, background: Color) {
+    let vga = unsafe { &mut *(VGA_BUFFER_ADDRESS as *mut Buffer) };
+    vga.chars[row][col].write(ScreenChar {
+        ascii_character: character,
+        color_code: ColorCode::new(foreground, background),
+    });
+}
+
+// Write a value to a CRT controller register
+fn write_crtc(register: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+
+    let mut index: Port<u8> = Port::new(CRTC_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
+// Read a CRT controller register
+fn read_crtc(register: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+
+    let mut index: Port<u8> = Port::new(CRTC_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+// Move the text output position and the hardware cursor to `row`, `col`
+pub fn set_cursor(row: usize, col: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().set_cursor(row, col);
+    });
+}
+
+// Hide the blinking hardware cursor
+pub fn hide_cursor() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        write_crtc(CRTC_CURSOR_START, read_crtc(CRTC_CURSOR_START) | CURSOR_DISABLE);
+    });
+}
+
+// Show the blinking hardware cursor as an underline
+pub fn show_cursor() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        let start = read_crtc(CRTC_CURSOR_START) & 0xC0;
+        write_crtc(CRTC_CURSOR_START, start | CURSOR_SCANLINE_START);
+        let end = read_crtc(CRTC_CURSOR_END) & 0xE0;
+        write_crtc(CRTC_CURSOR_END, end | CURSOR_SCANLINE_END);
+    });
+}
+
 // // Function to print a sample text using the Writer
 // pub fn print() {
 //     let mut writer = Writer {
@@ -172,10 +743,27 @@ impl Write for Writer {
 lazy_static!{
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
+        row_position: BUFFER_HEIGHT - 1,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
+        default_color: ColorCode::new(Color::Yellow, Color::Black),
+        escape: EscapeParser::new(),
         buffer: unsafe {
-            &mut *(0xb8000 as *mut Buffer)
+            // Start with what the bootloader left on the screen
+            let vga = &*(VGA_BUFFER_ADDRESS as *const Buffer);
+            let shadow = &mut *(core::ptr::addr_of_mut!(SHADOW_BUFFER) as *mut Buffer);
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    shadow.chars[row][col].write(vga.chars[row][col].read());
+                }
+            }
+            shadow
         },
+        visible: true,
+        focused: true,
+        dirty_rows: 0,
+        cursor_dirty: false,
+        viewport: Viewport::FULL_SCREEN,
+        reserved_rows: 0,
     });
 }
 
@@ -194,17 +782,137 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// Like `print!`, but with the given foreground and background colors
+#[macro_export]
+macro_rules! print_colored {
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+        $crate::vga_buffer::_print_colored($fg, $bg, format_args!($($arg)*))
+    );
+}
+
+// Like `println!`, but with the given foreground and background colors
+#[macro_export]
+macro_rules! println_colored {
+    ($fg:expr, $bg:expr) => ($crate::print_colored!($fg, $bg, "\n"));
+    ($fg:expr, $bg:expr, $($arg:tt)*) => (
+        $crate::print_colored!($fg, $bg, "{}\n", format_args!($($arg)*))
+    );
+}
+
 // Print the given string through the global `WRITER` instance. 
 #[doc(hidden)]
 pub fn _print(args: Arguments) {
     use core::fmt::write;
     use x86_64::instructions::interrupts;
 
+    let flush = !flush_is_deferred();
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        if crate::framebuffer::_print(args) {
+            return;
+        }
+        let mut writer = WRITER.lock();
+        writer.write_fmt(args).unwrap();
+        if flush {
+            writer.flush();
+        }
+    });
+}
+
+// Print the given string in the given colors, restoring the previous color
+// afterwards. The color change and the text are written under one lock, so
+// concurrent output can't end up in the wrong color.
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, background: Color, args: Arguments) {
+    use x86_64::instructions::interrupts;
+
+    let flush = !flush_is_deferred();
+    interrupts::without_interrupts(|| {
+        if crate::framebuffer::_print_colored(foreground, background, args) {
+            return;
+        }
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code;
+        writer.set_color(foreground, background);
+        writer.write_fmt(args).unwrap();
+        writer.color_code = previous;
+        if flush {
+            writer.flush();
+        }
     });
 }
 
+// Copy pending output of the global `WRITER` to the screen
+pub fn flush() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().flush();
+    });
+}
+
+// Leave flushing to the timer interrupt instead of flushing after every
+// print, which batches bursts of output into one screen update per tick
+pub fn enable_deferred_flush() {
+    DEFERRED_FLUSH.store(true, Ordering::Relaxed);
+}
+
+// Whether the output of a print can be left to the timer. Output from
+// exception handlers, which run with interrupts disabled, is always flushed
+// right away since the timer may never run again.
+pub(crate) fn flush_is_deferred() -> bool {
+    deferred_flush_enabled() && x86_64::instructions::interrupts::are_enabled()
+}
+
+// Whether `enable_deferred_flush` was called
+pub(crate) fn deferred_flush_enabled() -> bool {
+    DEFERRED_FLUSH.load(Ordering::Relaxed)
+}
+
+
+// Change the color used for all following output
+pub fn set_color(foreground: Color, background: Color) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITER.lock().set_color(foreground, background);
+    });
+}
+
+// Sets the output color and restores the previous one when dropped
+//
+//     let _color = ColorGuard::new(Color::LightRed, Color::Black);
+//     println!("shown in red");
+#[must_use = "the previous color is restored when the guard is dropped"]
+pub struct ColorGuard {
+    previous: ColorCode,
+}
+
+impl ColorGuard {
+    pub fn new(foreground: Color, background: Color) -> ColorGuard {
+        let previous = x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut writer = WRITER.lock();
+            let previous = writer.color_code;
+            writer.set_color(foreground, background);
+            previous
+        });
+        ColorGuard { previous }
+    }
+}
+
+impl Drop for ColorGuard {
+    fn drop(&mut self) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            WRITER.lock().color_code = self.previous;
+        });
+    }
+}
+
+// Run `f` with the output color set to `foreground` on `background` and
+// restore the previous color afterwards
+pub fn with_color<F, R>(foreground: Color, background: Color, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let _guard = ColorGuard::new(foreground, background);
+    f()
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("test_println_simple output");
@@ -229,17 +937,100 @@ fn test_println_many() {
 
 #[test_case]
 fn test_println_outout() {
-    
-    use core::fmt::Write;
+    use crate::snapshot;
     use x86_64::instructions::interrupts;
 
     let s = "Some test string that fits on a single line";
+    interrupts::without_interrupts(|| {
+        // Start on a fresh line, whatever was printed before
+        println!("\n{}", s);
+        assert_eq!(snapshot::capture().last_line(), s);
+    });
+}
+
+#[test_case]
+fn test_print_colored() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let previous = WRITER.lock().color_code;
+        print_colored!(Color::LightRed, Color::Black, "\nE");
+        println!();
+
+        let writer = WRITER.lock();
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+        assert_eq!(screen_char.ascii_character, b'E');
+        assert_eq!(screen_char.color_code, ColorCode::new(Color::LightRed, Color::Black));
+        assert_eq!(writer.color_code, previous);
+    });
+}
+
+#[test_case]
+fn test_ansi_colors() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
+        writeln!(writer, "\n\x1b[31;44mR\x1b[0mD").expect("writeln failed");
+        let colored = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+        let reset = writer.buffer.chars[BUFFER_HEIGHT - 2][1].read();
+        assert_eq!(colored.ascii_character, b'R');
+        assert_eq!(colored.color_code, ColorCode::new(Color::Red, Color::Blue));
+        assert_eq!(reset.ascii_character, b'D');
+        assert_eq!(reset.color_code, writer.default_color);
+    });
+}
+
+#[test_case]
+fn test_ansi_cursor_position() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        write!(writer, "\x1b[3;5HX").expect("write failed");
+        assert_eq!(writer.buffer.chars[2][4].read().ascii_character, b'X');
+
+        // Move back to the last row where regular output continues
+        write!(writer, "\x1b[{};1H\x1b[2K", BUFFER_HEIGHT).expect("write failed");
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+        assert_eq!(writer.column_position, 0);
     });
-}
\ No newline at end of file
+}
+
+#[test_case]
+fn test_color_guard() {
+    let previous = WRITER.lock().color_code;
+    {
+        let _guard = ColorGuard::new(Color::White, Color::Blue);
+        assert_eq!(WRITER.lock().color_code, ColorCode::new(Color::White, Color::Blue));
+        {
+            let _inner = ColorGuard::new(Color::Red, Color::Black);
+            assert_eq!(WRITER.lock().color_code, ColorCode::new(Color::Red, Color::Black));
+        }
+        assert_eq!(WRITER.lock().color_code, ColorCode::new(Color::White, Color::Blue));
+    }
+    assert_eq!(WRITER.lock().color_code, previous);
+}
+
+#[test_case]
+fn test_snapshot_of_pane() {
+    // A writer shown in the bottom right quarter of a split screen
+    static mut CELLS: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT] = [[ScreenChar {
+        ascii_character: b' ',
+        color_code: ColorCode(0x0E),
+    }; BUFFER_WIDTH]; BUFFER_HEIGHT];
+    let cells = unsafe { &mut *core::ptr::addr_of_mut!(CELLS) };
+    let mut writer = Writer::hidden(cells, ColorCode(0x0E));
+    writer.set_viewport(Viewport { top: 12, left: 40, height: 13, width: 40 });
+
+    // Wraps at the pane's edge
+    let long = "01234567890123456789012345678901234567890123456789";
+    write!(writer, "{}\npane", long).expect("write failed");
+    let snapshot = writer.snapshot();
+    assert_eq!((snapshot.width(), snapshot.height()), (40, 13));
+    assert_eq!(snapshot.row(10), &long[..40]);
+    assert_eq!(snapshot.row(11), &long[40..]);
+    assert_eq!(snapshot.last_line(), "pane");
+}
@@ -0,0 +1,360 @@
+// Virtio devices on the PCI bus.
+//
+// Virtio devices have vendor 0x1AF4. Their registers are described by
+// vendor-specific PCI capabilities pointing into the BARs: the common
+// configuration (feature negotiation, device status and the virtqueues),
+// where to notify the device of new buffers, and the device-specific
+// configuration. This is the "modern" transport of virtio 1.0; devices that
+// only implement the legacy I/O port interface are skipped.
+//
+// `init` finds the devices and hands them to their drivers, which exchange
+// buffers with the device through `queue::Virtqueue`s.
+
+use crate::{memory, pci};
+use core::ptr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, PhysFrame, Size4KiB,
+};
+use x86_64::PhysAddr;
+
+pub mod blk;
+pub mod queue;
+
+// The vendor ID of virtio devices
+const VENDOR_VIRTIO: u16 = 0x1AF4;
+
+// Transitional devices have IDs 0x1000 to 0x103F, modern ones 0x1040 plus
+// the device type
+const FIRST_TRANSITIONAL_ID: u16 = 0x1000;
+const FIRST_MODERN_ID: u16 = 0x1040;
+const LAST_MODERN_ID: u16 = 0x107F;
+
+// Device types
+pub const DEVICE_BLOCK: u16 = 2;
+
+// The transitional device IDs with their device types
+const TRANSITIONAL_IDS: [(u16, u16); 1] = [(0x1001, DEVICE_BLOCK)];
+
+// Configuration types of the virtio PCI capabilities
+const CAP_COMMON_CONFIG: u8 = 1;
+const CAP_NOTIFY_CONFIG: u8 = 2;
+const CAP_DEVICE_CONFIG: u8 = 4;
+
+// Offsets in a virtio PCI capability
+const CAP_CONFIG_TYPE: u16 = 3;
+const CAP_BAR: u16 = 4;
+const CAP_OFFSET: u16 = 8;
+const CAP_LENGTH: u16 = 12;
+const CAP_NOTIFY_MULTIPLIER: u16 = 16;
+
+// Common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: usize = 0x1A;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+// The device follows virtio 1.0 instead of the legacy interface
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+// The MSI-X vector meaning "no interrupt"
+const NO_VECTOR: u16 = 0xFFFF;
+
+// Errors from setting up a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    // The device doesn't accept the features the driver needs
+    FeaturesRejected,
+    // The device has no queue with the index
+    NoQueue,
+    // There was no frame for the queue's memory
+    OutOfMemory,
+}
+
+// The registers of a virtio device
+pub struct Transport {
+    pub device: &'static pci::PciDevice,
+    common: u64,
+    notify: u64,
+    notify_multiplier: u32,
+    config: u64,
+}
+
+impl Transport {
+    // Map the registers of a virtio device. Returns `None` if it lacks the
+    // capabilities of the modern interface.
+    fn new(
+        device: &'static pci::PciDevice,
+        mapper: &mut impl Mapper<Size4KiB>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Option<Transport>, MapToError<Size4KiB>> {
+        let (mut common, mut notify, mut config) = (None, None, None);
+        let mut notify_multiplier = 0;
+        let address = device.address;
+        for capability in address.capabilities() {
+            if capability.id != pci::CAP_VENDOR_SPECIFIC {
+                continue;
+            }
+            let offset = capability.offset;
+            let bar = address.read_u8(offset + CAP_BAR) as usize;
+            let start = address.read_u32(offset + CAP_OFFSET) as u64;
+            let length = address.read_u32(offset + CAP_LENGTH) as u64;
+            let base = match device.bars.get(bar) {
+                Some(Some(pci::Bar::Memory { address: base, .. })) => *base,
+                _ => continue,
+            };
+            let slot = match address.read_u8(offset + CAP_CONFIG_TYPE) {
+                CAP_COMMON_CONFIG => &mut common,
+                CAP_NOTIFY_CONFIG => {
+                    notify_multiplier = address.read_u32(offset + CAP_NOTIFY_MULTIPLIER);
+                    &mut notify
+                }
+                CAP_DEVICE_CONFIG => &mut config,
+                _ => continue,
+            };
+            if slot.is_none() {
+                *slot = Some(map_region(base + start, length, mapper, frame_allocator)?);
+            }
+        }
+
+        Ok(match (common, notify, config) {
+            (Some(common), Some(notify), Some(config)) => Some(Transport {
+                device,
+                common,
+                notify,
+                notify_multiplier,
+                config,
+            }),
+            _ => None,
+        })
+    }
+
+    // Reset the device and negotiate features: the driver gets the ones in
+    // `wanted` the device offers, and must get those in `required`
+    pub fn begin_init(&self, wanted: u64, required: u64) -> Result<u64, VirtioError> {
+        let required = required | FEATURE_VERSION_1;
+        unsafe {
+            self.set_status(0);
+            while self.status() != 0 {
+                core::hint::spin_loop();
+            }
+            self.set_status(STATUS_ACKNOWLEDGE);
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+            let offered = self.device_features();
+            let features = offered & (wanted | required);
+            if features & required != required {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+            write32(self.common, COMMON_DRIVER_FEATURE_SELECT, 0);
+            write32(self.common, COMMON_DRIVER_FEATURE, features as u32);
+            write32(self.common, COMMON_DRIVER_FEATURE_SELECT, 1);
+            write32(self.common, COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+
+            self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.set_status(STATUS_FAILED);
+                return Err(VirtioError::FeaturesRejected);
+            }
+            Ok(features)
+        }
+    }
+
+    // Let the device start working, once its queues are set up
+    pub fn finish_init(&self) {
+        unsafe {
+            self.set_status(
+                STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK | STATUS_DRIVER_OK,
+            );
+        }
+    }
+
+    // Mark the device as unusable after a failed setup
+    pub fn fail(&self) {
+        unsafe { self.set_status(STATUS_FAILED) };
+    }
+
+    fn device_features(&self) -> u64 {
+        unsafe {
+            write32(self.common, COMMON_DEVICE_FEATURE_SELECT, 0);
+            let low = read32(self.common, COMMON_DEVICE_FEATURE) as u64;
+            write32(self.common, COMMON_DEVICE_FEATURE_SELECT, 1);
+            let high = read32(self.common, COMMON_DEVICE_FEATURE) as u64;
+            high << 32 | low
+        }
+    }
+
+    unsafe fn status(&self) -> u8 {
+        ptr::read_volatile((self.common as usize + COMMON_DEVICE_STATUS) as *const u8)
+    }
+
+    unsafe fn set_status(&self, status: u8) {
+        ptr::write_volatile((self.common as usize + COMMON_DEVICE_STATUS) as *mut u8, status);
+    }
+
+    // Set up queue `index` with at most `max_size` entries
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        max_size: u16,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<queue::Virtqueue, VirtioError> {
+        unsafe {
+            write16(self.common, COMMON_QUEUE_SELECT, index);
+            let device_size = read16(self.common, COMMON_QUEUE_SIZE);
+            if device_size == 0 {
+                return Err(VirtioError::NoQueue);
+            }
+            // The size must stay a power of two
+            let mut size = device_size.min(max_size).min(queue::MAX_SIZE);
+            while !size.is_power_of_two() {
+                size &= size - 1;
+            }
+
+            let frame = frame_allocator.allocate_frame().ok_or(VirtioError::OutOfMemory)?;
+            Ok(self.install_queue(index, size, frame))
+        }
+    }
+
+    // Set `queue` up again, empty and in the same memory, after a reset
+    // between `begin_init` and `finish_init`. Requests the device had yet
+    // to finish are dropped with it.
+    pub fn reset_queue(&self, queue: &mut queue::Virtqueue) {
+        *queue = unsafe { self.install_queue(queue.index(), queue.size(), queue.frame()) };
+    }
+
+    // Lay out a queue in `frame` and tell the device about it
+    unsafe fn install_queue(&self, index: u16, size: u16, frame: PhysFrame) -> queue::Virtqueue {
+        write16(self.common, COMMON_QUEUE_SELECT, index);
+        let notify_off = read16(self.common, COMMON_QUEUE_NOTIFY_OFF) as u64;
+        let notify = self.notify + notify_off * self.notify_multiplier as u64;
+        let queue = queue::Virtqueue::new(index, size, frame, notify);
+
+        let (desc, driver, device) = queue.addresses();
+        write16(self.common, COMMON_QUEUE_SIZE, size);
+        write16(self.common, COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+        write64(self.common, COMMON_QUEUE_DESC, desc.as_u64());
+        write64(self.common, COMMON_QUEUE_DRIVER, driver.as_u64());
+        write64(self.common, COMMON_QUEUE_DEVICE, device.as_u64());
+        write16(self.common, COMMON_QUEUE_ENABLE, 1);
+        queue
+    }
+
+    // Read from the device-specific configuration
+    pub fn config_u32(&self, offset: usize) -> u32 {
+        unsafe { read32(self.config, offset) }
+    }
+
+    pub fn config_u64(&self, offset: usize) -> u64 {
+        // 64-bit fields may be read as two halves
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+}
+
+// Find the virtio devices and start their drivers.
+//
+// Requires the heap, `pci::init` and the timer.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    for device in pci::devices().iter().filter(|device| device.vendor_id == VENDOR_VIRTIO) {
+        let device_type = match device_type(device.device_id) {
+            Some(device_type) => device_type,
+            None => continue,
+        };
+        device.enable_decoding();
+        device.enable_bus_master();
+
+        let transport = match Transport::new(device, mapper, frame_allocator)? {
+            Some(transport) => transport,
+            None => {
+                log::warn!("virtio {}: only the legacy interface is supported", device.address);
+                continue;
+            }
+        };
+        let result = match device_type {
+            DEVICE_BLOCK => blk::probe(transport, frame_allocator),
+            _ => continue,
+        };
+        if let Err(err) = result {
+            log::warn!("virtio {}: {:?}", device.address, err);
+        }
+    }
+    Ok(())
+}
+
+// Return the virtio device type of a PCI device ID
+fn device_type(device_id: u16) -> Option<u16> {
+    match device_id {
+        FIRST_MODERN_ID..=LAST_MODERN_ID => Some(device_id - FIRST_MODERN_ID),
+        id if id >= FIRST_TRANSITIONAL_ID => TRANSITIONAL_IDS
+            .iter()
+            .find(|&&(transitional, _)| transitional == id)
+            .map(|&(_, device_type)| device_type),
+        _ => None,
+    }
+}
+
+// Identity map the pages of a register region and return its address
+fn map_region(
+    phys: u64,
+    length: u64,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let start = memory::map_mmio(PhysAddr::new(phys), mapper, frame_allocator)?;
+    let first_page = phys & !0xFFF;
+    let mut page = first_page + 0x1000;
+    while page < phys + length {
+        memory::map_mmio(PhysAddr::new(page), mapper, frame_allocator)?;
+        page += 0x1000;
+    }
+    Ok(start.as_u64())
+}
+
+unsafe fn read16(base: u64, offset: usize) -> u16 {
+    ptr::read_volatile((base as usize + offset) as *const u16)
+}
+
+unsafe fn write16(base: u64, offset: usize, value: u16) {
+    ptr::write_volatile((base as usize + offset) as *mut u16, value);
+}
+
+unsafe fn read32(base: u64, offset: usize) -> u32 {
+    ptr::read_volatile((base as usize + offset) as *const u32)
+}
+
+unsafe fn write32(base: u64, offset: usize, value: u32) {
+    ptr::write_volatile((base as usize + offset) as *mut u32, value);
+}
+
+unsafe fn write64(base: u64, offset: usize, value: u64) {
+    // 64-bit registers may be written as two halves, low first
+    write32(base, offset, value as u32);
+    write32(base, offset + 4, (value >> 32) as u32);
+}
+
+#[test_case]
+fn test_device_type() {
+    assert_eq!(device_type(0x1042), Some(DEVICE_BLOCK));
+    assert_eq!(device_type(0x1001), Some(DEVICE_BLOCK));
+    assert_eq!(device_type(0x1000), None);
+    assert_eq!(device_type(0x0042), None);
+}
@@ -0,0 +1,272 @@
+// The virtio block device driver.
+//
+// Every request is a descriptor chain of a header saying what to do and
+// where, the data, and a status byte the device writes when it is done.
+// Requests are sent one at a time and their completion is polled. Data
+// goes through frames of the driver's own, like in the AHCI driver, so
+// callers can pass any buffer. A request that times out is abandoned by
+// resetting the device, since the device may otherwise finish it later,
+// writing into the frames of the next request and holding on to its
+// descriptors until then.
+//
+// The disks are registered as block devices named "vda", "vdb" and so on.
+
+use super::queue::{Buffer, Virtqueue};
+use super::{Transport, VirtioError};
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::{memory, time};
+use alloc::format;
+use alloc::sync::Arc;
+use core::ptr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
+
+// Feature bits
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+// The capacity in 512 byte sectors, in the device configuration
+const CONFIG_CAPACITY: usize = 0;
+
+// Request types
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+// Request status values
+const STATUS_OK: u8 = 0;
+// Written to the status byte before a request, so a device that never
+// answers isn't mistaken for a successful one
+const STATUS_PENDING: u8 = 0xFF;
+
+// The layout of the request frame: the header, then the status byte
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+
+// The frames data is transferred through, one descriptor each
+const DATA_FRAMES: usize = 16;
+const FRAME_SIZE: usize = 4096;
+const MAX_SECTORS_PER_REQUEST: usize = DATA_FRAMES * FRAME_SIZE / SECTOR_SIZE;
+
+// Descriptors of the largest request: header, data and status
+const MAX_DESCRIPTORS: u16 = DATA_FRAMES as u16 + 2;
+
+// How long to wait for a request
+const TIMEOUT_MS: u64 = 1000;
+
+// The number of disks registered so far, for their names
+static DISKS: AtomicU8 = AtomicU8::new(0);
+
+// Set up a virtio block device and register it
+pub(super) fn probe(
+    transport: Transport,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), VirtioError> {
+    let features = transport.begin_init(FEATURE_READ_ONLY | FEATURE_FLUSH, 0)?;
+    let queue = match transport.setup_queue(0, super::queue::MAX_SIZE, frame_allocator) {
+        Ok(queue) if queue.size() >= MAX_DESCRIPTORS => queue,
+        Ok(_) => {
+            transport.fail();
+            return Err(VirtioError::NoQueue);
+        }
+        Err(err) => {
+            transport.fail();
+            return Err(err);
+        }
+    };
+
+    let (request, data) = match allocate_frames(frame_allocator) {
+        Some(frames) => frames,
+        None => {
+            transport.fail();
+            return Err(VirtioError::OutOfMemory);
+        }
+    };
+
+    transport.finish_init();
+    let disk = Disk {
+        sectors: transport.config_u64(CONFIG_CAPACITY),
+        read_only: features & FEATURE_READ_ONLY != 0,
+        flush: features & FEATURE_FLUSH != 0,
+        inner: Mutex::new(Inner {
+            transport,
+            features,
+            queue,
+            request,
+            data,
+        }),
+    };
+    let name = format!("vd{}", (b'a' + DISKS.fetch_add(1, Ordering::Relaxed)) as char);
+    // The names are unique, so this can't fail
+    let _ = block::register_cached(name, Arc::new(disk));
+    Ok(())
+}
+
+// Allocate the zeroed frame for the request header and status, and the
+// data frames
+fn allocate_frames(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Option<(PhysFrame, [PhysFrame; DATA_FRAMES])> {
+    let mut allocate = || {
+        let frame = frame_allocator.allocate_frame()?;
+        unsafe { ptr::write_bytes(frame_ptr(frame), 0, FRAME_SIZE) };
+        Some(frame)
+    };
+    let request = allocate()?;
+    let mut data = [request; DATA_FRAMES];
+    for frame in data.iter_mut() {
+        *frame = allocate()?;
+    }
+    Some((request, data))
+}
+
+// The state used while sending a request
+struct Inner {
+    transport: Transport,
+    // The negotiated features, negotiated again after a reset
+    features: u64,
+    queue: Virtqueue,
+    request: PhysFrame,
+    data: [PhysFrame; DATA_FRAMES],
+}
+
+impl Inner {
+    // Send a request for `bytes` bytes of the data frames and wait for it
+    fn request(&mut self, kind: u32, sector: u64, bytes: usize) -> Result<(), BlockError> {
+        let header = frame_ptr(self.request);
+        unsafe {
+            ptr::write_volatile(header as *mut u32, kind);
+            ptr::write_volatile(header.add(4) as *mut u32, 0);
+            ptr::write_volatile(header.add(8) as *mut u64, sector);
+            ptr::write_volatile(header.add(STATUS_OFFSET), STATUS_PENDING);
+        }
+
+        let request = self.request.start_address();
+        let mut buffers = [Buffer {
+            address: request,
+            len: HEADER_SIZE as u32,
+            writable: false,
+        }; MAX_DESCRIPTORS as usize];
+        let mut count = 1;
+        for (i, frame) in self.data.iter().enumerate() {
+            if i * FRAME_SIZE >= bytes {
+                break;
+            }
+            buffers[count] = Buffer {
+                address: frame.start_address(),
+                len: (bytes - i * FRAME_SIZE).min(FRAME_SIZE) as u32,
+                writable: kind == REQUEST_IN,
+            };
+            count += 1;
+        }
+        buffers[count] = Buffer {
+            address: request + STATUS_OFFSET as u64,
+            len: 1,
+            writable: true,
+        };
+        count += 1;
+
+        // Only one request is in flight, and `reset` empties the queue of a
+        // request that timed out, so there are always enough descriptors
+        let head = self.queue.add(&buffers[..count]).ok_or(BlockError::Io)?;
+        self.queue.notify();
+
+        let deadline = time::uptime_ms() + TIMEOUT_MS;
+        loop {
+            match self.queue.pop_used() {
+                Some((used, _)) if used == head => break,
+                // Can't happen with one request at a time
+                Some(_) => continue,
+                None => {}
+            }
+            if time::uptime_ms() >= deadline {
+                self.reset();
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        match unsafe { ptr::read_volatile(header.add(STATUS_OFFSET)) } {
+            STATUS_OK => Ok(()),
+            _ => Err(BlockError::Io),
+        }
+    }
+
+    // Make the device drop the request in flight and start over with an
+    // empty queue. The device stops using the queue and the frames when it
+    // is reset.
+    fn reset(&mut self) {
+        log::warn!("virtio-blk: request timed out, resetting the device");
+        let transport = &self.transport;
+        if let Err(err) = transport.begin_init(self.features, self.features) {
+            // The requests that follow fail too
+            log::error!("virtio-blk: reset failed: {:?}", err);
+            transport.fail();
+            return;
+        }
+        transport.reset_queue(&mut self.queue);
+        transport.finish_init();
+    }
+}
+
+// A virtio block device
+struct Disk {
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    inner: Mutex<Inner>,
+}
+
+impl BlockDevice for Disk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        let mut inner = self.inner.lock();
+        let chunk_size = MAX_SECTORS_PER_REQUEST * SECTOR_SIZE;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            inner.request(REQUEST_IN, lba, chunk.len())?;
+            for (part, &frame) in chunk.chunks_mut(FRAME_SIZE).zip(inner.data.iter()) {
+                unsafe { ptr::copy_nonoverlapping(frame_ptr(frame), part.as_mut_ptr(), part.len()) };
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut inner = self.inner.lock();
+        let chunk_size = MAX_SECTORS_PER_REQUEST * SECTOR_SIZE;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let lba = lba + (i * MAX_SECTORS_PER_REQUEST) as u64;
+            for (part, &frame) in chunk.chunks(FRAME_SIZE).zip(inner.data.iter()) {
+                unsafe { ptr::copy_nonoverlapping(part.as_ptr(), frame_ptr(frame), part.len()) };
+            }
+            inner.request(REQUEST_OUT, lba, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.flush {
+            // Without the feature, writes are never cached
+            return Ok(());
+        }
+        self.inner.lock().request(REQUEST_FLUSH, 0, 0)
+    }
+}
+
+// The CPU's view of a frame, through the physical memory mapping
+fn frame_ptr(frame: PhysFrame) -> *mut u8 {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
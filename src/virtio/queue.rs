@@ -0,0 +1,211 @@
+// Split virtqueues.
+//
+// A virtqueue passes buffers between the driver and a device through three
+// areas in memory shared with the device, all kept in one frame here:
+//
+// - the descriptor table, whose entries each point to a buffer and may be
+//   chained to describe a request made of several buffers;
+// - the available ring, where the driver puts the heads of the chains it
+//   hands to the device;
+// - the used ring, where the device returns the chains it is done with.
+//
+// The driver polls the used ring; interrupts from the queue are suppressed.
+
+use crate::memory;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+
+// The largest queue whose areas fit into one frame
+pub const MAX_SIZE: u16 = 128;
+
+// Descriptor flags
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+
+// Available ring flag asking the device not to interrupt
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEMENT_SIZE: usize = 8;
+
+// A buffer of a request, by physical address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub len: u32,
+    // Whether the device writes the buffer, instead of reading it
+    pub writable: bool,
+}
+
+// A split virtqueue
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    frame: PhysFrame,
+    notify: u64,        // Where to write the queue index to notify the device
+    free_head: u16,     // The first descriptor of the free list
+    free_count: u16,
+    avail_index: u16,   // The next index of the available ring to fill
+    last_used: u16,     // The next index of the used ring to look at
+}
+
+impl Virtqueue {
+    // Lay out a queue of `size` entries in `frame`, which the device is told
+    // about with `addresses`
+    pub(super) fn new(index: u16, size: u16, frame: PhysFrame, notify: u64) -> Virtqueue {
+        assert!(size.is_power_of_two() && size <= MAX_SIZE, "bad queue size {}", size);
+        let queue = Virtqueue {
+            index,
+            size,
+            frame,
+            notify,
+            free_head: 0,
+            free_count: size,
+            avail_index: 0,
+            last_used: 0,
+        };
+        unsafe {
+            ptr::write_bytes(queue.base(), 0, 4096);
+            // Chain all descriptors into the free list
+            for i in 0..size {
+                queue.write_desc(i, PhysAddr::zero(), 0, 0, (i + 1) % size);
+            }
+            ptr::write_volatile(queue.avail_ptr(0), AVAIL_NO_INTERRUPT);
+        }
+        queue
+    }
+
+    // The physical addresses of the descriptor table, the available ring
+    // and the used ring
+    pub fn addresses(&self) -> (PhysAddr, PhysAddr, PhysAddr) {
+        let start = self.frame.start_address();
+        (start, start + self.avail_offset() as u64, start + self.used_offset() as u64)
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    // The frame the queue's areas are in
+    pub fn frame(&self) -> PhysFrame {
+        self.frame
+    }
+
+    // Hand a request made of `buffers` to the device, without notifying it.
+    // Returns the head of its descriptor chain, or `None` if there are
+    // too few free descriptors.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut desc = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let next = unsafe { self.desc_next(desc) };
+            let mut flags = if buffer.writable { DESC_WRITE } else { 0 };
+            if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+            }
+            unsafe { self.write_desc(desc, buffer.address, buffer.len, flags, next) };
+            if i + 1 < buffers.len() {
+                desc = next;
+            } else {
+                self.free_head = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let slot = self.avail_index % self.size;
+            ptr::write_volatile(self.avail_ptr(2 + slot as usize), head);
+            // The device must see the descriptors and the ring entry before
+            // the new index
+            fence(Ordering::SeqCst);
+            self.avail_index = self.avail_index.wrapping_add(1);
+            ptr::write_volatile(self.avail_ptr(1), self.avail_index);
+        }
+        Some(head)
+    }
+
+    // Tell the device there are new requests
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { ptr::write_volatile(self.notify as *mut u16, self.index) };
+    }
+
+    // Take the next request the device is done with and free its
+    // descriptors. Returns the head of its chain and how many bytes the
+    // device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        unsafe {
+            let used_index = ptr::read_volatile(self.used_ptr(2) as *const u16);
+            if used_index == self.last_used {
+                return None;
+            }
+            // Read the element only after seeing the index
+            fence(Ordering::SeqCst);
+            let slot = (self.last_used % self.size) as usize;
+            let element = self.used_ptr(4 + slot * USED_ELEMENT_SIZE) as *const u32;
+            let head = ptr::read_volatile(element) as u16;
+            let len = ptr::read_volatile(element.add(1));
+            self.last_used = self.last_used.wrapping_add(1);
+
+            // Put the chain back on the free list
+            let mut desc = head;
+            self.free_count += 1;
+            while self.desc_flags(desc) & DESC_NEXT != 0 {
+                desc = self.desc_next(desc);
+                self.free_count += 1;
+            }
+            self.write_desc(desc, PhysAddr::zero(), 0, 0, self.free_head);
+            self.free_head = head;
+            Some((head, len))
+        }
+    }
+
+    fn base(&self) -> *mut u8 {
+        memory::phys_to_virt(self.frame.start_address()).as_mut_ptr()
+    }
+
+    fn avail_offset(&self) -> usize {
+        self.size as usize * DESC_SIZE
+    }
+
+    fn used_offset(&self) -> usize {
+        // Flags, index, the ring and the used event, aligned to 4 bytes
+        let avail_end = self.avail_offset() + 2 * (3 + self.size as usize);
+        (avail_end + 3) & !3
+    }
+
+    // The 16-bit word `index` of the available ring
+    fn avail_ptr(&self, index: usize) -> *mut u16 {
+        unsafe { (self.base().add(self.avail_offset()) as *mut u16).add(index) }
+    }
+
+    // The byte `offset` into the used ring
+    fn used_ptr(&self, offset: usize) -> *mut u8 {
+        unsafe { self.base().add(self.used_offset() + offset) }
+    }
+
+    unsafe fn write_desc(&self, desc: u16, address: PhysAddr, len: u32, flags: u16, next: u16) {
+        let entry = self.base().add(desc as usize * DESC_SIZE);
+        ptr::write_volatile(entry as *mut u64, address.as_u64());
+        ptr::write_volatile(entry.add(8) as *mut u32, len);
+        ptr::write_volatile(entry.add(12) as *mut u16, flags);
+        ptr::write_volatile(entry.add(14) as *mut u16, next);
+    }
+
+    unsafe fn desc_flags(&self, desc: u16) -> u16 {
+        ptr::read_volatile(self.base().add(desc as usize * DESC_SIZE + 12) as *const u16)
+    }
+
+    unsafe fn desc_next(&self, desc: u16) -> u16 {
+        ptr::read_volatile(self.base().add(desc as usize * DESC_SIZE + 14) as *const u16)
+    }
+}
@@ -0,0 +1,328 @@
+// XMODEM receive, for uploading files from the host over the serial port.
+//
+// The sender, e.g. `sx` from lrzsz or the upload of a terminal program,
+// sends the file in numbered blocks of 128 bytes, or 1024 with XMODEM-1K,
+// and waits for an ACK or NAK after each. The receiver starts the transfer
+// by asking for blocks checked with CRC-16 and falls back to the original
+// 8-bit checksum if the sender doesn't react. The last block is padded with
+// SUB bytes, which are dropped, so a file ending in SUB loses them.
+//
+// Nothing else may use the port during a transfer; with the log on COM1
+// (see `serial::Manager`), messages logged meanwhile can upset the sender.
+
+use crate::serial::RawInput;
+use crate::time;
+use crate::vfs::VfsError;
+use x86_64::instructions::{self, interrupts};
+
+const SOH: u8 = 0x01; // Starts a 128 byte block
+const STX: u8 = 0x02; // Starts a 1024 byte block
+const EOT: u8 = 0x04; // End of transmission
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18; // Cancel, sent twice
+const SUB: u8 = 0x1A; // Padding of the last block
+const CRC_REQUEST: u8 = b'C';
+
+const MAX_BLOCK_SIZE: usize = 1024;
+
+// How long to wait for the start of a block, and for the bytes within one
+const BLOCK_TIMEOUT_MS: u64 = 10_000;
+const BYTE_TIMEOUT_MS: u64 = 1_000;
+
+// How often the start of a transfer is requested, and the time in between
+const START_ATTEMPTS: u32 = 20;
+const CRC_ATTEMPTS: u32 = 3; // Before falling back to checksums
+const START_INTERVAL_MS: u64 = 3_000;
+
+// The number of bad blocks or timeouts in a row before giving up
+const MAX_ERRORS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmodemError {
+    Timeout,       // The sender didn't start or stopped sending
+    Cancelled,     // The sender cancelled the transfer
+    TooManyErrors, // Too many blocks were damaged
+    OutOfSequence, // A block was skipped, so the file can't be complete
+    Write(VfsError),
+}
+
+// A byte stream to receive on
+pub trait Channel {
+    // Return the next byte, or `None` if none came within `timeout_ms`
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8>;
+
+    fn write(&mut self, bytes: &[u8]);
+}
+
+// COM1, taken over for a transfer
+pub struct SerialChannel {
+    input: RawInput,
+}
+
+impl SerialChannel {
+    pub fn new(input: RawInput) -> SerialChannel {
+        SerialChannel { input }
+    }
+}
+
+impl Channel for SerialChannel {
+    // Halts the CPU until a byte arrives, so interrupts must be enabled
+    fn read_byte(&mut self, timeout_ms: u64) -> Option<u8> {
+        assert!(interrupts::are_enabled(), "read_byte called with interrupts disabled");
+        let deadline = time::uptime_ms() + timeout_ms;
+        loop {
+            if let Some(byte) = self.input.read_byte() {
+                return Some(byte);
+            }
+            if time::uptime_ms() >= deadline {
+                return None;
+            }
+            instructions::hlt();
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.input.write(bytes);
+    }
+}
+
+// Receive a file and pass its contents to `write` piece by piece. Returns
+// the size of the file.
+pub fn receive<C, W>(channel: &mut C, mut write: W) -> Result<u64, XmodemError>
+where
+    C: Channel,
+    W: FnMut(&[u8]) -> Result<(), VfsError>,
+{
+    let (mut header, crc) = start(channel)?;
+    let mut block = [0u8; MAX_BLOCK_SIZE];
+    // The last good block, held back until it is known whether it is padded
+    let mut pending = [0u8; MAX_BLOCK_SIZE];
+    let mut pending_len = 0;
+    let mut expected: u8 = 1;
+    let mut size = 0;
+    let mut errors = 0;
+
+    loop {
+        let good = match header {
+            SOH | STX => {
+                let len = if header == SOH { 128 } else { MAX_BLOCK_SIZE };
+                match read_block(channel, &mut block[..len], crc) {
+                    Some(number) if number == expected => {
+                        if let Err(err) = write(&pending[..pending_len]) {
+                            cancel(channel);
+                            return Err(XmodemError::Write(err));
+                        }
+                        size += pending_len as u64;
+                        pending[..len].copy_from_slice(&block[..len]);
+                        pending_len = len;
+                        expected = expected.wrapping_add(1);
+                        true
+                    }
+                    // Our ACK got lost and the sender repeats the block
+                    Some(number) if number == expected.wrapping_sub(1) => true,
+                    Some(_) => {
+                        cancel(channel);
+                        return Err(XmodemError::OutOfSequence);
+                    }
+                    None => false,
+                }
+            }
+            EOT => {
+                channel.write(&[ACK]);
+                let len = pending[..pending_len].iter().rposition(|&byte| byte != SUB);
+                let len = len.map_or(0, |last| last + 1);
+                write(&pending[..len]).map_err(XmodemError::Write)?;
+                return Ok(size + len as u64);
+            }
+            CAN if channel.read_byte(BYTE_TIMEOUT_MS) == Some(CAN) => {
+                return Err(XmodemError::Cancelled);
+            }
+            _ => false,
+        };
+
+        if good {
+            errors = 0;
+            channel.write(&[ACK]);
+        } else {
+            errors += 1;
+            if errors > MAX_ERRORS {
+                cancel(channel);
+                return Err(XmodemError::TooManyErrors);
+            }
+            purge(channel);
+            channel.write(&[NAK]);
+        }
+
+        header = loop {
+            if let Some(byte) = channel.read_byte(BLOCK_TIMEOUT_MS) {
+                break byte;
+            }
+            errors += 1;
+            if errors > MAX_ERRORS {
+                cancel(channel);
+                return Err(XmodemError::Timeout);
+            }
+            channel.write(&[NAK]);
+        };
+    }
+}
+
+// Ask the sender to start until it does. Returns the first byte it sent
+// and whether blocks are checked with CRC-16.
+fn start<C: Channel>(channel: &mut C) -> Result<(u8, bool), XmodemError> {
+    for attempt in 0..START_ATTEMPTS {
+        let crc = attempt < CRC_ATTEMPTS;
+        channel.write(&[if crc { CRC_REQUEST } else { NAK }]);
+        if let Some(byte @ (SOH | STX | EOT | CAN)) = channel.read_byte(START_INTERVAL_MS) {
+            return Ok((byte, crc));
+        }
+    }
+    Err(XmodemError::Timeout)
+}
+
+// Read the rest of a block after its first byte into `data`. Returns the
+// block number, or `None` if the block is damaged.
+fn read_block<C: Channel>(channel: &mut C, data: &mut [u8], crc: bool) -> Option<u8> {
+    let number = channel.read_byte(BYTE_TIMEOUT_MS)?;
+    let complement = channel.read_byte(BYTE_TIMEOUT_MS)?;
+    for byte in data.iter_mut() {
+        *byte = channel.read_byte(BYTE_TIMEOUT_MS)?;
+    }
+    let valid = if crc {
+        let high = channel.read_byte(BYTE_TIMEOUT_MS)?;
+        let low = channel.read_byte(BYTE_TIMEOUT_MS)?;
+        u16::from_be_bytes([high, low]) == crc16(data)
+    } else {
+        channel.read_byte(BYTE_TIMEOUT_MS)? == checksum(data)
+    };
+    if valid && number == !complement {
+        Some(number)
+    } else {
+        None
+    }
+}
+
+// Wait until the line is quiet, dropping what arrives
+fn purge<C: Channel>(channel: &mut C) {
+    while channel.read_byte(BYTE_TIMEOUT_MS).is_some() {}
+}
+
+fn cancel<C: Channel>(channel: &mut C) {
+    channel.write(&[CAN; 3]);
+}
+
+// CRC-16/XMODEM: polynomial 0x1021, starting at 0
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+// A channel replaying bytes, where `None` is a timeout, and recording what
+// is written
+#[cfg(test)]
+struct MockChannel<'a> {
+    input: &'a [Option<u8>],
+    output: [u8; 16],
+    written: usize,
+}
+
+#[cfg(test)]
+impl Channel for MockChannel<'_> {
+    fn read_byte(&mut self, _timeout_ms: u64) -> Option<u8> {
+        let (&first, rest) = self.input.split_first()?;
+        self.input = rest;
+        first
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.output[self.written] = byte;
+            self.written += 1;
+        }
+    }
+}
+
+// Append a 128 byte block with `text` to `input` and return the new length
+#[cfg(test)]
+fn push_block(input: &mut [Option<u8>], len: usize, number: u8, text: &[u8], crc: bool) -> usize {
+    let mut data = [SUB; 128];
+    data[..text.len()].copy_from_slice(text);
+    let mut bytes = [0u8; 133];
+    bytes[..3].copy_from_slice(&[SOH, number, !number]);
+    bytes[3..131].copy_from_slice(&data);
+    let count = if crc {
+        bytes[131..].copy_from_slice(&crc16(&data).to_be_bytes());
+        133
+    } else {
+        bytes[131] = checksum(&data);
+        132
+    };
+    for (slot, &byte) in input[len..].iter_mut().zip(&bytes[..count]) {
+        *slot = Some(byte);
+    }
+    len + count
+}
+
+#[test_case]
+fn test_crc16() {
+    assert_eq!(crc16(b"123456789"), 0x31C3);
+    assert_eq!(checksum(&[0xFF, 0x02]), 0x01);
+}
+
+#[test_case]
+fn test_xmodem_receive() {
+    let mut input = [None; 600];
+    // A damaged block and the line going quiet, then the block again twice
+    let mut len = push_block(&mut input, 0, 1, b"hello, ", true);
+    input[len - 1] = input[len - 1].map(|byte| !byte);
+    len += 1;
+    len = push_block(&mut input, len, 1, b"hello, ", true);
+    len = push_block(&mut input, len, 1, b"hello, ", true);
+    len = push_block(&mut input, len, 2, b"world\n", true);
+    input[len] = Some(EOT);
+
+    let mut channel = MockChannel { input: &input[..len + 1], output: [0; 16], written: 0 };
+    let mut file = [0u8; 256];
+    let mut file_len = 0;
+    let result = receive(&mut channel, |data| {
+        file[file_len..file_len + data.len()].copy_from_slice(data);
+        file_len += data.len();
+        Ok(())
+    });
+    assert_eq!(result, Ok(128 + 6));
+    assert_eq!(&file[..7], b"hello, ");
+    assert_eq!(file[7..128], [SUB; 121]);
+    assert_eq!(&file[128..file_len], b"world\n");
+    assert_eq!(channel.output[..channel.written], [b'C', NAK, ACK, ACK, ACK, ACK]);
+}
+
+#[test_case]
+fn test_xmodem_checksum_and_cancel() {
+    // No answer to the CRC requests, so checksums are used
+    let mut input = [None; 300];
+    let mut len = push_block(&mut input, CRC_ATTEMPTS as usize, 1, b"data", false);
+    input[len] = Some(CAN);
+    input[len + 1] = Some(CAN);
+    len += 2;
+
+    let mut channel = MockChannel { input: &input[..len], output: [0; 16], written: 0 };
+    assert_eq!(receive(&mut channel, |_| Ok(())), Err(XmodemError::Cancelled));
+    assert_eq!(channel.output[..channel.written], [b'C', b'C', b'C', NAK, ACK]);
+
+    // A skipped block
+    let len = push_block(&mut input, 0, 2, b"data", true);
+    let mut channel = MockChannel { input: &input[..len], output: [0; 16], written: 0 };
+    assert_eq!(receive(&mut channel, |_| Ok(())), Err(XmodemError::OutOfSequence));
+    assert_eq!(channel.output[..channel.written], [b'C', CAN, CAN, CAN]);
+}
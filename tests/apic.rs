@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::interrupts::{self, InterruptIndex};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{apic, exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// A line no device in QEMU's default machine uses
+const IRQ: u8 = 10;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("apic::routes_lines_registered_before_init...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    // Registered while the PICs deliver interrupts, like the serial line
+    // in `rust_os::init`
+    interrupts::register_irq(IRQ, || {});
+    apic::init(&mut mapper, &mut frame_allocator).expect("APIC initialization failed");
+    assert!(apic::is_enabled());
+
+    assert!(apic::is_routed(IRQ));
+    assert!(apic::is_routed(InterruptIndex::Timer.irq()));
+    assert!(apic::is_routed(InterruptIndex::Keyboard.irq()));
+    // Lines without a handler stay masked
+    assert!(!apic::is_routed(IRQ + 1));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
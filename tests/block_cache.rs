@@ -0,0 +1,195 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use rust_os::block::cache::{self, CachedDevice, CACHE_BLOCKS};
+use rust_os::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use rust_os::ramdisk::RamDisk;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// A disk counting the blocks read from and written to it
+struct CountingDisk {
+    disk: RamDisk,
+    reads: AtomicUsize,
+    writes: AtomicUsize,
+}
+
+impl CountingDisk {
+    fn new(blocks: u64) -> Arc<CountingDisk> {
+        Arc::new(CountingDisk {
+            disk: RamDisk::new(blocks),
+            reads: AtomicUsize::new(0),
+            writes: AtomicUsize::new(0),
+        })
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        (self.reads.load(Ordering::Relaxed), self.writes.load(Ordering::Relaxed))
+    }
+}
+
+impl BlockDevice for CountingDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.reads.fetch_add(buffer.len() / SECTOR_SIZE, Ordering::Relaxed);
+        self.disk.read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        self.writes.fetch_add(buffer.len() / SECTOR_SIZE, Ordering::Relaxed);
+        self.disk.write_blocks(lba, buffer)
+    }
+}
+
+#[test_case]
+fn reads_and_write_back() {
+    let disk = CountingDisk::new(16);
+    disk.write_blocks(3, &[3; SECTOR_SIZE]).unwrap();
+    let cached = CachedDevice::new(disk.clone());
+    let mut buffer = [0; 2 * SECTOR_SIZE];
+
+    // Only the first read of a block reaches the disk
+    cached.read_blocks(3, &mut buffer[..SECTOR_SIZE]).unwrap();
+    cached.read_blocks(3, &mut buffer[..SECTOR_SIZE]).unwrap();
+    assert_eq!(buffer[..SECTOR_SIZE], [3; SECTOR_SIZE]);
+    assert_eq!(disk.counts(), (1, 1));
+
+    // Writes stay in the cache until the device is flushed
+    cached.write_blocks(4, &[4; 2 * SECTOR_SIZE]).unwrap();
+    cached.read_blocks(4, &mut buffer).unwrap();
+    assert_eq!(buffer, [4; 2 * SECTOR_SIZE]);
+    assert_eq!(disk.counts(), (1, 1));
+    disk.read_blocks(4, &mut buffer).unwrap();
+    assert_eq!(buffer, [0; 2 * SECTOR_SIZE]);
+    assert!(cache::stats().dirty >= 2);
+
+    cached.flush().unwrap();
+    assert_eq!(disk.counts().1, 3);
+    disk.read_blocks(4, &mut buffer).unwrap();
+    assert_eq!(buffer, [4; 2 * SECTOR_SIZE]);
+    assert_eq!(cache::sync(), Ok(0));
+
+    assert_eq!(cached.read_blocks(15, &mut buffer), Err(BlockError::OutOfRange));
+    assert_eq!(cached.write_blocks(0, &[0; 100]), Err(BlockError::BadBufferSize));
+}
+
+#[test_case]
+fn eviction() {
+    let disk = CountingDisk::new(CACHE_BLOCKS as u64 + 1);
+    let cached = CachedDevice::new(disk.clone());
+    cached.write_blocks(0, &[7; SECTOR_SIZE]).unwrap();
+    assert_eq!(disk.counts(), (0, 0));
+
+    // Reading as many other blocks as the cache holds drops the written
+    // one, which is written back then
+    let mut buffer = [0; SECTOR_SIZE];
+    for lba in 1..=CACHE_BLOCKS as u64 {
+        cached.read_blocks(lba, &mut buffer).unwrap();
+    }
+    assert_eq!(disk.counts(), (CACHE_BLOCKS, 1));
+    disk.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(buffer, [7; SECTOR_SIZE]);
+
+    // So it is read again
+    cached.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(disk.counts().0, CACHE_BLOCKS + 2);
+    assert_eq!(cache::stats().blocks, CACHE_BLOCKS);
+}
+
+// A disk on which writing one block fails, like a bad sector
+struct BadBlockDisk {
+    disk: RamDisk,
+    bad: AtomicU64, // `u64::MAX` once writes work again
+}
+
+impl BlockDevice for BadBlockDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.disk.block_count()
+    }
+
+    fn read_blocks(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        self.disk.read_blocks(lba, buffer)
+    }
+
+    fn write_blocks(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let count = (buffer.len() / SECTOR_SIZE) as u64;
+        if (lba..lba + count).contains(&self.bad.load(Ordering::Relaxed)) {
+            return Err(BlockError::Io);
+        }
+        self.disk.write_blocks(lba, buffer)
+    }
+}
+
+#[test_case]
+fn failed_write_back() {
+    let disk = Arc::new(BadBlockDisk {
+        disk: RamDisk::new(CACHE_BLOCKS as u64 + 1),
+        bad: AtomicU64::new(0),
+    });
+    let cached = CachedDevice::new(disk.clone());
+    cached.write_blocks(0, &[9; SECTOR_SIZE]).unwrap();
+
+    // The block that can't be written back is the least recently used one,
+    // but other blocks are still cached in its place
+    let mut buffer = [0; SECTOR_SIZE];
+    for lba in 1..=CACHE_BLOCKS as u64 {
+        cached.read_blocks(lba, &mut buffer).unwrap();
+    }
+    for lba in 1..=CACHE_BLOCKS as u64 {
+        cached.read_blocks(lba, &mut buffer).unwrap();
+    }
+    assert!(cache::stats().dirty >= 1);
+    assert_eq!(cache::sync(), Err(BlockError::Io));
+
+    // It is still in the cache, and reaches the disk once writes work
+    cached.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(buffer, [9; SECTOR_SIZE]);
+    disk.bad.store(u64::MAX, Ordering::Relaxed);
+    assert_eq!(cache::sync(), Ok(1));
+    disk.read_blocks(0, &mut buffer).unwrap();
+    assert_eq!(buffer, [9; SECTOR_SIZE]);
+}
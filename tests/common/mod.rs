@@ -0,0 +1,49 @@
+// Helpers shared by the integration tests, which include this module with
+// `mod common;`. Not every test uses every helper.
+#![allow(dead_code)]
+
+use alloc::vec::Vec;
+
+// A segment without contents in the file, like a .bss
+pub struct Segment {
+    pub flags: u32, // 4 = R, 2 = W, 1 = X
+    pub vaddr: u64,
+    pub mem_size: u64,
+}
+
+// An executable loaded at `base`, with a read-only, executable segment
+// holding the headers and `code`, followed by `segments`. It starts at the
+// first byte of `code`.
+pub fn executable(base: u64, code: &[u8], segments: &[Segment]) -> Vec<u8> {
+    let headers = 1 + segments.len() as u16;
+    let code_offset = 64 + headers as u64 * 56;
+    let mut file = Vec::new();
+    file.extend_from_slice(b"\x7FELF");
+    file.extend_from_slice(&[2, 1, 1]); // 64 bit, little endian, version 1
+    file.resize(16, 0);
+    file.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    file.extend_from_slice(&0x3Eu16.to_le_bytes()); // x86_64
+    file.extend_from_slice(&1u32.to_le_bytes());
+    file.extend_from_slice(&(base + code_offset).to_le_bytes()); // Entry
+    file.extend_from_slice(&64u64.to_le_bytes()); // Program headers
+    file.extend_from_slice(&0u64.to_le_bytes()); // Section headers
+    file.extend_from_slice(&0u32.to_le_bytes());
+    for half in [64u16, 56, headers, 64, 0, 0] {
+        file.extend_from_slice(&half.to_le_bytes());
+    }
+    let file_size = code_offset + code.len() as u64;
+    program_header(&mut file, 5, 0, base, file_size, file_size); // R+X
+    for segment in segments {
+        program_header(&mut file, segment.flags, 0, segment.vaddr, 0, segment.mem_size);
+    }
+    file.extend_from_slice(code);
+    file
+}
+
+fn program_header(file: &mut Vec<u8>, flags: u32, offset: u64, vaddr: u64, size: u64, mem: u64) {
+    file.extend_from_slice(&1u32.to_le_bytes()); // PT_LOAD
+    file.extend_from_slice(&flags.to_le_bytes());
+    for word in [offset, vaddr, vaddr, size, mem, 0x1000] {
+        file.extend_from_slice(&word.to_le_bytes());
+    }
+}
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::compress::{decompress_lz4, decompress_lz4_block, DecompressError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn lz4_block_overlapping_match() {
+    // "abc" followed by a 9 byte match at offset 3
+    let block = [0x35, b'a', b'b', b'c', 0x03, 0x00, 0x00];
+    let mut output = Vec::new();
+    decompress_lz4_block(&block, &mut output, 64).expect("decompression failed");
+    assert_eq!(output.as_slice(), b"abcabcabcabc");
+}
+
+#[test_case]
+fn lz4_block_rejects_bad_offset() {
+    let block = [0x10, b'a', 0x02, 0x00, 0x00];
+    let mut output = Vec::new();
+    assert_eq!(
+        decompress_lz4_block(&block, &mut output, 64),
+        Err(DecompressError::InvalidOffset)
+    );
+}
+
+#[test_case]
+fn lz4_block_output_limit() {
+    let block = [0x35, b'a', b'b', b'c', 0x03, 0x00, 0x00];
+    let mut output = Vec::new();
+    assert_eq!(
+        decompress_lz4_block(&block, &mut output, 8),
+        Err(DecompressError::OutputTooLarge)
+    );
+}
+
+#[test_case]
+fn lz4_frame() {
+    let frame = [
+        0x04, 0x22, 0x4D, 0x18, // magic
+        0x40, 0x40, 0xC0,       // FLG, BD, header checksum
+        0x06, 0x00, 0x00, 0x00, // block size
+        0x50, b'h', b'e', b'l', b'l', b'o',
+        0x00, 0x00, 0x00, 0x00, // end mark
+    ];
+    let output = decompress_lz4(&frame, 64).expect("decompression failed");
+    assert_eq!(output.as_slice(), b"hello");
+}
@@ -0,0 +1,91 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use common::Segment;
+use core::panic::PanicInfo;
+use rust_os::memory::{self, cow, BootInfoFrameAllocator};
+use rust_os::process::Process;
+use rust_os::tmpfs::TmpFs;
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, vfs, QemuExitCode};
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::VirtAddr;
+
+mod common;
+
+entry_point!(main);
+
+// Where the program is linked; its .bss follows in the next page
+const BASE: u64 = 0x2000_0000_0000;
+const BSS: u64 = BASE + 0x1000;
+
+// Add argc to the first word of the .bss and exit with the sum
+#[rustfmt::skip]
+const CODE: [u8; 27] = [
+    0x48, 0xB8, 0x00, 0x10, 0x00, 0x00,       // movabs rax, BSS
+    0x00, 0x20, 0x00, 0x00,
+    0x48, 0x8B, 0x3C, 0x24,                   // mov rdi, [rsp]
+    0x48, 0x03, 0x38,                         // add rdi, [rax]
+    0x48, 0x89, 0x38,                         // mov [rax], rdi
+    0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov eax, SYS_EXIT
+    0x0F, 0x05,                               // syscall
+];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("process::Process::fork...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    // So the kernel's writes to shared pages are copied too
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    vfs::mount("/", Arc::new(TmpFs::new(4096))).expect("mount failed");
+    let bss = Segment { flags: 6, vaddr: BSS, mem_size: 8 }; // R+W
+    vfs::write_file("/prog", &common::executable(BASE, &CODE, &[bss])).unwrap();
+    let in_use = memory::frame_stats().in_use();
+
+    let mut parent = unsafe { Process::create_from_elf("/prog", &["a"], &mut frame_allocator) }
+        .unwrap();
+
+    // Forked before running, the child starts with the same zeroed .bss,
+    // and each one's write stays its own
+    let mut child = unsafe { parent.fork(&mut frame_allocator) }.unwrap();
+    assert_ne!(child.pid(), parent.pid());
+    assert_eq!(child.threads(), parent.threads());
+    let (shared, spares) = cow::stats();
+    assert!(shared > 0 && spares > 0);
+    assert_eq!(unsafe { parent.run() }, 1);
+    assert_eq!(unsafe { child.run() }, 1);
+
+    // Forked after running, it sees what the parent wrote
+    let mut late_child = unsafe { parent.fork(&mut frame_allocator) }.unwrap();
+    assert_eq!(unsafe { late_child.run() }, 2);
+
+    // The frames still shared are freed with their last mapping, and the
+    // spares no copy needed are given back
+    unsafe {
+        parent.destroy(&mut frame_allocator);
+        child.destroy(&mut frame_allocator);
+        late_child.destroy(&mut frame_allocator);
+    }
+    assert_eq!(cow::stats(), (0, 0));
+    assert_eq!(memory::frame_stats().in_use(), in_use);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use common::Segment;
+use core::panic::PanicInfo;
+use rust_os::elf::{self, ElfError};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::tmpfs::TmpFs;
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, vfs, QemuExitCode};
+use x86_64::VirtAddr;
+
+mod common;
+
+entry_point!(main);
+
+// Where the program is linked; its .bss follows in the next page
+const BASE: u64 = 0x2000_0000_0000;
+const BSS: u64 = BASE + 0x1000;
+
+// Exit with argc + argv[0][0] + the first word of the .bss
+#[rustfmt::skip]
+const CODE: [u8; 35] = [
+    0x48, 0x8B, 0x3C, 0x24,                   // mov rdi, [rsp]
+    0x48, 0x8B, 0x44, 0x24, 0x08,             // mov rax, [rsp + 8]
+    0x0F, 0xB6, 0x00,                         // movzx eax, byte [rax]
+    0x48, 0x01, 0xC7,                         // add rdi, rax
+    0x48, 0xB8, 0x00, 0x10, 0x00, 0x00,       // movabs rax, BSS
+    0x00, 0x20, 0x00, 0x00,
+    0x48, 0x03, 0x38,                         // add rdi, [rax]
+    0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov eax, SYS_EXIT
+    0x0F, 0x05,                               // syscall
+];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("elf::exec...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    vfs::mount("/", Arc::new(TmpFs::new(4096))).expect("mount failed");
+    let bss = Segment { flags: 6, vaddr: BSS, mem_size: 8 }; // R+W
+    vfs::write_file("/prog", &common::executable(BASE, &CODE, &[bss])).unwrap();
+    vfs::write_file("/text", b"not an executable").unwrap();
+
+    // The second run reuses the page tables the first one left, and frees
+    // every frame it takes
+    let mut in_use = 0;
+    for run in 0..2 {
+        let status = unsafe { elf::exec("/prog", &["a", "b"], &mut mapper, &mut frame_allocator) };
+        assert_eq!(status.unwrap(), 2 + b'a' as u64);
+        let now = memory::frame_stats().in_use();
+        if run == 1 {
+            assert_eq!(now, in_use);
+        }
+        in_use = now;
+    }
+
+    let result = unsafe { elf::load("/text", &mut mapper, &mut frame_allocator) };
+    assert!(matches!(result, Err(ElfError::NotElf)));
+    let result = unsafe { elf::load("/missing", &mut mapper, &mut frame_allocator) };
+    assert!(matches!(result, Err(ElfError::Io(vfs::VfsError::NotFound))));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
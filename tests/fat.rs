@@ -0,0 +1,208 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::block::{BlockDevice, SECTOR_SIZE};
+use rust_os::fat::{FatError, FatType, Volume};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::ramdisk::RamDisk;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("fat::fat16...\t");
+    let disk = RamDisk::with_frames(FAT16_SECTORS, &mut frame_allocator).expect("out of frames");
+    let disk = Arc::new(disk);
+    format_fat16(&disk);
+    check_volume(disk, FatType::Fat16);
+    serial_println!("[ok]");
+
+    serial_print!("fat::fat32...\t");
+    let disk = RamDisk::with_frames(FAT32_SECTORS, &mut frame_allocator).expect("out of frames");
+    let disk = Arc::new(disk);
+    format_fat32(&disk);
+    check_volume(disk, FatType::Fat32);
+    serial_println!("[ok]");
+
+    serial_print!("fat::append_to_full_volume...\t");
+    let disk = RamDisk::with_frames(FAT16_SECTORS, &mut frame_allocator).expect("out of frames");
+    let disk = Arc::new(disk);
+    format_fat16(&disk);
+    check_full_volume(disk);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+// Filesystems with one sector per cluster, just large enough for their type
+const FAT16_SECTORS: u64 = 8192;
+const FAT16_FAT_SECTORS: u16 = 32;
+const FAT32_SECTORS: u64 = 66600;
+const FAT32_FAT_SECTORS: u32 = 517;
+const FAT32_RESERVED: u16 = 32;
+
+// Write the boot sector fields both FAT types share
+fn boot_sector(reserved: u16, root_entries: u16) -> [u8; SECTOR_SIZE] {
+    let mut boot = [0u8; SECTOR_SIZE];
+    boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    boot[3..11].copy_from_slice(b"RUST_OS ");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = 1; // Sectors per cluster
+    boot[14..16].copy_from_slice(&reserved.to_le_bytes());
+    boot[16] = 2; // FATs
+    boot[17..19].copy_from_slice(&root_entries.to_le_bytes());
+    boot[21] = 0xF8; // Media
+    boot[510] = 0x55;
+    boot[511] = 0xAA;
+    boot
+}
+
+// Write the first entries of both FATs, which are reserved
+fn init_fats(disk: &RamDisk, fat_start: u64, fat_sectors: u64, entries: &[u8]) {
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[..entries.len()].copy_from_slice(entries);
+    disk.write_blocks(fat_start, &sector).unwrap();
+    disk.write_blocks(fat_start + fat_sectors, &sector).unwrap();
+}
+
+fn format_fat16(disk: &RamDisk) {
+    let mut boot = boot_sector(1, 512);
+    boot[19..21].copy_from_slice(&(FAT16_SECTORS as u16).to_le_bytes());
+    boot[22..24].copy_from_slice(&FAT16_FAT_SECTORS.to_le_bytes());
+    disk.write_blocks(0, &boot).unwrap();
+    init_fats(disk, 1, FAT16_FAT_SECTORS as u64, &[0xF8, 0xFF, 0xFF, 0xFF]);
+}
+
+fn format_fat32(disk: &RamDisk) {
+    let mut boot = boot_sector(FAT32_RESERVED, 0);
+    boot[32..36].copy_from_slice(&(FAT32_SECTORS as u32).to_le_bytes());
+    boot[36..40].copy_from_slice(&FAT32_FAT_SECTORS.to_le_bytes());
+    boot[44..48].copy_from_slice(&2u32.to_le_bytes()); // Root directory cluster
+    boot[48..50].copy_from_slice(&1u16.to_le_bytes()); // FSInfo sector
+    disk.write_blocks(0, &boot).unwrap();
+
+    let mut fsinfo = [0u8; SECTOR_SIZE];
+    fsinfo[..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    fsinfo[488..492].copy_from_slice(&1000u32.to_le_bytes());
+    fsinfo[492..496].copy_from_slice(&3u32.to_le_bytes());
+    fsinfo[510] = 0x55;
+    fsinfo[511] = 0xAA;
+    disk.write_blocks(1, &fsinfo).unwrap();
+
+    // The root directory takes cluster 2
+    let entries = [0xF8, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F, 0xFF, 0xFF, 0xFF, 0x0F];
+    init_fats(disk, FAT32_RESERVED as u64, FAT32_FAT_SECTORS as u64, &entries);
+}
+
+fn check_volume(disk: Arc<RamDisk>, fat_type: FatType) {
+    let volume = Volume::mount(disk.clone()).expect("mount failed");
+    assert_eq!(volume.fat_type(), fat_type);
+
+    // A small file, then appending across several clusters
+    volume.write_file("/hello.txt", b"Hello").unwrap();
+    assert_eq!(volume.read_file("/HELLO.TXT").unwrap(), b"Hello");
+    let mut expected = Vec::from(&b"Hello"[..]);
+    let data: Vec<u8> = (0..1500).map(|i| (i % 251) as u8).collect();
+    volume.append("/hello.txt", &data).unwrap();
+    expected.extend_from_slice(&data);
+    assert_eq!(volume.read_file("/hello.txt").unwrap(), expected);
+
+    let entry = volume.find("/hello.txt").unwrap();
+    assert_eq!(entry.size, 1505);
+    let mut part = [0u8; 8];
+    assert_eq!(volume.read(&entry, 1000, &mut part), Ok(8));
+    assert_eq!(part, expected[1000..1008]);
+    assert_eq!(volume.read(&entry, 1500, &mut part), Ok(5));
+
+    // Long names, enough of them that the directory grows past a cluster
+    volume.create_dir("/docs").unwrap();
+    for i in 0..8 {
+        let path = format!("/docs/A long file name {}.txt", i);
+        volume.write_file(&path, &[i as u8; 10]).unwrap();
+    }
+    let entries = volume.read_dir("/docs").unwrap();
+    assert_eq!(entries.len(), 8);
+    assert_eq!(entries[3].name, "A long file name 3.txt");
+    assert_eq!(volume.read_file("/docs/ALONGF~4.TXT").unwrap(), [3; 10]);
+    assert_eq!(volume.read_file("/docs/./a LONG file name 5.TXT").unwrap(), [5; 10]);
+
+    // Overwriting frees the old clusters for the next file
+    volume.write_file("/hello.txt", b"Bye").unwrap();
+    assert_eq!(volume.read_file("/hello.txt").unwrap(), b"Bye");
+    volume.write_file("/notes.md", &data).unwrap();
+    assert_eq!(volume.read_file("/notes.md").unwrap(), data);
+
+    assert_eq!(volume.create("/hello.txt").map(|_| ()), Err(FatError::Exists));
+    assert_eq!(volume.create("/bad:name").map(|_| ()), Err(FatError::BadName));
+    assert_eq!(volume.read_file("/docs"), Err(FatError::IsADirectory));
+    assert_eq!(volume.find("/missing").map(|_| ()), Err(FatError::NotFound));
+    assert_eq!(volume.find("/hello.txt/x").map(|_| ()), Err(FatError::NotADirectory));
+
+    // Everything is on the disk
+    let volume = Volume::mount(disk).expect("mount failed");
+    let names: Vec<_> = volume.read_dir("/").unwrap().into_iter().map(|e| e.name).collect();
+    assert_eq!(names, ["hello.txt", "docs", "notes.md"]);
+    assert_eq!(volume.read_file("/docs/A long file name 7.txt").unwrap(), [7; 10]);
+}
+
+fn check_full_volume(disk: Arc<RamDisk>) {
+    let volume = Volume::mount(disk).expect("mount failed");
+    let cluster_size = volume.cluster_size();
+    volume.write_file("/small", &[1; 10]).unwrap();
+
+    // Fill the volume, with large files first and then single clusters
+    let data = vec![2u8; 64 * 1024];
+    let mut files = 0;
+    while volume.write_file(&format!("/big{}", files), &data).is_ok() {
+        files += 1;
+    }
+    assert!(files > 0);
+    let mut files = 0;
+    while volume.write_file(&format!("/fill{}", files), &data[..cluster_size]).is_ok() {
+        files += 1;
+    }
+    assert_eq!(volume.free_clusters(), Ok(0));
+
+    // With one cluster free again, an append needing more fails partway.
+    // The cluster it took is given back, and the file is as it was.
+    volume.write_file("/small", b"").unwrap();
+    assert_eq!(volume.free_clusters(), Ok(1));
+    assert_eq!(volume.append("/big0", &data[..4096]), Err(FatError::NoSpace));
+    assert_eq!(volume.free_clusters(), Ok(1));
+    assert_eq!(volume.find("/big0").unwrap().size as usize, data.len());
+
+    // The free cluster can still be used
+    volume.append("/big0", &data[..cluster_size]).unwrap();
+    assert_eq!(volume.free_clusters(), Ok(0));
+    assert_eq!(volume.find("/big0").unwrap().size as usize, data.len() + cluster_size);
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::{self, HeapError};
+use rust_os::fault::{self, Site};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// A frame allocation half way through `init_heap` fails. This needs a test
+// of its own, since the heap can't be set up again afterwards.
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("fault::init_heap_without_frames...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    fault::fail_nth(Site::Frame, 50);
+    let result = allocator::init_heap(&mut mapper, &mut frame_allocator);
+    assert!(matches!(result, Err(HeapError::Map(MapToError::FrameAllocationFailed))));
+    assert_eq!(fault::injected(Site::Frame), 1);
+
+    // The caller gets an error instead of a heap in a half mapped state
+    let result = allocator::init_heap(&mut mapper, &mut frame_allocator);
+    assert!(matches!(result, Err(HeapError::AlreadyInitialized)));
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
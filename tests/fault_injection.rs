@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::block::cache::CachedDevice;
+use rust_os::block::BlockError;
+use rust_os::elf::ElfError;
+use rust_os::fault::{self, Site};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::partition::{self, PartitionError};
+use rust_os::process::Process;
+use rust_os::ramdisk::RamDisk;
+use rust_os::tmpfs::TmpFs;
+use rust_os::vfs::{self, FileSystem, VfsError};
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::VirtAddr;
+
+mod common;
+
+entry_point!(main);
+
+// Where the program is linked
+const BASE: u64 = 0x2000_0000_0000;
+
+// Exit with status 0
+#[rustfmt::skip]
+const CODE: [u8; 9] = [
+    0x31, 0xFF,                               // xor edi, edi
+    0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov eax, SYS_EXIT
+    0x0F, 0x05,                               // syscall
+];
+
+// Faults injected into real callers, each of which must fail cleanly and
+// work again once the fault has passed
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("fault::callers_handle_faults...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    let fs = Arc::new(TmpFs::new(4096));
+    vfs::mount("/", fs.clone()).expect("mount failed");
+    vfs::write_file("/prog", &common::executable(BASE, &CODE, &[])).unwrap();
+
+    // Frames: creating a process fails at each of its frame allocations in
+    // turn, and gives back the frames it took each time
+    let in_use = memory::frame_stats().in_use();
+    let mut allocations = 1;
+    let mut process = loop {
+        fault::fail_nth(Site::Frame, allocations);
+        match unsafe { Process::create_from_elf("/prog", &["a"], &mut frame_allocator) } {
+            Ok(process) => break process,
+            Err(err) => {
+                assert!(matches!(err, ElfError::Map(MapToError::FrameAllocationFailed)));
+                assert_eq!(memory::frame_stats().in_use(), in_use);
+            }
+        }
+        allocations += 1;
+    };
+    fault::clear();
+    assert!(allocations > 1);
+    assert_eq!(unsafe { process.run() }, 0);
+    unsafe { process.destroy(&mut frame_allocator) };
+    assert_eq!(memory::frame_stats().in_use(), in_use);
+
+    // Block reads: the error reaches the partition scan, and the cache
+    // doesn't keep the block that failed
+    let disk = CachedDevice::new(Arc::new(RamDisk::new(8)));
+    fault::fail_nth(Site::Block, 1);
+    assert_eq!(partition::read_table(&disk), Err(PartitionError::Io(BlockError::Io)));
+    assert_eq!(partition::read_table(&disk), Ok(None));
+
+    // Heap: a file can't grow, and the filesystem isn't left inconsistent
+    fs.create("/file").unwrap();
+    // No interrupt handler may take the fault meant for the write
+    let result = without_interrupts(|| {
+        fault::fail_nth(Site::Heap, 1);
+        fs.write("/file", 0, b"data")
+    });
+    assert_eq!(result, Err(VfsError::NoSpace));
+    assert_eq!(vfs::metadata("/file").unwrap().size, 0);
+    assert_eq!(fs.write("/file", 0, b"data"), Ok(4));
+    assert_eq!(fault::injected(Site::Heap), 1);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::errno::Errno;
+use rust_os::fd::{
+    self, Fd, Whence, MAX_FDS, O_APPEND, O_CREAT, O_EXCL, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
+};
+use rust_os::tmpfs::TmpFs;
+use rust_os::vfs;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    vfs::mount("/", Arc::new(TmpFs::new(4096))).expect("mount failed");
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+
+#[test_case]
+fn read_write_seek() {
+    let fd = fd::open("/file", O_RDWR | O_CREAT).unwrap();
+    assert_eq!(fd::write(fd, b"hello world"), Ok(11));
+    assert_eq!(fd::seek(fd, 0, Whence::Set), Ok(0));
+    let mut buf = [0; 5];
+    assert_eq!(fd::read(fd, &mut buf), Ok(5));
+    assert_eq!(&buf, b"hello");
+
+    // Relative to the current offset and to the end
+    assert_eq!(fd::seek(fd, 1, Whence::Current), Ok(6));
+    assert_eq!(fd::read(fd, &mut buf), Ok(5));
+    assert_eq!(&buf, b"world");
+    assert_eq!(fd::read(fd, &mut buf), Ok(0));
+    assert_eq!(fd::seek(fd, -5, Whence::End), Ok(6));
+    assert_eq!(fd::write(fd, b"there"), Ok(5));
+    assert_eq!(fd::seek(fd, -1, Whence::Set), Err(Errno::EINVAL));
+    fd::close(fd).unwrap();
+    assert_eq!(vfs::read_file("/file").unwrap(), b"hello there");
+
+    // Appending, then truncating
+    let fd = fd::open("/file", O_WRONLY | O_APPEND).unwrap();
+    assert_eq!(fd::write(fd, b"!"), Ok(1));
+    fd::close(fd).unwrap();
+    assert_eq!(vfs::read_file("/file").unwrap(), b"hello there!");
+    let fd = fd::open("/file", O_WRONLY | O_TRUNC).unwrap();
+    assert_eq!(vfs::metadata("/file").unwrap().size, 0);
+    fd::close(fd).unwrap();
+    vfs::remove("/file").unwrap();
+}
+
+#[test_case]
+fn errors() {
+    assert_eq!(fd::open("/missing", O_RDONLY), Err(Errno::ENOENT));
+    assert_eq!(fd::open("relative", O_RDONLY), Err(Errno::EINVAL));
+    assert_eq!(fd::open("/", O_WRONLY), Err(Errno::EISDIR));
+    assert_eq!(fd::open("/file", O_WRONLY | O_RDWR), Err(Errno::EINVAL));
+
+    let fd = fd::open("/file", O_WRONLY | O_CREAT | O_EXCL).unwrap();
+    assert_eq!(fd::open("/file", O_RDONLY | O_CREAT | O_EXCL), Err(Errno::EEXIST));
+    let mut buf = [0; 4];
+    assert_eq!(fd::read(fd, &mut buf), Err(Errno::EBADF));
+    // The filesystem holds 4096 bytes
+    assert_eq!(fd::write(fd, &[1; 5000]), Ok(4096));
+    assert_eq!(fd::write(fd, &[1; 10]), Err(Errno::ENOSPC));
+    fd::close(fd).unwrap();
+    assert_eq!(fd::close(fd), Err(Errno::EBADF));
+    assert_eq!(fd::read(Fd(1000), &mut buf), Err(Errno::EBADF));
+
+    // Numbers are reused, lowest first, up to the limit
+    let fds: Vec<Fd> = (0..MAX_FDS).map(|_| fd::open("/file", O_RDONLY).unwrap()).collect();
+    assert_eq!(fd::open("/file", O_RDONLY), Err(Errno::EMFILE));
+    fd::close(fds[3]).unwrap();
+    assert_eq!(fd::open("/file", O_RDONLY), Ok(fds[3]));
+    for fd in fds {
+        fd::close(fd).unwrap();
+    }
+    assert_eq!(fd::open_count(), 0);
+    vfs::remove("/file").unwrap();
+}
@@ -20,7 +20,8 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     rust_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
     let mut frame_allocator = unsafe {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
@@ -54,6 +55,32 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
+#[test_case]
+fn heap_translation() {
+    use rust_os::memory::{self, PageSize};
+    use x86_64::structures::paging::PageTableFlags;
+    use x86_64::VirtAddr;
+
+    let value = Box::new(41);
+    let translation = memory::translate(VirtAddr::from_ptr(&*value)).expect("heap not mapped");
+    assert_eq!(translation.page_size, PageSize::Size4KiB);
+    assert!(translation.flags.contains(PageTableFlags::WRITABLE));
+    assert!(!translation.flags.contains(PageTableFlags::USER_ACCESSIBLE));
+}
+
+#[test_case]
+fn heap_refused_as_user_memory() {
+    use rust_os::usercopy::{self, UserCopyError};
+
+    let value = Box::new([7u8; 16]);
+    let address = value.as_ptr() as u64;
+    assert!(address < usercopy::USER_SPACE_END);
+    let mut buffer = [0u8; 16];
+    assert_eq!(usercopy::copy_from_user(&mut buffer, address), Err(UserCopyError::BadAddress));
+    assert_eq!(usercopy::copy_to_user(address, &buffer), Err(UserCopyError::BadAddress));
+    assert_eq!(*value, [7; 16]);
+}
+
 #[test_case]
 fn many_boxes() {
     for i in 0..HEAP_SIZE {
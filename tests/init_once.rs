@@ -0,0 +1,49 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::{self, HeapError};
+use rust_os::memory::{self, AlreadyInitialized, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+extern crate alloc;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("init_once::memory_init_twice...\t");
+    let second = unsafe { memory::init(phys_mem_offset) };
+    assert_eq!(second.err(), Some(AlreadyInitialized));
+    serial_println!("[ok]");
+
+    serial_print!("init_once::init_heap_twice...\t");
+    let second = allocator::init_heap(&mut mapper, &mut frame_allocator);
+    assert!(matches!(second, Err(HeapError::AlreadyInitialized)));
+    // The heap set up by the first call still works
+    let value = alloc::boxed::Box::new(42);
+    assert_eq!(*value, 42);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
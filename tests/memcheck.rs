@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{gdt, memcheck};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn corruptions() -> u64 {
+    memcheck::stats().1
+}
+
+#[test_case]
+fn write_past_allocation() {
+    let before = corruptions();
+    let block = Box::new([0u8; 64]);
+    memcheck::check_all();
+    assert_eq!(corruptions(), before);
+
+    // Everything above `block` is poisoned now
+    unsafe { (block.as_ptr() as *mut u8).add(100).write(0) };
+    memcheck::check_all();
+    assert_eq!(corruptions(), before + 1);
+
+    // The poison was restored, so the write isn't reported again
+    memcheck::check_all();
+    assert_eq!(corruptions(), before + 1);
+}
+
+#[test_case]
+fn stack_overflow_canary() {
+    let before = corruptions();
+    let bottom = gdt::privilege_stack_top() - gdt::PRIVILEGE_STACK_SIZE as u64;
+    unsafe { bottom.as_mut_ptr::<u8>().write(0) };
+    memcheck::check_all();
+    assert_eq!(corruptions(), before + 1);
+    assert_eq!(gdt::check_stack_canaries(), None);
+}
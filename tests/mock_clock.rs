@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use rust_os::time::{self, clock, Timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Records whether it was woken
+struct Flag(AtomicBool);
+
+impl Wake for Flag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+#[test_case]
+fn timer_expires_on_advance() {
+    let _clock = clock::mock();
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
+
+    let mut timer = Timer::after_ms(10);
+    assert_eq!(Pin::new(&mut timer).poll(&mut context), Poll::Pending);
+
+    clock::advance_ms(9);
+    assert!(!flag.0.load(Ordering::SeqCst));
+    clock::advance_ms(1);
+    assert!(flag.0.load(Ordering::SeqCst));
+    assert_eq!(Pin::new(&mut timer).poll(&mut context), Poll::Ready(()));
+}
+
+#[test_case]
+fn ticks_dont_move_mocked_clock() {
+    let _clock = clock::mock();
+    let start = time::uptime_ms();
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 2 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(time::uptime_ms(), start);
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, BootInfoFrameAllocator, Frames};
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// An address in a level 4 entry nothing else uses
+const TEST_ADDRESS: u64 = 0x_5555_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+
+    let start = Page::containing_address(VirtAddr::new(TEST_ADDRESS));
+    let pages = Page::range_inclusive(start, start + 3);
+    let flags = PageTableFlags::WRITABLE;
+    let tables = || memory::page_table_frames(Cr3::read().0);
+
+    serial_print!("page_tables::map_range...\t");
+    let before = tables();
+    unsafe { memory::map_range(&mut mapper, pages, Frames::Allocate, flags, &mut frame_allocator) }
+        .expect("map_range failed");
+    // New level 3, 2 and 1 tables
+    assert_eq!(tables(), before + 3);
+    let ptr: *mut u64 = (start + 3).start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(42) };
+    assert_eq!(unsafe { ptr.read_volatile() }, 42);
+    serial_println!("[ok]");
+
+    serial_print!("page_tables::unmap_range...\t");
+    let mapped = memory::virt_to_phys(start.start_address()).expect("page not mapped");
+    unsafe { memory::unmap_range(&mut mapper, pages, true, &mut frame_allocator) }
+        .expect("unmap_range failed");
+    assert_eq!(memory::virt_to_phys(start.start_address()), None);
+    // The level 1 and 2 tables are freed, the level 3 table is kept
+    assert_eq!(tables(), before + 1);
+    serial_println!("[ok]");
+
+    serial_print!("page_tables::frames_reused...\t");
+    // Freed frames are handed out again, the last freed one first
+    let frame = frame_allocator.allocate_frame().expect("out of frames");
+    assert_ne!(frame.start_address(), mapped);
+    let mut reused = false;
+    for _ in 0..6 {
+        let frame = frame_allocator.allocate_frame().expect("out of frames");
+        reused |= frame.start_address() == mapped;
+    }
+    assert!(reused);
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
@@ -0,0 +1,171 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use rust_os::partition::{self, Guid, Kind, Partition, PartitionError, Scheme};
+use rust_os::ramdisk::RamDisk;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const DISK_BLOCKS: u64 = 64;
+
+// The GPT layout of the test disks: one block of 4 entries on each side
+const ENTRY_COUNT: u32 = 4;
+const ENTRY_SIZE: usize = 128;
+const LINUX_DATA: [u8; 16] = [
+    0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D,
+    0xE4,
+];
+
+// Write an MBR entry to sector 0
+fn write_mbr_entry(disk: &RamDisk, index: usize, kind: u8, start: u32, count: u32) {
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.read_blocks(0, &mut sector).unwrap();
+    let entry = &mut sector[446 + index * 16..][..16];
+    entry[4] = kind;
+    entry[8..12].copy_from_slice(&start.to_le_bytes());
+    entry[12..16].copy_from_slice(&count.to_le_bytes());
+    sector[510] = 0x55;
+    sector[511] = 0xAA;
+    disk.write_blocks(0, &sector).unwrap();
+}
+
+// Write a GPT header to `lba`, with the entries at `entries_lba`
+fn write_gpt_header(disk: &RamDisk, lba: u64, entries_lba: u64, entries_crc: u32) {
+    let mut header = [0u8; SECTOR_SIZE];
+    header[..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&92u32.to_le_bytes());
+    header[24..32].copy_from_slice(&lba.to_le_bytes());
+    // The other copy of the header
+    header[32..40].copy_from_slice(&(DISK_BLOCKS - lba).to_le_bytes());
+    header[40..48].copy_from_slice(&3u64.to_le_bytes());
+    header[48..56].copy_from_slice(&(DISK_BLOCKS - 3).to_le_bytes());
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&ENTRY_COUNT.to_le_bytes());
+    header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+    let crc = partition::crc32(&header[..92]);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    disk.write_blocks(lba, &header).unwrap();
+}
+
+// A disk with a GPT holding one partition at the blocks 8 to 15
+fn gpt_disk() -> RamDisk {
+    let disk = RamDisk::new(DISK_BLOCKS);
+    write_mbr_entry(&disk, 0, 0xEE, 1, DISK_BLOCKS as u32 - 1);
+
+    let mut entries = [0u8; SECTOR_SIZE];
+    entries[..16].copy_from_slice(&LINUX_DATA);
+    entries[16] = 1; // The unique GUID
+    entries[32..40].copy_from_slice(&8u64.to_le_bytes());
+    entries[40..48].copy_from_slice(&15u64.to_le_bytes());
+    let crc = partition::crc32(&entries);
+    disk.write_blocks(2, &entries).unwrap();
+    disk.write_blocks(DISK_BLOCKS - 2, &entries).unwrap();
+
+    write_gpt_header(&disk, 1, 2, crc);
+    write_gpt_header(&disk, DISK_BLOCKS - 1, DISK_BLOCKS - 2, crc);
+    disk
+}
+
+#[test_case]
+fn no_table() {
+    let disk = RamDisk::new(DISK_BLOCKS);
+    assert_eq!(partition::read_table(&disk), Ok(None));
+}
+
+#[test_case]
+fn mbr_partitions() {
+    let disk = RamDisk::new(DISK_BLOCKS);
+    write_mbr_entry(&disk, 0, 0x83, 4, 16);
+    write_mbr_entry(&disk, 1, 0x0F, 20, 8); // Extended, skipped
+    write_mbr_entry(&disk, 3, 0x0C, 32, 64); // Past the end, skipped
+
+    let table = partition::read_table(&disk).unwrap().unwrap();
+    assert_eq!(table.scheme, Scheme::Mbr);
+    assert_eq!(table.partitions.len(), 1);
+    let info = table.partitions[0];
+    assert_eq!((info.number, info.start, info.count), (1, 4, 16));
+    assert_eq!(info.kind, Kind::Mbr(0x83));
+}
+
+#[test_case]
+fn gpt_partitions() {
+    let table = partition::read_table(&gpt_disk()).unwrap().unwrap();
+    assert_eq!(table.scheme, Scheme::Gpt);
+    assert_eq!(table.partitions.len(), 1);
+    let info = table.partitions[0];
+    assert_eq!((info.number, info.start, info.count), (1, 8, 8));
+    assert_eq!(info.kind, Kind::Gpt(Guid(LINUX_DATA)));
+    assert_eq!(
+        alloc::format!("{}", Guid(LINUX_DATA)),
+        "0fc63daf-8483-4772-8e79-3d69d8477de4"
+    );
+}
+
+#[test_case]
+fn gpt_backup() {
+    let disk = gpt_disk();
+    // Break the primary entry array, then the backup header as well
+    let mut block = [0u8; SECTOR_SIZE];
+    disk.read_blocks(2, &mut block).unwrap();
+    block[32] ^= 1;
+    disk.write_blocks(2, &block).unwrap();
+    let table = partition::read_table(&disk).unwrap().unwrap();
+    assert_eq!(table.partitions[0].start, 8);
+
+    disk.read_blocks(DISK_BLOCKS - 1, &mut block).unwrap();
+    block[40] ^= 1;
+    disk.write_blocks(DISK_BLOCKS - 1, &block).unwrap();
+    assert_eq!(partition::read_table(&disk), Err(PartitionError::BadChecksum));
+}
+
+#[test_case]
+fn partition_device() {
+    let disk = Arc::new(RamDisk::new(DISK_BLOCKS));
+    write_mbr_entry(&disk, 0, 0x83, 4, 16);
+    let table = partition::read_table(&*disk).unwrap().unwrap();
+    let part = Partition::new(disk.clone(), &table.partitions[0]);
+    assert_eq!(part.block_count(), 16);
+
+    let block = [0x5A; SECTOR_SIZE];
+    part.write_blocks(15, &block).unwrap();
+    let mut read = [0u8; SECTOR_SIZE];
+    disk.read_blocks(19, &mut read).unwrap();
+    assert_eq!(read, block);
+    assert_eq!(part.read_blocks(16, &mut read), Err(BlockError::OutOfRange));
+    assert_eq!(part.read_blocks(15, &mut [0; 2 * SECTOR_SIZE]), Err(BlockError::OutOfRange));
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::elf::ElfError;
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::process::{self, Process};
+use rust_os::tmpfs::TmpFs;
+use rust_os::{allocator, exit_qemu, serial_print, serial_println, vfs, QemuExitCode};
+use x86_64::VirtAddr;
+
+mod common;
+
+entry_point!(main);
+
+// Where the program is linked
+const BASE: u64 = 0x2000_0000_0000;
+
+// Exit with the PID plus argc
+#[rustfmt::skip]
+const CODE: [u8; 21] = [
+    0xB8, 0x27, 0x00, 0x00, 0x00,             // mov eax, SYS_GETPID
+    0x0F, 0x05,                               // syscall
+    0x48, 0x89, 0xC7,                         // mov rdi, rax
+    0x48, 0x03, 0x3C, 0x24,                   // add rdi, [rsp]
+    0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov eax, SYS_EXIT
+    0x0F, 0x05,                               // syscall
+];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("process::Process...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    vfs::mount("/", Arc::new(TmpFs::new(4096))).expect("mount failed");
+    vfs::write_file("/prog", &common::executable(BASE, &CODE, &[])).unwrap();
+    let in_use = memory::frame_stats().in_use();
+
+    // Both processes map the program at the same address, each in its own
+    // address space, and none of it shows in the kernel's
+    let mut first = unsafe { Process::create_from_elf("/prog", &["a"], &mut frame_allocator) }
+        .unwrap();
+    let mut second =
+        unsafe { Process::create_from_elf("/prog", &["a", "b"], &mut frame_allocator) }.unwrap();
+    assert_ne!(first.pid(), second.pid());
+    assert_eq!(first.threads().len(), 1);
+    assert!(memory::translate(VirtAddr::new(BASE)).is_none());
+    assert_eq!(first.files().open_count(), 0);
+
+    assert_eq!(unsafe { second.run() }, second.pid().0 + 2);
+    assert_eq!(unsafe { first.run() }, first.pid().0 + 1);
+    assert_eq!(process::current(), None);
+    assert!(memory::translate(VirtAddr::new(BASE)).is_none());
+
+    assert!(unsafe { first.destroy(&mut frame_allocator) } > 0);
+    assert!(unsafe { second.destroy(&mut frame_allocator) } > 0);
+    assert_eq!(memory::frame_stats().in_use(), in_use);
+
+    // A failed creation leaves nothing behind
+    let result = unsafe { Process::create_from_elf("/missing", &[], &mut frame_allocator) };
+    assert!(matches!(result, Err(ElfError::Io(vfs::VfsError::NotFound))));
+    assert_eq!(memory::frame_stats().in_use(), in_use);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator;
+use rust_os::block::{BlockDevice, BlockError, SECTOR_SIZE};
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::ramdisk::RamDisk;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    serial_print!("ramdisk::heap_disk...\t");
+    check_disk(&RamDisk::new(16));
+    serial_println!("[ok]");
+
+    serial_print!("ramdisk::frame_disk...\t");
+    let disk = RamDisk::with_frames(32, &mut frame_allocator).expect("out of frames");
+    check_disk(&disk);
+    serial_println!("[ok]");
+
+    serial_print!("ramdisk::image...\t");
+    let disk = RamDisk::from_image(b"boot sector").read_only();
+    let mut block = [0xAA; SECTOR_SIZE];
+    assert_eq!(disk.block_count(), 1);
+    disk.read_blocks(0, &mut block).expect("read failed");
+    assert_eq!(&block[..11], b"boot sector");
+    assert!(block[11..].iter().all(|&byte| byte == 0));
+    assert_eq!(disk.write_blocks(0, &block), Err(BlockError::ReadOnly));
+    serial_println!("[ok]");
+
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+// Write a pattern across the middle of the disk, including the boundary
+// between the blocks 7 and 8, which lie in different frames of a frame
+// backed disk, and read it back
+fn check_disk(disk: &RamDisk) {
+    let mut pattern = [0u8; 4 * SECTOR_SIZE];
+    for (i, byte) in pattern.iter_mut().enumerate() {
+        *byte = (i % 251) as u8;
+    }
+    disk.write_blocks(6, &pattern).expect("write failed");
+
+    let mut read = [0u8; 4 * SECTOR_SIZE];
+    disk.read_blocks(6, &mut read).expect("read failed");
+    assert_eq!(read, pattern);
+    disk.read_blocks(5, &mut read[..SECTOR_SIZE]).expect("read failed");
+    assert!(read[..SECTOR_SIZE].iter().all(|&byte| byte == 0));
+
+    let last = disk.block_count() - 1;
+    assert_eq!(disk.read_blocks(last, &mut read[..2 * SECTOR_SIZE]), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read_blocks(0, &mut read[..100]), Err(BlockError::BadBufferSize));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    serial_println!("[failed]");
+    serial_println!("Error: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
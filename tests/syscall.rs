@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, usermode, QemuExitCode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Where the user code and its stack are mapped
+const CODE: u64 = 0x1000_0000_0000;
+const STACK: u64 = 0x1000_0001_0000;
+
+// write(1, message, 6), then exit with the result
+#[rustfmt::skip]
+const PROGRAM: [u8; 39] = [
+    0xB8, 0x01, 0x00, 0x00, 0x00,             // mov eax, SYS_WRITE
+    0xBF, 0x01, 0x00, 0x00, 0x00,             // mov edi, 1
+    0x48, 0x8D, 0x35, 0x10, 0x00, 0x00, 0x00, // lea rsi, [rip + 16]
+    0xBA, 0x06, 0x00, 0x00, 0x00,             // mov edx, 6
+    0x0F, 0x05,                               // syscall
+    0x89, 0xC7,                               // mov edi, eax
+    0xB8, 0x3C, 0x00, 0x00, 0x00,             // mov eax, SYS_EXIT
+    0x0F, 0x05,                               // syscall
+    b'h', b'e', b'l', b'l', b'o', b'\n',      // The message
+];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("syscall::write_and_exit...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK));
+    let flags = PageTableFlags::WRITABLE;
+    unsafe {
+        let pages = Page::range_inclusive(code, code);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the code failed");
+        let pages = Page::range_inclusive(stack, stack);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the stack failed");
+        (CODE as *mut [u8; 39]).write(PROGRAM);
+    }
+
+    // The kernel carries on after the program exits, and can run it again
+    for _ in 0..2 {
+        let status = unsafe { usermode::run(VirtAddr::new(CODE), VirtAddr::new(STACK + 4096)) };
+        assert_eq!(status, 6);
+    }
+    assert!(x86_64::instructions::interrupts::are_enabled());
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::tmpfs::TmpFs;
+use rust_os::vfs::{self, FileSystem, FileType, VfsError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn names(path: &str) -> Vec<alloc::string::String> {
+    let mut names: Vec<_> = vfs::read_dir(path).unwrap().into_iter().map(|e| e.name).collect();
+    names.sort();
+    names
+}
+
+#[test_case]
+fn files_and_directories() {
+    rust_os::tmpfs::init();
+    vfs::create_dir("/tmp/dir").unwrap();
+    vfs::write_file("/tmp/dir/a.txt", b"first").unwrap();
+    vfs::create("/tmp/b").unwrap();
+    assert_eq!(names("/tmp"), ["b", "dir"]);
+    assert_eq!(vfs::read_file("/tmp/dir/a.txt").unwrap(), b"first");
+    assert_eq!(vfs::metadata("/tmp/b").unwrap().size, 0);
+    assert_eq!(vfs::metadata("/tmp/dir").unwrap().file_type, FileType::Directory);
+
+    // Writing in the middle, then creating again empties the file
+    assert_eq!(vfs::write("/tmp/dir/a.txt", 2, b"RST!"), Ok(4));
+    assert_eq!(vfs::read_file("/tmp/dir/a.txt").unwrap(), b"fiRST!");
+    vfs::create("/tmp/dir/a.txt").unwrap();
+    assert_eq!(vfs::read_file("/tmp/dir/a.txt").unwrap(), b"");
+
+    assert_eq!(vfs::create_dir("/tmp/dir"), Err(VfsError::Exists));
+    assert_eq!(vfs::create("/tmp/dir"), Err(VfsError::IsADirectory));
+    assert_eq!(vfs::create("/tmp/b/c"), Err(VfsError::NotADirectory));
+    assert_eq!(vfs::create("/tmp/missing/c"), Err(VfsError::NotFound));
+    assert_eq!(vfs::remove("/tmp/dir"), Err(VfsError::NotEmpty));
+    assert_eq!(vfs::remove("/tmp"), Err(VfsError::Busy));
+
+    vfs::remove("/tmp/dir/a.txt").unwrap();
+    vfs::remove("/tmp/dir").unwrap();
+    vfs::remove("/tmp/b").unwrap();
+    assert!(names("/tmp").is_empty());
+    // Listed in "/" although nothing is mounted there
+    assert_eq!(names("/"), ["tmp"]);
+    vfs::unmount("/tmp").unwrap();
+}
+
+#[test_case]
+fn sparse_and_large_files() {
+    let fs = TmpFs::new(4096);
+    fs.create("/f").unwrap();
+    // A gap is read as zeros
+    assert_eq!(fs.write("/f", 1000, b"end"), Ok(3));
+    let mut buf = [0xFF; 1003];
+    assert_eq!(fs.read("/f", 0, &mut buf), Ok(1003));
+    assert!(buf[..1000].iter().all(|&byte| byte == 0));
+    assert_eq!(&buf[1000..], b"end");
+    assert_eq!(fs.read("/f", 1003, &mut buf), Ok(0));
+
+    // Across block boundaries
+    let data: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+    assert_eq!(fs.write("/f", 100, &data), Ok(1500));
+    let mut read = [0; 1500];
+    assert_eq!(fs.read("/f", 100, &mut read), Ok(1500));
+    assert_eq!(&read[..], &data[..]);
+}
+
+#[test_case]
+fn capacity() {
+    let fs = TmpFs::new(2048);
+    fs.create("/a").unwrap();
+    fs.create("/b").unwrap();
+    assert_eq!(fs.write("/a", 0, &[1; 1500]), Ok(1500));
+    assert_eq!(fs.available(), 512);
+    // Only what fits in the last block is written
+    assert_eq!(fs.write("/b", 0, &[2; 1000]), Ok(512));
+    assert_eq!(fs.write("/b", 512, &[2; 10]), Ok(0));
+    assert_eq!(fs.write("/b", u64::MAX, &[2; 10]), Ok(0));
+    assert_eq!(fs.metadata("/b").unwrap().size, 512);
+
+    // Removing and emptying files frees their blocks
+    fs.remove("/a").unwrap();
+    assert_eq!(fs.available(), 1536);
+    fs.create("/b").unwrap();
+    assert_eq!(fs.available(), 2048);
+    assert_eq!(fs.metadata("/a"), Err(VfsError::NotFound));
+}
+
+// Last, since it leaves little of the heap
+#[test_case]
+fn heap_exhaustion() {
+    use alloc::boxed::Box;
+    use rust_os::allocator::{self, HEAP_SIZE};
+    use rust_os::tmpfs::HEAP_RESERVE;
+
+    // Larger than the heap, so the heap runs out first
+    let fs = TmpFs::new(2 * HEAP_SIZE as u64);
+    fs.create("/f").unwrap();
+    fs.create("/early").unwrap();
+    assert_eq!(fs.write("/early", 0, &[1; 4096]), Ok(4096));
+    let data = [3; 4096];
+    let mut offset = 0;
+    let err = loop {
+        match fs.write("/f", offset, &data) {
+            Ok(len) => offset += len as u64,
+            Err(err) => break err,
+        }
+    };
+    assert_eq!(err, VfsError::NoSpace);
+    assert!(offset > 0 && offset < HEAP_SIZE as u64);
+    assert!(allocator::available() >= HEAP_RESERVE);
+
+    // Freeing blocks while others live doesn't give the bump allocator
+    // anything back, so the reserve still stops further writes
+    fs.remove("/early").unwrap();
+    assert_eq!(fs.write("/f", offset, &data), Err(VfsError::NoSpace));
+    assert!(allocator::available() >= HEAP_RESERVE);
+
+    // What was written is still there, and the kernel can still allocate
+    let mut buf = [0; 16];
+    assert_eq!(fs.read("/f", offset - 16, &mut buf), Ok(16));
+    assert_eq!(buf, [3; 16]);
+    let other = Box::new([0u8; 4096]);
+    assert_eq!(other.len(), 4096);
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, serial_print, serial_println, usermode, QemuExitCode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Where the user code and its stack are mapped
+const CODE: u64 = 0x1000_0000_0000;
+const STACK: u64 = 0x1000_0001_0000;
+
+// Programs that fault, each at its own offset into the code page
+const UD2: &[u8] = &[0x0F, 0x0B];
+// mov byte [0], 1
+const WRITE_NULL: &[u8] = &[0xC6, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00, 0x01];
+// xor ecx, ecx; div ecx
+const DIVIDE_BY_ZERO: &[u8] = &[0x31, 0xC9, 0xF7, 0xF1];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("usermode::kill_on_fault...\t");
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK));
+    let flags = PageTableFlags::WRITABLE;
+    unsafe {
+        let pages = Page::range_inclusive(code, code);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the code failed");
+        let pages = Page::range_inclusive(stack, stack);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the stack failed");
+    }
+
+    // Each program is killed with a status, and the kernel carries on
+    let programs = [
+        (UD2, usermode::SIGILL),
+        (WRITE_NULL, usermode::SIGSEGV),
+        (DIVIDE_BY_ZERO, usermode::SIGFPE),
+    ];
+    for (i, &(program, signal)) in programs.iter().enumerate() {
+        let entry = CODE + 0x100 * i as u64;
+        unsafe {
+            core::slice::from_raw_parts_mut(entry as *mut u8, program.len())
+                .copy_from_slice(program);
+        }
+        let status = unsafe { usermode::run(VirtAddr::new(entry), VirtAddr::new(STACK + 4096)) };
+        assert_eq!(status, 128 + signal);
+    }
+    assert!(!usermode::is_running());
+    assert!(x86_64::instructions::interrupts::are_enabled());
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
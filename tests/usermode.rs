@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::memory::{self, BootInfoFrameAllocator};
+use rust_os::{exit_qemu, gdt, serial_print, serial_println, usermode, QemuExitCode};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PrivilegeLevel, VirtAddr};
+
+entry_point!(main);
+
+// Where the user code and its stack are mapped
+const CODE: u64 = 0x1000_0000_0000;
+const STACK: u64 = 0x1000_0001_0000;
+
+// `int3`, then `jmp $`
+const PROGRAM: [u8; 3] = [0xCC, 0xEB, 0xFE];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("usermode::int3_from_ring_3...\t");
+
+    gdt::init();
+    TEST_IDT.load();
+    // Mask every PIC line, since entering ring 3 enables interrupts
+    unsafe {
+        Port::<u8>::new(0x21).write(0xFF);
+        Port::<u8>::new(0xA1).write(0xFF);
+    }
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    let code = Page::containing_address(VirtAddr::new(CODE));
+    let stack = Page::containing_address(VirtAddr::new(STACK));
+    let flags = PageTableFlags::WRITABLE;
+    unsafe {
+        let pages = Page::range_inclusive(code, code);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the code failed");
+        let pages = Page::range_inclusive(stack, stack);
+        usermode::map_pages(&mut mapper, pages, flags, &mut frame_allocator)
+            .expect("mapping the stack failed");
+        (CODE as *mut [u8; 3]).write(PROGRAM);
+        usermode::enter(VirtAddr::new(CODE), VirtAddr::new(STACK + 4096));
+    }
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.breakpoint
+            .set_handler_fn(breakpoint_handler)
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    // The program ran in ring 3, on its own stack
+    let selectors = gdt::selectors();
+    assert_eq!(stack_frame.code_segment, selectors.user_code_selector.0 as u64);
+    assert_eq!(stack_frame.stack_segment, selectors.user_data_selector.0 as u64);
+    assert_eq!(stack_frame.instruction_pointer.as_u64(), CODE + 1);
+    assert_eq!(stack_frame.stack_pointer.as_u64(), STACK + 4096);
+
+    // The handler runs on the stack from the TSS
+    let local = 0u8;
+    let address = VirtAddr::from_ptr(&local);
+    let top = gdt::privilege_stack_top();
+    assert!(address < top && address > top - gdt::PRIVILEGE_STACK_SIZE as u64);
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    panic!("general protection fault {:#x}\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    panic!("page fault {:?}\n{:#?}", error_code, stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    panic!("double fault\n{:#?}", stack_frame);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,124 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::initrd::InitrdFs;
+use rust_os::vfs::{self, FileType, VfsError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) }
+        .expect("memory initialization failed");
+    let mut frame_allocator = unsafe {
+        BootInfoFrameAllocator::init(&boot_info.memory_map)
+    };
+    allocator::init_heap(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// Append a newc cpio entry
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let fields = [0, mode, 0, 0, 1, 0, data.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08X}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize((archive.len() + 3) & !3, 0);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+// An archive with a file in a directory it doesn't list, a directory with
+// a file and a symbolic link
+fn archive() -> &'static [u8] {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, "etc/motd", 0o100644, b"hello\n");
+    cpio_entry(&mut archive, "./bin", 0o040755, b"");
+    cpio_entry(&mut archive, "bin/sh", 0o100755, b"#!");
+    cpio_entry(&mut archive, "etc/issue", 0o120777, b"motd");
+    cpio_entry(&mut archive, "TRAILER!!!", 0, b"");
+    archive.leak()
+}
+
+#[test_case]
+fn normalize() {
+    assert_eq!(vfs::normalize("/a/./b//../c/").as_deref(), Ok("/a/c"));
+    assert_eq!(vfs::normalize("/../..").as_deref(), Ok("/"));
+    assert_eq!(vfs::normalize("a/b"), Err(VfsError::BadPath));
+    assert_eq!(vfs::split_parent("/a/b"), ("/a", "b"));
+    assert_eq!(vfs::split_parent("/a"), ("/", "a"));
+}
+
+#[test_case]
+fn initrd_fs() {
+    let fs = InitrdFs::new(archive()).unwrap();
+    assert_eq!(fs.entry_count(), 5);
+    vfs::mount("/", Arc::new(fs)).unwrap();
+
+    assert_eq!(vfs::read_file("/etc/../etc/motd").unwrap(), b"hello\n");
+    let mut buf = [0; 4];
+    assert_eq!(vfs::read("/etc/motd", 4, &mut buf), Ok(2));
+    assert_eq!(&buf[..2], b"o\n");
+    assert_eq!(vfs::metadata("/bin/sh").unwrap().mode, 0o755);
+    assert!(vfs::metadata("/etc").unwrap().is_dir());
+
+    let mut names: Vec<_> = vfs::read_dir("/").unwrap().into_iter().map(|e| e.name).collect();
+    names.sort();
+    assert_eq!(names, ["bin", "etc"]);
+    let link = vfs::read_dir("/etc").unwrap().into_iter().find(|e| e.name == "issue").unwrap();
+    assert_eq!(link.metadata.file_type, FileType::Symlink);
+
+    assert_eq!(vfs::read("/etc/issue", 0, &mut buf), Err(VfsError::Unsupported));
+    assert_eq!(vfs::read_file("/etc"), Err(VfsError::IsADirectory));
+    assert_eq!(vfs::read_dir("/etc/motd"), Err(VfsError::NotADirectory));
+    assert_eq!(vfs::metadata("/etc/missing"), Err(VfsError::NotFound));
+    let other = Arc::new(InitrdFs::new(archive()).unwrap());
+    assert_eq!(vfs::mount("/", other), Err(VfsError::Exists));
+    vfs::unmount("/").unwrap();
+}
+
+#[test_case]
+fn mount_points() {
+    vfs::mount("/", Arc::new(InitrdFs::new(archive()).unwrap())).unwrap();
+    vfs::mount("/disk", Arc::new(InitrdFs::new(archive()).unwrap())).unwrap();
+    assert_eq!(vfs::read_file("/disk/etc/motd").unwrap(), b"hello\n");
+    assert_eq!(vfs::read_file("/disk/../etc/motd").unwrap(), b"hello\n");
+
+    // The mount point is listed in the root, which has no "disk" itself
+    let mut names: Vec<_> = vfs::read_dir("/").unwrap().into_iter().map(|e| e.name).collect();
+    names.sort();
+    assert_eq!(names, ["bin", "disk", "etc"]);
+
+    // Without a root, only the paths below the other mount point are there
+    vfs::unmount("/").unwrap();
+    assert_eq!(vfs::metadata("/etc"), Err(VfsError::NotFound));
+    assert_eq!(vfs::read_dir("/disk").unwrap().len(), 2);
+    vfs::unmount("/disk").unwrap();
+    assert_eq!(vfs::unmount("/disk"), Err(VfsError::NotFound));
+}